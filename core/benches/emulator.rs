@@ -0,0 +1,111 @@
+// Throughput of the cpu, gpu and apu, run with "cargo bench --features audio".
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use core::apu::APU;
+use core::bus::MemoryBus;
+use core::cartridge::from_rom;
+use core::cpu::CPU;
+
+const FRAME_CYCLES: u32 = 70_224;
+
+const LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// 32KB rom only cartridge of NOPs, jumping from the end of the rom back to 0x150.
+const NOP_ROM: &[u8] = &nop_rom();
+
+const fn nop_rom() -> [u8; 0x8000] {
+    let mut rom = [0; 0x8000];
+    // Entry point, NOP then JP 0x150.
+    rom[0x101] = 0xC3;
+    rom[0x102] = 0x50;
+    rom[0x103] = 0x01;
+    let mut i = 0;
+    while i < LOGO.len() {
+        rom[0x104 + i] = LOGO[i];
+        i += 1;
+    }
+    rom[0x7FFD] = 0xC3;
+    rom[0x7FFE] = 0x50;
+    rom[0x7FFF] = 0x01;
+
+    // Header checksum over 0x134-0x14C.
+    let mut checksum: u8 = 0;
+    let mut i = 0x134;
+    while i < 0x14D {
+        checksum = checksum.wrapping_sub(rom[i]).wrapping_sub(1);
+        i += 1;
+    }
+    rom[0x14D] = checksum;
+
+    // Global checksum of every other byte, big endian.
+    let mut sum: u16 = 0;
+    let mut i = 0;
+    while i < rom.len() {
+        if i != 0x14E && i != 0x14F {
+            sum = sum.wrapping_add(rom[i] as u16);
+        }
+        i += 1;
+    }
+    rom[0x14E] = (sum >> 8) as u8;
+    rom[0x14F] = sum as u8;
+    rom
+}
+
+// Rewind is off so frames aren't also timing a save state each.
+fn nop_cpu() -> CPU {
+    let mut cpu = CPU::new(from_rom(NOP_ROM.to_vec()).unwrap(), None);
+    cpu.set_rewind_frames(0);
+    cpu
+}
+
+fn cpu(c: &mut Criterion) {
+    let mut cpu = nop_cpu();
+    c.bench_function("cpu 1M ticks", |b| b.iter(|| {
+        for _ in 0..1_000_000 {
+            black_box(cpu.tick());
+        }
+    }));
+}
+
+// A second of frames, with the cpu and the rest of the system running alongside.
+fn gpu(c: &mut Criterion) {
+    let mut cpu = nop_cpu();
+    let mut group = c.benchmark_group("gpu");
+    group.sample_size(10);
+    group.bench_function("60 frames", |b| b.iter(|| {
+        black_box(cpu.run_for_frames(60));
+        black_box(&cpu.mem.gpu.pixels);
+    }));
+    group.finish();
+}
+
+// A second of a square wave on channel 1.
+fn apu(c: &mut Criterion) {
+    let mut apu = APU::power_up(48_000);
+    apu.write_byte(0xFF26, 0x80);
+    apu.write_byte(0xFF25, 0xFF);
+    apu.write_byte(0xFF24, 0x77);
+    apu.write_byte(0xFF11, 0x80);
+    apu.write_byte(0xFF12, 0xF0);
+    apu.write_byte(0xFF13, 0x00);
+    apu.write_byte(0xFF14, 0x87);
+
+    c.bench_function("apu 1M steps", |b| b.iter(|| {
+        let mut samples = 0;
+        for i in 0..1_048_576_u32 {
+            // DIV counts up once every 256 cycles, 64 steps.
+            apu.next(4, (i / 64) as u8);
+            if i % (FRAME_CYCLES / 4) == 0 {
+                samples += apu.buffer.lock().unwrap().drain(..).count();
+            }
+        }
+        black_box(samples)
+    }));
+}
+
+criterion_group!(benches, cpu, gpu, apu);
+criterion_main!(benches);
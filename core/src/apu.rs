@@ -1,11 +1,16 @@
 use super::clock::Clock;
 use super::bus::MemoryBus;
+use super::state::{check_len, ApuState, SaveStateError};
 use blip_buf::BlipBuf;
+use serde::Serialize;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
 const CLOCK_FREQUENCY: u32 = 4_194_304;
+const FRAME_CYCLES: u32 = 70_224;
+// Video frames of samples the output buffer holds before new ones are dropped, ~33ms.
+pub const DEFAULT_BUFFER_FRAMES: u32 = 2;
 
 /*
     Shamelessly taken from https://github.com/mohanson/gameboy.
@@ -90,9 +95,13 @@ impl Register {
         self.nrx0 & 0x07
     }
 
+    // The wave channel's DAC is controlled by NR30 bit 7, the others are on while any of the top 5 bits of NRx2
+    // (starting volume and envelope add mode) are set.
     fn get_dac_power(&self) -> bool {
-        assert!(self.channel == Channel::Wave);
-        self.nrx0 & 0x80 != 0x00
+        match self.channel {
+            Channel::Wave => self.nrx0 & 0x80 != 0x00,
+            _ => self.nrx2 & 0xf8 != 0x00,
+        }
     }
 
     fn get_duty(&self) -> u8 {
@@ -343,12 +352,15 @@ impl VolumeEnvelope {
 // run AGAIN immediately using this new value, but this second new frequency is not written back.
 // Square 1's frequency can be modified via NR13 and NR14 while sweep is active, but the shadow frequency won't be
 // affected so the next time the sweep updates the channel's frequency this modification will be lost.
+// On DMG, clearing the negate bit after a calculation has used negate since the last trigger disables square 1.
 struct FrequencySweep {
     reg: Rc<RefCell<Register>>,
     timer: Clock,
     enable: bool,
     shadow: u16,
     newfeq: u16,
+    negate_used: bool,
+    dmg_mode: bool,
 }
 
 impl FrequencySweep {
@@ -359,10 +371,13 @@ impl FrequencySweep {
             enable: false,
             shadow: 0x0000,
             newfeq: 0x0000,
+            negate_used: false,
+            dmg_mode: true,
         }
     }
 
     fn reload(&mut self) {
+        self.negate_used = false;
         self.shadow = self.reg.borrow().get_frequency();
         let p = self.reg.borrow().get_sweep_period();
         // The volume envelope and sweep timers treat a period of 0 as 8.
@@ -377,12 +392,20 @@ impl FrequencySweep {
     fn frequency_calculation(&mut self) {
         let offset = self.shadow >> self.reg.borrow().get_shift();
         if self.reg.borrow().get_negate() {
+            self.negate_used = true;
             self.newfeq = self.shadow.wrapping_sub(offset);
         } else {
             self.newfeq = self.shadow.wrapping_add(offset);
         }
     }
 
+    // Called after NR10 is written.
+    fn negate_cleared_check(&mut self) {
+        if self.dmg_mode && self.negate_used && !self.reg.borrow().get_negate() {
+            self.reg.borrow_mut().set_trigger(false);
+        }
+    }
+
     fn overflow_check(&mut self) {
         if self.newfeq >= 2048 {
             self.reg.borrow_mut().set_trigger(false);
@@ -502,12 +525,22 @@ impl MemoryBus for ChannelSquare {
 
     fn write_byte(&mut self, a: u16, v: u8) {
         match a {
-            0xff10 | 0xff15 => self.reg.borrow_mut().nrx0 = v,
+            0xff10 => {
+                self.reg.borrow_mut().nrx0 = v;
+                self.fs.negate_cleared_check();
+            }
+            0xff15 => self.reg.borrow_mut().nrx0 = v,
             0xff11 | 0xff16 => {
                 self.reg.borrow_mut().nrx1 = v;
                 self.lc.n = self.reg.borrow().get_length_load();
             }
-            0xff12 | 0xff17 => self.reg.borrow_mut().nrx2 = v,
+            0xff12 | 0xff17 => {
+                self.reg.borrow_mut().nrx2 = v;
+                // Turning the DAC off disables the channel.
+                if !self.reg.borrow().get_dac_power() {
+                    self.reg.borrow_mut().set_trigger(false);
+                }
+            }
             0xff13 | 0xff18 => {
                 self.reg.borrow_mut().nrx3 = v;
                 self.timer.period = period(self.reg.clone());
@@ -536,6 +569,9 @@ impl MemoryBus for ChannelSquare {
                     if self.reg.borrow().channel == Channel::Square1 {
                         self.fs.reload();
                     }
+                    if !self.reg.borrow().get_dac_power() {
+                        self.reg.borrow_mut().set_trigger(false);
+                    }
                 }
             }
             _ => unreachable!(),
@@ -558,6 +594,8 @@ impl MemoryBus for ChannelSquare {
 // 2      1        50%
 // 3      2        25%
 // Wave RAM can only be properly accessed when the channel is disabled (see obscure behavior).
+// After a trigger the position starts advancing 6 cycles late. On DMG, triggering while the channel is enabled
+// corrupts the start of wave RAM with the bytes being read.
 struct ChannelWave {
     reg: Rc<RefCell<Register>>,
     timer: Clock,
@@ -565,6 +603,8 @@ struct ChannelWave {
     blip: Blip,
     waveram: [u8; 16],
     waveidx: usize,
+    trigger_delay: u32,
+    dmg_mode: bool,
 }
 
 impl ChannelWave {
@@ -577,31 +617,60 @@ impl ChannelWave {
             blip: Blip::power_up(blip),
             waveram: [0x00; 16],
             waveidx: 0x00,
+            trigger_delay: 0,
+            dmg_mode: true,
+        }
+    }
+
+    fn trigger(&mut self, was_enabled: bool) {
+        if self.dmg_mode && was_enabled {
+            // Reading one of the first 4 bytes only rewrites byte 0, later bytes rewrite the first 4 with
+            // the aligned block being read.
+            let pos = self.waveidx / 2;
+            if pos < 4 {
+                self.waveram[0] = self.waveram[pos];
+            } else {
+                let block = pos & !0x03;
+                self.waveram.copy_within(block..block + 4, 0);
+            }
         }
+        self.lc.reload();
+        self.timer.n = 0;
+        self.waveidx = 0x00;
+        self.trigger_delay = 6;
     }
 
     fn next(&mut self, cycles: u32) {
-        let s = match self.reg.borrow().get_volume_code() {
+        let delay = self.trigger_delay.min(cycles);
+        self.trigger_delay -= delay;
+        let cycles = cycles - delay;
+
+        for _ in 0..self.timer.tick(cycles) {
+            let ampl = self.output();
+            self.blip.write_byte(self.blip.from + self.timer.period, ampl);
+            self.waveidx = (self.waveidx + 1) % 32;
+        }
+    }
+
+    // Sample at the current position shifted by the volume code, 0 (mute) shifts all 4 bits out.
+    // Silent while the channel or its DAC is off. Like the other channels the output is 0-15 and
+    // isn't centred, the DC offset is removed by the high-pass filter.
+    fn output(&self) -> i32 {
+        let reg = self.reg.borrow();
+        if !reg.get_trigger() || !reg.get_dac_power() {
+            return 0;
+        }
+        // The high nibble of each byte is played first.
+        let byte = self.waveram[self.waveidx / 2];
+        let sample = if self.waveidx & 0x01 == 0x00 { byte >> 4 } else { byte & 0x0f };
+        let shift = match reg.get_volume_code() {
             0 => 4,
             1 => 0,
             2 => 1,
             3 => 2,
             _ => unreachable!(),
         };
-        for _ in 0..self.timer.tick(cycles) {
-            let sample = if self.waveidx & 0x01 == 0x00 {
-                self.waveram[self.waveidx / 2] & 0x0f
-            } else {
-                self.waveram[self.waveidx / 2] >> 4
-            };
-            let ampl = if !self.reg.borrow().get_trigger() || !self.reg.borrow().get_dac_power() {
-                0x00
-            } else {
-                i32::from(sample >> s)
-            };
-            self.blip.write_byte(self.blip.from + self.timer.period, ampl);
-            self.waveidx = (self.waveidx + 1) % 32;
-        }
+        i32::from(sample >> shift)
     }
 }
 
@@ -631,11 +700,11 @@ impl MemoryBus for ChannelWave {
                 self.timer.period = period(self.reg.clone());
             }
             0xff1e => {
+                let was_enabled = self.reg.borrow().get_trigger() && self.reg.borrow().get_dac_power();
                 self.reg.borrow_mut().nrx4 = v;
                 self.timer.period = period(self.reg.clone());
                 if self.reg.borrow().get_trigger() {
-                    self.lc.reload();
-                    self.waveidx = 0x00;
+                    self.trigger(was_enabled);
                 }
             }
             0xff30..=0xff3f => self.waveram[a as usize - 0xff30] = v,
@@ -700,9 +769,11 @@ impl ChannelNoise {
 
     fn next(&mut self, cycles: u32) {
         for _ in 0..self.timer.tick(cycles) {
+            // The LFSR is clocked even while the channel is silent.
+            let high = self.lfsr.next();
             let ampl = if !self.reg.borrow().get_trigger() || self.ve.volume == 0 {
                 0x00
-            } else if self.lfsr.next() {
+            } else if high {
                 i32::from(self.ve.volume)
             } else {
                 i32::from(self.ve.volume) * -1
@@ -731,7 +802,12 @@ impl MemoryBus for ChannelNoise {
                 self.reg.borrow_mut().nrx1 = v;
                 self.lc.n = self.reg.borrow().get_length_load();
             }
-            0xff21 => self.reg.borrow_mut().nrx2 = v,
+            0xff21 => {
+                self.reg.borrow_mut().nrx2 = v;
+                if !self.reg.borrow().get_dac_power() {
+                    self.reg.borrow_mut().set_trigger(false);
+                }
+            }
             0xff22 => {
                 self.reg.borrow_mut().nrx3 = v;
                 self.timer.period = period(self.reg.clone());
@@ -742,6 +818,9 @@ impl MemoryBus for ChannelNoise {
                     self.lc.reload();
                     self.ve.reload();
                     self.lfsr.reload();
+                    if !self.reg.borrow().get_dac_power() {
+                        self.reg.borrow_mut().set_trigger(false);
+                    }
                 }
             }
             _ => unreachable!(),
@@ -749,20 +828,81 @@ impl MemoryBus for ChannelNoise {
     }
 }
 
+// Channel state changes caused by register writes, channels are numbered 1-4.
+// Serialized as e.g. {"type": "VolumeChanged", "ch": 1, "vol": 15}.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum ApuEvent {
+    ChannelEnabled { ch: usize },
+    ChannelDisabled { ch: usize },
+    FrequencyChanged { ch: usize, hz: f64 },
+    VolumeChanged { ch: usize, vol: u8 },
+}
+
+pub type ApuEventCallback = Box<dyn Fn(ApuEvent)>;
+
+// Snapshot of a channel used to detect transitions across a register write.
+#[derive(Clone, Copy, PartialEq)]
+struct ChannelState {
+    enabled: bool,
+    hz: f64,
+    vol: u8,
+}
+
+// The capacitor on the output, a high pass filter removing the DC offset of the channels.
+// It keeps 0.999958 of its charge per clock, scaled to the time between samples.
+struct HpFilter {
+    charge_l: f32,
+    charge_r: f32,
+    factor: f32,
+}
+
+impl HpFilter {
+    fn new(sample_rate: u32) -> Self {
+        let factor = 0.999958_f64.powf(f64::from(CLOCK_FREQUENCY) / f64::from(sample_rate)) as f32;
+        Self { charge_l: 0.0, charge_r: 0.0, factor }
+    }
+
+    fn filter(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let out_l = l - self.charge_l;
+        let out_r = r - self.charge_r;
+        self.charge_l = l - out_l * self.factor;
+        self.charge_r = r - out_r * self.factor;
+        (out_l, out_r)
+    }
+}
+
 pub struct APU {
     pub buffer: Arc<Mutex<Vec<(f32, f32)>>>,
     reg: Register,
     timer: Clock,
     fs: FrameSequencer,
+    // The frame sequencer steps when bit 4 of DIV falls, at 512 Hz.
+    last_div_bit4: bool,
     channel1: ChannelSquare,
     channel2: ChannelSquare,
     channel3: ChannelWave,
     channel4: ChannelNoise,
-    sample_rate: u32,
+    hpf: HpFilter,
+    // Samples discarded because the output buffer was full.
+    dropped_samples: u32,
+    // Size of the output buffer in samples, a whole number of frames.
+    max_buffered: usize,
+    // Channels 1-4 left out of the mix, they keep running.
+    muted: [bool; 4],
+    event_callback: Option<ApuEventCallback>,
 }
 
 impl APU {
     pub fn power_up(sample_rate: u32) -> Self {
+        Self::new(sample_rate, DEFAULT_BUFFER_FRAMES)
+    }
+
+    // Smaller buffers lower the latency, but the output runs dry if it isn't drained as often
+    // as frames are run.
+    pub fn new(sample_rate: u32, target_buffer_frames: u32) -> Self {
+        let samples_per_frame = (u64::from(sample_rate) * u64::from(FRAME_CYCLES))
+            .div_ceil(u64::from(CLOCK_FREQUENCY)) as usize;
         let blipbuf1 = create_blipbuf(sample_rate);
         let blipbuf2 = create_blipbuf(sample_rate);
         let blipbuf3 = create_blipbuf(sample_rate);
@@ -772,55 +912,168 @@ impl APU {
             reg: Register::power_up(Channel::Mixer),
             timer: Clock::new(CLOCK_FREQUENCY / 512),
             fs: FrameSequencer::power_up(),
+            last_div_bit4: false,
             channel1: ChannelSquare::power_up(blipbuf1, Channel::Square1),
             channel2: ChannelSquare::power_up(blipbuf2, Channel::Square2),
             channel3: ChannelWave::power_up(blipbuf3),
             channel4: ChannelNoise::power_up(blipbuf4),
-            sample_rate,
+            hpf: HpFilter::new(sample_rate),
+            dropped_samples: 0,
+            max_buffered: target_buffer_frames.max(1) as usize * samples_per_frame,
+            muted: [false; 4],
+            event_callback: None,
         }
     }
 
     fn play(&mut self, l: &[f32], r: &[f32]) {
         assert_eq!(l.len(), r.len());
+        let total = l.len();
         let mut buffer = self.buffer.lock().unwrap();
-        for (l, r) in l.iter().zip(r) {
-            // Do not fill the buffer with more than 1 second of data
-            // This speeds up the resync after the turning on and off the speed limiter
-            if buffer.len() > self.sample_rate as usize {
+        for (idx, (l, r)) in l.iter().zip(r).enumerate() {
+            // Do not fill the buffer with more than the target number of frames, which keeps the
+            // latency down and speeds up the resync after turning the speed limiter on and off.
+            if buffer.len() >= self.max_buffered {
+                self.dropped_samples += (total - idx) as u32;
                 return;
             }
-            buffer.push((*l, *r));
+            buffer.push(self.hpf.filter(*l, *r));
+        }
+    }
+
+    // Returns the number of samples dropped since the last call.
+    pub fn take_dropped_samples(&mut self) -> u32 {
+        std::mem::take(&mut self.dropped_samples)
+    }
+
+    // Channel 0-3 for channels 1-4, others are ignored.
+    pub fn mute_channel(&mut self, ch: u8, muted: bool) {
+        if let Some(m) = self.muted.get_mut(ch as usize) {
+            *m = muted;
         }
     }
 
-    pub fn next(&mut self, cycles: u32) {
+    // DMG and CGB differ in some obscure behaviour, the APU starts as a DMG.
+    pub fn set_dmg_mode(&mut self, dmg_mode: bool) {
+        self.channel1.fs.dmg_mode = dmg_mode;
+        self.channel3.dmg_mode = dmg_mode;
+    }
+
+    pub fn set_event_callback(&mut self, cb: Box<dyn Fn(ApuEvent)>) {
+        self.event_callback = Some(cb);
+    }
+
+    pub fn save_state(&self) -> ApuState {
+        let regs = [&self.channel1.reg, &self.channel2.reg, &self.channel3.reg, &self.channel4.reg]
+            .iter()
+            .map(|reg| {
+                let r = reg.borrow();
+                [r.nrx0, r.nrx1, r.nrx2, r.nrx3, r.nrx4]
+            })
+            .chain(std::iter::once([self.reg.nrx0, self.reg.nrx1, self.reg.nrx2, self.reg.nrx3, self.reg.nrx4]))
+            .collect();
+        ApuState { regs, wave_ram: self.channel3.waveram.to_vec() }
+    }
+
+    // Registers are restored without trigger side effects, channels restart their waveform.
+    pub fn load_state(&mut self, state: &ApuState) -> Result<(), SaveStateError> {
+        check_len("wave ram", &state.wave_ram, self.channel3.waveram.len())?;
+        let channels = [&self.channel1.reg, &self.channel2.reg, &self.channel3.reg, &self.channel4.reg];
+        for (reg, r) in channels.iter().zip(&state.regs) {
+            let mut reg = reg.borrow_mut();
+            reg.nrx0 = r[0];
+            reg.nrx1 = r[1];
+            reg.nrx2 = r[2];
+            reg.nrx3 = r[3];
+            reg.nrx4 = r[4];
+        }
+        if let Some(r) = state.regs.get(4) {
+            self.reg.nrx0 = r[0];
+            self.reg.nrx1 = r[1];
+            self.reg.nrx2 = r[2];
+            self.reg.nrx3 = r[3];
+            self.reg.nrx4 = r[4];
+        }
+        self.channel1.timer.period = period(self.channel1.reg.clone());
+        self.channel2.timer.period = period(self.channel2.reg.clone());
+        self.channel3.timer.period = period(self.channel3.reg.clone());
+        self.channel4.timer.period = period(self.channel4.reg.clone());
+        self.channel3.waveram.copy_from_slice(&state.wave_ram);
+        Ok(())
+    }
+
+    fn channel_state(&self, ch: usize) -> ChannelState {
+        match ch {
+            1 | 2 => {
+                let c = if ch == 1 { &self.channel1 } else { &self.channel2 };
+                ChannelState {
+                    enabled: c.reg.borrow().get_trigger(),
+                    // 8 duty steps per waveform.
+                    hz: f64::from(CLOCK_FREQUENCY) / f64::from(period(c.reg.clone()) * 8),
+                    vol: c.ve.volume,
+                }
+            }
+            3 => {
+                let reg = self.channel3.reg.borrow();
+                ChannelState {
+                    enabled: reg.get_trigger() && reg.get_dac_power(),
+                    // 32 samples per waveform.
+                    hz: f64::from(CLOCK_FREQUENCY) / f64::from(period(self.channel3.reg.clone()) * 32),
+                    // Volume code 0-3 scaled to the same 0-15 range as the envelopes.
+                    vol: match reg.get_volume_code() {
+                        0 => 0,
+                        n => 0x0f >> (n - 1),
+                    },
+                }
+            }
+            _ => ChannelState {
+                enabled: self.channel4.reg.borrow().get_trigger(),
+                hz: f64::from(CLOCK_FREQUENCY) / f64::from(period(self.channel4.reg.clone())),
+                vol: self.channel4.ve.volume,
+            },
+        }
+    }
+
+    fn emit_events(&self, before: [ChannelState; 4]) {
+        let cb = match &self.event_callback {
+            Some(cb) => cb,
+            None => return,
+        };
+        for (i, old) in before.iter().enumerate() {
+            let ch = i + 1;
+            let new = self.channel_state(ch);
+            if new.enabled != old.enabled {
+                cb(if new.enabled { ApuEvent::ChannelEnabled { ch } } else { ApuEvent::ChannelDisabled { ch } });
+            }
+            if new.hz != old.hz {
+                cb(ApuEvent::FrequencyChanged { ch, hz: new.hz });
+            }
+            if new.vol != old.vol {
+                cb(ApuEvent::VolumeChanged { ch, vol: new.vol });
+            }
+        }
+    }
+
+    // Runs for cycles, div is the DIV register after them.
+    pub fn next(&mut self, cycles: u32, div: u8) {
+        let div_bit4 = div & 0x10 != 0;
+        let falling_edge = self.last_div_bit4 && !div_bit4;
+        self.last_div_bit4 = div_bit4;
+
         if !self.reg.get_power() {
             return;
         }
 
+        if falling_edge {
+            self.step_frame_sequencer();
+        }
+
+        // Channels are run and mixed in blocks of the same length as a sequencer step.
         for _ in 0..self.timer.tick(cycles) {
             self.channel1.next(self.timer.period);
             self.channel2.next(self.timer.period);
             self.channel3.next(self.timer.period);
             self.channel4.next(self.timer.period);
 
-            let step = self.fs.next();
-            if step == 0 || step == 2 || step == 4 || step == 6 {
-                self.channel1.lc.next();
-                self.channel2.lc.next();
-                self.channel3.lc.next();
-                self.channel4.lc.next();
-            }
-            if step == 7 {
-                self.channel1.ve.next();
-                self.channel2.ve.next();
-                self.channel4.ve.next();
-            }
-            if step == 2 || step == 6 {
-                self.channel1.fs.next();
-                self.channel1.timer.period = period(self.channel1.reg.clone());
-            }
-
             self.channel1.blip.data.end_frame(self.timer.period);
             self.channel2.blip.data.end_frame(self.timer.period);
             self.channel3.blip.data.end_frame(self.timer.period);
@@ -833,6 +1086,32 @@ impl APU {
         }
     }
 
+    fn step_frame_sequencer(&mut self) {
+        let step = self.fs.next();
+        if step == 0 || step == 2 || step == 4 || step == 6 {
+            self.channel1.lc.next();
+            self.channel2.lc.next();
+            self.channel3.lc.next();
+            self.channel4.lc.next();
+        }
+        if step == 7 {
+            self.channel1.ve.next();
+            self.channel2.ve.next();
+            self.channel4.ve.next();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.fs.next();
+            self.channel1.timer.period = period(self.channel1.reg.clone());
+        }
+    }
+
+    // Runs the APU for one video frame and returns the mixed samples.
+    #[cfg(test)]
+    fn mix_single_frame(&mut self) -> Vec<(f32, f32)> {
+        self.next(70_224, 0);
+        std::mem::take(&mut *self.buffer.lock().unwrap())
+    }
+
     fn mix(&mut self) {
         let sc1 = self.channel1.blip.data.samples_avail();
         let sc2 = self.channel2.blip.data.samples_avail();
@@ -854,41 +1133,41 @@ impl APU {
             let buf = &mut [0i16; 2048];
 
             let count1 = self.channel1.blip.data.read_samples(buf, false);
-            for (i, v) in buf[..count1].iter().enumerate() {
-                if self.reg.nrx1 & 0x01 == 0x01 {
+            for (i, v) in buf[..count1].iter().enumerate().filter(|_| !self.muted[0]) {
+                if self.reg.nrx1 & PAN_LEFT[0] != 0 {
                     buf_l[i] += f32::from(*v) * l_vol;
                 }
-                if self.reg.nrx1 & 0x10 == 0x10 {
+                if self.reg.nrx1 & PAN_RIGHT[0] != 0 {
                     buf_r[i] += f32::from(*v) * r_vol;
                 }
             }
 
             let count2 = self.channel2.blip.data.read_samples(buf, false);
-            for (i, v) in buf[..count2].iter().enumerate() {
-                if self.reg.nrx1 & 0x02 == 0x02 {
+            for (i, v) in buf[..count2].iter().enumerate().filter(|_| !self.muted[1]) {
+                if self.reg.nrx1 & PAN_LEFT[1] != 0 {
                     buf_l[i] += f32::from(*v) * l_vol;
                 }
-                if self.reg.nrx1 & 0x20 == 0x20 {
+                if self.reg.nrx1 & PAN_RIGHT[1] != 0 {
                     buf_r[i] += f32::from(*v) * r_vol;
                 }
             }
 
             let count3 = self.channel3.blip.data.read_samples(buf, false);
-            for (i, v) in buf[..count3].iter().enumerate() {
-                if self.reg.nrx1 & 0x04 == 0x04 {
+            for (i, v) in buf[..count3].iter().enumerate().filter(|_| !self.muted[2]) {
+                if self.reg.nrx1 & PAN_LEFT[2] != 0 {
                     buf_l[i] += f32::from(*v) * l_vol;
                 }
-                if self.reg.nrx1 & 0x40 == 0x40 {
+                if self.reg.nrx1 & PAN_RIGHT[2] != 0 {
                     buf_r[i] += f32::from(*v) * r_vol;
                 }
             }
 
             let count4 = self.channel4.blip.data.read_samples(buf, false);
-            for (i, v) in buf[..count4].iter().enumerate() {
-                if self.reg.nrx1 & 0x08 == 0x08 {
+            for (i, v) in buf[..count4].iter().enumerate().filter(|_| !self.muted[3]) {
+                if self.reg.nrx1 & PAN_LEFT[3] != 0 {
                     buf_l[i] += f32::from(*v) * l_vol;
                 }
-                if self.reg.nrx1 & 0x80 == 0x80 {
+                if self.reg.nrx1 & PAN_RIGHT[3] != 0 {
                     buf_r[i] += f32::from(*v) * r_vol;
                 }
             }
@@ -903,6 +1182,10 @@ impl APU {
     }
 }
 
+// NR51 channel panning bits for channels 1-4, left output in the upper nibble.
+const PAN_LEFT:  [u8; 4] = [0x10, 0x20, 0x40, 0x80];
+const PAN_RIGHT: [u8; 4] = [0x01, 0x02, 0x04, 0x08];
+
 // Registers are ORed with this when reading
 const RD_MASK: [u8; 48] = [
     0x80, 0x3f, 0x00, 0xff, 0xbf, 0xff, 0x3f, 0x00, 0xff, 0xbf, 0x7f, 0xff, 0x9f, 0xff, 0xbf, 0xff, 0xff, 0x00, 0x00,
@@ -939,6 +1222,17 @@ impl MemoryBus for APU {
     }
 
     fn write_byte(&mut self, a: u16, v: u8) {
+        if self.event_callback.is_none() {
+            return self.write_register(a, v);
+        }
+        let before = [1, 2, 3, 4].map(|ch| self.channel_state(ch));
+        self.write_register(a, v);
+        self.emit_events(before);
+    }
+}
+
+impl APU {
+    fn write_register(&mut self, a: u16, v: u8) {
         if a != 0xff26 && !self.reg.get_power() {
             return;
         }
@@ -998,13 +1292,419 @@ fn period(reg: Rc<RefCell<Register>>) -> u32 {
     match reg.borrow().channel {
         Channel::Square1 | Channel::Square2 => 4 * (2048 - u32::from(reg.borrow().get_frequency())),
         Channel::Wave => 2 * (2048 - u32::from(reg.borrow().get_frequency())),
+        // Divisors are 8, 16, 32, 48 ... 112 for codes 0-7, shifted left by up to 15.
         Channel::Noise => {
             let d = match reg.borrow().get_dividor_code() {
                 0 => 8,
-                n => (u32::from(n) + 1) * 16,
+                n => u32::from(n) * 16,
             };
             d << reg.borrow().get_clock_shift()
         }
         Channel::Mixer => CLOCK_FREQUENCY / 512,
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::bus::MemoryBus;
+    use proptest::prelude::*;
+    use super::{APU, ApuEvent, HpFilter, FRAME_CYCLES};
+    use super::{Channel, Register, VolumeEnvelope, FrequencySweep, LengthCounter, Lfsr};
+
+    fn apu_with_events() -> (APU, Rc<RefCell<Vec<ApuEvent>>>) {
+        let mut apu = APU::power_up(44_100);
+        apu.write_byte(0xff26, 0x80);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = events.clone();
+        apu.set_event_callback(Box::new(move |e| sink.borrow_mut().push(e)));
+        (apu, events)
+    }
+
+    fn register(channel: Channel) -> Rc<RefCell<Register>> {
+        Rc::new(RefCell::new(Register::power_up(channel)))
+    }
+
+    proptest! {
+        #[test]
+        fn envelope_volume_in_range(volume in 0u8..16, add in any::<bool>(), period in 0u8..8, clocks in 0usize..200) {
+            let reg = register(Channel::Square2);
+            reg.borrow_mut().nrx2 = volume << 4 | u8::from(add) << 3 | period;
+            let mut envelope = VolumeEnvelope::power_up(reg);
+            envelope.reload();
+            for _ in 0..clocks {
+                envelope.next();
+                prop_assert!(envelope.volume <= 15);
+            }
+        }
+
+        #[test]
+        fn sweep_frequency_in_range(period in 0u8..8, negate in any::<bool>(), shift in 0u8..8, freq in 0u16..2048, clocks in 0usize..100) {
+            let reg = register(Channel::Square1);
+            reg.borrow_mut().nrx0 = period << 4 | u8::from(negate) << 3 | shift;
+            reg.borrow_mut().set_frequency(freq);
+            let mut sweep = FrequencySweep::power_up(reg.clone());
+            sweep.reload();
+            for _ in 0..clocks {
+                sweep.next();
+                // Frequencies of 2048 and up disable the channel and are never written back.
+                prop_assert!(reg.borrow().get_frequency() < 2048);
+                prop_assert!(sweep.shadow < 2048);
+            }
+        }
+
+        #[test]
+        fn length_counter_never_underflows(wave in any::<bool>(), enabled in any::<bool>(), ops in prop::collection::vec(any::<bool>(), 0..300)) {
+            let reg = register(if wave { Channel::Wave } else { Channel::Square2 });
+            reg.borrow_mut().nrx4 = u8::from(enabled) << 6;
+            let max = if wave { 256 } else { 64 };
+            let mut length = LengthCounter::power_up(reg);
+            // true reloads, as on trigger, false clocks the counter.
+            for reload in ops {
+                if reload { length.reload() } else { length.next() }
+                prop_assert!(length.n <= max);
+            }
+        }
+
+        #[test]
+        fn lfsr_never_zero(width_7 in any::<bool>(), clocks in 0usize..5000) {
+            let reg = register(Channel::Noise);
+            reg.borrow_mut().nrx3 = u8::from(width_7) << 3;
+            let mut lfsr = Lfsr::power_up(reg);
+            for _ in 0..clocks {
+                lfsr.next();
+            }
+            prop_assert_ne!(lfsr.n, 0);
+        }
+    }
+
+    #[test]
+    fn buffer_frames() {
+        // 803.7 samples per frame at 48kHz.
+        let mut apu = APU::new(48_000, 1);
+        apu.write_byte(0xff26, 0x80);
+        for _ in 0..3 {
+            apu.next(FRAME_CYCLES, 0);
+        }
+        assert_eq!(apu.buffer.lock().unwrap().len(), 804);
+        assert!(apu.take_dropped_samples() > 1_000);
+
+        let mut apu = APU::power_up(48_000);
+        apu.write_byte(0xff26, 0x80);
+        for _ in 0..3 {
+            apu.next(FRAME_CYCLES, 0);
+        }
+        assert_eq!(apu.buffer.lock().unwrap().len(), 2 * 804);
+    }
+
+    #[test]
+    fn high_pass_filter() {
+        let mut hpf = HpFilter::new(44_100);
+        // Changes pass straight through, a constant level decays away.
+        assert_eq!(hpf.filter(1.0, -1.0), (1.0, -1.0));
+        let mut out = (1.0, -1.0);
+        for _ in 0..44_100 {
+            out = hpf.filter(1.0, -1.0);
+        }
+        assert!(out.0.abs() < 0.001 && out.1.abs() < 0.001, "{:?}", out);
+    }
+
+    #[test]
+    fn trigger_events() {
+        let (mut apu, events) = apu_with_events();
+        // Envelope volume is only loaded on trigger.
+        apu.write_byte(0xff17, 0xf0);
+        assert!(events.borrow().is_empty());
+
+        // Frequency 1750 -> 131072 / (2048 - 1750) Hz.
+        apu.write_byte(0xff18, 0xd6);
+        events.borrow_mut().clear();
+        apu.write_byte(0xff19, 0x86);
+        assert_eq!(*events.borrow(), vec![
+            ApuEvent::ChannelEnabled { ch: 2 },
+            ApuEvent::FrequencyChanged { ch: 2, hz: 131_072.0 / 298.0 },
+            ApuEvent::VolumeChanged { ch: 2, vol: 15 },
+        ]);
+
+        // Retriggering with the same state fires nothing.
+        events.borrow_mut().clear();
+        apu.write_byte(0xff19, 0x86);
+        assert!(events.borrow().is_empty());
+    }
+
+    #[test]
+    fn power_off_disables_channels() {
+        let (mut apu, events) = apu_with_events();
+        apu.write_byte(0xff1a, 0x80);
+        apu.write_byte(0xff1c, 0x20);
+        apu.write_byte(0xff1e, 0x80);
+        assert!(events.borrow().contains(&ApuEvent::ChannelEnabled { ch: 3 }));
+        assert!(events.borrow().contains(&ApuEvent::VolumeChanged { ch: 3, vol: 15 }));
+
+        events.borrow_mut().clear();
+        apu.write_byte(0xff26, 0x00);
+        assert!(events.borrow().contains(&ApuEvent::ChannelDisabled { ch: 3 }));
+        assert!(events.borrow().contains(&ApuEvent::VolumeChanged { ch: 3, vol: 0 }));
+        assert!(!events.borrow().iter().any(|e| matches!(e, ApuEvent::ChannelDisabled { ch: 1 })));
+    }
+
+    // Powered APU with square channels 1 and/or 2 playing at full volume.
+    fn playing_apu(nr51: u8, ch1: bool, ch2: bool) -> APU {
+        let mut apu = APU::power_up(44_100);
+        apu.write_byte(0xff26, 0x80);
+        apu.write_byte(0xff24, 0x77);
+        apu.write_byte(0xff25, nr51);
+        for (on, base) in [(ch1, 0xff10), (ch2, 0xff15)] {
+            if !on { continue; }
+            apu.write_byte(base + 1, 0x80);
+            apu.write_byte(base + 2, 0xf0);
+            apu.write_byte(base + 3, 0x00);
+            apu.write_byte(base + 4, 0x87);
+        }
+        apu
+    }
+
+    fn left(frame: &[(f32, f32)]) -> bool { frame.iter().any(|(l, _)| *l != 0.0) }
+
+    fn right(frame: &[(f32, f32)]) -> bool { frame.iter().any(|(_, r)| *r != 0.0) }
+
+    #[test]
+    fn nr51_panning() {
+        // CH1 left only, CH2 right only.
+        let frame = playing_apu(0x12, true, false).mix_single_frame();
+        assert!(!frame.is_empty());
+        assert!(left(&frame) && !right(&frame));
+
+        let frame = playing_apu(0x12, false, true).mix_single_frame();
+        assert!(!left(&frame) && right(&frame));
+
+        let frame = playing_apu(0x12, true, true).mix_single_frame();
+        assert!(left(&frame) && right(&frame));
+    }
+
+    #[test]
+    fn nr51_silence_and_all() {
+        let frame = playing_apu(0x00, true, true).mix_single_frame();
+        assert!(!frame.is_empty());
+        assert!(!left(&frame) && !right(&frame));
+
+        let frame = playing_apu(0xff, true, false).mix_single_frame();
+        assert!(left(&frame) && right(&frame));
+    }
+
+    #[test]
+    fn channel_status_needs_dac() {
+        let mut apu = APU::power_up(44_100);
+        apu.write_byte(0xff26, 0x80);
+
+        // Zero volume with decreasing envelope leaves the DAC off, so the trigger doesn't enable the channel.
+        apu.write_byte(0xff12, 0x00);
+        apu.write_byte(0xff14, 0x80);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x00);
+        apu.write_byte(0xff12, 0xf0);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x00);
+        apu.write_byte(0xff14, 0x80);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x01);
+        // Turning the DAC off disables the channel, increasing envelope from zero keeps it on.
+        apu.write_byte(0xff12, 0x08);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x01);
+        apu.write_byte(0xff12, 0x00);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x00);
+
+        // Same for square 2 and noise.
+        apu.write_byte(0xff19, 0x80);
+        apu.write_byte(0xff23, 0x80);
+        assert_eq!(apu.read_byte(0xff26) & 0x0f, 0x00);
+        apu.write_byte(0xff17, 0x10);
+        apu.write_byte(0xff21, 0x10);
+        apu.write_byte(0xff19, 0x80);
+        apu.write_byte(0xff23, 0x80);
+        assert_eq!(apu.read_byte(0xff26) & 0x0f, 0x0a);
+
+        // Wave channel DAC is NR30 bit 7.
+        apu.write_byte(0xff1e, 0x80);
+        assert_eq!(apu.read_byte(0xff26) & 0x04, 0x00);
+        apu.write_byte(0xff1a, 0x80);
+        apu.write_byte(0xff1e, 0x80);
+        assert_eq!(apu.read_byte(0xff26) & 0x04, 0x04);
+    }
+
+    // Square 1 triggered with sweep shift 1, where the trigger runs a frequency calculation.
+    fn sweeping_apu(nr10: u8, dmg_mode: bool) -> APU {
+        let mut apu = APU::power_up(44_100);
+        apu.set_dmg_mode(dmg_mode);
+        apu.write_byte(0xff26, 0x80);
+        apu.write_byte(0xff10, nr10);
+        apu.write_byte(0xff12, 0xf0);
+        apu.write_byte(0xff13, 0x00);
+        apu.write_byte(0xff14, 0x84);
+        apu
+    }
+
+    #[test]
+    fn sweep_negate_cleared() {
+        // Period 1, negate and shift 1, then negate is cleared.
+        let mut apu = sweeping_apu(0x19, true);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x01);
+        apu.write_byte(0xff10, 0x11);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x00);
+
+        // Other NR10 writes that keep negate are fine.
+        let mut apu = sweeping_apu(0x19, true);
+        apu.write_byte(0xff10, 0x29);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x01);
+
+        // Negate was set but never used in a calculation, shift 0 skips it on trigger.
+        let mut apu = sweeping_apu(0x08, true);
+        apu.write_byte(0xff10, 0x00);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x01);
+
+        // Only on DMG.
+        let mut apu = sweeping_apu(0x19, false);
+        apu.write_byte(0xff10, 0x11);
+        assert_eq!(apu.read_byte(0xff26) & 0x01, 0x01);
+    }
+
+    // Wave channel at frequency 2047, advancing a sample every 2 cycles, wave RAM holding 0x00-0x0F.
+    fn wave_apu(dmg_mode: bool) -> APU {
+        let mut apu = APU::power_up(44_100);
+        apu.set_dmg_mode(dmg_mode);
+        apu.write_byte(0xff26, 0x80);
+        for idx in 0..16 {
+            apu.write_byte(0xff30 + idx, idx as u8);
+        }
+        apu.write_byte(0xff1a, 0x80);
+        apu.write_byte(0xff1d, 0xff);
+        apu.write_byte(0xff1e, 0x87);
+        apu
+    }
+
+    #[test]
+    fn wave_volume() {
+        let mut apu = wave_apu(true);
+        // Position p plays sample p % 16.
+        for idx in 0..16 {
+            let p = idx as u8 * 2;
+            apu.write_byte(0xff30 + idx, ((p % 16) << 4) | ((p + 1) % 16));
+        }
+        // 0%, 100%, 50% and 25%.
+        for (code, shift) in [(0, 4), (1, 0), (2, 1), (3, 2)] {
+            apu.write_byte(0xff1c, code << 5);
+            for p in 0..32 {
+                apu.channel3.waveidx = p;
+                assert_eq!(apu.channel3.output(), (p as i32 % 16) >> shift, "NR32 {:#04x} position {}", code << 5, p);
+                assert!((0..=0x0f >> shift).contains(&apu.channel3.output()));
+            }
+        }
+
+        // DAC off.
+        apu.write_byte(0xff1a, 0x00);
+        for p in 0..32 {
+            apu.channel3.waveidx = p;
+            assert_eq!(apu.channel3.output(), 0);
+        }
+    }
+
+    #[test]
+    fn wave_trigger_delay() {
+        let mut apu = wave_apu(true);
+        apu.channel3.next(6);
+        assert_eq!(apu.channel3.waveidx, 0);
+        apu.channel3.next(2);
+        assert_eq!(apu.channel3.waveidx, 1);
+        apu.channel3.next(10);
+        assert_eq!(apu.channel3.waveidx, 6);
+    }
+
+    #[test]
+    fn wave_retrigger_corruption() {
+        // Reading byte 2, only byte 0 is rewritten.
+        let mut apu = wave_apu(true);
+        apu.channel3.next(6 + 2 * 5);
+        apu.write_byte(0xff1e, 0x87);
+        let ram: Vec<u8> = (0..16).map(|idx| apu.read_byte(0xff30 + idx)).collect();
+        assert_eq!(ram[..4], [0x02, 0x01, 0x02, 0x03]);
+        assert_eq!(ram[4..], (4..16).collect::<Vec<u8>>()[..]);
+
+        // Reading byte 9, bytes 8-11 are copied to 0-3.
+        let mut apu = wave_apu(true);
+        apu.channel3.next(6 + 2 * 18);
+        apu.write_byte(0xff1e, 0x87);
+        let ram: Vec<u8> = (0..16).map(|idx| apu.read_byte(0xff30 + idx)).collect();
+        assert_eq!(ram[..4], [0x08, 0x09, 0x0a, 0x0b]);
+        assert_eq!(ram[4..], (4..16).collect::<Vec<u8>>()[..]);
+        assert_eq!(apu.channel3.waveidx, 0);
+
+        // CGB leaves wave RAM alone.
+        let mut apu = wave_apu(false);
+        apu.channel3.next(6 + 2 * 18);
+        apu.write_byte(0xff1e, 0x87);
+        assert_eq!(apu.channel3.waveram, std::array::from_fn(|idx| idx as u8));
+    }
+
+    #[test]
+    fn frame_sequencer_div_edge() {
+        let mut apu = APU::power_up(44_100);
+        apu.write_byte(0xff26, 0x80);
+        // Only a falling edge of bit 4 steps it.
+        for (div, step) in [(0x10, 0), (0x00, 1), (0x00, 1), (0x1f, 1), (0x20, 2), (0x30, 2), (0x00, 3)] {
+            apu.next(4, div);
+            assert_eq!(apu.fs.step, step, "DIV {:#04x}", div);
+        }
+
+        // Stopped while powered off.
+        apu.write_byte(0xff26, 0x00);
+        apu.next(4, 0x10);
+        apu.next(4, 0x00);
+        assert_eq!(apu.fs.step, 3);
+    }
+
+    #[test]
+    fn muted_channel() {
+        let mut apu = playing_apu(0xff, true, false);
+        apu.mute_channel(0, true);
+        let frame = apu.mix_single_frame();
+        assert!(!frame.is_empty());
+        assert!(!left(&frame) && !right(&frame));
+
+        // Muting another channel leaves CH1 playing.
+        apu.mute_channel(0, false);
+        apu.mute_channel(1, true);
+        let frame = apu.mix_single_frame();
+        assert!(left(&frame) && right(&frame));
+    }
+
+    #[test]
+    fn noise_period() {
+        let mut apu = APU::power_up(44_100);
+        apu.write_byte(0xff26, 0x80);
+        for (nr43, period) in [(0x00, 8), (0x01, 16), (0x02, 32), (0x07, 112), (0x10, 16), (0xd7, 112 << 13)] {
+            apu.write_byte(0xff22, nr43);
+            assert_eq!(apu.channel4.timer.period, period, "NR43 {:#04x}", nr43);
+        }
+    }
+
+    #[test]
+    fn noise_max_period_clocks() {
+        let mut apu = APU::power_up(44_100);
+        apu.write_byte(0xff26, 0x80);
+        // Divisor code 7 and clock shift 13, 917504 cycles per clock.
+        apu.write_byte(0xff22, 0xd7);
+        apu.write_byte(0xff23, 0x80);
+        let period = 112 << 13;
+
+        // Run ~4M cycles in steps shorter than the period, counting LFSR clocks.
+        let mut clocks = 0;
+        let mut lfsr = apu.channel4.lfsr.n;
+        for step in 1..=1024 {
+            apu.channel4.next(4096);
+            if apu.channel4.lfsr.n != lfsr {
+                lfsr = apu.channel4.lfsr.n;
+                clocks += 1;
+                assert_eq!(step * 4096 / period, clocks);
+            }
+        }
+        assert_eq!(clocks, 1024 * 4096 / period);
+        assert_eq!(apu.channel4.timer.n, 1024 * 4096 % period);
+    }
+}
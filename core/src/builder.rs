@@ -0,0 +1,257 @@
+use crate::cartridge::{self, Cartridge, CartError, strip_crc};
+use crate::cpu::CPU;
+use crate::serial::SerialCallback;
+pub use crate::serial::LinkCable;
+#[cfg(feature = "audio")]
+use crate::apu::{APU, DEFAULT_BUFFER_FRAMES};
+
+// Which hardware the emulator presents itself as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompatMode {
+    // Decided by the CGB flag in the cartridge header.
+    #[default]
+    Auto,
+    Dmg,
+    Cgb,
+    // A DMG, with the palettes sent by SGB roms.
+    Sgb,
+}
+
+// Collects the options for a new emulator, e.g.
+//   GameboyBuilder::new().rom(data).audio(44_100).build()?
+#[derive(Default)]
+pub struct GameboyBuilder {
+    rom:            Option<Vec<u8>>,
+    cartridge:      Option<Box<dyn Cartridge>>,
+    // Battery RAM with the CRC32 footer added by append_crc.
+    save_data:      Option<Vec<u8>>,
+    callback:       SerialCallback,
+    link:           Option<Box<dyn LinkCable>>,
+    #[cfg(feature = "audio")]
+    sample_rate:    Option<u32>,
+    #[cfg(feature = "audio")]
+    audio_buffer_frames:    Option<u32>,
+    mode:           CompatMode,
+}
+
+impl GameboyBuilder {
+
+    pub fn new() -> Self { Self::default() }
+
+    pub fn rom(mut self, data: Vec<u8>) -> Self {
+        self.rom = Some(data);
+        self
+    }
+
+    // Already opened cartridge, used in place of rom data, for example one opened
+    // from a path so that it saves next to the rom.
+    pub fn cartridge(mut self, cartridge: Box<dyn Cartridge>) -> Self {
+        self.cartridge = Some(cartridge);
+        self
+    }
+
+    pub fn save_data(mut self, data: Vec<u8>) -> Self {
+        self.save_data = Some(data);
+        self
+    }
+
+    pub fn serial_callback(mut self, cb: Box<dyn Fn(u8)>) -> Self {
+        self.callback = Some(cb);
+        self
+    }
+
+    // Connects the serial port to another Game Boy, in place of the callback.
+    pub fn link_cable(mut self, link: Box<dyn LinkCable>) -> Self {
+        self.link = Some(link);
+        self
+    }
+
+    #[cfg(feature = "audio")]
+    pub fn audio(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    // Video frames of samples buffered before more are dropped, see APU::new.
+    #[cfg(feature = "audio")]
+    pub fn audio_buffer_frames(mut self, frames: u32) -> Self {
+        self.audio_buffer_frames = Some(frames);
+        self
+    }
+
+    pub fn cgb_mode(mut self, mode: CompatMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn build(self) -> Result<CPU, CartError> {
+        let mut cartridge = match (self.cartridge, self.rom) {
+            (Some(cartridge), _) => cartridge,
+            (None, Some(rom)) => cartridge::from_rom(rom)?,
+            (None, None) => return Err(CartError::NoRom),
+        };
+
+        if let Some(data) = self.save_data {
+            let mut state = cartridge.save_state();
+            let ram = strip_crc(&data, state.ram.len())?;
+            let len = state.ram.len().min(ram.len());
+            state.ram[..len].copy_from_slice(&ram[..len]);
+            cartridge.load_state(&state).expect("state taken from the same cartridge");
+        }
+
+        let cgb = match self.mode {
+            CompatMode::Auto => cartridge.is_cgb(),
+            CompatMode::Dmg | CompatMode::Sgb => false,
+            CompatMode::Cgb  => true,
+        };
+        let sgb = self.mode == CompatMode::Sgb && cartridge.is_sgb();
+
+        let mut cpu = CPU::new(cartridge, self.callback);
+        if let Some(link) = self.link {
+            cpu.mem.set_link(link);
+        }
+        #[cfg(feature = "audio")]
+        if let Some(sample_rate) = self.sample_rate {
+            let frames = self.audio_buffer_frames.unwrap_or(DEFAULT_BUFFER_FRAMES);
+            cpu.mem.apu = Some(APU::new(sample_rate, frames));
+        }
+        // After the APU is added, which also depends on the mode.
+        cpu.set_cgb(cgb);
+        cpu.mem.set_sgb(sgb);
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::bus::MemoryBus;
+    use crate::cartridge::{append_crc, CartError, NINTENDO_LOGO};
+    use super::{GameboyBuilder, CompatMode};
+
+    // Rom with a valid header for the given cartridge type, CGB flag and RAM size code.
+    fn rom(cart_type: u8, cgb_flag: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x134..0x13B].copy_from_slice(b"BUILDER");
+        rom[0x143] = cgb_flag;
+        rom[0x147] = cart_type;
+        rom[0x149] = ram_size;
+        let mut checksum: u8 = 0;
+        for b in &rom[0x134..0x14D] {
+            checksum = checksum.wrapping_sub(*b).wrapping_sub(1);
+        }
+        rom[0x14D] = checksum;
+        rom
+    }
+
+    fn reg_a(cpu: &crate::cpu::CPU) -> u8 {
+        (cpu.to_save_state().cpu.af >> 8) as u8
+    }
+
+    #[test]
+    fn defaults() {
+        let cpu = GameboyBuilder::new().rom(rom(0x00, 0x00, 0)).build().unwrap();
+        assert!(!cpu.is_cgb());
+        assert_eq!(reg_a(&cpu), 0x01);
+        assert_eq!(cpu.mem.cart_title(), "BUILDER");
+        #[cfg(feature = "audio")]
+        assert!(cpu.mem.apu.is_none());
+    }
+
+    #[test]
+    fn compat_mode() {
+        let cpu = GameboyBuilder::new().rom(rom(0x00, 0x80, 0)).build().unwrap();
+        assert!(cpu.is_cgb());
+        assert_eq!(reg_a(&cpu), 0x11);
+
+        let cpu = GameboyBuilder::new().rom(rom(0x00, 0x80, 0)).cgb_mode(CompatMode::Dmg).build().unwrap();
+        assert!(!cpu.is_cgb());
+        assert_eq!(reg_a(&cpu), 0x01);
+
+        let cpu = GameboyBuilder::new().rom(rom(0x00, 0x00, 0)).cgb_mode(CompatMode::Cgb).build().unwrap();
+        assert!(cpu.is_cgb());
+    }
+
+    #[test]
+    fn sgb() {
+        // PAL01 with colour 0 white and colours 1-3 black, sent over FF00 LSB first.
+        let mut packet = [0; 16];
+        packet[1..3].copy_from_slice(&[0xFF, 0x7F]);
+        let mut writes = vec![0x00, 0x30];
+        for i in 0..128 {
+            writes.push(if packet[i / 8] >> (i % 8) & 0x01 == 1 { 0x10 } else { 0x20 });
+            writes.push(0x30);
+        }
+        writes.extend([0x20, 0x30]);
+        let colours = [0xFFFFFF, 0x000000, 0x000000, 0x000000];
+
+        let mut sgb_rom = rom(0x00, 0x00, 0);
+        sgb_rom[0x146] = 0x03;
+        sgb_rom[0x14D] = sgb_rom[0x14D].wrapping_sub(0x03);
+        let mut cpu = GameboyBuilder::new().rom(sgb_rom.clone()).cgb_mode(CompatMode::Sgb).build().unwrap();
+        assert!(!cpu.is_cgb());
+        assert!(cpu.mem.sgb().is_some());
+        for b in &writes {
+            cpu.mem.write_byte(0xFF00, *b);
+        }
+        assert_eq!(cpu.mem.gpu.colours(), colours);
+
+        // Only SGB roms on an SGB.
+        for (rom, mode) in [(sgb_rom, CompatMode::Auto), (rom(0x00, 0x00, 0), CompatMode::Sgb)] {
+            let mut cpu = GameboyBuilder::new().rom(rom).cgb_mode(mode).build().unwrap();
+            assert!(cpu.mem.sgb().is_none());
+            for b in &writes {
+                cpu.mem.write_byte(0xFF00, *b);
+            }
+            assert_ne!(cpu.mem.gpu.colours(), colours);
+        }
+    }
+
+    #[test]
+    fn save_data() {
+        let mut ram = vec![0; 0x2000];
+        ram[0x10] = 0x42;
+        let mut cpu = GameboyBuilder::new()
+            .rom(rom(0x03, 0x00, 2))
+            .save_data(append_crc(&ram))
+            .build()
+            .unwrap();
+        cpu.mem.write_byte(0x0000, 0x0A);
+        assert_eq!(cpu.mem.read_byte(0xA010), 0x42);
+
+        let mut corrupt = append_crc(&ram);
+        corrupt[0] ^= 0xFF;
+        let result = GameboyBuilder::new().rom(rom(0x03, 0x00, 2)).save_data(corrupt).build();
+        assert!(matches!(result, Err(CartError::CorruptSave)));
+    }
+
+    #[test]
+    fn serial_callback() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sink = sent.clone();
+        let mut cpu = GameboyBuilder::new()
+            .rom(rom(0x00, 0x00, 0))
+            .serial_callback(Box::new(move |b| sink.borrow_mut().push(b)))
+            .build()
+            .unwrap();
+        cpu.mem.write_byte(0xFF01, b'A');
+        cpu.mem.write_byte(0xFF02, 0x81);
+        assert_eq!(*sent.borrow(), vec![b'A']);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn audio() {
+        let cpu = GameboyBuilder::new().rom(rom(0x00, 0x00, 0)).audio(48_000).build().unwrap();
+        assert!(cpu.mem.apu.is_some());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(GameboyBuilder::new().build(), Err(CartError::NoRom)));
+        assert!(matches!(GameboyBuilder::new().rom(vec![0; 0x100]).build(), Err(CartError::MissingInfo)));
+    }
+}
@@ -2,10 +2,19 @@ use thiserror::Error;
 use std::path::Path;
 
 use super::bus::MemoryBus;
+use super::state::{CartState, SaveStateError};
 use super::mbc::mbc1::MBC1;
 use super::mbc::mbc2::MBC2;
 use super::mbc::mbc3::MBC3;
 use super::mbc::mbc5::MBC5;
+use super::mbc::mbc6::MBC6;
+use super::mbc::mbc7::MBC7;
+use super::mbc::huc1::HuC1;
+use super::mbc::rom_ram::RomRam;
+#[cfg(not(target_arch = "wasm32"))]
+use super::mbc::verify_save;
+pub use super::mbc::{append_crc, strip_crc};
+pub use super::mbc::mbc7::TiltSensor;
 
 #[derive(Error, Debug)]
 pub enum CartError {
@@ -13,25 +22,47 @@ pub enum CartError {
     IncorrectLogo,
     #[error("header checksum incorrect")]
     IncorrectChecksum,
+    #[error("global checksum incorrect")]
+    IncorrectGlobalChecksum,
     #[error("missing info in cartridge header")]
     MissingInfo,
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error("unsupported cartridge type: {0}")]
     UnsupportedCartType(u8),
+    #[error("save data failed its integrity check")]
+    CorruptSave,
+    #[error("no rom was provided")]
+    NoRom,
+    #[cfg(feature = "zip")]
+    #[error(transparent)]
+    ZipError(#[from] zip::result::ZipError),
+    #[cfg(feature = "zip")]
+    #[error("no .gb or .gbc rom found in zip archive")]
+    NoRomInArchive,
 }
 
 type Result<T> = std::result::Result<T, CartError>;
 
+// What to do when battery save data fails its CRC32 check.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SaveRecovery {
+    // Refuse to open the cartridge with CartError::CorruptSave.
+    #[default]
+    Fail,
+    // Discard the save and start with zeroed RAM.
+    Reset,
+}
+
 // Nintendo logo bitmap, cartridge address range $0104-$0133 must match.
 // https://gbdev.io/pandocs/The_Cartridge_Header.html#0104-0133---nintendo-logo
-const NINTENDO_LOGO: [u8; 48] = [
+pub(crate) const NINTENDO_LOGO: [u8; 48] = [
     0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11,
     0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E,
     0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
 ];
 
-const SAVEABLE : [u8; 11] = [0x03, 0x06, 0x09, 0x0D, 0x0F, 0x10, 0x13, 0x1B, 0x1E, 0x22, 0xFF];
+const SAVEABLE : [u8; 12] = [0x03, 0x06, 0x09, 0x0D, 0x0F, 0x10, 0x13, 0x1B, 0x1E, 0x20, 0x22, 0xFF];
 
 pub trait Cartridge: MemoryBus {
     #[cfg(not(target_arch = "wasm32"))]
@@ -41,6 +72,9 @@ pub trait Cartridge: MemoryBus {
     fn save(&self) -> *const u8;
 
     fn len(&self) -> usize;
+
+    // Whole ROM, regardless of which banks are mapped.
+    fn rom(&self) -> &[u8];
     
     // The Game Boy’s boot procedure first displays the logo and then checks that it matches the dump above. 
     // If it doesn’t, the boot ROM locks itself up.
@@ -66,6 +100,20 @@ pub trait Cartridge: MemoryBus {
         }
     }
 
+    // Bytes 0x014E-0x014F hold the big endian sum of every other byte in the ROM.
+    fn verify_global_checksum(&self) -> Result<()> {
+        let sum = self.rom().iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0x014E && *i != 0x014F)
+            .fold(0_u16, |sum, (_, b)| sum.wrapping_add(*b as u16));
+        let stored = u16::from_be_bytes([self.read_byte(0x014E), self.read_byte(0x014F)]);
+        if sum != stored {
+            Err(CartError::IncorrectGlobalChecksum)
+        } else {
+            Ok(())
+        }
+    }
+
     // Retrieve title of game in upper-case ASCI.
     fn title(&self) -> String {
         let mut title = String::new();
@@ -77,37 +125,7 @@ pub trait Cartridge: MemoryBus {
 
     // Retrieve type of cartridge.
     fn cartridge_type(&self) -> String {
-        match self.read_byte(0x147) {
-            0x00 => "ROM ONLY",
-            0x01 => "MBC1",
-            0x02 => "MBC1+RAM",
-            0x03 => "MBC1+RAM+BATTERY",
-            0x05 => "MBC2",
-            0x06 => "MBC2+BATTERY",
-            0x08 => "ROM+RAM",
-            0x09 => "ROM+RAM+BATTERY",
-            0x0B => "MMM01",
-            0x0C => "MMM01+RAM",
-            0x0D => "MMM01+RAM+BATTERY",
-            0x0F => "MBC3+TIMER+BATTERY",
-            0x10 => "MBC3+TIMER+RAM+BATTERY",
-            0x11 => "MBC3",
-            0x12 => "MBC3+RAM",
-            0x13 => "MBC3+RAM+BATTERY",
-            0x19 => "MBC5",
-            0x1A => "MBC5+RAM",
-            0x1B => "MBC5+RAM+BATTERY",
-            0x1C => "MBC5+RUMBLE",
-            0x1D => "MBC5+RUMBLE+RAM",
-            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
-            0x20 => "MBC6",
-            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
-            0xFC => "POCKET CAMERA",
-            0xFD => "BANDAI TAMA5",
-            0xFE => "HuC3",
-            0xFF => "HuC1+RAM+BATTERY",
-            _ => "UNKNOWN",
-        }.to_string()
+        cart_type_name(self.read_byte(0x147)).to_string()
     }
 
     fn is_cgb(&self) -> bool {
@@ -117,22 +135,180 @@ pub trait Cartridge: MemoryBus {
         }
     }
 
+    // Supports Super Game Boy functions.
+    fn is_sgb(&self) -> bool {
+        self.read_byte(0x146) == 0x03
+    }
+
     fn is_saveable(&self) -> bool {
         if SAVEABLE.contains(&self.read_byte(0x147)) { true } else { false }
     }
+
+    // Banking registers and RAM for save states, ROM only cartridges have none.
+    fn save_state(&self) -> CartState { CartState::default() }
+
+    fn load_state(&mut self, _: &CartState) -> std::result::Result<(), SaveStateError> { Ok(()) }
+
+    // Every header field in one place.
+    fn info(&self) -> CartridgeInfo {
+        let mut manufacturer_code = [0; 4];
+        for (i, b) in manufacturer_code.iter_mut().enumerate() {
+            *b = self.read_byte(0x13F + i as u16);
+        }
+        // Ends at the first NUL, newer cartridges use the end of the title area for other fields.
+        let title = self.title().split('\0').next().unwrap_or_default()
+            .trim_end_matches(|c: char| !c.is_ascii_graphic()).to_string();
+        let cart_type_byte = self.read_byte(0x147);
+        CartridgeInfo {
+            title,
+            manufacturer_code,
+            cart_type_byte,
+            cart_type_name: cart_type_name(cart_type_byte),
+            rom_size_bytes: rom_size_from_header(self.read_byte(0x148)),
+            ram_size_bytes: ram_size(self.read_byte(0x149)),
+            is_cgb:         self.is_cgb(),
+            is_sgb:         self.is_sgb(),
+            is_saveable:    self.is_saveable(),
+            destination:    self.read_byte(0x14A),
+            rom_version:    self.read_byte(0x14C),
+            logo_valid:             self.verify_logo().is_ok(),
+            header_checksum_valid:  self.verify_checksum().is_ok(),
+            global_checksum_valid:  self.verify_global_checksum().is_ok(),
+        }
+    }
+
+    // Accelerometer of MBC7 cartridges.
+    fn tilt_sensor(&mut self) -> Option<&mut TiltSensor> { None }
+
+    // Cartridges with their own IR port receive the signal from another device here.
+    fn receive_ir(&mut self, _signal: bool) {}
+
+    // Whether the cartridge is sending, its IR LED is on.
+    fn send_ir(&self) -> bool { false }
+
+    // Whether the rumble motor is running.
+    fn is_rumbling(&self) -> bool { false }
+}
+
+// Parsed cartridge header, see Cartridge::info.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CartridgeInfo {
+    pub title:              String,
+    pub manufacturer_code:  [u8; 4],
+    pub cart_type_byte:     u8,
+    pub cart_type_name:     &'static str,
+    pub rom_size_bytes:     usize,
+    pub ram_size_bytes:     usize,
+    pub is_cgb:             bool,
+    pub is_sgb:             bool,
+    pub is_saveable:        bool,
+    // 0x00 Japan, 0x01 elsewhere.
+    pub destination:        u8,
+    pub rom_version:        u8,
+    pub logo_valid:             bool,
+    pub header_checksum_valid:  bool,
+    pub global_checksum_valid:  bool,
+}
+
+impl std::fmt::Display for CartridgeInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Title:         {}", self.title)?;
+        writeln!(f, "Manufacturer:  {}", String::from_utf8_lossy(&self.manufacturer_code).trim_end_matches('\0'))?;
+        writeln!(f, "Type:          {} ({:#04X})", self.cart_type_name, self.cart_type_byte)?;
+        writeln!(f, "ROM size:      {} KiB", self.rom_size_bytes / 1024)?;
+        writeln!(f, "RAM size:      {} KiB", self.ram_size_bytes / 1024)?;
+        writeln!(f, "CGB:           {}", self.is_cgb)?;
+        writeln!(f, "SGB:           {}", self.is_sgb)?;
+        writeln!(f, "Battery save:  {}", self.is_saveable)?;
+        writeln!(f, "Destination:   {}", if self.destination == 0x00 { "Japan" } else { "Overseas" })?;
+        writeln!(f, "Version:       {}", self.rom_version)?;
+        writeln!(f, "Logo:          {}", check(self.logo_valid))?;
+        writeln!(f, "Checksum:      {}", check(self.header_checksum_valid))?;
+        write!(f, "Global sum:    {}", check(self.global_checksum_valid))
+    }
+}
+
+fn check(valid: bool) -> &'static str {
+    if valid { "ok" } else { "incorrect" }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn open_cartridge(path: &Path) -> Result<Box<dyn Cartridge>> {
-    let buf = std::fs::read(path)?; 
+    open_cartridge_with(path, SaveRecovery::default())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn open_cartridge_with(path: &Path, recovery: SaveRecovery) -> Result<Box<dyn Cartridge>> {
+    let (buf, save_path, rtc_path) = read_rom(path, recovery)?;
+    build_cartridge(buf, save_path, rtc_path)
+}
+
+// Opens roms with an incorrect logo or header checksum too, which the boot ROM would refuse.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn open_cartridge_unverified(path: &Path, recovery: SaveRecovery) -> Result<Box<dyn Cartridge>> {
+    let (buf, save_path, rtc_path) = read_rom(path, recovery)?;
+    mapper(buf, save_path, rtc_path)
+}
+
+// Rom data and the paths of its save and clock files.
+#[cfg(not(target_arch = "wasm32"))]
+fn read_rom(
+    path: &Path,
+    recovery: SaveRecovery,
+) -> Result<(Vec<u8>, Option<std::path::PathBuf>, Option<std::path::PathBuf>)> {
+    let buf = match path.extension().and_then(|ext| ext.to_str()) {
+        #[cfg(feature = "zip")]
+        Some("zip") => read_zip(std::fs::File::open(path)?)?,
+        _ => std::fs::read(path)?,
+    };
 
     let save_path = Some(path.to_path_buf().with_extension("sav"));
     let rtc_path  = Some(path.to_path_buf().with_extension("rtc"));
 
+    if recovery == SaveRecovery::Fail {
+        verify_save(save_path.as_ref().unwrap(), save_ram_size(&buf))?;
+    }
+    Ok((buf, save_path, rtc_path))
+}
+
+// Cartridge from rom data alone, battery RAM is not loaded from or saved to a file.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn from_rom(buf: Vec<u8>) -> Result<Box<dyn Cartridge>> {
+    build_cartridge(buf, None, None)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn build_cartridge(
+    buf: Vec<u8>,
+    save_path: Option<std::path::PathBuf>,
+    rtc_path: Option<std::path::PathBuf>,
+) -> Result<Box<dyn Cartridge>> {
+    let cartridge = mapper(buf, save_path, rtc_path)?;
+    // If verification of logo or checksum fails, program should panic.
+    cartridge.verify_logo()?;
+    cartridge.verify_checksum()?;
+    Ok(cartridge)
+}
+
+// Cartridge from rom data without checking the logo and header checksum, for test roms
+// that leave the header empty.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn from_rom_unchecked(buf: Vec<u8>) -> Result<Box<dyn Cartridge>> {
+    mapper(buf, None, None)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn mapper(
+    buf: Vec<u8>,
+    save_path: Option<std::path::PathBuf>,
+    rtc_path: Option<std::path::PathBuf>,
+) -> Result<Box<dyn Cartridge>> {
+
     // Cartridge has a header addr range $0100—$014F, followed by a JUMP @ $0150
     if buf.len() < 0x0150 {
         return Err(CartError::MissingInfo);
     }
+    check_rom_size(&buf)?;
     // byte 0x0147 indicates what kind of hardware is present on the cartridge — most notably its mapper.
     let cartridge: Box<dyn Cartridge> = match buf[0x147] {
         // ROM only.
@@ -149,6 +325,10 @@ pub fn open_cartridge(path: &Path) -> Result<Box<dyn Cartridge>> {
             let ram_size = ram_size(buf[0x149]);
             Box::new(MBC1::new(buf, ram_size, save_path))
         },
+        // ROM + RAM.
+        0x08 => Box::new(RomRam::new(buf, None)),
+        // ROM + RAM + BATTERY.
+        0x09 => Box::new(RomRam::new(buf, save_path)),
         // MBC2.
         0x05 => Box::new(MBC2::new(buf, 512, None)),
         // MBC2 + BATTERY.
@@ -173,33 +353,75 @@ pub fn open_cartridge(path: &Path) -> Result<Box<dyn Cartridge>> {
             Box::new(MBC3::new(buf, ram_size, save_path, None))
         },
         // MBC5.
-        0x19 => Box::new(MBC5::new(buf, 0, None)),
+        0x19 => Box::new(MBC5::new(buf, 0, None, false)),
         // MBC5 + RAM.
         0x1A => {
             let ram_size = ram_size(buf[0x149]);
-            Box::new(MBC5::new(buf, ram_size, None))
+            Box::new(MBC5::new(buf, ram_size, None, false))
         },
         // MBC5 + RAM + BATTERY.
         0x1B => {
             let ram_size = ram_size(buf[0x149]);
-            Box::new(MBC5::new(buf, ram_size, save_path))
+            Box::new(MBC5::new(buf, ram_size, save_path, false))
+        },
+        // MBC5 + RUMBLE.
+        0x1C => Box::new(MBC5::new(buf, 0, None, true)),
+        // MBC5 + RUMBLE + RAM.
+        0x1D => {
+            let ram_size = ram_size(buf[0x149]);
+            Box::new(MBC5::new(buf, ram_size, None, true))
+        },
+        // MBC5 + RUMBLE + RAM + BATTERY.
+        0x1E => {
+            let ram_size = ram_size(buf[0x149]);
+            Box::new(MBC5::new(buf, ram_size, save_path, true))
+        },
+        // MBC6.
+        0x20 => {
+            let ram_size = ram_size(buf[0x149]);
+            Box::new(MBC6::new(buf, ram_size, save_path))
+        },
+        // MBC7 + SENSOR + RUMBLE + RAM + BATTERY.
+        0x22 => Box::new(MBC7::new(buf, save_path)),
+        // HuC1 + RAM + BATTERY.
+        0xFF => {
+            let ram_size = ram_size(buf[0x149]);
+            Box::new(HuC1::new(buf, ram_size, save_path))
         },
         unknown => return Err(CartError::UnsupportedCartType(unknown)),
     };
-    
-    // If verification of logo or checksum fails, program should panic.
-    cartridge.verify_logo()?;
-    cartridge.verify_checksum()?;
     Ok(cartridge)
 }
 
 #[cfg(target_arch = "wasm32")]
-pub fn open_cartridge(buf: Vec<u8>, save_data: Option<Vec<u8>>) -> Result<Box<dyn Cartridge>>{
+pub fn open_cartridge(buf: Vec<u8>, save_data: Option<Vec<u8>>) -> Result<Box<dyn Cartridge>> {
+    open_cartridge_with(buf, save_data, SaveRecovery::default())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn from_rom(buf: Vec<u8>) -> Result<Box<dyn Cartridge>> {
+    open_cartridge(buf, None)
+}
+
+// Save data ends with the CRC32 footer added by append_crc, or is the RAM alone for older saves.
+#[cfg(target_arch = "wasm32")]
+pub fn open_cartridge_with(
+    buf: Vec<u8>,
+    save_data: Option<Vec<u8>>,
+    recovery: SaveRecovery,
+) -> Result<Box<dyn Cartridge>> {
+
+    let save_data = match save_data.map(|data| strip_crc(&data, save_ram_size(&buf))) {
+        Some(Ok(ram)) => Some(ram),
+        Some(Err(e)) if recovery == SaveRecovery::Fail => return Err(e),
+        _ => None,
+    };
 
     // Cartridge has a header addr range $0100—$014F, followed by a JUMP @ $0150
     if buf.len() < 0x0150 {
         return Err(CartError::MissingInfo);
     }
+    check_rom_size(&buf)?;
     // byte 0x0147 indicates what kind of hardware is present on the cartridge — most notably its mapper.
     let cartridge: Box<dyn Cartridge> = match buf[0x147] {
         // ROM only.
@@ -216,6 +438,10 @@ pub fn open_cartridge(buf: Vec<u8>, save_data: Option<Vec<u8>>) -> Result<Box<dy
             let ram_size = ram_size(buf[0x149]);
             Box::new(MBC1::new(buf, ram_size, save_data))
         },
+        // ROM + RAM.
+        0x08 => Box::new(RomRam::new(buf, None)),
+        // ROM + RAM + BATTERY.
+        0x09 => Box::new(RomRam::new(buf, save_data)),
         // MBC2.
         0x05 => Box::new(MBC2::new(buf, 512, None)),
         // MBC2 + BATTERY.
@@ -240,16 +466,40 @@ pub fn open_cartridge(buf: Vec<u8>, save_data: Option<Vec<u8>>) -> Result<Box<dy
             Box::new(MBC3::new(buf, ram_size, save_data, None))
         },
         // MBC5.
-        0x19 => Box::new(MBC5::new(buf, 0, None)),
+        0x19 => Box::new(MBC5::new(buf, 0, None, false)),
         // MBC5 + RAM.
         0x1A => {
             let ram_size = ram_size(buf[0x149]);
-            Box::new(MBC5::new(buf, ram_size, None))
+            Box::new(MBC5::new(buf, ram_size, None, false))
         },
         // MBC5 + RAM + BATTERY.
         0x1B => {
             let ram_size = ram_size(buf[0x149]);
-            Box::new(MBC5::new(buf, ram_size, save_data))
+            Box::new(MBC5::new(buf, ram_size, save_data, false))
+        },
+        // MBC5 + RUMBLE.
+        0x1C => Box::new(MBC5::new(buf, 0, None, true)),
+        // MBC5 + RUMBLE + RAM.
+        0x1D => {
+            let ram_size = ram_size(buf[0x149]);
+            Box::new(MBC5::new(buf, ram_size, None, true))
+        },
+        // MBC5 + RUMBLE + RAM + BATTERY.
+        0x1E => {
+            let ram_size = ram_size(buf[0x149]);
+            Box::new(MBC5::new(buf, ram_size, save_data, true))
+        },
+        // MBC6.
+        0x20 => {
+            let ram_size = ram_size(buf[0x149]);
+            Box::new(MBC6::new(buf, ram_size, save_data))
+        },
+        // MBC7 + SENSOR + RUMBLE + RAM + BATTERY.
+        0x22 => Box::new(MBC7::new(buf, save_data)),
+        // HuC1 + RAM + BATTERY.
+        0xFF => {
+            let ram_size = ram_size(buf[0x149]);
+            Box::new(HuC1::new(buf, ram_size, save_data))
         },
         unknown => return Err(CartError::UnsupportedCartType(unknown)),
     };
@@ -260,6 +510,87 @@ pub fn open_cartridge(buf: Vec<u8>, save_data: Option<Vec<u8>>) -> Result<Box<dy
     Ok(cartridge)
 }
 
+// Extracts a rom from a zip archive, if there are multiple the first alphabetically is used.
+#[cfg(feature = "zip")]
+pub fn read_zip<R: std::io::Read + std::io::Seek>(reader: R) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut roms: Vec<String> = archive.file_names()
+        .filter(|name| {
+            let name = name.to_lowercase();
+            name.ends_with(".gb") || name.ends_with(".gbc")
+        })
+        .map(String::from)
+        .collect();
+    roms.sort();
+
+    let name = roms.first().ok_or(CartError::NoRomInArchive)?;
+    let mut file = archive.by_name(name)?;
+    let mut buf = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+// Name of the cartridge type in header byte 0x0147.
+pub fn cart_type_name(b: u8) -> &'static str {
+    match b {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0B => "MMM01",
+        0x0C => "MMM01+RAM",
+        0x0D => "MMM01+RAM+BATTERY",
+        0x0F => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1A => "MBC5+RAM",
+        0x1B => "MBC5+RAM+BATTERY",
+        0x1C => "MBC5+RUMBLE",
+        0x1D => "MBC5+RUMBLE+RAM",
+        0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xFC => "POCKET CAMERA",
+        0xFD => "BANDAI TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1+RAM+BATTERY",
+        _ => "UNKNOWN",
+    }
+}
+
+// ROM size in bytes from header byte 0x0148, 0 if unknown.
+pub fn rom_size_from_header(b: u8) -> usize {
+    let bank = 16 * 1024;
+    match b {
+        0x00 ..= 0x08 => 32_768 * (1 << b),
+        0x52 => 72 * bank,
+        0x53 => 80 * bank,
+        0x54 => 96 * bank,
+        _ => 0,
+    }
+}
+
+// Truncated roms are rejected, padded ones only warned about.
+fn check_rom_size(buf: &[u8]) -> Result<()> {
+    let expected = rom_size_from_header(buf[0x148]);
+    if buf.len() < expected {
+        return Err(CartError::MissingInfo);
+    }
+    if expected != 0 && buf.len() > expected {
+        eprintln!("rom is {} bytes, larger than the {} given in its header", buf.len(), expected);
+    }
+    Ok(())
+}
+
 // byte 0x0149 indicates size of RAM, if any.
 // https://gbdev.io/pandocs/The_Cartridge_Header.html#0149---ram-size
 pub fn ram_size(n: u8) -> usize {
@@ -275,6 +606,16 @@ pub fn ram_size(n: u8) -> usize {
     }
 }
 
+// RAM kept in a battery save, MBC2, ROM + RAM and MBC7 have a fixed size whatever the header says.
+fn save_ram_size(buf: &[u8]) -> usize {
+    match buf.get(0x147) {
+        Some(0x05 | 0x06) => 512,
+        Some(0x08 | 0x09) => crate::mbc::rom_ram::RAM_SIZE,
+        Some(0x22) => crate::mbc::mbc7::EEPROM_SIZE,
+        _ => ram_size(buf.get(0x149).copied().unwrap_or(0)),
+    }
+}
+
 // Small games of not more than 32 KiB ROM do not require a MBC chip for ROM banking.
 pub struct ROM(Vec<u8>);
 
@@ -285,7 +626,7 @@ impl ROM {
 }
 
 impl MemoryBus for ROM {
-    fn read_byte(&self, address: u16) -> u8 { self.0[address as usize] }
+    fn read_byte(&self, address: u16) -> u8 { self.0.get(address as usize).copied().unwrap_or(0xFF) }
     // ROM is read-only so no write functionality.
     fn write_byte(&mut self, _: u16, _: u8) {}
 }
@@ -294,6 +635,8 @@ impl Cartridge for ROM {
 
     fn len(&self) -> usize { self.0.len() }
 
+    fn rom(&self) -> &[u8] { &self.0 }
+
     #[cfg(not(target_arch = "wasm32"))]
     fn save(&self) {}
 
@@ -329,4 +672,179 @@ mod test {
 
         assert_eq!(cart.read_byte(0x4000), 0xC3);
     }
+
+    // Random truncated roms for every mapper, with random reads and writes to the cartridge.
+    #[test]
+    fn malformed_roms() {
+        use crate::cartridge::mapper;
+
+        // Clock file of the wrong length, for the MBC3 + TIMER carts.
+        let rtc_path = std::env::temp_dir().join("gameboy_malformed_test.rtc");
+        std::fs::write(&rtc_path, [0x12, 0x34, 0x56]).unwrap();
+
+        // Xorshift, the same sequence every run.
+        let mut state: u32 = 0x2545_F491;
+        let mut rand = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        for cart_type in [
+            0x00, 0x01, 0x02, 0x03, 0x05, 0x06, 0x08, 0x09, 0x0F, 0x10, 0x11, 0x12, 0x13,
+            0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x20, 0x22, 0xFF,
+        ] {
+            let len = 0x150 + rand() as usize % 0x8000;
+            let mut rom = (0..len).map(|_| rand() as u8).collect::<Vec<_>>();
+            rom[0x147] = cart_type;
+            // Unknown rom size, so the length isn't checked against the header.
+            rom[0x148] = 0xFF;
+            rom[0x149] = rand() as u8 % 6;
+            let mut cart = mapper(rom, None, Some(rtc_path.clone())).unwrap();
+
+            for _ in 0..10_000 {
+                let r = rand();
+                let address = if r & 0x8000_0000 != 0 { 0xA000 | r as u16 & 0x1FFF } else { r as u16 & 0x7FFF };
+                if r & 0x4000_0000 != 0 {
+                    cart.write_byte(address, (r >> 16) as u8);
+                } else {
+                    cart.read_byte(address);
+                }
+            }
+        }
+        std::fs::remove_file(rtc_path).unwrap();
+    }
+
+    // Smallest rom that passes logo and checksum verification.
+    fn minimal_rom(title: &str) -> Vec<u8> {
+        use crate::cartridge::NINTENDO_LOGO;
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        let mut checksum: u8 = 0;
+        for b in &rom[0x134..0x14D] {
+            checksum = checksum.wrapping_sub(*b).wrapping_sub(1);
+        }
+        rom[0x14D] = checksum;
+        rom
+    }
+
+    #[test]
+    fn header_info() {
+        use crate::cartridge::from_rom;
+
+        let mut rom = minimal_rom("INFO");
+        rom[0x13F..0x143].copy_from_slice(b"ABCD");
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x02;
+        rom[0x14A] = 0x01;
+        rom[0x14C] = 0x02;
+        rom.resize(0x10000, 0);
+        let mut checksum: u8 = 0;
+        for b in &rom[0x134..0x14D] {
+            checksum = checksum.wrapping_sub(*b).wrapping_sub(1);
+        }
+        rom[0x14D] = checksum;
+
+        let info = from_rom(rom).unwrap().info();
+        assert_eq!(info.title, "INFO");
+        assert_eq!(&info.manufacturer_code, b"ABCD");
+        assert_eq!(info.cart_type_byte, 0x03);
+        assert_eq!(info.cart_type_name, "MBC1+RAM+BATTERY");
+        assert_eq!(info.rom_size_bytes, 0x10000);
+        assert_eq!(info.ram_size_bytes, 0x2000);
+        assert!(!info.is_cgb);
+        assert!(info.is_sgb);
+        assert!(info.is_saveable);
+        assert_eq!(info.destination, 0x01);
+        assert_eq!(info.rom_version, 0x02);
+        assert!(info.logo_valid);
+        assert!(info.header_checksum_valid);
+        assert!(!info.global_checksum_valid);
+
+        // Opened without verification, the failed checks are reported instead.
+        let mut rom = minimal_rom("BROKEN");
+        rom[0x104] = 0;
+        rom[0x14D] ^= 0xFF;
+        let info = super::from_rom_unchecked(rom).unwrap().info();
+        assert!(!info.logo_valid);
+        assert!(!info.header_checksum_valid);
+    }
+
+    #[test]
+    fn global_checksum() {
+        use crate::cartridge::{from_rom, CartError};
+
+        let mut rom = minimal_rom("GLOBAL");
+        rom.resize(0x10000, 0);
+        rom[0x148] = 0x01;
+        rom[0x14D] = rom[0x14D].wrapping_sub(1);
+        // In the second bank, out of reach of read_byte while bank 1 is mapped.
+        rom[0xC000] = 0x12;
+        let sum = rom.iter().fold(0_u16, |sum, b| sum.wrapping_add(*b as u16));
+        rom[0x14E..0x150].copy_from_slice(&sum.to_be_bytes());
+        let cart = from_rom(rom.clone()).unwrap();
+        assert!(cart.verify_global_checksum().is_ok());
+
+        rom[0xC000] = 0x13;
+        let cart = from_rom(rom).unwrap();
+        assert!(matches!(cart.verify_global_checksum(), Err(CartError::IncorrectGlobalChecksum)));
+    }
+
+    #[test]
+    fn rom_size() {
+        use crate::cartridge::{from_rom, rom_size_from_header, CartError};
+
+        assert_eq!(rom_size_from_header(0x00), 0x8000);
+        assert_eq!(rom_size_from_header(0x05), 0x10_0000);
+        assert_eq!(rom_size_from_header(0x08), 0x80_0000);
+        assert_eq!(rom_size_from_header(0x52), 72 * 0x4000);
+        assert_eq!(rom_size_from_header(0x54), 96 * 0x4000);
+
+        let rom = minimal_rom("SIZE");
+        assert!(from_rom(rom.clone()).is_ok());
+
+        let mut truncated = rom.clone();
+        truncated.truncate(0x4000);
+        assert!(matches!(from_rom(truncated), Err(CartError::MissingInfo)));
+
+        let mut padded = rom;
+        padded.resize(0xC000, 0xFF);
+        assert!(from_rom(padded).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "zip")]
+    fn zip_archive() {
+        use std::io::{Cursor, Write};
+        use zip::{ZipWriter, write::FileOptions};
+        use crate::cartridge::{read_zip, CartError};
+
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("readme.txt", FileOptions::default()).unwrap();
+        writer.write_all(b"not a rom").unwrap();
+        writer.start_file("b.gb", FileOptions::default()).unwrap();
+        writer.write_all(&minimal_rom("SECOND")).unwrap();
+        writer.start_file("a.gb", FileOptions::default()).unwrap();
+        writer.write_all(&minimal_rom("FIRST")).unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        // First rom alphabetically is loaded.
+        assert_eq!(read_zip(Cursor::new(archive.clone())).unwrap(), minimal_rom("FIRST"));
+
+        let path = std::env::temp_dir().join("gameboy_zip_archive_test.zip");
+        std::fs::write(&path, archive).unwrap();
+        let cart = open_cartridge(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(cart.title().starts_with("FIRST"));
+
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("readme.txt", FileOptions::default()).unwrap();
+        let empty = writer.finish().unwrap().into_inner();
+        assert!(matches!(read_zip(Cursor::new(empty)), Err(CartError::NoRomInArchive)));
+    }
 }
\ No newline at end of file
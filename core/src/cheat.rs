@@ -0,0 +1,146 @@
+use std::str::FromStr;
+use thiserror::Error;
+
+// Cheat codes, entered as hex digits.
+//
+// Game Genie, ABC-DEF-GHI or ABC-DEF: patches reads from rom. AB is the new value and the
+// address is (F ^ 0xF) C D E. GHI is optional, G and I are the expected value xored with 0xBA
+// then rotated left by 2, the patch only applies while the rom holds that value, H is unused.
+//
+// GameShark, TTVVLLHH: writes VV to address HHLL every frame. TT is usually 01, 90-97 write
+// to that WRAM bank on CGB.
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CheatError {
+    #[error("invalid cheat code '{0}', expected ABC-DEF(-GHI) or TTVVAAAA")]
+    InvalidCode(String),
+    #[error("game genie code '{0}' patches {1:#06X}, outside of rom")]
+    OutsideRom(String, u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatCode {
+    GameGenie { addr: u16, new_val: u8, compare: Option<u8> },
+    GameShark { bank: u8, addr: u16, new_val: u8 },
+}
+
+impl FromStr for CheatCode {
+    type Err = CheatError;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let invalid = || CheatError::InvalidCode(code.to_string());
+        let digits = code.trim().chars()
+            .filter(|c| *c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let byte = |i: usize| digits[i] << 4 | digits[i + 1];
+
+        match digits.len() {
+            8 => Ok(CheatCode::GameShark {
+                bank:       byte(0),
+                new_val:    byte(2),
+                addr:       u16::from_le_bytes([byte(4), byte(6)]),
+            }),
+            6 | 9 => {
+                let addr = ((digits[5] ^ 0xF) as u16) << 12
+                    | (digits[2] as u16) << 8
+                    | (digits[3] as u16) << 4
+                    | digits[4] as u16;
+                if addr >= 0x8000 {
+                    return Err(CheatError::OutsideRom(code.to_string(), addr));
+                }
+                let compare = (digits.len() == 9)
+                    .then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA);
+                Ok(CheatCode::GameGenie { addr, new_val: byte(0), compare })
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CheatEngine {
+    codes: Vec<CheatCode>,
+}
+
+impl CheatEngine {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, code: &str) -> Result<(), CheatError> {
+        self.codes.push(code.parse()?);
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.codes.clear();
+    }
+
+    pub fn codes(&self) -> &[CheatCode] { &self.codes }
+
+    // Value read from rom at address after any Game Genie codes.
+    pub fn patch_read(&self, address: u16, value: u8) -> u8 {
+        for code in &self.codes {
+            if let CheatCode::GameGenie { addr, new_val, compare } = *code {
+                if addr == address && compare.is_none_or(|c| c == value) {
+                    return new_val;
+                }
+            }
+        }
+        value
+    }
+
+    // GameShark codes as (bank, address, value), to be written every frame.
+    pub fn writes(&self) -> impl Iterator<Item = (u8, u16, u8)> + '_ {
+        self.codes.iter().filter_map(|code| match *code {
+            CheatCode::GameShark { bank, addr, new_val } => Some((bank, addr, new_val)),
+            CheatCode::GameGenie { .. } => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CheatCode, CheatEngine, CheatError};
+
+    #[test]
+    fn game_genie() {
+        assert_eq!("00A-17B-C49".parse(), Ok(CheatCode::GameGenie {
+            addr: 0x4A17, new_val: 0x00, compare: Some(0xC8),
+        }));
+        assert_eq!("3E1-0BF".parse(), Ok(CheatCode::GameGenie {
+            addr: 0x010B, new_val: 0x3E, compare: None,
+        }));
+        assert_eq!("00A-170".parse::<CheatCode>(), Err(CheatError::OutsideRom("00A-170".to_string(), 0xFA17)));
+
+        let mut cheats = CheatEngine::new();
+        cheats.add("00A-17B-C49").unwrap();
+        cheats.add("3E1-0BF").unwrap();
+        // Only replaced while the rom holds the compare value.
+        assert_eq!(cheats.patch_read(0x4A17, 0xC8), 0x00);
+        assert_eq!(cheats.patch_read(0x4A17, 0xC9), 0xC9);
+        assert_eq!(cheats.patch_read(0x010B, 0x12), 0x3E);
+        assert_eq!(cheats.patch_read(0x010C, 0x12), 0x12);
+        assert_eq!(cheats.writes().count(), 0);
+    }
+
+    #[test]
+    fn game_shark() {
+        assert_eq!("010238CD".parse(), Ok(CheatCode::GameShark {
+            bank: 0x01, addr: 0xCD38, new_val: 0x02,
+        }));
+        assert_eq!("0102 38C".parse::<CheatCode>(), Err(CheatError::InvalidCode("0102 38C".to_string())));
+        assert_eq!("0102".parse::<CheatCode>(), Err(CheatError::InvalidCode("0102".to_string())));
+
+        let mut cheats = CheatEngine::new();
+        cheats.add("010238CD").unwrap();
+        cheats.add("92FF00D0").unwrap();
+        assert_eq!(cheats.writes().collect::<Vec<_>>(), vec![(0x01, 0xCD38, 0x02), (0x92, 0xD000, 0xFF)]);
+        assert_eq!(cheats.patch_read(0xCD38, 0x00), 0x00);
+        cheats.clear();
+        assert!(cheats.codes().is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+use crate::bus::MemoryBus;
+
+// Opcodes are decoded from their bit fields, xxyyyzzz with y split into ppq.
+// https://gb-archive.github.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
+const R8:  [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP:  [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC:  [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A, ", "ADC A, ", "SUB ", "SBC A, ", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+// Mnemonic of the instruction at address and the address of the instruction after it.
+pub fn disassemble(mem: &dyn MemoryBus, addr: u16) -> (String, u16) {
+    let (asm, len) = decode(mem, addr);
+    (asm, addr.wrapping_add(len))
+}
+
+// Mnemonic and length in bytes. Operands are written as d8, d16, a8, a16 and r8 in the
+// templates, then filled in.
+fn decode(mem: &dyn MemoryBus, address: u16) -> (String, u16) {
+    let opcode = mem.read_byte(address);
+    let b1 = mem.read_byte(address.wrapping_add(1));
+    let b2 = mem.read_byte(address.wrapping_add(2));
+    if opcode == 0xCB {
+        return (cb_template(b1), 2);
+    }
+
+    let template = template(opcode);
+    let word = u16::from_le_bytes([b1, b2]);
+    if template.contains("d16") || template.contains("a16") {
+        (template.replace("d16", &format!("${:04X}", word)).replace("a16", &format!("${:04X}", word)), 3)
+    } else if template.starts_with("JR") {
+        // Shown as the target address.
+        let target = address.wrapping_add(2).wrapping_add(b1 as i8 as u16);
+        (template.replace("r8", &format!("${:04X}", target)), 2)
+    } else if template.contains("r8") {
+        (template.replace("r8", &format!("{:+}", b1 as i8)), 2)
+    } else if template.contains("d8") || template.contains("a8") {
+        (template.replace("d8", &format!("${:02X}", b1)).replace("a8", &format!("${:02X}", b1)), 2)
+    } else {
+        (template, 1)
+    }
+}
+
+fn template(opcode: u8) -> String {
+    let x = (opcode >> 6) as usize;
+    let y = ((opcode >> 3) & 7) as usize;
+    let z = (opcode & 7) as usize;
+    let p = y >> 1;
+    let q = y & 1;
+
+    match (x, z) {
+        (0, 0) => match y {
+            0 => "NOP".to_string(),
+            1 => "LD (a16), SP".to_string(),
+            2 => "STOP".to_string(),
+            3 => "JR r8".to_string(),
+            _ => format!("JR {}, r8", CC[y - 4]),
+        },
+        (0, 1) if q == 0 => format!("LD {}, d16", RP[p]),
+        (0, 1) => format!("ADD HL, {}", RP[p]),
+        (0, 2) => {
+            let addr = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            if q == 0 { format!("LD {}, A", addr) } else { format!("LD A, {}", addr) }
+        },
+        (0, 3) if q == 0 => format!("INC {}", RP[p]),
+        (0, 3) => format!("DEC {}", RP[p]),
+        (0, 4) => format!("INC {}", R8[y]),
+        (0, 5) => format!("DEC {}", R8[y]),
+        (0, 6) => format!("LD {}, d8", R8[y]),
+        (0, _) => ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y].to_string(),
+
+        (1, 6) if y == 6 => "HALT".to_string(),
+        (1, _) => format!("LD {}, {}", R8[y], R8[z]),
+
+        (2, _) => format!("{}{}", ALU[y], R8[z]),
+
+        (_, 0) => match y {
+            0..=3 => format!("RET {}", CC[y]),
+            4 => "LDH (a8), A".to_string(),
+            5 => "ADD SP, r8".to_string(),
+            6 => "LDH A, (a8)".to_string(),
+            // r8 is shown with its sign.
+            _ => "LD HL, SPr8".to_string(),
+        },
+        (_, 1) if q == 0 => format!("POP {}", RP2[p]),
+        (_, 1) => ["RET", "RETI", "JP HL", "LD SP, HL"][p].to_string(),
+        (_, 2) => match y {
+            0..=3 => format!("JP {}, a16", CC[y]),
+            4 => "LD (C), A".to_string(),
+            5 => "LD (a16), A".to_string(),
+            6 => "LD A, (C)".to_string(),
+            _ => "LD A, (a16)".to_string(),
+        },
+        (_, 3) => match y {
+            0 => "JP a16".to_string(),
+            6 => "DI".to_string(),
+            7 => "EI".to_string(),
+            _ => format!("ILLEGAL ${:02X}", opcode),
+        },
+        (_, 4) if y < 4 => format!("CALL {}, a16", CC[y]),
+        (_, 5) if q == 0 => format!("PUSH {}", RP2[p]),
+        (_, 5) if p == 0 => "CALL a16".to_string(),
+        (_, 6) => format!("{}d8", ALU[y]),
+        (_, 7) => format!("RST ${:02X}", y * 8),
+        _ => format!("ILLEGAL ${:02X}", opcode),
+    }
+}
+
+fn cb_template(opcode: u8) -> String {
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 7) as usize;
+    let r = R8[(opcode & 7) as usize];
+    match x {
+        0 => format!("{} {}", ROT[y], r),
+        1 => format!("BIT {}, {}", y, r),
+        2 => format!("RES {}, {}", y, r),
+        _ => format!("SET {}, {}", y, r),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use super::disassemble;
+
+    struct Bytes(Vec<u8>);
+
+    impl MemoryBus for Bytes {
+        fn read_byte(&self, address: u16) -> u8 { self.0.get(address as usize).copied().unwrap_or(0) }
+        fn write_byte(&mut self, _: u16, _: u8) {}
+    }
+
+    // Disassembled from address 0, so the next address is the length.
+    fn dis(bytes: &[u8]) -> (String, u16) {
+        disassemble(&Bytes(bytes.to_vec()), 0)
+    }
+
+    #[test]
+    fn disassemble_opcodes() {
+        assert_eq!(dis(&[0x00]), ("NOP".to_string(), 1));
+        assert_eq!(dis(&[0x18, 0xFE]), ("JR $0000".to_string(), 2));
+        assert_eq!(dis(&[0x20, 0x05]), ("JR NZ, $0007".to_string(), 2));
+        assert_eq!(dis(&[0x21, 0x34, 0x12]), ("LD HL, $1234".to_string(), 3));
+        assert_eq!(dis(&[0x3E, 0x42]), ("LD A, $42".to_string(), 2));
+        assert_eq!(dis(&[0x76]), ("HALT".to_string(), 1));
+        assert_eq!(dis(&[0x78]), ("LD A, B".to_string(), 1));
+        assert_eq!(dis(&[0x7E]), ("LD A, (HL)".to_string(), 1));
+        assert_eq!(dis(&[0xAF]), ("XOR A".to_string(), 1));
+        assert_eq!(dis(&[0xC3, 0x50, 0x01]), ("JP $0150".to_string(), 3));
+        assert_eq!(dis(&[0xCD, 0x00, 0x40]), ("CALL $4000".to_string(), 3));
+        assert_eq!(dis(&[0xD9]), ("RETI".to_string(), 1));
+        assert_eq!(dis(&[0xE0, 0x40]), ("LDH ($40), A".to_string(), 2));
+        assert_eq!(dis(&[0xE8, 0xFE]), ("ADD SP, -2".to_string(), 2));
+        assert_eq!(dis(&[0xF8, 0x05]), ("LD HL, SP+5".to_string(), 2));
+        assert_eq!(dis(&[0x80]), ("ADD A, B".to_string(), 1));
+        assert_eq!(dis(&[0x90]), ("SUB B".to_string(), 1));
+        assert_eq!(dis(&[0xF5]), ("PUSH AF".to_string(), 1));
+        assert_eq!(dis(&[0xFF]), ("RST $38".to_string(), 1));
+        assert_eq!(dis(&[0xD3]), ("ILLEGAL $D3".to_string(), 1));
+        assert_eq!(dis(&[0xCB, 0x7C]), ("BIT 7, H".to_string(), 2));
+        assert_eq!(dis(&[0xCB, 0x37]), ("SWAP A".to_string(), 2));
+    }
+
+    #[test]
+    fn next_address() {
+        let bytes = Bytes(vec![0x00, 0xCD, 0x00, 0xC0, 0x18, 0x00]);
+        assert_eq!(disassemble(&bytes, 1), ("CALL $C000".to_string(), 4));
+        assert_eq!(disassemble(&bytes, 4), ("JR $0006".to_string(), 6));
+        // Wraps at the end of the address space.
+        assert_eq!(disassemble(&Bytes(vec![]), 0xFFFF).1, 0x0000);
+    }
+}
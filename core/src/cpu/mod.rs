@@ -1,15 +1,35 @@
+use std::collections::HashSet;
+use std::io::Write;
+
 use super::cartridge::Cartridge;
 use super::bus::MemoryBus;
 use super::memory::Memory;
 use super::serial::SerialCallback;
+use super::state::{SaveState, CpuState, SaveStateError, RewindBuffer};
+use super::cheat::CheatError;
 
 mod registers;
 mod opcodes;
+pub mod disasm;
 
 use registers::Registers;
 
 const STEP_TIME: u32 = 16;
 const STEP_CYCLES: u32 = (STEP_TIME as f64 / (1_000_f64 / 4_194_304_f64) ) as u32;
+// A frame is 154 scanlines of 456 dots, run_until_vblank gives up after two
+// so that a disabled LCD can't stall the caller.
+pub(crate) const FRAME_CYCLES: u32 = 154 * 456;
+
+// Returned by tick instead of a cycle count when stopped at a breakpoint.
+pub const BREAKPOINT_CYCLES: u32 = u32::MAX;
+
+// Result of running a single frame with run_until_vblank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameResult {
+    pub cycles_elapsed: u32,
+    // Address of the breakpoint the frame stopped at, if any.
+    pub breakpoint_hit: Option<u16>,
+}
 
 pub struct CPU {
     regs:               Registers,
@@ -27,11 +47,31 @@ pub struct CPU {
 
     // Provide control over speed of cpu clock.
     step_cycles:        u32,
+    // Multiplier of the real time clock rate, 0 is uncapped.
+    speed:              u32,
 
     #[cfg(not(target_arch = "wasm32"))] 
     step_zero:          std::time::Instant,
     
     step_flip:          bool,
+
+    // Addresses tick stops at before executing.
+    breakpoints:        HashSet<u16>,
+    last_breakpoint:    Option<u16>,
+    // The next tick runs the instruction at the breakpoint it stopped at.
+    resume:             bool,
+
+    // Running as a Game Boy Color, chosen by GameboyBuilder.
+    cgb:                bool,
+
+    // Set by HALT, the next fetch does not advance pc.
+    halt_bug:           bool,
+
+    // Receives a line of register state before each instruction.
+    trace:              Option<Box<dyn Write + Send>>,
+
+    // A save state of every frame finished by run_until_vblank.
+    rewind_buffer:      RewindBuffer,
 }
 
 impl CPU {
@@ -45,18 +85,41 @@ impl CPU {
             disable_interrupt:    0,
             enable_interrupt:     0,
             step_cycles:          0,
+            speed:                1,
 
             #[cfg(not(target_arch = "wasm32"))]
             step_zero:            std::time::Instant::now(),
             
             step_flip:            false,
+            breakpoints:          HashSet::new(),
+            last_breakpoint:      None,
+            resume:               false,
+            cgb:                  false,
+            halt_bug:             false,
+            trace:                None,
+            rewind_buffer:        RewindBuffer::default(),
         }
     }
 
+    // Games detect the CGB by A being 0x11 after the boot rom.
+    pub(crate) fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.regs.a = if cgb { 0x11 } else { 0x01 };
+        self.mem.set_cgb(cgb);
+    }
+
+    pub fn is_cgb(&self) -> bool { self.cgb }
+
+    pub fn is_double_speed(&self) -> bool { self.mem.is_double_speed() }
+
     // Reads next byte at stack pointer, increments pointer.
     fn next_byte(&mut self) -> u8 {
         let byte = self.mem.read_byte(self.regs.pc);
-        self.regs.pc += 1;
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.regs.pc += 1;
+        }
         byte
     }
 
@@ -68,13 +131,13 @@ impl CPU {
     }
 
     fn stack_push(&mut self, val: u16) {
-        self.regs.sp -= 2;
+        self.regs.sp = self.regs.sp.wrapping_sub(2);
         self.mem.write_word(self.regs.sp, val);
     }
 
     fn stack_pop(&mut self) -> u16 {
         let val = self.mem.read_word(self.regs.sp);
-        self.regs.sp += 2;
+        self.regs.sp = self.regs.sp.wrapping_add(2);
         val
     }
 }
@@ -82,7 +145,16 @@ impl CPU {
 impl CPU {
 
     // Performs a singular instruction or interrupt event.
+    // Returns BREAKPOINT_CYCLES without executing at a breakpoint, the following tick continues past it.
     pub fn tick(&mut self) -> u32 {
+        if self.resume {
+            self.resume = false;
+        } else if !self.halted && self.breakpoints.contains(&self.regs.pc) {
+            self.last_breakpoint = Some(self.regs.pc);
+            self.resume = true;
+            return BREAKPOINT_CYCLES;
+        }
+
         self.update_ime();
 
         let interrupt_cycles = self.check_interrupts();
@@ -93,17 +165,19 @@ impl CPU {
         if self.halted { 
             4 
         } else {
+            if self.trace.is_some() { self.write_trace() }
             let opcode = self.next_byte();
             self.execute(opcode) 
         }
     }
 
-    // Step runs at the documented 4.19 MHz
+    // Step runs at the documented 4.19 MHz, times the speed multiplier.
     #[cfg(not(target_arch = "wasm32"))]
     pub fn step(&mut self) -> u32 {
         
-        if self.step_cycles > STEP_CYCLES {
-            self.step_cycles -= STEP_CYCLES;
+        let step_cycles = STEP_CYCLES.saturating_mul(self.speed);
+        if self.speed != 0 && self.step_cycles > step_cycles {
+            self.step_cycles -= step_cycles;
             let now = std::time::Instant::now();
             
             let d = now.duration_since(self.step_zero);
@@ -117,10 +191,22 @@ impl CPU {
         }
 
         let cycles = self.tick();
-        self.step_cycles += cycles;
+        if cycles != BREAKPOINT_CYCLES && self.speed != 0 {
+            self.step_cycles += cycles;
+        }
         cycles
     }
 
+    // 1 is real time, 0 runs as fast as possible.
+    pub fn set_speed(&mut self, speed: u32) {
+        self.speed = speed;
+        self.step_cycles = 0;
+        #[cfg(not(target_arch = "wasm32"))]
+        { self.step_zero = std::time::Instant::now(); }
+    }
+
+    pub fn speed(&self) -> u32 { self.speed }
+
     /* Any set bits in the IF register are only requesting an interrupt. 
     The actual execution of the interrupt handler happens only if both the 
     IME flag and the corresponding bit in the IE register are set; otherwise 
@@ -130,11 +216,7 @@ impl CPU {
         // Neither halted not master interrupt flag set.
         if !self.halted && !self.ime { return 0 }
 
-        // Check for requests from interrupt registers.
-        let intf = self.mem.read_byte(0xFFFF);
-        let inte = self.mem.read_byte(0xFF0F);
-        
-        let pending_interrupts = intf & inte;
+        let pending_interrupts = self.pending_interrupts();
         if pending_interrupts == 0 { return 0 }
 
         // Halt is reset in case of interrupt.
@@ -148,6 +230,11 @@ impl CPU {
         16
     }
 
+    // Interrupts both requested (IF) and enabled (IE).
+    fn pending_interrupts(&self) -> u8 {
+        self.mem.read_byte(0xFFFF) & self.mem.read_byte(0xFF0F) & 0x1F
+    }
+
     fn handle_interrupt(&mut self, mut int: u8) {
         
         // The priorities follow the order of the bits in the IE and IF registers.
@@ -176,9 +263,662 @@ impl CPU {
         };
     }
 
+    pub fn to_save_state(&self) -> SaveState {
+        SaveState {
+            cpu: self.cpu_state(),
+            ..self.mem.to_save_state()
+        }
+    }
+
+    // Snapshot of the whole system encoded with SaveState::to_bytes.
+    pub fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        self.to_save_state().to_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        self.load_save_state(&SaveState::from_bytes(data)?)
+    }
+
+    // Registers and interrupt flags, without the rest of the system.
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState {
+            af:                 self.regs.get_af(),
+            bc:                 self.regs.get_bc(),
+            de:                 self.regs.get_de(),
+            hl:                 self.regs.get_hl(),
+            sp:                 self.regs.sp,
+            pc:                 self.regs.pc,
+            halted:             self.halted,
+            ime:                self.ime,
+            disable_interrupt:  self.disable_interrupt,
+            enable_interrupt:   self.enable_interrupt,
+            halt_bug:           self.halt_bug,
+        }
+    }
+
+    // The state must have been taken with the same cartridge.
+    pub fn from_save_state(
+        cartridge: Box<dyn Cartridge>,
+        callback: SerialCallback,
+        state: &SaveState,
+    ) -> Result<Self, SaveStateError> {
+        let mut cpu = CPU::new(cartridge, callback);
+        cpu.load_save_state(state)?;
+        Ok(cpu)
+    }
+
+    pub fn load_save_state(&mut self, state: &SaveState) -> Result<(), SaveStateError> {
+        if self.mem.cart_title() != state.cart_title || self.mem.cart_type() != state.cart_type {
+            return Err(SaveStateError::WrongCartridge(state.cart_title.clone()));
+        }
+        self.mem.load_state(state)?;
+        let cpu = &state.cpu;
+        self.regs.set_af(cpu.af);
+        self.regs.set_bc(cpu.bc);
+        self.regs.set_de(cpu.de);
+        self.regs.set_hl(cpu.hl);
+        self.regs.sp            = cpu.sp;
+        self.regs.pc            = cpu.pc;
+        self.halted             = cpu.halted;
+        self.ime                = cpu.ime;
+        self.disable_interrupt  = cpu.disable_interrupt;
+        self.enable_interrupt   = cpu.enable_interrupt;
+        self.halt_bug           = cpu.halt_bug;
+        Ok(())
+    }
+
+    // Runs until the gpu finishes a frame or a breakpoint is reached. The instruction
+    // at the current pc always runs, so calling again continues past a breakpoint.
+    pub fn run_until_vblank(&mut self) -> FrameResult {
+        let mut cycles_elapsed = 0;
+        // A frame is twice as many CPU cycles in double speed mode.
+        while cycles_elapsed < 2 * FRAME_CYCLES * if self.is_double_speed() { 2 } else { 1 } {
+            let cycles = self.tick();
+            if cycles == BREAKPOINT_CYCLES {
+                return FrameResult { cycles_elapsed, breakpoint_hit: self.last_breakpoint };
+            }
+            self.mem.update(cycles);
+            cycles_elapsed += cycles;
+            if self.mem.gpu.check_updated() {
+                self.push_rewind();
+                break;
+            }
+        }
+        FrameResult { cycles_elapsed, breakpoint_hit: None }
+    }
+
+    // Frames of history kept for rewind, 0 disables it.
+    pub fn set_rewind_frames(&mut self, frames: usize) {
+        self.rewind_buffer = RewindBuffer::new(frames);
+    }
+
+    fn push_rewind(&mut self) {
+        if !self.rewind_buffer.is_enabled() {
+            return;
+        }
+        if let Ok(snapshot) = self.save_state() {
+            self.rewind_buffer.push(snapshot);
+        }
+    }
+
+    // Goes back n frames, false if there isn't that much history. The frame before is loaded
+    // and run again, such that the screen shows the frame rewound to.
+    pub fn rewind(&mut self, n: usize) -> bool {
+        let Some(snapshot) = self.rewind_buffer.rewind(n + 1) else {
+            return false;
+        };
+        if self.load_state(&snapshot).is_err() {
+            return false;
+        }
+        self.run_until_vblank();
+        true
+    }
+
+    // Runs n frames with run_until_vblank, returning the total cycles. Stops early at a breakpoint.
+    pub fn run_for_frames(&mut self, n: u32) -> u64 {
+        let mut cycles = 0;
+        for _ in 0..n {
+            let frame = self.run_until_vblank();
+            cycles += u64::from(frame.cycles_elapsed);
+            if frame.breakpoint_hit.is_some() { break; }
+        }
+        cycles
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.remove(&address);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // Game Genie or GameShark code, see cheat.rs for the formats.
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), CheatError> {
+        self.mem.add_cheat(code)
+    }
+
+    // Address of the most recent breakpoint hit.
+    pub fn last_breakpoint(&self) -> Option<u16> { self.last_breakpoint }
+
+    pub fn pc(&self) -> u16 { self.regs.pc }
+
+    // (B, C, D, E, H, L), which mooneye test roms use to report their result.
+    pub fn register_snapshot(&self) -> (u8, u8, u8, u8, u8, u8) {
+        let r = &self.regs;
+        (r.b, r.c, r.d, r.e, r.h, r.l)
+    }
+
+    // Mnemonic of the instruction at address and the address of the next one.
+    pub fn disassemble(&self, address: u16) -> (String, u16) {
+        disasm::disassemble(&self.mem, address)
+    }
+
+    // Listing of count instructions from start.
+    pub fn disassemble_range(&self, start: u16, count: u16) -> Vec<(u16, String)> {
+        let mut address = start;
+        (0..count).map(|_| {
+            let (asm, next) = self.disassemble(address);
+            let line = (address, asm);
+            address = next;
+            line
+        }).collect()
+    }
+
+    // Writes are per instruction, so the writer should be buffered.
+    pub fn enable_trace(&mut self, w: Box<dyn Write + Send>) {
+        self.trace = Some(w);
+    }
+
+    pub fn disable_trace(&mut self) {
+        if let Some(mut trace) = self.trace.take() {
+            let _ = trace.flush();
+        }
+    }
+
+    fn write_trace(&mut self) {
+        let opcode = self.mem.read_byte(self.regs.pc);
+        let regs = &self.regs;
+        if let Some(trace) = self.trace.as_mut() {
+            // Tracing is best effort, a failed write shouldn't stop emulation.
+            let _ = writeln!(
+                trace,
+                "PC:{:04X} OP:{:02X} AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X}",
+                regs.pc, opcode, regs.get_af(), regs.get_bc(), regs.get_de(), regs.get_hl(), regs.sp,
+            );
+        }
+    }
+
     pub fn flip(&mut self) -> bool {
         let flipped = self.step_flip;
         if flipped { self.step_flip = false; }
         flipped
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use crate::cartridge::ROM;
+    use super::{CPU, FRAME_CYCLES};
+
+    // JR -2 at the entry point, loops forever.
+    fn spin_rom() -> Box<ROM> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x18;
+        rom[0x101] = 0xFE;
+        Box::new(ROM::new(rom))
+    }
+
+    #[test]
+    fn run_until_vblank() {
+        let mut cpu = CPU::new(spin_rom(), None);
+        // Align to the first vblank.
+        let first = cpu.run_until_vblank();
+        assert!(first.cycles_elapsed <= FRAME_CYCLES);
+        for _ in 0..60 {
+            let frame = cpu.run_until_vblank();
+            assert_eq!(frame.breakpoint_hit, None);
+            assert!(frame.cycles_elapsed.abs_diff(FRAME_CYCLES) <= 16, "{}", frame.cycles_elapsed);
+        }
+    }
+
+    #[test]
+    fn run_until_vblank_double_speed() {
+        let mut cpu = CPU::new(spin_rom(), None);
+        cpu.set_cgb(true);
+        cpu.mem.write_byte(0xFF4D, 0x01);
+        assert!(cpu.mem.switch_speed());
+        cpu.run_until_vblank();
+        for _ in 0..10 {
+            let frames = cpu.mem.gpu.frame_count();
+            let frame = cpu.run_until_vblank();
+            assert_eq!(cpu.mem.gpu.frame_count(), frames + 1);
+            assert!(frame.cycles_elapsed.abs_diff(2 * FRAME_CYCLES) <= 16, "{}", frame.cycles_elapsed);
+        }
+
+        // With the LCD off the cap is two frames long, at double speed too.
+        cpu.mem.write_byte(0xFF40, 0x11);
+        cpu.run_until_vblank();
+        let frame = cpu.run_until_vblank();
+        assert!(frame.cycles_elapsed.abs_diff(4 * FRAME_CYCLES) <= 16, "{}", frame.cycles_elapsed);
+    }
+
+    #[test]
+    fn run_for_frames() {
+        let mut cpu = CPU::new(spin_rom(), None);
+        cpu.run_until_vblank();
+        let cycles = cpu.run_for_frames(60);
+        assert!(cycles.abs_diff(60 * u64::from(FRAME_CYCLES)) <= 16, "{}", cycles);
+
+        // Stops at the breakpoint.
+        cpu.add_breakpoint(0x100);
+        assert!(cpu.run_for_frames(60) <= u64::from(FRAME_CYCLES));
+        assert_eq!(cpu.last_breakpoint(), Some(0x100));
+    }
+
+    #[test]
+    fn rewind() {
+        // Counts frames in C000 from the vblank interrupt.
+        let mut rom = vec![0; 0x8000];
+        rom[0x40..0x43].copy_from_slice(&[0x34, 0xFB, 0xC9]);     // INC (HL), EI, RET
+        rom[0x100..0x10B].copy_from_slice(&[
+            0x21, 0x00, 0xC0,   // LD HL, 0xC000
+            0x3E, 0x01,         // LD A, 0x01
+            0xE0, 0xFF,         // LDH (0xFF), A
+            0xFB,               // EI
+            0x76,               // HALT
+            0x18, 0xFD,         // JR -3
+        ]);
+        let mut cpu = CPU::new(Box::new(ROM::new(rom)), None);
+        cpu.set_rewind_frames(10);
+        cpu.run_for_frames(20);
+        let frames = cpu.mem.read_byte(0xC000);
+
+        assert!(cpu.rewind(3));
+        assert_eq!(cpu.mem.read_byte(0xC000), frames - 3);
+        assert!(cpu.rewind(1));
+        assert_eq!(cpu.mem.read_byte(0xC000), frames - 4);
+        // 6 frames of history left, including the one shown, and the frame before the one
+        // rewound to is needed.
+        assert!(!cpu.rewind(5));
+        assert!(cpu.rewind(4));
+        assert_eq!(cpu.mem.read_byte(0xC000), frames - 8);
+
+        // Runs forwards again from there.
+        cpu.run_for_frames(2);
+        assert_eq!(cpu.mem.read_byte(0xC000), frames - 6);
+
+        cpu.set_rewind_frames(0);
+        cpu.run_for_frames(2);
+        assert!(!cpu.rewind(1));
+    }
+
+    // DI, NOP, HALT, INC A.
+    fn halt_rom() -> Box<ROM> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0xF3, 0x00, 0x76, 0x3C]);
+        Box::new(ROM::new(rom))
+    }
+
+    #[test]
+    fn halt_bug() {
+        let mut cpu = CPU::new(halt_rom(), None);
+        cpu.tick();
+        cpu.tick();
+        cpu.mem.write_byte(0xFFFF, 0x01);
+        cpu.mem.write_byte(0xFF0F, 0x01);
+
+        cpu.tick();
+        assert!(!cpu.ime);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.regs.pc, 0x103);
+
+        // INC A runs twice, A starts at 0x01.
+        cpu.tick();
+        assert_eq!(cpu.regs.pc, 0x103);
+        cpu.tick();
+        assert_eq!(cpu.regs.pc, 0x104);
+        assert_eq!(cpu.regs.a, 0x03);
+    }
+
+    #[test]
+    fn halt_without_pending_interrupt() {
+        let mut cpu = CPU::new(halt_rom(), None);
+        for _ in 0..3 { cpu.tick(); }
+        assert!(cpu.halted);
+        cpu.tick();
+        assert_eq!(cpu.regs.pc, 0x103);
+        assert_eq!(cpu.regs.a, 0x01);
+    }
+
+    #[test]
+    fn dec_registers() {
+        use super::registers::{Registers, Flag::{Z, N, H}};
+        type RegAccess = fn(&mut Registers) -> &mut u8;
+
+        let registers: [(u8, RegAccess); 7] = [
+            (0x3D, |r| &mut r.a),
+            (0x05, |r| &mut r.b),
+            (0x0D, |r| &mut r.c),
+            (0x15, |r| &mut r.d),
+            (0x1D, |r| &mut r.e),
+            (0x25, |r| &mut r.h),
+            (0x2D, |r| &mut r.l),
+        ];
+        // Value, result, Z, H.
+        let cases = [(0x05, 0x04, false, false), (0x01, 0x00, true, false), (0x10, 0x0F, false, true), (0x00, 0xFF, false, true)];
+
+        for (opcode, reg) in registers {
+            for (value, result, z, h) in cases {
+                let mut cpu = CPU::new(Box::new(ROM::new(vec![0; 0x8000])), None);
+                *reg(&mut cpu.regs) = value;
+                assert_eq!(cpu.execute(opcode), 4);
+                assert_eq!(*reg(&mut cpu.regs), result, "DEC {:#04X} of {:#04X}", opcode, value);
+                assert_eq!(cpu.regs.get_flag(Z), z);
+                assert!(cpu.regs.get_flag(N));
+                assert_eq!(cpu.regs.get_flag(H), h);
+            }
+        }
+    }
+
+    #[test]
+    fn xor_flags() {
+        use super::registers::Flag::{Z, N, H, C};
+
+        // XOR A, the boot flags have H and C set.
+        let mut cpu = CPU::new(Box::new(ROM::new(vec![0; 0x8000])), None);
+        assert_eq!(cpu.execute(0xAF), 4);
+        assert_eq!(cpu.regs.a, 0x00);
+        assert_eq!([Z, N, H, C].map(|f| cpu.regs.get_flag(f)), [true, false, false, false]);
+
+        // XOR B.
+        let mut cpu = CPU::new(Box::new(ROM::new(vec![0; 0x8000])), None);
+        cpu.regs.a = 0x0F;
+        cpu.regs.b = 0x0F;
+        assert_eq!(cpu.execute(0xA8), 4);
+        assert_eq!(cpu.regs.a, 0x00);
+        assert_eq!([Z, N, H, C].map(|f| cpu.regs.get_flag(f)), [true, false, false, false]);
+
+        cpu.regs.b = 0xF0;
+        cpu.execute(0xA8);
+        assert_eq!(cpu.regs.a, 0xF0);
+        assert_eq!([Z, N, H, C].map(|f| cpu.regs.get_flag(f)), [false, false, false, false]);
+    }
+
+    #[test]
+    fn daa() {
+        use super::registers::Flag::{Z, N, H, C};
+
+        // (A, ZNHC in, A out, ZNHC out), flags as the high nibble of F.
+        let vectors = [
+            // After an addition, each nibble over 9 or a carry out of it adds 6.
+            (0x00, 0x0, 0x00, 0x8), (0x09, 0x0, 0x09, 0x0), (0x0A, 0x0, 0x10, 0x0), (0x0F, 0x0, 0x15, 0x0),
+            (0x10, 0x2, 0x16, 0x0), (0x45, 0x0, 0x45, 0x0), (0x4F, 0x0, 0x55, 0x0), (0x99, 0x0, 0x99, 0x0),
+            (0x9A, 0x0, 0x00, 0x9), (0xA0, 0x0, 0x00, 0x9), (0xFF, 0x0, 0x65, 0x1), (0x00, 0x1, 0x60, 0x1),
+            (0x00, 0x3, 0x66, 0x1), (0x12, 0x1, 0x72, 0x1), (0x3C, 0x2, 0x42, 0x0), (0x9A, 0x2, 0x00, 0x9),
+            // After a subtraction only H and C subtract 6, C is kept and N is unchanged.
+            (0x00, 0x4, 0x00, 0xC), (0x0F, 0x6, 0x09, 0x4), (0xFA, 0x6, 0xF4, 0x4), (0x9A, 0x5, 0x3A, 0x5),
+            (0x45, 0x5, 0xE5, 0x5), (0xA0, 0x5, 0x40, 0x5), (0x66, 0x7, 0x00, 0xD), (0x66, 0xF, 0x00, 0xD),
+            (0x0B, 0x4, 0x0B, 0x4), (0x90, 0x4, 0x90, 0x4),
+        ];
+        let mut cpu = CPU::new(Box::new(ROM::new(vec![0; 0x8000])), None);
+        for (a, flags, a_out, flags_out) in vectors {
+            cpu.regs.a = a;
+            for (bit, flag) in [Z, N, H, C].into_iter().enumerate() {
+                cpu.regs.set_flag(flag, flags & (0x8 >> bit) != 0);
+            }
+            assert_eq!(cpu.execute(0x27), 4);
+            let flags_after = [Z, N, H, C].into_iter().fold(0, |f, flag| f << 1 | u8::from(cpu.regs.get_flag(flag)));
+            assert_eq!((cpu.regs.a, flags_after), (a_out, flags_out), "DAA {:#04X} ZNHC {:04b}", a, flags);
+        }
+
+        // ADD A, B and SUB B of every pair of BCD bytes then DAA gives the BCD result,
+        // C as the carry or borrow.
+        let bcd = |n: u32| (((n / 10) << 4) | (n % 10)) as u8;
+        for x in 0..100 {
+            for y in 0..100 {
+                cpu.regs.a = bcd(x);
+                cpu.regs.b = bcd(y);
+                cpu.execute(0x80);
+                cpu.execute(0x27);
+                assert_eq!((cpu.regs.a, cpu.regs.get_flag(C)), (bcd((x + y) % 100), x + y >= 100), "{} + {}", x, y);
+
+                cpu.regs.a = bcd(x);
+                cpu.execute(0x90);
+                cpu.execute(0x27);
+                assert_eq!((cpu.regs.a, cpu.regs.get_flag(C)), (bcd((x + 100 - y) % 100), x < y), "{} - {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn ld_hl_sp_offset() {
+        use super::registers::Flag::{Z, N, H, C};
+
+        // LD HL, SP+n with n as the byte after the opcode, and Z set beforehand.
+        for (sp, n, hl, h, c) in [(0x0001, 0xFF, 0x0000, true, true), (0xFFF8, 0x10, 0x0008, false, true), (0x1000, 0x80, 0x0F80, false, false)] {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100] = n;
+            let mut cpu = CPU::new(Box::new(ROM::new(rom)), None);
+            cpu.regs.pc = 0x100;
+            cpu.regs.sp = sp;
+            cpu.regs.set_flag(Z, true);
+            assert_eq!(cpu.execute(0xF8), 12);
+            assert_eq!(cpu.regs.get_hl(), hl, "SP {:#06X} + {:#04X}", sp, n);
+            assert_eq!([Z, N, H, C].map(|f| cpu.regs.get_flag(f)), [false, false, h, c]);
+            assert_eq!(cpu.regs.sp, sp);
+        }
+    }
+
+    #[test]
+    fn add_sp_offset() {
+        use super::registers::Flag::{Z, N, H, C};
+
+        // ADD SP, n with n as the byte after the opcode, and Z set beforehand.
+        for (sp, n, result, h, c) in [(0x0010, 0xF0, 0x0000, false, true), (0x000F, 0xFF, 0x000E, true, true), (0xFFFF, 0x01, 0x0000, true, true)] {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100] = n;
+            let mut cpu = CPU::new(Box::new(ROM::new(rom)), None);
+            cpu.regs.pc = 0x100;
+            cpu.regs.sp = sp;
+            cpu.regs.set_flag(Z, true);
+            assert_eq!(cpu.execute(0xE8), 16);
+            assert_eq!(cpu.regs.sp, result, "SP {:#06X} + {:#04X}", sp, n);
+            assert_eq!([Z, N, H, C].map(|f| cpu.regs.get_flag(f)), [false, false, h, c]);
+        }
+    }
+
+    #[test]
+    fn ld_a_indirect() {
+        let mut cpu = CPU::new(Box::new(ROM::new(vec![0; 0x8000])), None);
+        cpu.mem.write_byte(0xC123, 0x45);
+        cpu.mem.write_byte(0xD456, 0x67);
+        cpu.regs.set_bc(0xC123);
+        cpu.regs.set_de(0xD456);
+
+        // LD A, (BC) then LD A, (DE).
+        assert_eq!(cpu.execute(0x0A), 8);
+        assert_eq!(cpu.regs.a, 0x45);
+        assert_eq!(cpu.execute(0x1A), 8);
+        assert_eq!(cpu.regs.a, 0x67);
+    }
+
+    #[test]
+    fn unused_opcodes() {
+        let opcodes = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + opcodes.len()].copy_from_slice(&opcodes);
+        let mut cpu = CPU::new(Box::new(ROM::new(rom)), None);
+        let (af, sp) = (cpu.regs.get_af(), cpu.regs.sp);
+        for (i, opcode) in opcodes.iter().enumerate() {
+            assert_eq!(cpu.tick(), 4, "{:#04X}", opcode);
+            assert_eq!(cpu.regs.pc, 0x101 + i as u16);
+        }
+        assert_eq!((cpu.regs.get_af(), cpu.regs.sp), (af, sp));
+    }
+
+    #[test]
+    fn rst() {
+        for (i, opcode) in [0xC7, 0xCF, 0xD7, 0xDF, 0xE7, 0xEF, 0xF7, 0xFF].into_iter().enumerate() {
+            let mut cpu = CPU::new(Box::new(ROM::new(vec![0; 0x8000])), None);
+            // As if fetched from 0x1233.
+            cpu.regs.pc = 0x1234;
+            cpu.regs.sp = 0xDFF0;
+            assert_eq!(cpu.execute(opcode), 16, "RST {:#04X}", opcode);
+            assert_eq!(cpu.regs.pc, i as u16 * 8);
+            assert_eq!(cpu.regs.sp, 0xDFEE);
+            assert_eq!(cpu.mem.read_word(0xDFEE), 0x1234);
+        }
+    }
+
+    #[test]
+    fn jr_negative_offset() {
+        use super::registers::Flag::{Z, C};
+
+        // Each variant with its flags set so that the jump is taken.
+        for (opcode, z, c) in [(0x18, false, false), (0x20, false, false), (0x28, true, false), (0x30, false, false), (0x38, false, true)] {
+            let mut rom = vec![0; 0x8000];
+            rom[0x100] = opcode;
+            rom[0x101] = 0xFE;
+            let mut cpu = CPU::new(Box::new(ROM::new(rom)), None);
+            cpu.regs.set_flag(Z, z);
+            cpu.regs.set_flag(C, c);
+            for _ in 0..3 {
+                cpu.tick();
+                assert_eq!(cpu.regs.pc, 0x100, "JR {:#04X}", opcode);
+            }
+        }
+
+        // JR -128 from the end of the instruction.
+        let mut rom = vec![0; 0x8000];
+        rom[0x200] = 0x18;
+        rom[0x201] = 0x80;
+        let mut cpu = CPU::new(Box::new(ROM::new(rom)), None);
+        cpu.regs.pc = 0x200;
+        cpu.tick();
+        assert_eq!(cpu.regs.pc, 0x202 - 128);
+    }
+
+    #[test]
+    fn reti_from_interrupt() {
+        // NOPs with RETI as the VBlank handler.
+        let mut rom = vec![0; 0x8000];
+        rom[0x40] = 0xD9;
+        let mut cpu = CPU::new(Box::new(ROM::new(rom)), None);
+        cpu.mem.write_byte(0xFFFF, 0x01);
+        cpu.mem.write_byte(0xFF0F, 0x01);
+
+        assert_eq!(cpu.tick(), 16);
+        assert_eq!(cpu.regs.pc, 0x40);
+        assert_eq!(cpu.regs.sp, 0xFFFC);
+        assert!(!cpu.ime);
+
+        assert_eq!(cpu.tick(), 16);
+        assert_eq!(cpu.regs.pc, 0x100);
+        assert_eq!(cpu.regs.sp, 0xFFFE);
+        assert!(cpu.ime);
+        assert_eq!(cpu.mem.read_byte(0xFF0F) & 0x01, 0);
+    }
+
+    // Shared buffer, the cpu owns the boxed writer.
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.0.lock().unwrap().write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn trace() {
+        let buf = SharedBuf::default();
+        let mut cpu = CPU::new(spin_rom(), None);
+        cpu.enable_trace(Box::new(buf.clone()));
+        for _ in 0..10 { cpu.tick(); }
+        cpu.disable_trace();
+        cpu.tick();
+
+        let trace = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].starts_with("PC:0100"));
+        assert_eq!(lines[0], "PC:0100 OP:18 AF:01B0 BC:0013 DE:00D8 HL:014D SP:FFFE");
+    }
+
+    #[test]
+    fn breakpoint() {
+        let mut cpu = CPU::new(spin_rom(), None);
+        cpu.add_breakpoint(0x100);
+        let frame = cpu.run_until_vblank();
+        assert_eq!(frame.breakpoint_hit, Some(0x100));
+        assert!(frame.cycles_elapsed < FRAME_CYCLES);
+
+        cpu.remove_breakpoint(0x100);
+        assert_eq!(cpu.run_until_vblank().breakpoint_hit, None);
+    }
+
+    #[test]
+    fn step_speed() {
+        use super::STEP_CYCLES;
+
+        // Uncapped never accumulates cycles towards a sleep.
+        let mut cpu = CPU::new(spin_rom(), None);
+        cpu.set_speed(0);
+        let mut cycles = 0;
+        while cycles < 4 * STEP_CYCLES { cycles += cpu.step(); }
+        assert_eq!(cpu.step_cycles, 0);
+
+        // At 3x, three times as many cycles run before the first sleep.
+        cpu.set_speed(3);
+        let mut cycles = 0;
+        while cycles < 2 * STEP_CYCLES { cycles += cpu.step(); }
+        assert_eq!(cpu.step_cycles, cycles);
+        while cycles <= 3 * STEP_CYCLES { cycles += cpu.step(); }
+        cpu.step();
+        assert!(cpu.step_cycles < STEP_CYCLES);
+
+        // Large multipliers don't overflow.
+        cpu.set_speed(u32::MAX);
+        cpu.step();
+    }
+
+    #[test]
+    fn tick_breakpoint() {
+        use super::BREAKPOINT_CYCLES;
+
+        // NOPs, stopping at 0x102.
+        let mut cpu = CPU::new(Box::new(ROM::new(vec![0; 0x8000])), None);
+        cpu.add_breakpoint(0x102);
+        assert_eq!(cpu.tick(), 4);
+        assert_eq!(cpu.tick(), 4);
+        assert_eq!(cpu.tick(), BREAKPOINT_CYCLES);
+        assert_eq!(cpu.pc(), 0x102);
+        assert_eq!(cpu.last_breakpoint(), Some(0x102));
+
+        // Continues past it.
+        assert_eq!(cpu.tick(), 4);
+        assert_eq!(cpu.pc(), 0x103);
+
+        cpu.clear_breakpoints();
+        cpu.regs.pc = 0x102;
+        assert_eq!(cpu.tick(), 4);
+    }
+
+    #[test]
+    fn disassemble_range() {
+        let cpu = CPU::new(halt_rom(), None);
+        let listing = cpu.disassemble_range(0x100, 3);
+        assert_eq!(listing, vec![
+            (0x100, "DI".to_string()),
+            (0x101, "NOP".to_string()),
+            (0x102, "HALT".to_string()),
+        ]);
+        let cpu = CPU::new(spin_rom(), None);
+        assert_eq!(cpu.disassemble_range(0x100, 2)[1].0, 0x102);
+    }
+}
@@ -522,9 +522,19 @@ impl CPU {
             // NOP - no instruction.
             0x00 => { 4 },
             // HALT - power down CPU until interrupt occers. For energy conservation.
-            0x76 => { self.halted = true; 4 },
+            // With IME unset and an interrupt already pending, HALT exits immediately and the
+            // next byte is read twice (the halt bug).
+            0x76 => {
+                if !self.ime && self.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+                4
+            },
             // STOP - halt CPU and LCD display until button pressed.
-            0x10 => { 4 },
+            // On CGB switches between normal and double speed if armed through KEY1.
+            0x10 => { self.mem.switch_speed(); 4 },
 
             // DI - interupts disabled after instruciton after DI is executed.
             0xF3 => { self.disable_interrupt = 2; 4 },
@@ -556,6 +566,7 @@ impl CPU {
                     a = a.wrapping_sub(correction);
                 }
 
+                // An incoming carry already set 0x60 so it is kept, whether adding or subtracting.
                 self.regs.set_flag(C, correction >= 0x60);
                 self.regs.set_flag(H, false);
                 self.regs.set_flag(Z, a == 0);
@@ -618,14 +629,14 @@ impl CPU {
             
             // Restarts
             // RST n - push present address onto stack, jump to address $0000 + n.
-            0xC7 => { self.stack_push(self.regs.pc); self.regs.pc = 0x00; 32 },
-            0xCF => { self.stack_push(self.regs.pc); self.regs.pc = 0x08; 32 },
-            0xD7 => { self.stack_push(self.regs.pc); self.regs.pc = 0x10; 32 },
-            0xDF => { self.stack_push(self.regs.pc); self.regs.pc = 0x18; 32 },
-            0xE7 => { self.stack_push(self.regs.pc); self.regs.pc = 0x20; 32 },
-            0xEF => { self.stack_push(self.regs.pc); self.regs.pc = 0x28; 32 },
-            0xF7 => { self.stack_push(self.regs.pc); self.regs.pc = 0x30; 32 },
-            0xFF => { self.stack_push(self.regs.pc); self.regs.pc = 0x38; 32 },
+            0xC7 => { self.stack_push(self.regs.pc); self.regs.pc = 0x00; 16 },
+            0xCF => { self.stack_push(self.regs.pc); self.regs.pc = 0x08; 16 },
+            0xD7 => { self.stack_push(self.regs.pc); self.regs.pc = 0x10; 16 },
+            0xDF => { self.stack_push(self.regs.pc); self.regs.pc = 0x18; 16 },
+            0xE7 => { self.stack_push(self.regs.pc); self.regs.pc = 0x20; 16 },
+            0xEF => { self.stack_push(self.regs.pc); self.regs.pc = 0x28; 16 },
+            0xF7 => { self.stack_push(self.regs.pc); self.regs.pc = 0x30; 16 },
+            0xFF => { self.stack_push(self.regs.pc); self.regs.pc = 0x38; 16 },
 
             // Returns
             // RET - pop two bytes from stack and jump to that address.
@@ -635,7 +646,7 @@ impl CPU {
             0xD0 => { if !self.regs.get_flag(C) { self.regs.pc = self.stack_pop(); 20 } else { 8 }},
             0xD8 => { if self.regs.get_flag(C) { self.regs.pc = self.stack_pop(); 20 } else { 8 }},
             // RETI - pop two bytes from stack and jump to that address then enables interrupts.
-            0xD9 => { self.regs.pc = self.stack_pop(); self.ime = true; 8 },
+            0xD9 => { self.regs.pc = self.stack_pop(); self.ime = true; 16 },
 
             0xCB => {   // Instruction set extension.
                 let cb_opcode = self.next_byte();
@@ -1032,7 +1043,10 @@ impl CPU {
                     },
                 }
             }
-            unknown => panic!("unsuppored opcode: {:#2X}", unknown),
+
+            // Unused opcodes, which lock up the CPU on hardware. Treated as NOP so that a game
+            // jumping into data carries on instead of crashing the emulator.
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => 4,
         }
     }
 }
\ No newline at end of file
@@ -9,19 +9,38 @@ use self::stat::Mode;
 use super::bit::Bit;
 use super::bus::MemoryBus;
 use super::intf::{Intf, InterruptSource};
+use super::state::{check_len, GpuState, SaveStateError};
 use super::{SCREEN_HEIGHT, SCREEN_WIDTH};
 
 use ldlc::LCDC;
 use stat::STAT;
-use palette::Palette;
+use palette::{Palette, PaletteScheduler};
 
 const VRAM_SIZE: usize = 16_384;
+const VRAM_BANK_SIZE: usize = 0x2000;
 const OAM_SIZE: usize = 160;
+const FRAME_DOTS: u32 = 154 * 456;
 
-#[derive(PartialEq, Copy, Clone)]
-enum Priority {
-    Colour0,
-    None,
+// Background or window pixel of the current scanline.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+struct BgPixel {
+    colour_idx: u8,
+    // CGB BG map attribute bits 0-2, BG palette 0-7.
+    palette:    u8,
+    // CGB BG map attribute bit 7, BG colours 1-3 are drawn over sprites.
+    priority:   bool,
+}
+
+// Opaque sprite pixel of the current scanline.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct SpritePixel {
+    colour_idx: u8,
+    // 0 = OBP0, 1 = OBP1, or OBJ palette 0-7 on CGB.
+    palette:    u8,
+    above_bg:   bool,
+    // Position and OAM index of the sprite, used to resolve overlaps.
+    x:          i16,
+    oam_idx:    u8,
 }
 
 struct Sprite {
@@ -43,11 +62,16 @@ struct Sprite {
     is_palette_1:   bool,
     // Bit3   Tile VRAM-Bank  **CGB Mode Only**     (0=Bank 0, 1=Bank 1)
     // Bit2-0 Palette number  **CGB Mode Only**     (OBP0-7)   */
+    vram_bank:      usize,
+    cgb_palette:    u8,
 }
 
 pub struct GPU {
     // Tile data is stored in VRAM in the memory area at $8000-$97FF.
+    // CGB has 2 banks, bank 1 holding more tile data and the BG map attributes.
     vram: [u8; VRAM_SIZE],
+    // 0xFF4F - VBK (VRAM bank) | Bank mapped to $8000-$9FFF, CGB only.
+    vram_bank: usize,
     // Sprite attributes reside in the Sprite Attribute Table (OAM - Object Attribute Memory) at $FE00-FE9F.
     oam: [u8; OAM_SIZE],
     
@@ -63,6 +87,8 @@ pub struct GPU {
     lcdc: LCDC,
     stat: STAT,
     h_blank: bool,
+    // OR of the enabled STAT interrupt conditions, the interrupt is only requested when it rises.
+    stat_irq_line: bool,
 
     // 0xFF42 - SCY (scroll Y) | 0xFF43 - SCX (scroll X)
     scroll_y: u8,
@@ -73,6 +99,8 @@ pub struct GPU {
     // 0xFF4A - WY (window y position) | 0xFF4B - WX (window x position + 7)
     window_y: u8,
     window_x: u8,
+    // Line of the window drawn next, only advances on lines the window is drawn on.
+    window_line: u8,
 
     // LCD monochrome palettes, CGB has extra palettes.
     // 0xFF47 - BGP (BG palette data)
@@ -81,12 +109,41 @@ pub struct GPU {
     sprite_palette_0:   Palette,
     sprite_palette_1:   Palette,
 
-    bg_priority: [Priority; SCREEN_WIDTH],
+    // CGB palette RAM, 8 palettes of 4 colours in 2 bytes each.
+    // 0xFF68 - BCPS/BGPI (BG palette index) | 0xFF69 - BCPD/BGPD (BG palette data)
+    // 0xFF6A - OCPS/OBPI (OBJ palette index) | 0xFF6B - OCPD/OBPD (OBJ palette data)
+    // Bits 0-5 of an index are the byte address, bit 7 auto-increments it after each data write.
+    bg_palette_idx:     u8,
+    bg_palette_ram:     [u8; 64],
+    obj_palette_idx:    u8,
+    obj_palette_ram:    [u8; 64],
+    // Colours come from palette RAM rather than the monochrome palettes.
+    cgb:                bool,
+
+    // OAM indexes of the sprites on the current line, found during mode 2.
+    // Hardware stops at 10, which can be turned off to reduce flicker.
+    scanline_sprites:   Vec<usize>,
+    sprite_limit:       bool,
+    // CGB colours as they look on the LCD rather than the raw RGB555 values.
+    color_correction:   bool,
+
+    // Pixels of the scanline being drawn, mixed together by render_pixel.
+    bg_fifo:     [BgPixel; SCREEN_WIDTH],
+    sprite_fifo: [Option<SpritePixel>; SCREEN_WIDTH],
 
     /* The LCD controller operates on a 2^22 Hz = 4.194 MHz dot clock. An entire frame is 154 scanlines = 
     70224 dots = 16.74 ms. On scanlines 0 through 143, the PPU cycles through modes 2, 3, and 0 once 
     every 456 dots. Scanlines 144 through 153 are mode 1. */
     dots: u32,
+    // Cycles left of the first frame after the LCD is switched on, which requests no VBlank interrupt.
+    warm_up_cycles: u32,
+    // Extra dots of mode 3 on this line, the fetcher drops SCX % 8 pixels of the first tile.
+    // Latched when mode 3 starts so SCX writes during the line don't change its length.
+    scx_penalty:    u32,
+
+    // Frames completed since power on, and colour changes queued against them.
+    frame_count:            u64,
+    pub palette_scheduler:  PaletteScheduler,
 
     // Request for interrupt.
     intf: Rc<RefCell<Intf>>,
@@ -97,11 +154,13 @@ impl GPU {
     pub fn new(intf: Rc<RefCell<Intf>>) -> Self {
         Self { 
             vram: [0; VRAM_SIZE],
+            vram_bank: 0,
             oam:  [0; OAM_SIZE],
 
             lcdc: LCDC::new(),
             stat: STAT::new(),
             h_blank: false,
+            stat_irq_line: false,
 
             scroll_y:   0,
             scroll_x:   0,
@@ -109,13 +168,29 @@ impl GPU {
             ly_compare: 0,
             window_y:   0,
             window_x:   0,
+            window_line: 0,
             
             bg_palette:         Palette::new(),
             sprite_palette_0:   Palette::new(),
             sprite_palette_1:   Palette::new(),
 
-            bg_priority: [Priority::None; SCREEN_WIDTH],
+            bg_palette_idx:     0,
+            bg_palette_ram:     [0; 64],
+            obj_palette_idx:    0,
+            obj_palette_ram:    [0; 64],
+            cgb:                false,
+
+            scanline_sprites:   Vec::with_capacity(40),
+            sprite_limit:       true,
+            color_correction:   false,
+
+            bg_fifo:     [BgPixel::default(); SCREEN_WIDTH],
+            sprite_fifo: [None; SCREEN_WIDTH],
             dots: 0,
+            warm_up_cycles:     0,
+            scx_penalty:        0,
+            frame_count:        0,
+            palette_scheduler:  PaletteScheduler::default(),
             intf,
 
             #[cfg(not(target_arch = "wasm32"))]
@@ -136,14 +211,17 @@ impl GPU {
             let current_cycles = if cycles >= 80 { 80 } else { cycles };
             self.dots += current_cycles;
             cycles -= current_cycles;
+            self.warm_up_cycles = self.warm_up_cycles.saturating_sub(current_cycles);
             
             // Full line.
             if self.dots >= 456 {
                 self.dots -= 456;
-                self.ly = (self.ly + 1) % 154;
-
-                if self.stat.lyc_interrupt && (self.ly == self.ly_compare) {
-                    self.intf.borrow_mut().set_interrupt(InterruptSource::STAT);
+                if self.stat.mode == Mode::VBlank && self.ly == 0 {
+                    // End of line 153, LY already reads 0.
+                    self.switch_mode(Mode::OAMRead);
+                } else {
+                    self.ly += 1;
+                    self.update_stat_line();
                 }
 
                 /* Mode 1: This mode is called V-Blank and happens when the last visible row has been processed, 
@@ -154,14 +232,20 @@ impl GPU {
                 }
             }
 
+            // LY reads 0 from 4 dots into line 153, which is otherwise still VBlank.
+            if self.ly == 153 && self.dots >= 4 {
+                self.ly = 0;
+                self.update_stat_line();
+            }
+
             // Normal line.
-            if self.ly < SCREEN_HEIGHT as u8 {
+            if self.ly < SCREEN_HEIGHT as u8 && self.stat.mode != Mode::VBlank {
                 // Fetch assets from memory.
                 if self.dots <= 80 {
                     if self.stat.mode != Mode::OAMRead { self.switch_mode(Mode::OAMRead) }
                 
                 // Tiles and sprites are rendered.
-                } else if self.dots <= (80 + 172) {
+                } else if self.dots <= (80 + 172 + self.scx_penalty) {
                     if self.stat.mode != Mode::VRAMRead { self.switch_mode(Mode::VRAMRead) }
                 
                 } else if self.stat.mode != Mode::HBlank{
@@ -174,60 +258,122 @@ impl GPU {
     fn switch_mode(&mut self, mode: Mode) {
         self.stat.mode = mode;
 
-        let interrupt = match self.stat.mode {
+        match self.stat.mode {
             Mode::HBlank => {
                 self.render_scanline();
                 self.h_blank = true;
-                self.stat.hblank_interrupt
             },
             Mode::VBlank => {
-                self.intf.borrow_mut().set_interrupt(InterruptSource::VBlank);
+                if self.warm_up_cycles == 0 {
+                    self.intf.borrow_mut().set_interrupt(InterruptSource::VBlank);
+                }
                 self.updated = true;
-                self.stat.vblank_interrupt
+                self.window_line = 0;
+                self.frame_count += 1;
+                if let Some(colours) = self.palette_scheduler.take_due(self.frame_count) {
+                    self.set_colours(colours);
+                }
             },
-            Mode::OAMRead => { self.stat.oam_interrupt },
-            Mode::VRAMRead => false,
+            Mode::OAMRead => self.scan_oam(),
+            Mode::VRAMRead => self.scx_penalty = (self.scroll_x % 8) as u32,
         };
 
-        if interrupt { self.intf.borrow_mut().set_interrupt(InterruptSource::STAT) }
+        self.update_stat_line();
+    }
+
+    fn stat_line(&self) -> bool {
+        if !self.lcdc.lcd_enable {
+            return false;
+        }
+        (self.stat.lyc_interrupt && self.ly == self.ly_compare) || match self.stat.mode {
+            Mode::HBlank    => self.stat.hblank_interrupt,
+            Mode::VBlank    => self.stat.vblank_interrupt,
+            Mode::OAMRead   => self.stat.oam_interrupt,
+            Mode::VRAMRead  => false,
+        }
+    }
+
+    // While one condition holds the line high, others becoming true do not request
+    // another interrupt (STAT blocking).
+    fn update_stat_line(&mut self) {
+        let line = self.stat_line();
+        if line && !self.stat_irq_line {
+            self.intf.borrow_mut().set_interrupt(InterruptSource::STAT);
+        }
+        self.stat_irq_line = line;
     }
 
     fn render_scanline(&mut self) {
+        self.bg_fifo = [BgPixel::default(); SCREEN_WIDTH];
+        self.sprite_fifo = [None; SCREEN_WIDTH];
         if self.lcdc.bg_window_enable { self.render_bg();      }
         if self.lcdc.sprite_enable    { self.render_sprites(); }
+        for x in 0..SCREEN_WIDTH { self.render_pixel(x); }
+    }
+
+    fn render_pixel(&mut self, x: usize) {
+        if self.cgb {
+            let colour = match mix_pixel(self.bg_fifo[x], self.sprite_fifo[x]) {
+                Some(sprite) => cgb_colour(&self.obj_palette_ram, sprite.palette, sprite.colour_idx, self.color_correction),
+                None => cgb_colour(&self.bg_palette_ram, self.bg_fifo[x].palette, self.bg_fifo[x].colour_idx, self.color_correction),
+            };
+            return self.set_pixel(x, colour);
+        }
+
+        let colour = match mix_pixel(self.bg_fifo[x], self.sprite_fifo[x]) {
+            Some(sprite) if sprite.palette == 1 => self.sprite_palette_1.get_shade(sprite.colour_idx as usize),
+            Some(sprite) => self.sprite_palette_0.get_shade(sprite.colour_idx as usize),
+            // Background and window are blank (white) when disabled.
+            None if !self.lcdc.bg_window_enable => self.bg_palette.colours()[0],
+            None => self.bg_palette.get_shade(self.bg_fifo[x].colour_idx as usize),
+        };
+        self.set_pixel(x, colour);
     }
 
     fn render_bg(&mut self) {
         
         let is_window_y = self.lcdc.window_enable && self.ly >= self.window_y;
         let bg_y = self.ly.wrapping_add(self.scroll_y);
+        let mut window_drawn = false;
 
         for x in 0..SCREEN_WIDTH as u8 {
 
             let is_window_x = self.lcdc.window_enable && x >= self.window_x.wrapping_sub(7);
             let is_window = is_window_x && is_window_y;
+            window_drawn |= is_window;
+            // The first SCX % 8 pixels of the leftmost tile are discarded, screen x 0 is
+            // pixel SCX % 8 of that tile.
             let bg_x = x.wrapping_add(self.scroll_x);
 
             let tile_address = if is_window {
-                let offset_y = self.ly.wrapping_sub(self.window_y);
+                let offset_y = self.window_line;
                 let offset_x = x.wrapping_sub(self.window_x.wrapping_sub(7));
                 GPU::get_address(self.lcdc.window_tilemap, offset_x, offset_y)
             } else {
                 GPU::get_address(self.lcdc.bg_tilemap, bg_x, bg_y)
             };
-            let tile = self.read_byte(tile_address);
+            let tile = self.vram_byte(0, tile_address);
+            /* The attributes of a tile are at the same map address in VRAM bank 1, CGB only.
+            Bit7   BG-to-OAM Priority
+            Bit6   Y flip
+            Bit5   X flip
+            Bit3   Tile VRAM Bank number
+            Bit2-0 Background Palette number (BGP0-7) */
+            let attr = if self.cgb { self.vram_byte(1, tile_address) } else { 0 };
+            let bank = usize::from(attr.bit(3));
 
             let tile_base_address = self.get_tile_address(tile);
-            let tile_offset = if is_window {
-                (self.ly - self.window_y) % 8 * 2
+            let tile_row = if is_window {
+                self.window_line % 8
             } else {
-                bg_y % 8 * 2
+                bg_y % 8
             } as u16;
+            let tile_row = if attr.bit(6) { 7 - tile_row } else { tile_row };
 
-            let tile_data_address = tile_base_address + tile_offset;
+            let tile_data_address = tile_base_address + tile_row * 2;
             let tile_data = [
-                self.read_byte(tile_data_address),
-                self.read_byte(tile_data_address + 1),
+                self.vram_byte(bank, tile_data_address),
+                self.vram_byte(bank, tile_data_address + 1),
             ];
 
             let x_bit = if is_window {
@@ -235,33 +381,63 @@ impl GPU {
             } else {
                 7 - (bg_x % 8)
             };
+            let x_bit = if attr.bit(5) { 7 - x_bit } else { x_bit };
 
             let colour_num = 
-                usize::from(tile_data[0] & (1 << x_bit) > 0) |
+                u8::from(tile_data[0] & (1 << x_bit) > 0) |
                 if tile_data[1] & (1 << x_bit) > 0 { 2 } else { 0 };
                 
-            self.bg_priority[x as usize] = if colour_num == 0 {
-                Priority::Colour0
-            } else {
-                Priority::None
+            self.bg_fifo[x as usize] = BgPixel {
+                colour_idx: colour_num,
+                palette:    attr & 0x07,
+                priority:   attr.bit(7),
             };
+        }
+
+        if window_drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+    }
+
+    // For the LCD being switched on by the boot rom, long before the game starts.
+    pub(crate) fn skip_warm_up(&mut self) {
+        self.warm_up_cycles = 0;
+    }
+
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.color_correction = enabled;
+    }
 
-            self.set_pixel(x as usize, self.bg_palette.get_shade(colour_num));
-        }   
+    // Finds the sprites overlapping the current line, in OAM order.
+    fn scan_oam(&mut self) {
+        let line = self.ly as i16;
+        let size = self.lcdc.sprite_size as i16;
+        let limit = if self.sprite_limit { 10 } else { 40 };
+        self.scanline_sprites.clear();
+        for idx in 0..40 {
+            let y = self.oam[idx * 4] as i16 - 16;
+            if line >= y && line < y + size {
+                self.scanline_sprites.push(idx);
+                if self.scanline_sprites.len() == limit { break }
+            }
+        }
     }
 
     fn render_sprites(&mut self) {
         
         let line = self.ly as i16;
         let size = self.lcdc.sprite_size as i16;
-        // We reverse as sprites with lower idx have pixel priority.
-        for idx in (0..40).rev() {
 
+        for &idx in &self.scanline_sprites {
             let sprite = self.fetch_sprite(idx);
-            // Skip if out of bounds.
-            if line < sprite.y || line >= sprite.y + size { continue; }
 
             let tile_base_address = 0x8000 + (sprite.tile_num as u16 * 16);
+            // The two tiles of an 8x16 sprite are consecutive, flipping all 16 rows also
+            // swaps them.
             let tile_offset = if sprite.y_flip {
                 size - 1 - (line - sprite.y)
             } else {
@@ -270,11 +446,11 @@ impl GPU {
             let tile_data_address = tile_base_address + (tile_offset * 2) as u16;
             
             let tile_data = [
-                self.read_byte(tile_data_address),
-                self.read_byte(tile_data_address + 1),
+                self.vram_byte(sprite.vram_bank, tile_data_address),
+                self.vram_byte(sprite.vram_bank, tile_data_address + 1),
             ];
 
-            // Iterate width setting each pixel.
+            // Iterate width pushing each pixel.
             for x in 0..8 {
                 let pix_x = sprite.x + x;
                 // Skip out of bounds pixels.
@@ -283,24 +459,19 @@ impl GPU {
                 let x_bit = if sprite.x_flip { x } else { 7 - x };
                 
                 let colour_idx = 
-                    usize::from(tile_data[0] & (1 << x_bit) > 0) |
+                    u8::from(tile_data[0] & (1 << x_bit) > 0) |
                     if tile_data[1] & (1 << x_bit) > 0 { 2 } else { 0 };
                     
                 // Skip transparent pixels.
                 if colour_idx == 0 { continue; }
 
-                let colour = if sprite.is_palette_1 {
-                    self.sprite_palette_1.get_shade(colour_idx)
-                } else {
-                    self.sprite_palette_0.get_shade(colour_idx)
-                };
-
-                // Skip if background has priority.
-                if sprite.below_bg && self.bg_priority[(sprite.x + x) as usize] != Priority::Colour0 {
-                    continue;
-                }
-
-                self.set_pixel(pix_x as usize, colour);
+                push_sprite_pixel(&mut self.sprite_fifo[pix_x as usize], SpritePixel {
+                    colour_idx,
+                    palette:    if self.cgb { sprite.cgb_palette } else { u8::from(sprite.is_palette_1) },
+                    above_bg:   !sprite.below_bg,
+                    x:          sprite.x,
+                    oam_idx:    idx as u8,
+                });
             }
         }
     }
@@ -322,9 +493,16 @@ impl GPU {
             y_flip:         attr.bit(6),
             x_flip:         attr.bit(5),
             is_palette_1:   attr.bit(4),
+            vram_bank:      if self.cgb { usize::from(attr.bit(3)) } else { 0 },
+            cgb_palette:    attr & 0x07,
         }
     }
 
+    // Reads VRAM from the given bank, regardless of the bank selected by VBK.
+    fn vram_byte(&self, bank: usize, address: u16) -> u8 {
+        self.vram[bank * VRAM_BANK_SIZE + address as usize - 0x8000]
+    }
+
     fn get_address(base: u16, x: u8, y: u8) -> u16 {
         base + (y as u16 / 8 * 32) + (x as u16 / 8)
     }
@@ -345,9 +523,8 @@ impl GPU {
                 *pix = u8::MAX;
             }
         }
-        for prio in self.bg_priority.iter_mut() {
-            *prio = Priority::None;
-        }
+        self.bg_fifo = [BgPixel::default(); SCREEN_WIDTH];
+        self.sprite_fifo = [None; SCREEN_WIDTH];
         self.updated = true;
     }
 
@@ -366,28 +543,17 @@ impl GPU {
         }
     }
 
+    // Colours of the monochrome palettes, CGB colours come from palette RAM and are unaffected.
     pub fn set_colours(&mut self, colours: [u32; 4]) {
         let old_colours = self.bg_palette.colours();
         let old_ly = self.ly;
         
         #[cfg(not(target_arch = "wasm32"))]
-        {
-            for y in 0..SCREEN_HEIGHT {
-                self.ly = y as u8;
-                for x in 0..SCREEN_WIDTH as usize {
-                    let idx = (y * 166 + x) as usize;
-                    match self.pixels[idx] {
-                        c if c == old_colours[0] => self.set_pixel(x, colours[0]),
-                        c if c == old_colours[1] => self.set_pixel(x, colours[1]),
-                        c if c == old_colours[2] => self.set_pixel(x, colours[2]),
-                        c if c == old_colours[3] => self.set_pixel(x, colours[3]),
-                        _ => unreachable!(),
-                    }
-                }
-            }
+        if !self.cgb {
+            remap_colours(&mut self.pixels, old_colours, colours);
         }
         #[cfg(target_arch = "wasm32")]
-        {
+        if !self.cgb {
             for y in 0..SCREEN_HEIGHT {
                 self.ly = y as u8;
                 for x in 0..SCREEN_WIDTH {
@@ -410,6 +576,8 @@ impl GPU {
         self.sprite_palette_1.set_colours(colours);
     }
 
+    pub fn colours(&self) -> [u32; 4] { self.bg_palette.colours() }
+
     #[cfg(target_arch = "wasm32")]
     pub fn get_pixel(&self, x: usize, y: usize) -> [u8; 4] {
         let start = (y * SCREEN_WIDTH + x) * 4;
@@ -418,19 +586,204 @@ impl GPU {
         rgba
     }
 
+    pub fn frame_count(&self) -> u64 { self.frame_count }
+
+    pub(crate) fn set_cgb(&mut self, cgb: bool) { self.cgb = cgb }
+
+    // Mode 0 was entered during the last update.
+    pub fn hblank_started(&self) -> bool { self.h_blank }
+
+    // Mode 3, VRAM is in use by the PPU.
+    pub fn is_drawing(&self) -> bool { self.stat.mode == Mode::VRAMRead }
+
     pub fn check_updated(&mut self) -> bool {
         let updated = self.updated;
         self.updated = false;
         updated
     }
+
+    pub fn save_state(&self) -> GpuState {
+        GpuState {
+            vram:       self.vram.to_vec(),
+            oam:        self.oam.to_vec(),
+            lcdc:       self.lcdc.read_byte(0xFF40),
+            stat:       self.stat.read_byte(0xFF41),
+            mode:       self.stat.read_byte(0xFF41) & 0b11,
+            h_blank:    self.h_blank,
+            scroll_y:   self.scroll_y,
+            scroll_x:   self.scroll_x,
+            ly:         self.ly,
+            ly_compare: self.ly_compare,
+            window_y:   self.window_y,
+            window_x:   self.window_x,
+            window_line:    self.window_line,
+            bg_palette: self.bg_palette.read_byte(0xFF47),
+            obp0:       self.sprite_palette_0.read_byte(0xFF48),
+            obp1:       self.sprite_palette_1.read_byte(0xFF49),
+            dots:       self.dots,
+            warm_up_cycles: self.warm_up_cycles,
+            vram_bank:  self.vram_bank as u8,
+            bg_palette_idx:     self.bg_palette_idx,
+            bg_palette_ram:     self.bg_palette_ram.to_vec(),
+            obj_palette_idx:    self.obj_palette_idx,
+            obj_palette_ram:    self.obj_palette_ram.to_vec(),
+        }
+    }
+
+    // The screen is not part of the state, it is redrawn from the next scanline.
+    pub fn load_state(&mut self, state: &GpuState) -> Result<(), SaveStateError> {
+        check_len("vram", &state.vram, VRAM_SIZE)?;
+        check_len("oam", &state.oam, OAM_SIZE)?;
+        check_len("bg palette ram", &state.bg_palette_ram, 64)?;
+        check_len("obj palette ram", &state.obj_palette_ram, 64)?;
+        self.vram.copy_from_slice(&state.vram);
+        self.oam.copy_from_slice(&state.oam);
+        self.lcdc.write_byte(0xFF40, state.lcdc);
+        self.stat.write_byte(0xFF41, state.stat);
+        self.stat.mode = match state.mode {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAMRead,
+            _ => Mode::VRAMRead,
+        };
+        self.h_blank    = state.h_blank;
+        self.scroll_y   = state.scroll_y;
+        self.scroll_x   = state.scroll_x;
+        self.ly         = state.ly;
+        self.ly_compare = state.ly_compare;
+        self.window_y   = state.window_y;
+        self.window_x   = state.window_x;
+        self.window_line = state.window_line;
+        self.bg_palette.write_byte(0xFF47, state.bg_palette);
+        self.sprite_palette_0.write_byte(0xFF48, state.obp0);
+        self.sprite_palette_1.write_byte(0xFF49, state.obp1);
+        self.dots       = state.dots;
+        self.warm_up_cycles = state.warm_up_cycles;
+        self.scx_penalty = (self.scroll_x % 8) as u32;
+        self.vram_bank  = (state.vram_bank & 0x01) as usize;
+        self.bg_palette_idx     = state.bg_palette_idx;
+        self.bg_palette_ram.copy_from_slice(&state.bg_palette_ram);
+        self.obj_palette_idx    = state.obj_palette_idx;
+        self.obj_palette_ram.copy_from_slice(&state.obj_palette_ram);
+        self.scan_oam();
+        // Conditions already true when saved do not fire again.
+        self.stat_irq_line = self.stat_line();
+        Ok(())
+    }
+}
+
+// Writes a byte of CGB palette RAM, incrementing the index if bit 7 of it is set.
+fn write_palette_ram(ram: &mut [u8; 64], idx: &mut u8, b: u8) {
+    ram[(*idx & 0x3F) as usize] = b;
+    if *idx & 0x80 != 0 {
+        *idx = 0x80 | ((*idx + 1) & 0x3F);
+    }
+}
+
+// Colour of a CGB palette as 0xRRGGBB. Each colour is 2 bytes little endian,
+// bits 0-4 red, 5-9 green and 10-14 blue.
+fn cgb_colour(ram: &[u8; 64], palette: u8, colour_idx: u8, correct: bool) -> u32 {
+    let idx = (palette as usize & 0x07) * 8 + colour_idx as usize * 2;
+    let rgb555 = u16::from_le_bytes([ram[idx], ram[idx + 1]]) as u32;
+    let r = rgb555 & 0x1F;
+    let g = (rgb555 >> 5) & 0x1F;
+    let b = (rgb555 >> 10) & 0x1F;
+    if correct {
+        return cgb_correct_color(r as u8, g as u8, b as u8);
+    }
+    rgb888(r, g, b)
+}
+
+// Approximates how the CGB LCD shows a colour, each channel bleeds into the others.
+// Same weights as Gambatte.
+fn cgb_correct_color(r5: u8, g5: u8, b5: u8) -> u32 {
+    let (r, g, b) = (r5 as u32, g5 as u32, b5 as u32);
+    rgb888(
+        ((r * 26 + g * 4 + b * 2) / 32).min(31),
+        ((g * 24 + b * 8) / 32).min(31),
+        ((r * 6 + g * 4 + b * 22) / 32).min(31),
+    )
 }
 
+// Scales 5 bit channels to 8 by repeating the top bits in the bottom.
+pub(crate) fn rgb888(r: u32, g: u32, b: u32) -> u32 {
+    let scale = |c: u32| (c << 3) | (c >> 2);
+    (scale(r) << 16) | (scale(g) << 8) | scale(b)
+}
+
+// On DMG the sprite with the lowest x wins overlaps, then the lowest OAM index.
+fn push_sprite_pixel(slot: &mut Option<SpritePixel>, pixel: SpritePixel) {
+    match slot {
+        Some(current) if (current.x, current.oam_idx) <= (pixel.x, pixel.oam_idx) => {},
+        _ => *slot = Some(pixel),
+    }
+}
+
+// Replaces each of the old colours with the new one at the same index, ignoring the alpha
+// channel added by set_pixel. Pixels of other colours are left as they are.
+#[cfg(not(target_arch = "wasm32"))]
+fn remap_colours(pixels: &mut [u32], old: [u32; 4], new: [u32; 4]) {
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+    remap_colours_sse2(pixels, old, new);
+    #[cfg(not(all(target_arch = "x86_64", target_feature = "sse2")))]
+    remap_colours_scalar(pixels, old, new);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn remap_colours_scalar(pixels: &mut [u32], old: [u32; 4], new: [u32; 4]) {
+    for pixel in pixels {
+        if let Some(i) = old.iter().position(|c| *c == *pixel & 0x00FF_FFFF) {
+            *pixel = 0xFF00_0000 | new[i];
+        }
+    }
+}
+
+// 8 pixels at a time in two registers, the rest by remap_colours_scalar.
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+fn remap_colours_sse2(pixels: &mut [u32], old: [u32; 4], new: [u32; 4]) {
+    use std::arch::x86_64::*;
+
+    let mut chunks = pixels.chunks_exact_mut(8);
+    // SAFETY: sse2 is enabled at compile time, loads and stores are unaligned and each
+    // covers 4 pixels within an 8 pixel chunk.
+    unsafe {
+        let rgb_mask = _mm_set1_epi32(0x00FF_FFFF);
+        let old = old.map(|c| _mm_set1_epi32(c as i32));
+        let new = new.map(|c| _mm_set1_epi32((0xFF00_0000 | c) as i32));
+        for chunk in &mut chunks {
+            for half in chunk.chunks_exact_mut(4) {
+                let ptr = half.as_mut_ptr() as *mut __m128i;
+                let pixels = _mm_loadu_si128(ptr);
+                let rgb = _mm_and_si128(pixels, rgb_mask);
+                // Last to first so that the first matching colour wins, as in the scalar version.
+                let mut out = pixels;
+                for i in (0..4).rev() {
+                    let matched = _mm_cmpeq_epi32(rgb, old[i]);
+                    out = _mm_or_si128(_mm_and_si128(matched, new[i]), _mm_andnot_si128(matched, out));
+                }
+                _mm_storeu_si128(ptr, out);
+            }
+        }
+    }
+    remap_colours_scalar(chunks.into_remainder(), old, new);
+}
+
+// Returns the sprite pixel if it is drawn over the background, None if the background shows.
+fn mix_pixel(bg: BgPixel, sprite: Option<SpritePixel>) -> Option<SpritePixel> {
+    let sprite = sprite.filter(|s| s.colour_idx != 0)?;
+    // BG colour 0 is always behind sprites.
+    if bg.colour_idx == 0 || (sprite.above_bg && !bg.priority) {
+        Some(sprite)
+    } else {
+        None
+    }
+}
 
 impl MemoryBus for GPU {
 
     fn read_byte(&self, address: u16) -> u8 {
         match address {
-            0x8000 ..= 0x9FFF => self.vram[address as usize - 0x8000],
+            0x8000 ..= 0x9FFF => self.vram_byte(self.vram_bank, address),
             0xFE00 ..= 0xFE9F => self.oam[address as usize - 0xFE00],
             0xFF40 => self.lcdc.read_byte(address),
             0xFF41 => {
@@ -447,13 +800,20 @@ impl MemoryBus for GPU {
             0xFF49 => self.sprite_palette_1.read_byte(address),
             0xFF4A => self.window_y,
             0xFF4B => self.window_x,
+            // Only bit 0 is used, the rest read as set. DMG has no register.
+            0xFF4F if self.cgb => 0xFE | self.vram_bank as u8,
+            0xFF4F => 0xFF,
+            0xFF68 => self.bg_palette_idx,
+            0xFF69 => self.bg_palette_ram[(self.bg_palette_idx & 0x3F) as usize],
+            0xFF6A => self.obj_palette_idx,
+            0xFF6B => self.obj_palette_ram[(self.obj_palette_idx & 0x3F) as usize],
             _ => panic!("invalid memory read for gpu at {:#2X}", address),
         }
     }
 
     fn write_byte(&mut self, address: u16, b: u8) {
         match address {
-            0x8000 ..= 0x9FFF => self.vram[address as usize - 0x8000] = b,
+            0x8000 ..= 0x9FFF => self.vram[self.vram_bank * VRAM_BANK_SIZE + address as usize - 0x8000] = b,
             0xFE00 ..= 0xFE9F => self.oam[address as usize - 0xFE00] = b,
             0xFF40 => {
                 let prev = self.lcdc.lcd_enable;
@@ -463,24 +823,38 @@ impl MemoryBus for GPU {
                     self.dots = 0;
                     self.ly   = 0;
                     self.stat.mode = Mode::HBlank;
+                    self.stat_irq_line = false;
                     self.clear_screen();
                 }
 
                 if !prev && self.lcdc.lcd_enable {
                     self.switch_mode(Mode::OAMRead);
                     self.dots = 4;
+                    self.warm_up_cycles = FRAME_DOTS;
                 }
             },
-            0xFF41 => self.stat.write_byte(address, b),
+            0xFF41 => {
+                self.stat.write_byte(address, b);
+                self.update_stat_line();
+            },
             0xFF42 => self.scroll_y     = b,
             0xFF43 => self.scroll_x     = b,
             0xFF44 => {},   // Read only.
-            0xFF45 => self.ly_compare   = b,
+            0xFF45 => {
+                self.ly_compare = b;
+                self.update_stat_line();
+            },
             0xFF47 => self.bg_palette.write_byte(address, b),
             0xFF48 => self.sprite_palette_0.write_byte(address, b),
             0xFF49 => self.sprite_palette_1.write_byte(address, b),
             0xFF4A => self.window_y     = b,
             0xFF4B => self.window_x     = b,
+            0xFF4F if self.cgb => self.vram_bank = (b & 0x01) as usize,
+            0xFF4F => {},
+            0xFF68 => self.bg_palette_idx   = b & 0xBF,
+            0xFF69 => write_palette_ram(&mut self.bg_palette_ram, &mut self.bg_palette_idx, b),
+            0xFF6A => self.obj_palette_idx  = b & 0xBF,
+            0xFF6B => write_palette_ram(&mut self.obj_palette_ram, &mut self.obj_palette_idx, b),
             _ => panic!("invalid memory write for gpu at {:#2X}", address),
         };
     }
@@ -488,3 +862,549 @@ impl MemoryBus for GPU {
 
 
 
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::bus::MemoryBus;
+    use crate::intf::Intf;
+    use super::{GPU, BgPixel, SpritePixel, cgb_colour, mix_pixel, push_sprite_pixel};
+
+    fn sprite(colour_idx: u8, above_bg: bool) -> SpritePixel {
+        SpritePixel { colour_idx, palette: 0, above_bg, x: 0, oam_idx: 0 }
+    }
+
+    fn bg(colour_idx: u8) -> BgPixel {
+        BgPixel { colour_idx, palette: 0, priority: false }
+    }
+
+    #[test]
+    fn sprite_above_bg() {
+        assert_eq!(mix_pixel(bg(2), Some(sprite(1, true))), Some(sprite(1, true)));
+    }
+
+    #[test]
+    fn sprite_below_bg() {
+        assert_eq!(mix_pixel(bg(2), Some(sprite(1, false))), None);
+    }
+
+    #[test]
+    fn transparent_sprite() {
+        assert_eq!(mix_pixel(bg(0), Some(sprite(0, true))), None);
+        assert_eq!(mix_pixel(bg(3), None), None);
+    }
+
+    #[test]
+    fn bg_colour_0_shows_sprite() {
+        assert_eq!(mix_pixel(bg(0), Some(sprite(3, false))), Some(sprite(3, false)));
+        let cgb_priority = BgPixel { colour_idx: 0, palette: 0, priority: true };
+        assert_eq!(mix_pixel(cgb_priority, Some(sprite(3, true))), Some(sprite(3, true)));
+    }
+
+    #[test]
+    fn cgb_bg_priority() {
+        let bg = BgPixel { colour_idx: 1, palette: 0, priority: true };
+        assert_eq!(mix_pixel(bg, Some(sprite(3, true))), None);
+    }
+
+    #[test]
+    fn sprite_overlap_priority() {
+        let mut slot = None;
+        let right = SpritePixel { x: 12, oam_idx: 0, ..sprite(1, true) };
+        let left  = SpritePixel { x: 10, oam_idx: 5, ..sprite(2, true) };
+        let same  = SpritePixel { x: 10, oam_idx: 3, ..sprite(3, true) };
+
+        push_sprite_pixel(&mut slot, right);
+        assert_eq!(slot, Some(right));
+        // Lower x wins regardless of OAM index.
+        push_sprite_pixel(&mut slot, left);
+        assert_eq!(slot, Some(left));
+        // Equal x, lower OAM index wins.
+        push_sprite_pixel(&mut slot, same);
+        assert_eq!(slot, Some(same));
+        push_sprite_pixel(&mut slot, right);
+        assert_eq!(slot, Some(same));
+    }
+
+    #[test]
+    fn palette_auto_increment() {
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        for (index, data) in [(0xFF68, 0xFF69), (0xFF6A, 0xFF6B)] {
+            gpu.write_byte(index, 0x80);
+            for b in 0..64 {
+                gpu.write_byte(data, b);
+            }
+            // Wrapped back round to 0.
+            assert_eq!(gpu.read_byte(index), 0x80);
+            for b in 0..64 {
+                gpu.write_byte(index, b);
+                assert_eq!(gpu.read_byte(data), b);
+            }
+        }
+    }
+
+    #[test]
+    fn palette_no_increment() {
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        gpu.write_byte(0xFF68, 0x05);
+        gpu.write_byte(0xFF69, 0x11);
+        gpu.write_byte(0xFF69, 0x22);
+        assert_eq!(gpu.read_byte(0xFF68), 0x05);
+        assert_eq!(gpu.read_byte(0xFF69), 0x22);
+
+        gpu.write_byte(0xFF6A, 0xBF);
+        gpu.write_byte(0xFF6B, 0x33);
+        assert_eq!(gpu.read_byte(0xFF6A), 0x80);
+        gpu.write_byte(0xFF6A, 0x3F);
+        assert_eq!(gpu.read_byte(0xFF6B), 0x33);
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+    #[test]
+    fn simd_palette_matches_scalar() {
+        use super::{remap_colours_scalar, remap_colours_sse2};
+
+        let old = [0xE0F8D0, 0x88C070, 0x346856, 0x081820];
+        // Duplicate colours map to the first, 0x123456 is not in the palette.
+        let new = [0xFFFFFF, 0xAAAAAA, 0xAAAAAA, 0x000000];
+        let colours = [old[0], old[1], old[2], old[3], 0x123456];
+        // Not a multiple of 8, with and without alpha.
+        let pixels: Vec<u32> = (0..101u32)
+            .map(|i| colours[(i * 7 % 5) as usize] | if i % 3 == 0 { 0xFF00_0000 } else { 0 })
+            .collect();
+
+        let mut scalar = pixels.clone();
+        remap_colours_scalar(&mut scalar, old, new);
+        let mut simd = pixels.clone();
+        remap_colours_sse2(&mut simd, old, new);
+        assert_eq!(simd, scalar);
+        assert_ne!(simd, pixels);
+        assert!(simd.contains(&0x123456));
+
+        let old = [0x111111, 0x111111, 0x222222, 0x333333];
+        let mut scalar = pixels.iter().map(|p| p & 0xFF00_0000 | old[(p & 3) as usize]).collect::<Vec<_>>();
+        let mut simd = scalar.clone();
+        remap_colours_scalar(&mut scalar, old, new);
+        remap_colours_sse2(&mut simd, old, new);
+        assert_eq!(simd, scalar);
+    }
+
+    #[test]
+    fn cgb_colours() {
+        let mut ram = [0; 64];
+        // Palette 3 colour 2, red 31, green 10 and blue 0.
+        ram[3 * 8 + 4..3 * 8 + 6].copy_from_slice(&(31_u16 | 10 << 5).to_le_bytes());
+        assert_eq!(cgb_colour(&ram, 3, 2, false), 0xFF5200);
+        assert_eq!(cgb_colour(&ram, 3, 1, false), 0x000000);
+        ram[62..64].copy_from_slice(&0x7FFF_u16.to_le_bytes());
+        assert_eq!(cgb_colour(&ram, 7, 3, false), 0xFFFFFF);
+
+        // Corrected, white and black stay the same and red bleeds into blue.
+        assert_eq!(cgb_colour(&ram, 7, 3, true), 0xFFFFFF);
+        assert_eq!(cgb_colour(&ram, 3, 1, true), 0x000000);
+        assert_eq!(cgb_colour(&ram, 3, 2, true), 0xD63939);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn cgb_bg_palette() {
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        gpu.set_cgb(true);
+        gpu.write_byte(0xFF40, 0x91);
+        // Tile 0 is colour 1 on every row, the first map entry uses BG palette 2.
+        for row in 0..8 {
+            gpu.write_byte(0x8000 + row * 2, 0xFF);
+        }
+        gpu.vram[0x2000 + 0x1800] = 0x02;
+
+        // Red 31, green 0 and blue 16 as palette 2 colour 1.
+        gpu.write_byte(0xFF68, 0x80 | (2 * 8 + 2));
+        gpu.write_byte(0xFF69, 0x1F);
+        gpu.write_byte(0xFF69, 0x40);
+        assert_eq!(gpu.read_byte(0xFF68), 0x80 | (2 * 8 + 4));
+
+        gpu.render_scanline();
+        assert!(gpu.pixels[0..8].iter().all(|p| p & 0xFFFFFF == 0xFF0084));
+        // The next tile uses palette 0, which is still black.
+        assert_eq!(gpu.pixels[8] & 0xFFFFFF, 0x000000);
+    }
+
+    #[test]
+    fn vram_bank() {
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        // DMG has a single bank and no register.
+        gpu.write_byte(0xFF4F, 0x01);
+        assert_eq!(gpu.read_byte(0xFF4F), 0xFF);
+        gpu.write_byte(0x8000, 0x11);
+
+        gpu.set_cgb(true);
+        assert_eq!(gpu.read_byte(0xFF4F), 0xFE);
+        gpu.write_byte(0xFF4F, 0xFF);
+        assert_eq!(gpu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(gpu.read_byte(0x8000), 0x00);
+        gpu.write_byte(0x8000, 0x22);
+        gpu.write_byte(0x9FFF, 0x33);
+        assert_eq!(gpu.vram[0x2000], 0x22);
+        assert_eq!(gpu.vram[0x3FFF], 0x33);
+
+        gpu.write_byte(0xFF4F, 0x00);
+        assert_eq!(gpu.read_byte(0x8000), 0x11);
+        assert_eq!(gpu.read_byte(0x9FFF), 0x00);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn cgb_bg_attributes() {
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        gpu.set_cgb(true);
+        gpu.write_byte(0xFF40, 0x91);
+        // Palette 0 colours 0-3 are red 0-3, 0x00, 0x08, 0x10 and 0x18 once scaled.
+        gpu.write_byte(0xFF68, 0x80);
+        for colour in 0..4 {
+            gpu.write_byte(0xFF69, colour);
+            gpu.write_byte(0xFF69, 0x00);
+        }
+        let colour = |idx: u32| (idx * 8) << 16;
+        let colours = |gpu: &GPU, tile: usize| -> Vec<u32> {
+            gpu.pixels[tile * 8..tile * 8 + 8].iter().map(|p| p & 0xFFFFFF).collect()
+        };
+
+        // Tile 1 in bank 0, row 0 colour 1 in the left pixel and row 7 colour 2.
+        gpu.vram[0x0010] = 0x80;
+        gpu.vram[0x001F] = 0x80;
+        // Tile 1 in bank 1, row 0 colour 3 in the right pixel.
+        gpu.vram[0x2010] = 0x01;
+        gpu.vram[0x2011] = 0x01;
+        let attrs = [0x00, 0x20, 0x40, 0x08, 0x80];
+        for (idx, attr) in attrs.iter().enumerate() {
+            gpu.vram[0x1800 + idx] = 1;
+            gpu.vram[0x3800 + idx] = *attr;
+        }
+
+        gpu.render_scanline();
+        let mut expected = vec![colour(0); 8];
+        // No attributes.
+        expected[0] = colour(1);
+        assert_eq!(colours(&gpu, 0), expected);
+        // X flip.
+        expected.reverse();
+        assert_eq!(colours(&gpu, 1), expected);
+        // Y flip shows row 7.
+        expected = vec![colour(0); 8];
+        expected[0] = colour(2);
+        assert_eq!(colours(&gpu, 2), expected);
+        // Tile data from bank 1.
+        expected = vec![colour(0); 8];
+        expected[7] = colour(3);
+        assert_eq!(colours(&gpu, 3), expected);
+        // Priority is kept for mixing with sprites.
+        assert!(gpu.bg_fifo[32].priority);
+        assert!(!gpu.bg_fifo[0].priority);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn cgb_bg_priority_over_sprites() {
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        gpu.set_cgb(true);
+        gpu.write_byte(0xFF40, 0x93);
+        // BG palette 0 colour 1 is red, OBJ palette 0 colour 3 is green.
+        gpu.write_byte(0xFF68, 0x82);
+        gpu.write_byte(0xFF69, 0x1F);
+        gpu.write_byte(0xFF69, 0x00);
+        gpu.write_byte(0xFF6A, 0x86);
+        gpu.write_byte(0xFF6B, 0xE0);
+        gpu.write_byte(0xFF6B, 0x03);
+
+        // Tile 1 is colour 1, tile 2 colour 3.
+        gpu.vram[0x0010] = 0xFF;
+        gpu.vram[0x0020] = 0xFF;
+        gpu.vram[0x0021] = 0xFF;
+        // Tile 1 with priority, tile 1 without, then tile 0 (colour 0) with priority.
+        gpu.vram[0x1800..0x1803].copy_from_slice(&[1, 1, 0]);
+        gpu.vram[0x3800..0x3803].copy_from_slice(&[0x80, 0x00, 0x80]);
+        // Sprites above the BG over x 4-11 and 16-23.
+        gpu.oam[0..8].copy_from_slice(&[16, 12, 2, 0x00, 16, 24, 2, 0x00]);
+
+        gpu.scan_oam();
+        gpu.render_scanline();
+        let red = |x: usize| gpu.pixels[x] & 0xFFFFFF == 0xFF0000;
+        let green = |x: usize| gpu.pixels[x] & 0xFFFFFF == 0x00FF00;
+        assert!((4..8).all(red));
+        assert!((8..12).all(green));
+        // BG colour 0 is always behind sprites.
+        assert!((16..24).all(green));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn scheduled_palette() {
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        let old = gpu.bg_palette.colours();
+        let new = [0x112233, 0x445566, 0x778899, 0xAABBCC];
+        gpu.write_byte(0xFF47, 0xE4);
+        gpu.write_byte(0xFF40, 0x91);
+        gpu.palette_scheduler.schedule_at(10, new);
+
+        while gpu.frame_count() < 10 {
+            let frame = gpu.frame_count();
+            gpu.update(456);
+            if gpu.frame_count() == frame && frame > 0 {
+                assert_eq!(gpu.pixels[0] & 0xFFFFFF, old[0]);
+            }
+        }
+        assert_eq!(gpu.pixels[0] & 0xFFFFFF, new[0]);
+        assert!(gpu.pixels.iter().all(|p| p & 0xFFFFFF == new[0]));
+        assert_eq!(gpu.bg_palette.colours(), new);
+    }
+
+    #[test]
+    fn vblank_interrupt_timing() {
+        let intf = Rc::new(RefCell::new(Intf::new()));
+        let mut gpu = GPU::new(intf.clone());
+        gpu.write_byte(0xFF40, 0x91);
+
+        // Fires at dot 0 of line 144, 144 * 456 cycles into each frame but the first after
+        // the LCD is switched on. Its first line starts 4 dots in, count from line 0 dot 0.
+        let mut fired = Vec::new();
+        let mut cycles = gpu.dots;
+        while fired.len() < 2 {
+            gpu.update(4);
+            cycles += 4;
+            if intf.borrow().read_byte(0xFF0F) & 0x01 != 0 {
+                assert_eq!(gpu.read_byte(0xFF44), 144);
+                assert_eq!(gpu.dots, 0);
+                fired.push(cycles);
+                intf.borrow_mut().write_byte(0xFF0F, 0);
+            }
+        }
+        assert_eq!(fired, vec![144 * 456 + 154 * 456, 144 * 456 + 2 * 154 * 456]);
+    }
+
+    #[test]
+    fn window_line_counter() {
+        use super::Mode;
+
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        // Window at the top left using the 0x9C00 map, with 0x8000 tile data.
+        gpu.write_byte(0xFF40, 0xF1);
+        gpu.write_byte(0xFF4A, 0);
+        gpu.write_byte(0xFF4B, 7);
+        // Tile 1 is colour 1 and tile 2 colour 2, the window's first row of tiles is
+        // tile 1 and the second tile 2. The background is tile 0, colour 0.
+        for row in 0..8 {
+            gpu.write_byte(0x8010 + row * 2, 0xFF);
+            gpu.write_byte(0x8021 + row * 2, 0xFF);
+        }
+        for x in 0..32 {
+            gpu.write_byte(0x9C00 + x, 1);
+            gpu.write_byte(0x9C20 + x, 2);
+        }
+
+        let mut colours = Vec::new();
+        for ly in 0..20 {
+            // Disabled for lines 5-14.
+            gpu.write_byte(0xFF40, if (5..15).contains(&ly) { 0xD1 } else { 0xF1 });
+            gpu.ly = ly;
+            gpu.render_scanline();
+            colours.push(gpu.bg_fifo[0].colour_idx);
+        }
+        // Line 15 continues from window line 5, the second row of tiles starts at line 18.
+        assert_eq!(colours[..5], [1; 5]);
+        assert_eq!(colours[5..15], [0; 10]);
+        assert_eq!(colours[15..18], [1; 3]);
+        assert_eq!(colours[18..], [2; 2]);
+        assert_eq!(gpu.window_line, 10);
+
+        gpu.switch_mode(Mode::VBlank);
+        assert_eq!(gpu.window_line, 0);
+    }
+
+    #[test]
+    fn stat_blocking() {
+        let intf = Rc::new(RefCell::new(Intf::new()));
+        let mut gpu = GPU::new(intf.clone());
+        gpu.write_byte(0xFF40, 0x91);
+        // HBlank and OAM interrupts, between them the line is only low during mode 3.
+        gpu.write_byte(0xFF41, 0x28);
+        intf.borrow_mut().write_byte(0xFF0F, 0);
+
+        let mut fired = 0;
+        for _ in 0..10 * 456 / 4 {
+            gpu.update(4);
+            if intf.borrow().read_byte(0xFF0F) & 0x02 != 0 {
+                fired += 1;
+                intf.borrow_mut().write_byte(0xFF0F, 0);
+            }
+        }
+        // Once per line, OAM directly follows HBlank so it is blocked.
+        assert_eq!(fired, 10);
+
+        // LYC matching while HBlank holds the line high is blocked too.
+        gpu.write_byte(0xFF41, 0x48);
+        gpu.write_byte(0xFF45, gpu.read_byte(0xFF44) + 1);
+        while gpu.stat.mode != super::Mode::HBlank { gpu.update(4); }
+        intf.borrow_mut().write_byte(0xFF0F, 0);
+        while gpu.stat.mode != super::Mode::VRAMRead { gpu.update(4); }
+        assert_eq!(intf.borrow().read_byte(0xFF0F) & 0x02, 0);
+    }
+
+    #[test]
+    fn lcd_warm_up() {
+        let intf = Rc::new(RefCell::new(Intf::new()));
+        let mut gpu = GPU::new(intf.clone());
+        gpu.write_byte(0xFF40, 0x91);
+
+        // The first frame is drawn but requests no VBlank interrupt.
+        while gpu.frame_count() == 0 { gpu.update(4); }
+        assert_eq!(intf.borrow().read_byte(0xFF0F) & 0x01, 0);
+        assert!(gpu.check_updated());
+        while gpu.frame_count() == 1 { gpu.update(4); }
+        assert_eq!(intf.borrow().read_byte(0xFF0F) & 0x01, 0x01);
+
+        // Switching the LCD off and on again starts another warm up.
+        gpu.write_byte(0xFF40, 0x11);
+        gpu.write_byte(0xFF40, 0x91);
+        intf.borrow_mut().write_byte(0xFF0F, 0);
+        while gpu.frame_count() == 2 { gpu.update(4); }
+        assert_eq!(intf.borrow().read_byte(0xFF0F) & 0x01, 0);
+    }
+
+    #[test]
+    fn scx_fine_scroll() {
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        gpu.write_byte(0xFF40, 0x91);
+        // Colour 1 in pixel 5 of every tile.
+        gpu.vram[0x0000] = 0x04;
+        gpu.write_byte(0xFF43, 5);
+
+        gpu.render_scanline();
+        let colours = gpu.bg_fifo[..16].iter().map(|p| p.colour_idx).collect::<Vec<_>>();
+        assert_eq!(colours, vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn scx_mode_3_penalty() {
+        use super::Mode;
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        gpu.write_byte(0xFF40, 0x91);
+
+        // Dot of the next line HBlank starts on.
+        let hblank_start = |gpu: &mut GPU| {
+            while gpu.stat.mode != Mode::OAMRead { gpu.update(1); }
+            while gpu.stat.mode != Mode::HBlank { gpu.update(1); }
+            gpu.dots
+        };
+        assert_eq!(hblank_start(&mut gpu), 80 + 172 + 1);
+        gpu.write_byte(0xFF43, 5);
+        assert_eq!(hblank_start(&mut gpu), 80 + 172 + 5 + 1);
+
+        // Writing SCX during mode 3 doesn't change the line's length.
+        while gpu.stat.mode != Mode::VRAMRead { gpu.update(1); }
+        gpu.write_byte(0xFF43, 0);
+        while gpu.stat.mode != Mode::HBlank { gpu.update(1); }
+        assert_eq!(gpu.dots, 80 + 172 + 5 + 1);
+    }
+
+    #[test]
+    fn ly_153_reads_zero() {
+        let intf = Rc::new(RefCell::new(Intf::new()));
+        let mut gpu = GPU::new(intf.clone());
+        gpu.write_byte(0xFF40, 0x91);
+        // LYC=0 interrupt, which matches straight away when the LCD is switched on.
+        gpu.write_byte(0xFF41, 0x40);
+        intf.borrow_mut().write_byte(0xFF0F, 0);
+
+        // Cycles from line 0 dot 0.
+        let mut cycles = gpu.dots;
+        let mut fired = Vec::new();
+        while fired.len() < 2 {
+            gpu.update(4);
+            cycles += 4;
+            if intf.borrow().read_byte(0xFF0F) & 0x02 != 0 {
+                assert_eq!(gpu.read_byte(0xFF44), 0);
+                assert_eq!(gpu.read_byte(0xFF41) & 0x03, 1);
+                fired.push(cycles);
+                intf.borrow_mut().write_byte(0xFF0F, 0);
+            }
+            // Still 0 at the start of the next frame, then counting up again.
+            if cycles == 154 * 456 {
+                assert_eq!(gpu.read_byte(0xFF44), 0);
+                assert_eq!(gpu.read_byte(0xFF41) & 0x03, 2);
+            }
+            if cycles == 155 * 456 {
+                assert_eq!(gpu.read_byte(0xFF44), 1);
+            }
+        }
+        assert_eq!(fired, vec![153 * 456 + 4, 153 * 456 + 4 + 154 * 456]);
+    }
+
+    #[test]
+    fn sprite_limit() {
+        use super::Mode;
+
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        gpu.write_byte(0xFF40, 0x93);
+        // Tile 1 is solid, 12 sprites side by side on line 0.
+        for row in 0..8 {
+            gpu.write_byte(0x8010 + row * 2, 0xFF);
+        }
+        for idx in 0..12 {
+            gpu.write_byte(0xFE00 + idx * 4, 16);
+            gpu.write_byte(0xFE01 + idx * 4, 8 + idx as u8 * 8);
+            gpu.write_byte(0xFE02 + idx * 4, 1);
+        }
+        let drawn = |gpu: &mut GPU| {
+            gpu.switch_mode(Mode::OAMRead);
+            gpu.render_scanline();
+            gpu.sprite_fifo.iter().filter(|p| p.is_some()).count()
+        };
+
+        // The last two in OAM are dropped.
+        assert_eq!(drawn(&mut gpu), 10 * 8);
+        assert!(gpu.sprite_fifo[10 * 8].is_none());
+        gpu.set_sprite_limit(false);
+        assert_eq!(drawn(&mut gpu), 12 * 8);
+    }
+
+    #[test]
+    fn tall_sprite_y_flip() {
+        use super::Mode;
+
+        let mut gpu = GPU::new(Rc::new(RefCell::new(Intf::new())));
+        gpu.write_byte(0xFF40, 0x97);
+        // Row r of tile 2 has colour 1 at pixel r, row r of tile 3 colour 2.
+        for row in 0..8 {
+            gpu.write_byte(0x8020 + row * 2, 0x80 >> row);
+            gpu.write_byte(0x8031 + row * 2, 0x80 >> row);
+        }
+        // Bit 0 of the tile number is ignored for 8x16 sprites.
+        gpu.write_byte(0xFE00, 16);
+        gpu.write_byte(0xFE01, 8);
+        gpu.write_byte(0xFE02, 3);
+        // Colour of the sprite's pixels on each of its 16 lines.
+        let lines = |gpu: &mut GPU| -> Vec<Vec<u8>> {
+            (0..16).map(|ly| {
+                gpu.ly = ly;
+                gpu.switch_mode(Mode::OAMRead);
+                gpu.render_scanline();
+                gpu.sprite_fifo[..8].iter().map(|p| p.map_or(0, |p| p.colour_idx)).collect()
+            }).collect()
+        };
+        let line = |x: usize, colour: u8| {
+            let mut line = vec![0; 8];
+            line[x] = colour;
+            line
+        };
+
+        let expected = (0..16).map(|y| if y < 8 { line(y, 1) } else { line(y - 8, 2) }).collect::<Vec<_>>();
+        assert_eq!(lines(&mut gpu), expected);
+
+        // Flipped, tile 3 is on top and both tiles are upside down.
+        gpu.write_byte(0xFE03, 0x40);
+        let expected = (0..16).map(|y| if y < 8 { line(7 - y, 2) } else { line(15 - y, 1) }).collect::<Vec<_>>();
+        assert_eq!(lines(&mut gpu), expected);
+    }
+}
@@ -44,6 +44,27 @@ impl Palette {
     }
 }
 
+// Colour changes queued for specific frames, applied by the gpu at vblank.
+#[derive(Default)]
+pub struct PaletteScheduler {
+    events: Vec<(u64, [u32; 4])>,
+}
+
+impl PaletteScheduler {
+
+    pub fn schedule_at(&mut self, frame: u64, palette: [u32; 4]) {
+        self.events.push((frame, palette));
+    }
+
+    // Removes and returns the colours due at frame, if several were scheduled the last wins.
+    // Events for earlier frames are dropped.
+    pub fn take_due(&mut self, frame: u64) -> Option<[u32; 4]> {
+        let due = self.events.iter().rev().find(|(f, _)| *f == frame).map(|(_, p)| *p);
+        self.events.retain(|(f, _)| *f > frame);
+        due
+    }
+}
+
 impl MemoryBus for Palette {
     fn read_byte(&self, _: u16) -> u8 { self.data }
 
@@ -66,4 +87,72 @@ mod test {
         assert_eq!(pal.shades[2], 0x081820);
         assert_eq!(pal.shades[3], 0x346856);
     }
+
+    const CLASSIC: [u32; 4] = [0xe0f8d0, 0x88c070, 0x346856, 0x081820];
+
+    #[test]
+    fn all_lightest() {
+        let mut pal = Palette::new();
+        pal.write_byte(0x0000, 0x00);
+        assert_eq!(pal.shades, [CLASSIC[0]; 4]);
+    }
+
+    #[test]
+    fn all_darkest() {
+        let mut pal = Palette::new();
+        pal.write_byte(0x0000, 0xFF);
+        assert_eq!(pal.shades, [CLASSIC[3]; 4]);
+    }
+
+    #[test]
+    fn alternating() {
+        let mut pal = Palette::new();
+        pal.write_byte(0x0000, 0b1100_1100);
+        assert_eq!(pal.shades, [CLASSIC[0], CLASSIC[3], CLASSIC[0], CLASSIC[3]]);
+        pal.write_byte(0x0000, 0b0011_0011);
+        assert_eq!(pal.shades, [CLASSIC[3], CLASSIC[0], CLASSIC[3], CLASSIC[0]]);
+    }
+
+    #[test]
+    fn shade_bits() {
+        // Bits 1-0 are shade 0, 3-2 shade 1, 5-4 shade 2 and 7-6 shade 3.
+        let values: [(u8, [usize; 4]); 12] = [
+            (0x00, [0, 0, 0, 0]),
+            (0xFF, [3, 3, 3, 3]),
+            (0xE4, [0, 1, 2, 3]),
+            (0x1B, [3, 2, 1, 0]),
+            (0x01, [1, 0, 0, 0]),
+            (0x04, [0, 1, 0, 0]),
+            (0x10, [0, 0, 1, 0]),
+            (0x40, [0, 0, 0, 1]),
+            (0x55, [1, 1, 1, 1]),
+            (0xAA, [2, 2, 2, 2]),
+            (0xD2, [2, 0, 1, 3]),
+            (0x8C, [0, 3, 0, 2]),
+        ];
+        let mut pal = Palette::new();
+        for (data, expected) in values {
+            pal.write_byte(0x0000, data);
+            assert_eq!(pal.read_byte(0x0000), data);
+            for idx in 0..4 {
+                assert_eq!(pal.get_shade(idx), CLASSIC[expected[idx]], "{:#04X} shade {}", data, idx);
+                assert_eq!(pal.shades[idx], CLASSIC[expected[idx]]);
+            }
+        }
+    }
+
+    #[test]
+    fn colours_round_trip() {
+        let mut pal = Palette::new();
+        assert_eq!(pal.colours(), CLASSIC);
+
+        let colours = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+        pal.set_colours(colours);
+        assert_eq!(pal.colours(), colours);
+
+        pal.write_byte(0x0000, 0xE4);
+        for (idx, &colour) in colours.iter().enumerate() {
+            assert_eq!(pal.get_shade(idx), colour);
+        }
+    }
 }
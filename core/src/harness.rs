@@ -0,0 +1,156 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::cartridge::{self, CartError};
+use crate::cpu::{CPU, BREAKPOINT_CYCLES, FRAME_CYCLES};
+
+// Runs a rom without a window, audio or any speed limit, for integration tests.
+// The cartridge header is not verified, so hand written test roms can leave it empty.
+pub struct EmulatorHarness {
+    cpu:    CPU,
+    // Everything the rom has sent over the serial port.
+    serial: Rc<RefCell<Vec<u8>>>,
+}
+
+// Result of EmulatorHarness::run_for_cycles.
+pub struct FrameStats<'a> {
+    pub frames_rendered:    u32,
+    // Bytes sent over the serial port during the run.
+    pub serial_output:      Vec<u8>,
+    pub final_pixels:       &'a [u32],
+}
+
+impl EmulatorHarness {
+
+    pub fn new(rom: Vec<u8>) -> Result<Self, CartError> {
+        let cartridge = cartridge::from_rom_unchecked(rom)?;
+        let serial = Rc::new(RefCell::new(Vec::new()));
+        let sink = serial.clone();
+        let cpu = CPU::new(cartridge, Some(Box::new(move |b| sink.borrow_mut().push(b))));
+        Ok(Self { cpu, serial })
+    }
+
+    pub fn cpu(&mut self) -> &mut CPU { &mut self.cpu }
+
+    // Serial output since the start, as text.
+    pub fn serial_output(&self) -> String {
+        String::from_utf8_lossy(&self.serial.borrow()).into_owned()
+    }
+
+    // Runs at least n cycles, finishing the last instruction.
+    pub fn run_for_cycles(&mut self, n: u64) -> FrameStats<'_> {
+        let serial_start = self.serial.borrow().len();
+        let mut frames_rendered = 0;
+        let mut cycles = 0;
+        while cycles < n {
+            cycles += self.tick() as u64;
+            if self.cpu.mem.gpu.check_updated() {
+                frames_rendered += 1;
+            }
+        }
+        FrameStats {
+            frames_rendered,
+            serial_output: self.serial.borrow()[serial_start..].to_vec(),
+            final_pixels: &self.cpu.mem.gpu.pixels,
+        }
+    }
+
+    // Runs until the gpu has finished n frames, a second of emulated time is 60. Like
+    // CPU::run_until_vblank, two frames worth of cycles with the LCD off count as a frame.
+    pub fn run_for_frames(&mut self, n: u32) -> FrameStats<'_> {
+        let serial_start = self.serial.borrow().len();
+        let mut frames_rendered = 0;
+        let mut cycles = 0;
+        while frames_rendered < n {
+            cycles += self.tick();
+            let lcd_off_frame = 2 * FRAME_CYCLES * if self.cpu.is_double_speed() { 2 } else { 1 };
+            if self.cpu.mem.gpu.check_updated() || cycles >= lcd_off_frame {
+                frames_rendered += 1;
+                cycles = 0;
+            }
+        }
+        FrameStats {
+            frames_rendered,
+            serial_output: self.serial.borrow()[serial_start..].to_vec(),
+            final_pixels: &self.cpu.mem.gpu.pixels,
+        }
+    }
+
+    // Runs until the serial output contains needle, returning all of it, or None if
+    // max_cycles pass first.
+    pub fn run_until_serial_contains(&mut self, needle: &str, max_cycles: u64) -> Option<String> {
+        let mut cycles = 0;
+        let mut checked_len = usize::MAX;
+        while cycles < max_cycles {
+            // Only search the output when something new has been written.
+            let len = self.serial.borrow().len();
+            if len != checked_len {
+                checked_len = len;
+                let output = self.serial_output();
+                if output.contains(needle) {
+                    return Some(output);
+                }
+            }
+            cycles += self.tick() as u64;
+        }
+        None
+    }
+
+    // Breakpoints set through cpu() are run straight past.
+    fn tick(&mut self) -> u32 {
+        let mut cycles = self.cpu.tick();
+        if cycles == BREAKPOINT_CYCLES {
+            cycles = self.cpu.tick();
+        }
+        self.cpu.mem.update(cycles);
+        cycles
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+    use super::EmulatorHarness;
+
+    // Sends "ok" over the serial port then loops, the header is left empty.
+    fn serial_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x112].copy_from_slice(&[
+            0x3E, b'o', 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02,
+            0x3E, b'k', 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02,
+            0x18, 0xFE,
+        ]);
+        rom
+    }
+
+    #[test]
+    fn serial_output() {
+        let mut harness = EmulatorHarness::new(serial_rom()).unwrap();
+        assert_eq!(harness.run_until_serial_contains("ok", 1_000), Some("ok".to_string()));
+
+        let mut harness = EmulatorHarness::new(serial_rom()).unwrap();
+        assert_eq!(harness.run_until_serial_contains("Passed", 100_000), None);
+        assert_eq!(harness.serial_output(), "ok");
+    }
+
+    #[test]
+    fn run_for_cycles() {
+        let mut harness = EmulatorHarness::new(serial_rom()).unwrap();
+        let stats = harness.run_for_cycles(10 * 154 * 456);
+        assert!((9..=10).contains(&stats.frames_rendered), "{}", stats.frames_rendered);
+        assert_eq!(stats.serial_output, b"ok");
+        assert_eq!(stats.final_pixels.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+
+        // Output is only what was sent during the run.
+        assert!(harness.run_for_cycles(1_000).serial_output.is_empty());
+    }
+
+    #[test]
+    fn run_for_frames() {
+        let mut harness = EmulatorHarness::new(serial_rom()).unwrap();
+        let stats = harness.run_for_frames(60);
+        assert_eq!(stats.frames_rendered, 60);
+        assert_eq!(stats.serial_output, b"ok");
+        assert!(harness.run_for_frames(1).serial_output.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use super::bit::Bit;
+use super::bus::MemoryBus;
+
+// CGB VRAM DMA transfers (HDMA), registers FF51-FF55.
+/*
+FF51 - HDMA1 - Source, High
+FF52 - HDMA2 - Source, Low      (lower 4 bits ignored)
+FF53 - HDMA3 - Destination, High (upper 3 bits ignored, always in VRAM)
+FF54 - HDMA4 - Destination, Low  (lower 4 bits ignored)
+FF55 - HDMA5 - Length/Mode/Start
+    Bit 7    - Transfer mode (0=General purpose DMA, 1=H-Blank DMA)
+    Bits 6-0 - Transfer length divided by 16, minus 1
+*/
+#[derive(Default)]
+pub struct HDMA {
+    // Source must be in 0000-7FFF or A000-DFFF, aligned to 16 bytes.
+    pub src:    u16,
+    // Destination is always in VRAM 8000-9FFF, aligned to 16 bytes.
+    pub dst:    u16,
+    // Remaining 16 byte blocks minus 1.
+    pub len:    u8,
+    // False for general purpose DMA, true for H-Blank DMA.
+    pub hblank: bool,
+    pub active: bool,
+}
+
+impl HDMA {
+    pub fn new() -> Self {
+        Self { dst: 0x8000, ..Default::default() }
+    }
+
+    // Sources in VRAM or E000-FFFF aren't readable by the transfer, they copy 0xFF.
+    pub fn valid_source(address: u16) -> bool {
+        matches!(address, 0x0000 ..= 0x7FFF | 0xA000 ..= 0xDFFF)
+    }
+
+    // Source and destination of the next 16 byte block, moving both past it.
+    // The transfer finishes with the block copied when the length is 0.
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.src, self.dst);
+        self.src = self.src.wrapping_add(16);
+        self.dst = 0x8000 | (self.dst.wrapping_add(16) & 0x1FF0);
+        if self.len == 0 {
+            self.active = false;
+            self.len    = 0x7F;
+        } else {
+            self.len -= 1;
+        }
+        block
+    }
+}
+
+impl MemoryBus for HDMA {
+
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            // Source and destination registers are write only.
+            0xFF51 ..= 0xFF54 => 0xFF,
+            // Bit 7 is 0 while a transfer is active, 1 otherwise.
+            0xFF55 => if self.active { self.len & 0x7F } else { 0xFF },
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, b: u8) {
+        match address {
+            0xFF51 => self.src = ((b as u16) << 8) | (self.src & 0x00F0),
+            0xFF52 => self.src = (self.src & 0xFF00) | (b & 0xF0) as u16,
+            0xFF53 => self.dst = 0x8000 | (((b as u16) << 8) & 0x1F00) | (self.dst & 0x00F0),
+            0xFF54 => self.dst = (self.dst & 0xFF00) | (b & 0xF0) as u16,
+            0xFF55 => {
+                // Writing bit 7 = 0 during an H-Blank transfer stops it.
+                if self.active && self.hblank && !b.bit(7) {
+                    self.active = false;
+                    return;
+                }
+                self.src   &= 0xFFF0;
+                self.dst    = 0x8000 | (self.dst & 0x1FF0);
+                self.len    = b & 0x7F;
+                self.hblank = b.bit(7);
+                self.active = true;
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use super::HDMA;
+
+    #[test]
+    fn address_clamping() {
+        let mut hdma = HDMA::new();
+        hdma.write_byte(0xFF51, 0xC1);
+        hdma.write_byte(0xFF52, 0x2F);
+        hdma.write_byte(0xFF53, 0xFF);
+        hdma.write_byte(0xFF54, 0xFF);
+        assert_eq!(hdma.src, 0xC120);
+        assert_eq!(hdma.dst, 0x9FF0);
+
+        hdma.write_byte(0xFF53, 0x01);
+        hdma.write_byte(0xFF54, 0x08);
+        assert_eq!(hdma.dst, 0x8100);
+    }
+
+    #[test]
+    fn valid_source() {
+        for (address, valid) in [
+            (0x0000, true), (0x7FF0, true), (0x8000, false), (0x9FF0, false),
+            (0xA000, true), (0xDFF0, true), (0xE000, false), (0xFFF0, false),
+        ] {
+            assert_eq!(HDMA::valid_source(address), valid, "{:#06X}", address);
+        }
+    }
+
+    #[test]
+    fn status() {
+        let mut hdma = HDMA::new();
+        assert_eq!(hdma.read_byte(0xFF55), 0xFF);
+
+        hdma.write_byte(0xFF55, 0x85);
+        assert!(hdma.active);
+        assert!(hdma.hblank);
+        assert_eq!(hdma.read_byte(0xFF55), 0x05);
+
+        // Cancel the H-Blank transfer.
+        hdma.write_byte(0xFF55, 0x00);
+        assert!(!hdma.active);
+        assert_eq!(hdma.read_byte(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn next_block() {
+        let mut hdma = HDMA::new();
+        hdma.write_byte(0xFF51, 0xC0);
+        hdma.write_byte(0xFF53, 0x1F);
+        hdma.write_byte(0xFF54, 0xF0);
+        hdma.write_byte(0xFF55, 0x81);
+
+        assert_eq!(hdma.next_block(), (0xC000, 0x9FF0));
+        assert!(hdma.active);
+        assert_eq!(hdma.read_byte(0xFF55), 0x00);
+        // Destination wraps round within VRAM.
+        assert_eq!(hdma.next_block(), (0xC010, 0x8000));
+        assert!(!hdma.active);
+        assert_eq!(hdma.read_byte(0xFF55), 0xFF);
+    }
+}
@@ -0,0 +1,104 @@
+use super::bus::MemoryBus;
+
+// CGB infrared communications port, register FF56.
+/*
+FF56 - RP - CGB Mode Only - Infrared Communications Port
+    Bit 0   - Write Data   (0=LED Off, 1=LED On)             (Read/Write)
+    Bit 1   - Read Data    (0=Receiving Signal, 1=No Signal) (Read Only)
+    Bit 6-7 - Data Read Enable (0=Disable, 3=Enable)         (Read/Write)
+*/
+#[derive(Default)]
+pub struct IrPort {
+    // Bits 0, 6 and 7 as written by the game.
+    rp:     u8,
+    // Signal from the other device, set by the frontend or by loopback.
+    input:  bool,
+    // Receives its own LED, LOOPBACK_DELAY cycles after it changes.
+    loopback:   bool,
+    loopback_cycles:    u32,
+}
+
+const LOOPBACK_DELAY: u32 = 2;
+
+impl IrPort {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn set_input(&mut self, signal: bool) { self.input = signal; }
+
+    pub fn set_loopback(&mut self, enabled: bool) {
+        self.loopback = enabled;
+        self.loopback_cycles = 0;
+    }
+
+    pub fn led_on(&self) -> bool { self.rp & 0x01 != 0 }
+
+    fn read_enabled(&self) -> bool { self.rp & 0xC0 == 0xC0 }
+
+    pub fn update(&mut self, cycles: u32) {
+        if !self.loopback || self.input == self.led_on() {
+            self.loopback_cycles = 0;
+            return;
+        }
+        self.loopback_cycles += cycles;
+        if self.loopback_cycles >= LOOPBACK_DELAY {
+            self.input = self.led_on();
+            self.loopback_cycles = 0;
+        }
+    }
+}
+
+impl MemoryBus for IrPort {
+
+    fn read_byte(&self, address: u16) -> u8 {
+        assert_eq!(address, 0xFF56);
+        // Unused bits 2-5 read as 1, bit 1 is low while a signal is received and reads are enabled.
+        self.rp | 0x3C | if self.input && self.read_enabled() { 0x00 } else { 0x02 }
+    }
+
+    fn write_byte(&mut self, address: u16, b: u8) {
+        assert_eq!(address, 0xFF56);
+        self.rp = b & 0xC1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use super::IrPort;
+
+    #[test]
+    fn led_and_signal() {
+        let mut ir = IrPort::new();
+        assert_eq!(ir.read_byte(0xFF56), 0x3E);
+
+        // Enable the LED and data reads, bit 1 is read only.
+        ir.write_byte(0xFF56, 0xC3);
+        assert!(ir.led_on());
+        assert_eq!(ir.read_byte(0xFF56), 0xFF);
+
+        ir.set_input(true);
+        assert_eq!(ir.read_byte(0xFF56), 0xFD);
+        ir.set_input(false);
+        assert_eq!(ir.read_byte(0xFF56) & 0x02, 0x02);
+
+        // No signal is seen with reads disabled.
+        ir.write_byte(0xFF56, 0x01);
+        ir.set_input(true);
+        assert_eq!(ir.read_byte(0xFF56), 0x3F);
+    }
+
+    #[test]
+    fn loopback() {
+        let mut ir = IrPort::new();
+        ir.set_loopback(true);
+        ir.write_byte(0xFF56, 0xC1);
+        ir.update(1);
+        assert_eq!(ir.read_byte(0xFF56) & 0x02, 0x02);
+        ir.update(1);
+        assert_eq!(ir.read_byte(0xFF56) & 0x02, 0x00);
+
+        ir.write_byte(0xFF56, 0xC0);
+        ir.update(4);
+        assert_eq!(ir.read_byte(0xFF56) & 0x02, 0x02);
+    }
+}
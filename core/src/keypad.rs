@@ -14,7 +14,7 @@ use super::bus::MemoryBus;
 // Bit 1 - P11 Input Left  or Button B (0=Pressed) (Read Only)
 // Bit 0 - P10 Input Right or Button A (0=Pressed) (Read Only)
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum GbKey {
     Right,
     Left, 
@@ -61,6 +61,9 @@ impl KeyPad {
         self.intf.borrow_mut().set_interrupt(InterruptSource::Keypad);
     }
 
+    // Button group selected through FF00.
+    pub fn select(&self) -> u8 { self.select }
+
     pub fn key_release(&mut self, key: GbKey) {
         match key {
             GbKey::Right  => self.reg[1] |= !(0b1110),
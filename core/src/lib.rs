@@ -4,6 +4,13 @@ pub mod cpu;
 pub mod cartridge;
 pub mod keypad;
 pub mod bus;
+pub mod state;
+pub mod builder;
+pub mod printer;
+pub mod cheat;
+pub mod sgb;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod harness;
 #[cfg(feature = "audio")]
 pub mod apu;
 
@@ -18,3 +25,5 @@ mod bit;
 mod serial;
 mod clock;
 mod intf;
+mod hdma;
+mod ir;
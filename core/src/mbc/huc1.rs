@@ -0,0 +1,208 @@
+use std::{path::PathBuf, fs::File, io::Write};
+
+use crate::{bus::MemoryBus, cartridge::Cartridge, state::{check_len, CartState, SaveStateError}};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{load_save, append_crc};
+
+// HuC1 banks like MBC1 but has an infrared LED and receiver, mapped to A000-BFFF in
+// place of RAM after 0x0E is written to 0000-1FFF.
+// https://gbdev.io/pandocs/HuC1.html
+pub struct HuC1 {
+    rom:        Vec<u8>,
+    rom_bank:   usize,
+
+    ram:        Vec<u8>,
+    ram_bank:   usize,
+    ir_mode:    bool,
+
+    // Signal from another device, and the LED.
+    ir_rx:      bool,
+    ir_tx:      bool,
+
+    save_path:  Option<PathBuf>
+}
+
+impl HuC1 {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(rom: Vec<u8>, ram_size: usize, save_path: Option<PathBuf>) -> Self {
+
+        let ram = match save_path {
+            Some(ref path) => load_save(path, ram_size),
+            None => vec![0; ram_size],
+        };
+
+        Self {
+            rom,
+            rom_bank: 1,
+            ram,
+            ram_bank: 0,
+            ir_mode: false,
+            ir_rx: false,
+            ir_tx: false,
+            save_path,
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn new(rom: Vec<u8>, ram_size: usize, save_data: Option<Vec<u8>>) -> Self {
+
+        let ram = match save_data {
+            Some(data) => data,
+            None => vec![0; ram_size],
+        };
+
+        Self {
+            rom,
+            rom_bank: 1,
+            ram,
+            ram_bank: 0,
+            ir_mode: false,
+            ir_rx: false,
+            ir_tx: false,
+            save_path: None,
+        }
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        Some((0x2000 * self.ram_bank + (address as usize - 0xA000)) % self.ram.len())
+    }
+}
+
+impl Cartridge for HuC1 {
+
+    fn len(&self) -> usize { self.rom.len() }
+
+    fn rom(&self) -> &[u8] { &self.rom }
+
+    fn receive_ir(&mut self, signal: bool) { self.ir_rx = signal; }
+
+    fn send_ir(&self) -> bool { self.ir_tx }
+
+    fn save_state(&self) -> CartState {
+        CartState {
+            rom_bank:   self.rom_bank,
+            ram_bank:   self.ram_bank,
+            mode:       self.ir_mode,
+            ram:        self.ram.clone(),
+            ir_rx:      self.ir_rx,
+            ir_tx:      self.ir_tx,
+            ..Default::default()
+        }
+    }
+
+    fn load_state(&mut self, state: &CartState) -> Result<(), SaveStateError> {
+        check_len("cartridge ram", &state.ram, self.ram.len())?;
+        self.rom_bank = state.rom_bank;
+        self.ram_bank = state.ram_bank;
+        self.ir_mode  = state.mode;
+        self.ram.clone_from(&state.ram);
+        self.ir_rx    = state.ir_rx;
+        self.ir_tx    = state.ir_tx;
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save(&self) {
+        if let Some(path) = &self.save_path {
+            File::create(path).and_then(
+                |mut f| f.write_all(&append_crc(&self.ram))
+            ).unwrap()
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn save(&self) -> *const u8 {
+        self.ram.as_ptr()
+    }
+}
+
+impl MemoryBus for HuC1 {
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0x0000 ..= 0x3FFF => self.rom.get(address as usize).copied().unwrap_or(0xFF),
+            0x4000 ..= 0x7FFF => {
+                let banks = (self.rom.len() / 0x4000).max(1);
+                let offset = 0x4000 * (self.rom_bank % banks);
+                self.rom.get(offset + (address as usize - 0x4000)).copied().unwrap_or(0xFF)
+            },
+            // Bit 0 is set while a signal is received.
+            0xA000 ..= 0xBFFF if self.ir_mode => 0xC0 | self.ir_rx as u8,
+            0xA000 ..= 0xBFFF => match self.ram_index(address) {
+                Some(idx) => self.ram.get(idx).copied().unwrap_or(0xFF),
+                None => 0xFF,
+            },
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, b: u8) {
+        match address {
+            0x0000 ..= 0x1FFF => self.ir_mode = b & 0x0F == 0x0E,
+            0x2000 ..= 0x3FFF => self.rom_bank = ((b & 0x3F) as usize).max(1),
+            0x4000 ..= 0x5FFF => self.ram_bank = (b & 0x03) as usize,
+            // Bit 0 switches the LED.
+            0xA000 ..= 0xBFFF if self.ir_mode => self.ir_tx = b & 0x01 != 0,
+            0xA000 ..= 0xBFFF => {
+                if let Some(idx) = self.ram_index(address) {
+                    if let Some(v) = self.ram.get_mut(idx) {
+                        *v = b;
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use crate::cartridge::Cartridge;
+    use super::HuC1;
+
+    fn huc1() -> HuC1 {
+        // Each bank is filled with its own number.
+        let rom = (0..64).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        HuC1::new(rom, 0x8000, None)
+    }
+
+    #[test]
+    fn rom_ram_banks() {
+        let mut mbc = huc1();
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        mbc.write_byte(0x2000, 0x3F);
+        assert_eq!(mbc.read_byte(0x4000), 0x3F);
+        mbc.write_byte(0x2000, 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+
+        mbc.write_byte(0xA000, 0x11);
+        mbc.write_byte(0x4000, 0x02);
+        mbc.write_byte(0xA000, 0x22);
+        assert_eq!(mbc.read_byte(0xA000), 0x22);
+        mbc.write_byte(0x4000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0x11);
+    }
+
+    #[test]
+    fn infrared() {
+        let mut mbc = huc1();
+        mbc.write_byte(0xA000, 0x55);
+
+        mbc.write_byte(0x0000, 0x0E);
+        assert_eq!(mbc.read_byte(0xA000), 0xC0);
+        mbc.receive_ir(true);
+        assert_eq!(mbc.read_byte(0xA000), 0xC1);
+
+        mbc.write_byte(0xA000, 0x01);
+        assert!(mbc.send_ir());
+        mbc.write_byte(0xA000, 0x00);
+        assert!(!mbc.send_ir());
+
+        // Back to RAM, which the LED writes did not touch.
+        mbc.write_byte(0x0000, 0x0A);
+        assert_eq!(mbc.read_byte(0xA000), 0x55);
+    }
+}
@@ -1,9 +1,10 @@
 use std::{path::PathBuf, io::Write, fs::File, vec};
 
 use crate::cartridge::Cartridge;
+use crate::state::{check_len, CartState, SaveStateError};
 use super::super::bus::MemoryBus;
 #[cfg(not(target_arch = "wasm32"))]
-use super::load_save;
+use super::{load_save, append_crc};
 
 /*
 In its default configuration, MBC1 supports up to 512 KiB ROM with up to 
@@ -17,7 +18,8 @@ compilation carts below.
 #[derive(Default)]
 pub struct MBC1 {
     rom: Vec<u8>, 
-    // This 5-bit register (range $01-$1F) selects the ROM bank number for the 4000-7FFF region.
+    // This 5-bit register (range $01-$1F) selects the lower bits of the ROM bank number 
+    // for the 4000-7FFF region.
     rom_bank: u8,
     
     // Enables the reading and writing of external RAM.
@@ -35,6 +37,26 @@ pub struct MBC1 {
 }
 
 impl MBC1 {
+    // Bank of 0000-3FFF, bank 0 unless mode 1 is selected which applies the upper bits.
+    fn low_bank(&self) -> usize {
+        if self.mode { (self.ram_bank as usize) << 5 } else { 0 }
+    }
+
+    fn high_bank(&self) -> usize {
+        (self.ram_bank as usize) << 5 | self.rom_bank as usize
+    }
+
+    // Banks past the end of the ROM wrap around, as the unused upper bits are not wired.
+    fn rom_index(&self, bank: usize, address: u16) -> usize {
+        (bank * 0x4000 + (address as usize & 0x3FFF)) % self.rom.len()
+    }
+
+    // RAM is only banked in mode 1.
+    fn ram_index(&self, address: u16) -> usize {
+        let bank = if self.mode { self.ram_bank as usize } else { 0 };
+        (bank * 0x2000 + (address as usize - 0xA000)) % self.ram.len()
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     pub fn new(rom: Vec<u8>, ram_size: usize, save_path: Option<PathBuf>) -> Self {
         
@@ -74,12 +96,35 @@ impl Cartridge for MBC1 {
 
     fn len(&self) -> usize { self.rom.len() }
 
+    fn rom(&self) -> &[u8] { &self.rom }
+
+    fn save_state(&self) -> CartState {
+        CartState {
+            rom_bank:   self.rom_bank as usize,
+            ram_bank:   self.ram_bank as usize,
+            ram_enable: self.ram_enable,
+            mode:       self.mode,
+            ram:        self.ram.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn load_state(&mut self, state: &CartState) -> Result<(), SaveStateError> {
+        check_len("cartridge ram", &state.ram, self.ram.len())?;
+        self.rom_bank   = state.rom_bank as u8;
+        self.ram_bank   = state.ram_bank as u8;
+        self.ram_enable = state.ram_enable;
+        self.mode       = state.mode;
+        self.ram.clone_from(&state.ram);
+        Ok(())
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     fn save(&self) {
         match &self.save_path {
             Some(path) => {
                 File::create(path).and_then(
-                    |mut f| f.write_all(&self.ram)
+                    |mut f| f.write_all(&append_crc(&self.ram))
                 ).unwrap()
             }
             None => {},
@@ -97,20 +142,12 @@ impl MemoryBus for MBC1 {
     fn read_byte(&self, address: u16) -> u8 {
         match address {
             // 0000-3FFF - ROM Bank X0 (Read Only)
-            0x0000 ..= 0x3FFF => self.rom[address as usize],
+            0x0000 ..= 0x3FFF => self.rom.get(self.rom_index(self.low_bank(), address)).copied().unwrap_or(0xFF),
             // 4000-7FFF - ROM Bank 01-7F (Read Only)
-            0x4000 ..= 0x7FFF => {
-                let offset = 0x4000 * self.rom_bank as usize;
-                self.rom[offset + (address as usize - 0x4000)]
-            }
+            0x4000 ..= 0x7FFF => self.rom.get(self.rom_index(self.high_bank(), address)).copied().unwrap_or(0xFF),
             // A000-BFFF - RAM Bank 00-03, if any (Read/Write)
-            0xA000 ..= 0xBFFF => {
-                if self.ram_enable {
-                    let offset = self.ram_bank as usize * 8_192;  
-                    self.ram[offset + address as usize - 0xA000] 
-                } else { 
-                    0 
-                }
+            0xA000 ..= 0xBFFF if self.ram_enable && !self.ram.is_empty() => {
+                self.ram.get(self.ram_index(address)).copied().unwrap_or(0xFF)
             },
             _ => 0,
         }
@@ -122,21 +159,124 @@ impl MemoryBus for MBC1 {
             // Any value with 0xa in the lower 4 bits enables ram.
             0x0000 ..= 0x1FFF => self.ram_enable = b & 0x0F == 0x0A,
             // ROM bank number (write only) - lower 5 bits.
+            // Zero is checked after masking, so banks 0x20, 0x40 and 0x60 map to the next bank.
             0x2000 ..= 0x3FFF => {
-                let n = if b == 0 { 1 } else { b };
-                self.rom_bank = self.rom_bank & 0b0110_0000 | n & 0b0001_1111;
+                let n = b & 0b0001_1111;
+                self.rom_bank = if n == 0 { 1 } else { n };
             }
             // RAM Bank Number - or - Upper Bits of ROM Bank Number (Write Only)
-            0x4000 ..= 0x5FFF => {
-                if self.mode {
-                    self.ram_bank = b & 0b11;
-                } else {
-                    self.rom_bank = self.rom_bank & 0b0001_1111 | (b & 3) << 5;
+            0x4000 ..= 0x5FFF => self.ram_bank = b & 0b11,
+            // Banking Mode Select (Write Only)
+            0x6000 ..= 0x7FFF => self.mode = b & 1 == 1,
+            0xA000 ..= 0xBFFF if self.ram_enable && !self.ram.is_empty() => {
+                let idx = self.ram_index(address);
+                if let Some(v) = self.ram.get_mut(idx) {
+                    *v = b;
                 }
             },
-            // Banking Mode Select (Write Only)
-            0x6000 ..= 0x7FFF => self.mode = b == 1,
             _ => {},
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use super::MBC1;
+
+    fn mbc1() -> MBC1 {
+        // 1 MiB, each bank is filled with its own number.
+        let rom = (0..64).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        MBC1::new(rom, 0x2000, None)
+    }
+
+    #[test]
+    fn simple_banking() {
+        let mut mbc = mbc1();
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        mbc.write_byte(0x2000, 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        mbc.write_byte(0x2000, 0x12);
+        assert_eq!(mbc.read_byte(0x4000), 0x12);
+
+        // Upper bits apply to 4000-7FFF only.
+        mbc.write_byte(0x4000, 0x01);
+        assert_eq!(mbc.read_byte(0x4000), 0x32);
+        assert_eq!(mbc.read_byte(0x0000), 0x00);
+        // Bank 0x20 can not be selected.
+        mbc.write_byte(0x2000, 0x20);
+        assert_eq!(mbc.read_byte(0x4000), 0x21);
+    }
+
+    #[test]
+    fn advanced_banking() {
+        let mut mbc = mbc1();
+        mbc.write_byte(0x6000, 0x01);
+        mbc.write_byte(0x2000, 0x03);
+        assert_eq!(mbc.read_byte(0x0000), 0x00);
+
+        // 0000-3FFF is switched to the start of each 512 KiB half.
+        mbc.write_byte(0x4000, 0x01);
+        assert_eq!(mbc.read_byte(0x0000), 0x20);
+        assert_eq!(mbc.read_byte(0x3FFF), 0x20);
+        assert_eq!(mbc.read_byte(0x4000), 0x23);
+
+        mbc.write_byte(0x6000, 0x00);
+        assert_eq!(mbc.read_byte(0x0000), 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 0x23);
+    }
+
+    #[test]
+    fn small_rom_mirroring() {
+        // 64 KiB, 4 banks.
+        let rom = (0..4).flat_map(|bank| vec![0x10 + bank as u8; 0x4000]).collect();
+        let mut mbc = MBC1::new(rom, 0, None);
+        for bank in 4..8 {
+            mbc.write_byte(0x2000, bank);
+            assert_eq!(mbc.read_byte(0x4000), 0x10 + bank % 4);
+        }
+
+        // The upper bits select banks 0x20 and up, which mirror the low banks in both modes.
+        mbc.write_byte(0x2000, 0x01);
+        mbc.write_byte(0x4000, 0x01);
+        assert_eq!(mbc.read_byte(0x4000), 0x11);
+        mbc.write_byte(0x6000, 0x01);
+        assert_eq!(mbc.read_byte(0x0000), 0x10);
+        assert_eq!(mbc.read_byte(0x7FFF), 0x11);
+    }
+
+    #[test]
+    fn ram_banking() {
+        let rom = vec![0; 0x8000];
+        let mut mbc = MBC1::new(rom, 0x8000, None);
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0xA000, 0x11);
+
+        // Bank register is ignored for RAM in mode 0.
+        mbc.write_byte(0x4000, 0x02);
+        assert_eq!(mbc.read_byte(0xA000), 0x11);
+        mbc.write_byte(0x6000, 0x01);
+        mbc.write_byte(0xA000, 0x22);
+        assert_eq!(mbc.read_byte(0xA000), 0x22);
+        mbc.write_byte(0x6000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0x11);
+    }
+
+    #[test]
+    fn state_ram_length() {
+        use crate::cartridge::Cartridge;
+        use crate::state::SaveStateError;
+
+        let mut mbc = mbc1();
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0xA000, 0x42);
+        let mut state = mbc.save_state();
+        state.ram.truncate(0x1000);
+        mbc.write_byte(0xA000, 0x00);
+        assert!(matches!(
+            mbc.load_state(&state),
+            Err(SaveStateError::InvalidLength { field: "cartridge ram", found: 0x1000, expected: 0x2000 })
+        ));
+        assert_eq!(mbc.read_byte(0xA000), 0x00);
+    }
+}
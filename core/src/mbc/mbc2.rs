@@ -1,8 +1,8 @@
 use std::{path::PathBuf, fs::File, io::Write};
 
-use crate::{bus::MemoryBus, cartridge::Cartridge};
+use crate::{bus::MemoryBus, cartridge::Cartridge, state::{check_len, CartState, SaveStateError}};
 #[cfg(not(target_arch = "wasm32"))]
-use super::load_save;
+use super::{load_save, append_crc};
 
 // (max 256 KiB ROM and 512x4 bits RAM)
 
@@ -57,12 +57,31 @@ impl Cartridge for MBC2 {
 
     fn len(&self) -> usize { self.rom.len() }
 
+    fn rom(&self) -> &[u8] { &self.rom }
+
+    fn save_state(&self) -> CartState {
+        CartState {
+            rom_bank:   self.rom_bank,
+            ram_enable: self.ram_enable,
+            ram:        self.ram.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn load_state(&mut self, state: &CartState) -> Result<(), SaveStateError> {
+        check_len("cartridge ram", &state.ram, self.ram.len())?;
+        self.rom_bank   = state.rom_bank;
+        self.ram_enable = state.ram_enable;
+        self.ram.clone_from(&state.ram);
+        Ok(())
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     fn save(&self) {
         match &self.save_path {
             Some(path) => {
                 File::create(path).and_then(
-                    |mut f| f.write_all(&self.ram)
+                    |mut f| f.write_all(&append_crc(&self.ram))
                 ).unwrap()
             }
             None => {},
@@ -80,16 +99,17 @@ impl MemoryBus for MBC2 {
     fn read_byte(&self, address: u16) -> u8 {
         match address {
             // 0000–3FFF — ROM Bank 0 [read-only]
-            0x0000 ..= 0x3FFF => self.rom[address as usize],
+            0x0000 ..= 0x3FFF => self.rom.get(address as usize).copied().unwrap_or(0xFF),
             // 4000–7FFF — ROM Bank $01-0F [read-only]
             0x4000 ..= 0x7FFF => {
                 let offset = 0x4000 * self.rom_bank;
-                self.rom[offset + (address as usize - 0x4000)]
+                self.rom.get(offset + (address as usize - 0x4000)).copied().unwrap_or(0xFF)
             },
-            // A000–A1FF — Built-in RAM
-            0xA000 ..= 0xA1FF => {
+            // A000–A1FF — Built-in RAM, echoed up to BFFF
+            // Only the lower 4 bits are stored, the upper read as 1s.
+            0xA000 ..= 0xBFFF => {
                 if self.ram_enable {
-                    self.ram[(address - 0xA000) as usize]
+                    self.ram.get(ram_index(address)).copied().unwrap_or(0xFF) | 0xF0
                 } else {
                     0
                 }
@@ -102,22 +122,100 @@ impl MemoryBus for MBC2 {
         // All MBC2 writes only use lower 4 bits.
         let b = b & 0x0F;
         match address {
-            0x0000 ..= 0x1FFF => {
-                if address & 0x0100 == 0 {
-                    self.ram_enable = b == 0x0a;
-                }
-            },
-            0x2000 ..= 0x3FFF => {
-                if address & 0x0100 != 0 {
-                    self.rom_bank = b as usize;
+            // 0000–3FFF — RAM Enable, ROM Bank Number [write-only]
+            // Bit 8 of the address selects the register, set for the ROM bank.
+            0x0000 ..= 0x3FFF => {
+                if (address >> 8) & 0x01 == 1 {
+                    self.rom_bank = if b == 0 { 1 } else { b as usize };
+                } else {
+                    self.ram_enable = b == 0x0A;
                 }
             },
-            0xA000 ..= 0xA1FF => {
+            0xA000 ..= 0xBFFF => {
                 if self.ram_enable {
-                    self.ram[(address - 0xA000) as usize] = b;
+                    if let Some(v) = self.ram.get_mut(ram_index(address)) {
+                        *v = b;
+                    }
                 }
             },
             _ => {},
         }
     }
-}
\ No newline at end of file
+}
+
+// RAM is 512 bytes, mirrored through A000-BFFF.
+fn ram_index(address: u16) -> usize {
+    (address as usize - 0xA000) & 0x1FF
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use super::MBC2;
+
+    fn mbc2() -> MBC2 {
+        // Each bank is filled with its own number.
+        let rom = (0..16).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        MBC2::new(rom, 512, None)
+    }
+
+    #[test]
+    fn rom_bank_select() {
+        let mut mbc = mbc2();
+        mbc.write_byte(0x2000, 0x05);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        mbc.write_byte(0x2100, 0x05);
+        assert_eq!(mbc.read_byte(0x4000), 5);
+        mbc.write_byte(0x2100, 0xFE);
+        assert_eq!(mbc.read_byte(0x4000), 14);
+        mbc.write_byte(0x2100, 0x00);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+    }
+
+    #[test]
+    fn ram_enable_select() {
+        let mut mbc = mbc2();
+        mbc.write_byte(0x2100, 0x0A);
+        mbc.write_byte(0xA000, 0x07);
+        assert_eq!(mbc.read_byte(0xA000), 0);
+
+        mbc.write_byte(0x2000, 0x0A);
+        mbc.write_byte(0xA000, 0x07);
+        assert_eq!(mbc.read_byte(0xA000), 0xF7);
+        assert_eq!(mbc.read_byte(0x4000), 10);
+
+        mbc.write_byte(0x0000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0);
+    }
+
+    #[test]
+    fn ram_nibbles() {
+        let mut mbc = mbc2();
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0xA000, 0xA5);
+        assert_eq!(mbc.read_byte(0xA000), 0xF5);
+
+        // The 512 half bytes are echoed through A200-BFFF.
+        assert_eq!(mbc.read_byte(0xA200), 0xF5);
+        assert_eq!(mbc.read_byte(0xBE00), 0xF5);
+        mbc.write_byte(0xB1FF, 0x03);
+        assert_eq!(mbc.read_byte(0xA1FF), 0xF3);
+    }
+
+    #[test]
+    fn register_select() {
+        let mut mbc = mbc2();
+        // Bit 8 clear selects RAM enable, whatever the rest of the address.
+        mbc.write_byte(0x3E00, 0x0A);
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        mbc.write_byte(0xA000, 0x01);
+        assert_eq!(mbc.read_byte(0xA000), 0xF1);
+
+        // Bit 8 set selects the ROM bank, including from 0000-1FFF.
+        mbc.write_byte(0x0100, 0x03);
+        assert_eq!(mbc.read_byte(0x4000), 3);
+        assert_eq!(mbc.read_byte(0xA000), 0xF1);
+        mbc.write_byte(0x3F00, 0x0A);
+        assert_eq!(mbc.read_byte(0x4000), 10);
+    }
+}
@@ -5,9 +5,9 @@ use std::{
     time::SystemTime,
 };
 
-use crate::{bus::MemoryBus, cartridge::Cartridge};
+use crate::{bus::MemoryBus, cartridge::Cartridge, state::{check_len, CartState, RtcState, SaveStateError}, bit::Bit};
 #[cfg(not(target_arch = "wasm32"))]
-use super::load_save;
+use super::{load_save, append_crc};
 
 /*
 (max 2MByte ROM and/or 32KByte RAM and Timer)
@@ -21,23 +21,23 @@ struct RealTimeClock {
     hours:      u8,
     dl:         u8,
     dh:         u8,
+    // Set by bit 6 of DH, the clock does not advance while halted.
+    halted:     bool,
     pub zero:       u64,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    path:       PathBuf,
 }
 
 impl RealTimeClock {
     fn new(rtc_path: Option<PathBuf>) -> Option<RealTimeClock> {
         match rtc_path {
             Some(path) => {
-                let zero = match std::fs::read(path) {
-                    Ok(f) => {
-                        let mut b = [0_u8; 8];
-                        b.copy_from_slice(&f);
-                        u64::from_be_bytes(b)
-                    }
-                    Err(_) => SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs(),
+                let saved = std::fs::read(&path).ok()
+                    .and_then(|f| <[u8; 8]>::try_from(f.as_slice()).ok());
+                // Started now if the file is missing or isn't the 8 bytes written by save.
+                let zero = match saved {
+                    Some(b) => u64::from_be_bytes(b),
+                    None => now(),
                 };
                 Some(Self {
                     seconds: 0,
@@ -45,7 +45,9 @@ impl RealTimeClock {
                     hours: 0,
                     dl: 0,
                     dh: 0,
-                    zero
+                    halted: false,
+                    zero,
+                    path,
                     })
             },
             None => None,
@@ -53,28 +55,58 @@ impl RealTimeClock {
     }
 
     fn step(&mut self) {
-        let duration = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() - self.zero;
+        if self.halted {
+            return;
+        }
+        let duration = now().saturating_sub(self.zero);
         
         self.seconds = (duration % 60) as u8;
         self.mintues = (duration / 60 % 60) as u8;
         self.hours   = (duration / 3600 % 24) as u8;
 
-        let days = (duration / 3600 / 24) as u16;
+        let days = duration / 3600 / 24;
         self.dl = (days % 256) as u8;
-        match days {
-            0x0 ..= 0xFF => {},
-            0x100 ..= 0x1FF => {
-                self.dh |= 1;
-            },
-            _ => {
-                self.dh |= 1;
-                self.dh |= 0x80;
-            },
+        self.dh = (self.dh & 0xFE) | ((days >> 8) & 1) as u8;
+        // Carry stays set until cleared by the game.
+        if days > 0x1FF {
+            self.dh |= 0x80;
+        }
+    }
+
+    fn save_state(&self) -> RtcState {
+        RtcState {
+            seconds:    self.seconds,
+            minutes:    self.mintues,
+            hours:      self.hours,
+            dl:         self.dl,
+            dh:         self.dh,
+            halted:     self.halted,
+            zero:       self.zero,
         }
     }
+
+    fn load_state(&mut self, state: &RtcState) {
+        self.seconds = state.seconds;
+        self.mintues = state.minutes;
+        self.hours   = state.hours;
+        self.dl      = state.dl;
+        self.dh      = state.dh;
+        self.halted  = state.halted;
+        self.zero    = state.zero;
+    }
+
+    // Seconds counted by the registers, ignoring the day carry.
+    fn duration(&self) -> u64 {
+        let days = u64::from(self.dl) | u64::from(self.dh & 1) << 8;
+        u64::from(self.seconds) + u64::from(self.mintues) * 60 + u64::from(self.hours) * 3600 + days * 86400
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
 /*
@@ -98,7 +130,8 @@ impl MemoryBus for RealTimeClock {
             0x0A => self.hours,
             0x0B => self.dl,
             0x0C => self.dh,
-            _ => panic!("invalid address rtc (read): {:#2X}", address),
+            // Unused register select values, e.g. 0x04-0x07, read open bus.
+            _ => 0xFF,
         }
     }
     
@@ -108,8 +141,19 @@ impl MemoryBus for RealTimeClock {
             0x09 => self.mintues = b,
             0x0A => self.hours = b,
             0x0B => self.dl = b,
-            0x0C => self.dh = b,
-            _ => panic!("invalid address rtc (write): {:#2X}", address),
+            0x0C => {
+                // Catch up before stopping, and carry on from the registers when restarted.
+                if b.bit(6) && !self.halted {
+                    self.step();
+                }
+                let resumed = self.halted && !b.bit(6);
+                self.dh = b;
+                self.halted = b.bit(6);
+                if resumed {
+                    self.zero = now().saturating_sub(self.duration());
+                }
+            },
+            _ => {},
         }
     }
 }
@@ -123,6 +167,8 @@ pub struct MBC3 {
     ram_enable: bool,
 
     rtc:        Option<RealTimeClock>,
+    // Last value written to 6000-7FFF, the clock is latched when 0x00 then 0x01 is written.
+    latch_state: u8,
     save_path:  Option<PathBuf>,
 }
 
@@ -137,12 +183,13 @@ impl MBC3 {
 
         Self {
             ram,
-            ram_bank: 1,
+            ram_bank: 0,
             rom,
             rom_bank: 0,
             ram_enable: false,
             save_path,
             rtc: RealTimeClock::new(rtc_path),
+            latch_state: 0xFF,
         }
     }
 
@@ -156,12 +203,13 @@ impl MBC3 {
 
         Self {
             ram,
-            ram_bank: 1,
+            ram_bank: 0,
             rom,
             rom_bank: 0,
             ram_enable: false,
             save_path: None, 
             rtc: RealTimeClock::new(rtc_path),
+            latch_state: 0xFF,
         }
     }
 }
@@ -170,6 +218,33 @@ impl Cartridge for MBC3 {
 
     fn len(&self) -> usize { self.rom.len() }
 
+    fn rom(&self) -> &[u8] { &self.rom }
+
+    fn save_state(&self) -> CartState {
+        CartState {
+            rom_bank:   self.rom_bank,
+            ram_bank:   self.ram_bank,
+            ram_enable: self.ram_enable,
+            ram:        self.ram.clone(),
+            rtc:        self.rtc.as_ref().map(RealTimeClock::save_state),
+            latch:      self.latch_state,
+            ..Default::default()
+        }
+    }
+
+    fn load_state(&mut self, state: &CartState) -> Result<(), SaveStateError> {
+        check_len("cartridge ram", &state.ram, self.ram.len())?;
+        self.rom_bank   = state.rom_bank;
+        self.ram_bank   = state.ram_bank;
+        self.ram_enable = state.ram_enable;
+        self.ram.clone_from(&state.ram);
+        if let (Some(rtc), Some(saved)) = (self.rtc.as_mut(), &state.rtc) {
+            rtc.load_state(saved);
+        }
+        self.latch_state = state.latch;
+        Ok(())
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     fn save(&self) {
         match self.save_path.clone() {
@@ -179,14 +254,12 @@ impl Cartridge for MBC3 {
                     Ok(f) => f,
                     Err(_) => return,
                 };
-                // Write real time clock.
-                if self.rtc.is_some() {
-                    file.write_all(
-                        &self.rtc.as_ref().unwrap().zero.to_be_bytes()
-                    ).unwrap();
+                // Write real time clock to its own file.
+                if let Some(rtc) = &self.rtc {
+                    std::fs::write(&rtc.path, rtc.zero.to_be_bytes()).unwrap();
                 }
                 // Write ram.
-                file.write_all(&self.ram).unwrap();
+                file.write_all(&append_crc(&self.ram)).unwrap();
             },
         }
     }
@@ -202,18 +275,18 @@ impl MemoryBus for MBC3 {
     fn read_byte(&self, address: u16) -> u8 {
         match address {
             // 0000-3FFF - ROM Bank 00 (Read Only)
-            0x0000 ..= 0x3FFF => self.rom[address as usize],
+            0x0000 ..= 0x3FFF => self.rom.get(address as usize).copied().unwrap_or(0xFF),
             // 4000-7FFF - ROM Bank 01-7F (Read Only)
             0x4000 ..= 0x7FFF => {
                 let offset = 0x4000 * self.rom_bank;
-                self.rom[offset + (address as usize - 0x4000)]
+                self.rom.get(offset + (address as usize - 0x4000)).copied().unwrap_or(0xFF)
             },
             // A000-BFFF - RAM Bank 00-03, if any (Read/Write)
             0xA000 ..= 0xBFFF => {
                 if self.ram_enable {
                     if self.ram_bank <= 3 {
                         let offset = self.ram_bank * 0x2000;
-                        self.ram[offset + (address as usize - 0xA000)]
+                        self.ram.get(offset + (address as usize - 0xA000)).copied().unwrap_or(0xFF)
                     } else {
                         match &self.rtc {
                             Some(rtc) => rtc.read_byte(self.ram_bank as u16),
@@ -246,18 +319,23 @@ impl MemoryBus for MBC3 {
             },
             // 6000-7FFF - Latch Clock Data (Write Only)
             0x6000 ..= 0x7FFF => {
-                if b & 1 != 0 && self.rtc.is_some() {
-                    self.rtc.as_mut().unwrap().step();
+                if self.latch_state == 0x00 && b == 0x01 {
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.step();
+                    }
                 }
+                self.latch_state = b;
             },
             0xA000 ..= 0xBFFF => {
                 if self.ram_enable {
                     if self.ram_bank <= 3 {
                         let offset = 0x2000 * self.ram_bank;
-                        self.ram[offset + (address as usize - 0xA000)] = b;
+                        if let Some(v) = self.ram.get_mut(offset + (address as usize - 0xA000)) {
+                            *v = b;
+                        }
                     } else {
                         match &mut self.rtc {
-                            Some(rtc) => rtc.write_byte(address, b),
+                            Some(rtc) => rtc.write_byte(self.ram_bank as u16, b),
                             None => {},
                         }
                     }
@@ -266,4 +344,89 @@ impl MemoryBus for MBC3 {
             _ => {},
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use super::MBC3;
+
+    fn mbc3() -> MBC3 {
+        let path = std::env::temp_dir().join("gameboy_mbc3_missing_test.rtc");
+        let mut mbc = MBC3::new(vec![0; 0x8000], 0x2000, None, Some(path));
+        mbc.write_byte(0x0000, 0x0A);
+        mbc
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.write_byte(0x6000, 0x00);
+        mbc.write_byte(0x6000, 0x01);
+    }
+
+    // Moves the start of the clock back by seconds.
+    fn wind(mbc: &mut MBC3, seconds: u64) {
+        mbc.rtc.as_mut().unwrap().zero -= seconds;
+    }
+
+    fn rtc_register(mbc: &mut MBC3, register: u8) -> u8 {
+        mbc.write_byte(0x4000, register);
+        mbc.read_byte(0xA000)
+    }
+
+    #[test]
+    fn ram_bank_zero_at_power_on() {
+        let mut mbc = MBC3::new(vec![0; 0x8000], 0x8000, None, None);
+        for (bank, chunk) in mbc.ram.chunks_mut(0x2000).enumerate() {
+            chunk.fill(bank as u8 + 1);
+        }
+        mbc.write_byte(0x0000, 0x0A);
+        assert_eq!(mbc.read_byte(0xA000), 1);
+        assert_eq!(mbc.read_byte(0xBFFF), 1);
+
+        mbc.write_byte(0x4000, 0x03);
+        assert_eq!(mbc.read_byte(0xA000), 4);
+    }
+
+    #[test]
+    fn rtc_latch() {
+        let mut mbc = mbc3();
+        wind(&mut mbc, 2 * 3600 + 5);
+
+        // Only a 0x00 then 0x01 write updates the registers.
+        mbc.write_byte(0x6000, 0x01);
+        assert_eq!(rtc_register(&mut mbc, 0x0A), 0);
+        latch(&mut mbc);
+        assert_eq!(rtc_register(&mut mbc, 0x0A), 2);
+
+        // Held until the next latch.
+        wind(&mut mbc, 3600);
+        mbc.write_byte(0x6000, 0x01);
+        assert_eq!(rtc_register(&mut mbc, 0x0A), 2);
+        latch(&mut mbc);
+        assert_eq!(rtc_register(&mut mbc, 0x0A), 3);
+    }
+
+    #[test]
+    fn rtc_halt() {
+        let mut mbc = mbc3();
+        wind(&mut mbc, 3600);
+
+        mbc.write_byte(0x4000, 0x0C);
+        mbc.write_byte(0xA000, 0x40);
+        assert_eq!(rtc_register(&mut mbc, 0x0A), 1);
+
+        // Stopped, and the registers can be set.
+        wind(&mut mbc, 3600);
+        latch(&mut mbc);
+        assert_eq!(rtc_register(&mut mbc, 0x0A), 1);
+        mbc.write_byte(0xA000, 5);
+        latch(&mut mbc);
+        assert_eq!(rtc_register(&mut mbc, 0x0A), 5);
+
+        // Restarted from the value written.
+        mbc.write_byte(0x4000, 0x0C);
+        mbc.write_byte(0xA000, 0x00);
+        latch(&mut mbc);
+        assert_eq!(rtc_register(&mut mbc, 0x0A), 5);
+        assert_eq!(rtc_register(&mut mbc, 0x0C) & 0x40, 0);
+    }
+}
@@ -1,8 +1,8 @@
 use std::{path::PathBuf, fs::File, io::Write};
 
-use crate::{bus::MemoryBus, cartridge::Cartridge};
+use crate::{bus::MemoryBus, cartridge::Cartridge, state::{check_len, CartState, SaveStateError}};
 #[cfg(not(target_arch = "wasm32"))]
-use super::load_save;
+use super::{load_save, append_crc};
 
 pub struct MBC5 {
     rom:        Vec<u8>,
@@ -12,12 +12,16 @@ pub struct MBC5 {
     ram_bank:   usize,
     ram_enable: bool,
 
+    // Cartridges with a rumble motor use bit 3 of the ram bank register to drive it.
+    rumble:         bool,
+    rumble_active:  bool,
+
     save_path:  Option<PathBuf>
 }
 
 impl MBC5 {
     #[cfg(not(target_arch = "wasm32"))]
-    pub fn new(rom: Vec<u8>, ram_size: usize, save_path: Option<PathBuf>) -> Self {
+    pub fn new(rom: Vec<u8>, ram_size: usize, save_path: Option<PathBuf>, rumble: bool) -> Self {
         
         let ram = match save_path {
             Some(ref path) => load_save(path, ram_size),
@@ -30,12 +34,14 @@ impl MBC5 {
             ram_enable: false,
             rom, 
             rom_bank: 1, 
+            rumble,
+            rumble_active: false,
             save_path, 
         }
     }
 
     #[cfg(target_arch = "wasm32")]
-    pub fn new(rom: Vec<u8>, ram_size: usize, save_data: Option<Vec<u8>>) -> Self {
+    pub fn new(rom: Vec<u8>, ram_size: usize, save_data: Option<Vec<u8>>, rumble: bool) -> Self {
         
         let ram = match save_data {
             Some(data) => data,
@@ -48,6 +54,8 @@ impl MBC5 {
             ram_enable: false,
             rom,
             rom_bank: 1,
+            rumble,
+            rumble_active: false,
             save_path: None, 
         }
     }
@@ -57,12 +65,35 @@ impl Cartridge for MBC5 {
 
     fn len(&self) -> usize { self.rom.len() }
 
+    fn rom(&self) -> &[u8] { &self.rom }
+
+    fn save_state(&self) -> CartState {
+        CartState {
+            rom_bank:   self.rom_bank,
+            ram_bank:   self.ram_bank,
+            ram_enable: self.ram_enable,
+            ram:        self.ram.clone(),
+            rumble:     self.rumble_active,
+            ..Default::default()
+        }
+    }
+
+    fn load_state(&mut self, state: &CartState) -> Result<(), SaveStateError> {
+        check_len("cartridge ram", &state.ram, self.ram.len())?;
+        self.rom_bank   = state.rom_bank;
+        self.ram_bank   = state.ram_bank;
+        self.ram_enable = state.ram_enable;
+        self.ram.clone_from(&state.ram);
+        self.rumble_active = self.rumble && state.rumble;
+        Ok(())
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     fn save(&self) {
         match &self.save_path {
             Some(path) => {
                 File::create(path).and_then(
-                    |mut f| f.write_all(&self.ram)
+                    |mut f| f.write_all(&append_crc(&self.ram))
                 ).unwrap()
             }
             None => {},
@@ -73,20 +104,22 @@ impl Cartridge for MBC5 {
     fn save(&self) -> *const u8 {
         self.ram.as_ptr()
     }
+
+    fn is_rumbling(&self) -> bool { self.rumble_active }
 }
 
 impl MemoryBus for MBC5 {
     fn read_byte(&self, address: u16) -> u8 {
         match address {
-            0x0000 ..= 0x3FFF => self.rom[address as usize],
+            0x0000 ..= 0x3FFF => self.rom.get(address as usize).copied().unwrap_or(0xFF),
             0x4000 ..= 0x7FFF => {
                 let offset = 0x4000 * self.rom_bank;
-                self.rom[offset + (address as usize - 0x4000)]
+                self.rom.get(offset + (address as usize - 0x4000)).copied().unwrap_or(0xFF)
             },
             0xA000 ..= 0xBFFF => {
                 if self.ram_enable {
                     let offset = 0x2000 * self.ram_bank;
-                    self.ram[offset + (address as usize - 0xA000)]
+                    self.ram.get(offset + (address as usize - 0xA000)).copied().unwrap_or(0xFF)
                 } else {
                     0
                 }
@@ -100,14 +133,48 @@ impl MemoryBus for MBC5 {
             0x0000 ..= 0x1FFF => self.ram_enable = b & 0x0F == 0xA,
             0x2000 ..= 0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | b as usize,
             0x3000 ..= 0x3FFF => self.rom_bank = (self.rom_bank & 0xFF)  | ((b as usize) << 8), 
+            0x4000 ..= 0x5FFF if self.rumble => {
+                self.rumble_active = b & 0x08 != 0;
+                self.ram_bank = (b & 0x07) as usize;
+            },
             0x4000 ..= 0x5FFF => self.ram_bank = (b & 0xF) as usize,
             0xA000 ..= 0xBFFF => {
                 if self.ram_enable {
                     let offset = 0x2000 * self.ram_bank;
-                    self.ram[offset + (address as usize - 0xA000)] = b;
+                    if let Some(v) = self.ram.get_mut(offset + (address as usize - 0xA000)) {
+                        *v = b;
+                    }
                 }
             },
             _ => {},
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{bus::MemoryBus, cartridge::Cartridge};
+    use super::MBC5;
+
+    #[test]
+    fn rumble() {
+        let mut mbc = MBC5::new(vec![0; 0x8000], 0x8000, None, true);
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x4000, 0x00);
+        mbc.write_byte(0xA000, 0x12);
+        assert!(!mbc.is_rumbling());
+
+        // Bit 3 switches the motor on without changing the ram bank.
+        mbc.write_byte(0x4000, 0x08);
+        assert!(mbc.is_rumbling());
+        assert_eq!(mbc.read_byte(0xA000), 0x12);
+        mbc.write_byte(0x4000, 0x00);
+        assert!(!mbc.is_rumbling());
+
+        // Without a motor bit 3 selects the ram bank.
+        let mut mbc = MBC5::new(vec![0; 0x8000], 0x20000, None, false);
+        mbc.write_byte(0x4000, 0x08);
+        assert!(!mbc.is_rumbling());
+        assert_eq!(mbc.ram_bank, 8);
+    }
+}
@@ -0,0 +1,225 @@
+use std::{path::PathBuf, fs::File, io::Write};
+
+use crate::{bus::MemoryBus, cartridge::Cartridge, state::{check_len, CartState, SaveStateError}};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{load_save, append_crc};
+
+// MBC6 splits the switchable ROM area into two 8 KiB windows, A at 4000-5FFF and
+// B at 6000-7FFF, each with its own bank number.
+// Flash ROM is not emulated, only the mask ROM can be mapped.
+const ROM_BANK_SIZE: usize = 0x2000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+pub struct MBC6 {
+    rom:        Vec<u8>,
+    rom_bank_a: usize,
+    rom_bank_b: usize,
+
+    ram:        Vec<u8>,
+    ram_bank:   usize,
+    ram_enable: bool,
+
+    save_path:  Option<PathBuf>
+}
+
+impl MBC6 {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(rom: Vec<u8>, ram_size: usize, save_path: Option<PathBuf>) -> Self {
+
+        let ram = match save_path {
+            Some(ref path) => load_save(path, ram_size),
+            None => vec![0; ram_size],
+        };
+
+        Self {
+            ram,
+            ram_bank: 0,
+            ram_enable: false,
+            rom,
+            // Maps the same as a 32 KiB ROM until switched.
+            rom_bank_a: 2,
+            rom_bank_b: 3,
+            save_path,
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn new(rom: Vec<u8>, ram_size: usize, save_data: Option<Vec<u8>>) -> Self {
+
+        let ram = match save_data {
+            Some(data) => data,
+            None => vec![0; ram_size],
+        };
+
+        Self {
+            ram,
+            ram_bank: 0,
+            ram_enable: false,
+            rom,
+            rom_bank_a: 2,
+            rom_bank_b: 3,
+            save_path: None,
+        }
+    }
+
+    fn read_rom(&self, bank: usize, address: u16) -> u8 {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        let offset = ROM_BANK_SIZE * (bank % banks);
+        self.rom.get(offset + (address as usize & (ROM_BANK_SIZE - 1))).copied().unwrap_or(0xFF)
+    }
+
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if !self.ram_enable || self.ram.is_empty() {
+            return None;
+        }
+        let banks = (self.ram.len() / RAM_BANK_SIZE).max(1);
+        let offset = RAM_BANK_SIZE * (self.ram_bank % banks);
+        Some((offset + (address as usize - 0xA000)) % self.ram.len())
+    }
+}
+
+impl Cartridge for MBC6 {
+
+    fn len(&self) -> usize { self.rom.len() }
+
+    fn rom(&self) -> &[u8] { &self.rom }
+
+    fn save_state(&self) -> CartState {
+        CartState {
+            rom_bank:   self.rom_bank_a,
+            rom_bank_b: self.rom_bank_b,
+            ram_bank:   self.ram_bank,
+            ram_enable: self.ram_enable,
+            ram:        self.ram.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn load_state(&mut self, state: &CartState) -> Result<(), SaveStateError> {
+        check_len("cartridge ram", &state.ram, self.ram.len())?;
+        self.rom_bank_a = state.rom_bank;
+        self.rom_bank_b = state.rom_bank_b;
+        self.ram_bank   = state.ram_bank;
+        self.ram_enable = state.ram_enable;
+        self.ram.clone_from(&state.ram);
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save(&self) {
+        if let Some(path) = &self.save_path {
+            File::create(path).and_then(
+                |mut f| f.write_all(&append_crc(&self.ram))
+            ).unwrap()
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn save(&self) -> *const u8 {
+        self.ram.as_ptr()
+    }
+}
+
+impl MemoryBus for MBC6 {
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0x0000 ..= 0x3FFF => self.rom.get(address as usize).copied().unwrap_or(0xFF),
+            0x4000 ..= 0x5FFF => self.read_rom(self.rom_bank_a, address),
+            0x6000 ..= 0x7FFF => self.read_rom(self.rom_bank_b, address),
+            0xA000 ..= 0xBFFF => match self.ram_index(address) {
+                Some(idx) => self.ram.get(idx).copied().unwrap_or(0xFF),
+                None => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, b: u8) {
+        match address {
+            0x0000 ..= 0x03FF => self.ram_enable = b & 0x0F == 0xA,
+            0x0400 ..= 0x07FF => self.ram_bank = (b & 0x07) as usize,
+            0x2000 ..= 0x27FF => self.rom_bank_a = (self.rom_bank_a & 0x40) | (b & 0x3F) as usize,
+            0x2800 ..= 0x2FFF => self.rom_bank_a = (self.rom_bank_a & 0x3F) | ((b & 1) as usize) << 6,
+            0x3000 ..= 0x37FF => self.rom_bank_b = (self.rom_bank_b & 0x40) | (b & 0x3F) as usize,
+            0x3800 ..= 0x3FFF => self.rom_bank_b = (self.rom_bank_b & 0x3F) | ((b & 1) as usize) << 6,
+            0xA000 ..= 0xBFFF => {
+                if let Some(idx) = self.ram_index(address) {
+                    if let Some(v) = self.ram.get_mut(idx) {
+                        *v = b;
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use super::MBC6;
+
+    fn mbc6() -> MBC6 {
+        // Each 8 KiB bank is filled with its own number.
+        let rom = (0..128).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        MBC6::new(rom, 0x2000, None)
+    }
+
+    #[test]
+    fn rom_bank_windows() {
+        let mut mbc = mbc6();
+        assert_eq!(mbc.read_byte(0x4000), 2);
+        assert_eq!(mbc.read_byte(0x6000), 3);
+
+        mbc.write_byte(0x2000, 0x05);
+        mbc.write_byte(0x3000, 0x2A);
+        assert_eq!(mbc.read_byte(0x4000), 0x05);
+        assert_eq!(mbc.read_byte(0x5FFF), 0x05);
+        assert_eq!(mbc.read_byte(0x6000), 0x2A);
+        assert_eq!(mbc.read_byte(0x7FFF), 0x2A);
+
+        // Bit 6 of each bank number is written separately.
+        mbc.write_byte(0x3800, 0x01);
+        assert_eq!(mbc.read_byte(0x6000), 0x6A);
+        assert_eq!(mbc.read_byte(0x4000), 0x05);
+        mbc.write_byte(0x2800, 0x01);
+        mbc.write_byte(0x2000, 0x3F);
+        assert_eq!(mbc.read_byte(0x4000), 0x7F);
+    }
+
+    #[test]
+    fn ram_enable() {
+        let mut mbc = mbc6();
+        mbc.write_byte(0xA000, 0x07);
+        assert_eq!(mbc.read_byte(0xA000), 0);
+
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0xA000, 0x07);
+        assert_eq!(mbc.read_byte(0xA000), 0x07);
+
+        mbc.write_byte(0x0000, 0x00);
+        assert_eq!(mbc.read_byte(0xA000), 0);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn ram_persists() {
+        use crate::cartridge::Cartridge;
+
+        let path = std::env::temp_dir().join("gameboy_mbc6_test.sav");
+        let _ = std::fs::remove_file(&path);
+        let rom = vec![0; 0x8000];
+
+        let mut mbc = MBC6::new(rom.clone(), 0x8000, Some(path.clone()));
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x0400, 0x01);
+        mbc.write_byte(0xA123, 0x42);
+        mbc.save();
+
+        let mut mbc = MBC6::new(rom, 0x8000, Some(path.clone()));
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x0400, 0x01);
+        assert_eq!(mbc.read_byte(0xA123), 0x42);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
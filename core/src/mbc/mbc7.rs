@@ -0,0 +1,507 @@
+use std::{path::PathBuf, fs::File, io::Write};
+
+use crate::{bus::MemoryBus, cartridge::Cartridge, state::{check_len, CartState, Mbc7State, SaveStateError}};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{load_save, append_crc};
+
+// MBC7 has a 93LC56 EEPROM and an ADXL202E accelerometer in place of cartridge RAM,
+// both accessed through registers at A000-AFFF selected by bits 4-7 of the address.
+// https://gbdev.io/pandocs/MBC7.html
+pub(crate) const EEPROM_SIZE: usize = 256;
+// Accelerometer reading when level, and the change for a tilt of 1g.
+const TILT_CENTRE: u16 = 0x81D0;
+const TILT_1G: i32 = 0x70;
+// set_tilt values for 1g.
+const TILT_RANGE: i16 = 512;
+
+pub struct MBC7 {
+    rom:        Vec<u8>,
+    rom_bank:   usize,
+
+    // Both must be set to access A000-AFFF, by writing 0x0A to 0000-1FFF and 0x40 to 4000-5FFF.
+    ram_enable:  bool,
+    ram_enable2: bool,
+
+    eeprom:     Eeprom,
+    tilt:       TiltSensor,
+
+    save_path:  Option<PathBuf>
+}
+
+impl MBC7 {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(rom: Vec<u8>, save_path: Option<PathBuf>) -> Self {
+
+        let data = match save_path {
+            Some(ref path) => load_save(path, EEPROM_SIZE),
+            None => vec![0xFF; EEPROM_SIZE],
+        };
+
+        Self {
+            rom,
+            rom_bank: 1,
+            ram_enable: false,
+            ram_enable2: false,
+            eeprom: Eeprom::new(data),
+            tilt: TiltSensor::default(),
+            save_path,
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn new(rom: Vec<u8>, save_data: Option<Vec<u8>>) -> Self {
+
+        let data = match save_data {
+            Some(data) => data,
+            None => vec![0xFF; EEPROM_SIZE],
+        };
+
+        Self {
+            rom,
+            rom_bank: 1,
+            ram_enable: false,
+            ram_enable2: false,
+            eeprom: Eeprom::new(data),
+            tilt: TiltSensor::default(),
+            save_path: None,
+        }
+    }
+
+    fn read_register(&self, address: u16) -> u8 {
+        match (address >> 4) & 0xF {
+            0x2 => self.tilt.latched_x as u8,
+            0x3 => (self.tilt.latched_x >> 8) as u8,
+            0x4 => self.tilt.latched_y as u8,
+            0x5 => (self.tilt.latched_y >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    fn mbc7_state(&self) -> Mbc7State {
+        let (eeprom_mode, write_addr) = match self.eeprom.state {
+            EepromState::Idle => (0, 0),
+            EepromState::Command => (1, 0),
+            EepromState::Read => (2, 0),
+            EepromState::Write(Some(addr)) => (3, addr),
+            EepromState::Write(None) => (4, 0),
+        };
+        Mbc7State {
+            eeprom_mode,
+            write_addr,
+            shift:          self.eeprom.shift,
+            bits:           self.eeprom.bits,
+            addr:           self.eeprom.addr,
+            write_enable:   self.eeprom.write_enable,
+            cs:             self.eeprom.cs,
+            clk:            self.eeprom.clk,
+            di:             self.eeprom.di,
+            dout:           self.eeprom.dout,
+            tilt_x:         self.tilt.x,
+            tilt_y:         self.tilt.y,
+            latched_x:      self.tilt.latched_x,
+            latched_y:      self.tilt.latched_y,
+            latched:        self.tilt.latched,
+        }
+    }
+
+    fn load_mbc7_state(&mut self, state: &Mbc7State) {
+        self.eeprom.state = match state.eeprom_mode {
+            1 => EepromState::Command,
+            2 => EepromState::Read,
+            3 => EepromState::Write(Some(state.write_addr & 0x7F)),
+            4 => EepromState::Write(None),
+            _ => EepromState::Idle,
+        };
+        self.eeprom.shift        = state.shift;
+        // A word or command completes at its last bit, so fewer than 16 are ever pending.
+        self.eeprom.bits         = state.bits.min(15);
+        self.eeprom.addr         = state.addr & 0x7F;
+        self.eeprom.write_enable = state.write_enable;
+        self.eeprom.cs           = state.cs;
+        self.eeprom.clk          = state.clk;
+        self.eeprom.di           = state.di;
+        self.eeprom.dout         = state.dout;
+        self.tilt.set_tilt(state.tilt_x, state.tilt_y);
+        self.tilt.latched_x      = state.latched_x;
+        self.tilt.latched_y      = state.latched_y;
+        self.tilt.latched        = state.latched;
+    }
+
+    fn write_register(&mut self, address: u16, b: u8) {
+        match (address >> 4) & 0xF {
+            0x0 if b == 0x55 => self.tilt.erase(),
+            0x1 if b == 0xAA => self.tilt.latch(),
+            0x8 => self.eeprom.write(b),
+            _ => {},
+        }
+    }
+}
+
+impl Cartridge for MBC7 {
+
+    fn len(&self) -> usize { self.rom.len() }
+
+    fn rom(&self) -> &[u8] { &self.rom }
+
+    fn tilt_sensor(&mut self) -> Option<&mut TiltSensor> { Some(&mut self.tilt) }
+
+    fn save_state(&self) -> CartState {
+        CartState {
+            rom_bank:   self.rom_bank,
+            ram_enable: self.ram_enable,
+            mode:       self.ram_enable2,
+            ram:        self.eeprom.data.clone(),
+            mbc7:       Some(self.mbc7_state()),
+            ..Default::default()
+        }
+    }
+
+    fn load_state(&mut self, state: &CartState) -> Result<(), SaveStateError> {
+        check_len("cartridge ram", &state.ram, self.eeprom.data.len())?;
+        self.rom_bank    = state.rom_bank;
+        self.ram_enable  = state.ram_enable;
+        self.ram_enable2 = state.mode;
+        self.eeprom.data.clone_from(&state.ram);
+        if let Some(mbc7) = &state.mbc7 {
+            self.load_mbc7_state(mbc7);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save(&self) {
+        if let Some(path) = &self.save_path {
+            File::create(path).and_then(
+                |mut f| f.write_all(&append_crc(&self.eeprom.data))
+            ).unwrap()
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn save(&self) -> *const u8 {
+        self.eeprom.data.as_ptr()
+    }
+}
+
+impl MemoryBus for MBC7 {
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0x0000 ..= 0x3FFF => self.rom.get(address as usize).copied().unwrap_or(0xFF),
+            0x4000 ..= 0x7FFF => {
+                let banks = (self.rom.len() / 0x4000).max(1);
+                let offset = 0x4000 * (self.rom_bank % banks);
+                self.rom.get(offset + (address as usize - 0x4000)).copied().unwrap_or(0xFF)
+            },
+            0xA000 ..= 0xAFFF if self.ram_enable && self.ram_enable2 => self.read_register(address),
+            0xA000 ..= 0xBFFF => 0xFF,
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, b: u8) {
+        match address {
+            0x0000 ..= 0x1FFF => self.ram_enable = b == 0x0A,
+            0x2000 ..= 0x3FFF => self.rom_bank = (b & 0x7F) as usize,
+            0x4000 ..= 0x5FFF => self.ram_enable2 = b == 0x40,
+            0xA000 ..= 0xAFFF if self.ram_enable && self.ram_enable2 => self.write_register(address, b),
+            _ => {},
+        }
+    }
+}
+
+// ADXL202E accelerometer, the reading is latched by the game before it is read.
+#[derive(Debug)]
+pub struct TiltSensor {
+    x:          i16,
+    y:          i16,
+    latched_x:  u16,
+    latched_y:  u16,
+    latched:    bool,
+}
+
+impl Default for TiltSensor {
+    fn default() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            latched_x: 0x8000,
+            latched_y: 0x8000,
+            latched: false,
+        }
+    }
+}
+
+impl TiltSensor {
+    // -512 to 512 for -1g to 1g on each axis, 0 is level.
+    // Positive x tilts the cartridge to the right and positive y towards the player.
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        self.x = x.clamp(-TILT_RANGE, TILT_RANGE);
+        self.y = y.clamp(-TILT_RANGE, TILT_RANGE);
+    }
+
+    fn erase(&mut self) {
+        self.latched_x = 0x8000;
+        self.latched_y = 0x8000;
+        self.latched = false;
+    }
+
+    // Only the first latch after an erase takes a reading.
+    fn latch(&mut self) {
+        if self.latched {
+            return;
+        }
+        let reading = |tilt: i16| (i32::from(TILT_CENTRE) + i32::from(tilt) * TILT_1G / i32::from(TILT_RANGE)) as u16;
+        self.latched_x = reading(-self.x);
+        self.latched_y = reading(self.y);
+        self.latched = true;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EepromState {
+    // Waiting for a start bit.
+    Idle,
+    // Shifting in the opcode and address.
+    Command,
+    // Shifting out the word at addr.
+    Read,
+    // Shifting in a word for addr, or every word if None.
+    Write(Option<u8>),
+}
+
+// 93LC56 in 16 bit mode, driven by bit banging the chip select, clock and data lines.
+// Commands are a start bit, a 2 bit opcode and 8 address bits, shifted in on the rising clock edge.
+struct Eeprom {
+    data:           Vec<u8>,
+    state:          EepromState,
+    shift:          u16,
+    bits:           u8,
+    addr:           u8,
+    write_enable:   bool,
+
+    cs:             bool,
+    clk:            bool,
+    di:             bool,
+    // Data out, high when ready.
+    dout:           bool,
+}
+
+impl Eeprom {
+    fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            state: EepromState::Idle,
+            shift: 0,
+            bits: 0,
+            addr: 0,
+            write_enable: false,
+            cs: false,
+            clk: false,
+            di: false,
+            dout: true,
+        }
+    }
+
+    // Bit 7 chip select, 6 clock, 1 data in and 0 data out.
+    fn read(&self) -> u8 {
+        (self.cs as u8) << 7 | (self.clk as u8) << 6 | (self.di as u8) << 1 | self.dout as u8
+    }
+
+    fn write(&mut self, b: u8) {
+        let cs  = b & 0x80 != 0;
+        let clk = b & 0x40 != 0;
+        let di  = b & 0x02 != 0;
+
+        if !cs {
+            self.state = EepromState::Idle;
+            self.dout = true;
+        } else if clk && !self.clk {
+            self.clock(di);
+        }
+        self.cs  = cs;
+        self.clk = clk;
+        self.di  = di;
+    }
+
+    fn clock(&mut self, di: bool) {
+        match self.state {
+            EepromState::Idle => if di {
+                self.state = EepromState::Command;
+                self.shift = 0;
+                self.bits = 0;
+            },
+            EepromState::Command => {
+                self.shift_in(di);
+                if self.bits == 10 {
+                    self.command();
+                }
+            },
+            EepromState::Read => {
+                self.dout = self.word(self.addr) & (0x8000 >> self.bits) != 0;
+                self.bits += 1;
+                // Reads carry on to the next word.
+                if self.bits == 16 {
+                    self.bits = 0;
+                    self.addr = (self.addr + 1) & 0x7F;
+                }
+            },
+            EepromState::Write(addr) => {
+                self.shift_in(di);
+                if self.bits == 16 {
+                    if self.write_enable {
+                        match addr {
+                            Some(addr) => self.set_word(addr, self.shift),
+                            None => (0..0x80).for_each(|addr| self.set_word(addr, self.shift)),
+                        }
+                    }
+                    self.state = EepromState::Idle;
+                    self.dout = true;
+                }
+            },
+        }
+    }
+
+    fn shift_in(&mut self, di: bool) {
+        self.shift = self.shift << 1 | di as u16;
+        self.bits += 1;
+    }
+
+    fn command(&mut self) {
+        let opcode = self.shift >> 8 & 0x3;
+        // The top address bit is unused, it selects the variant of opcode 00.
+        let addr = self.shift as u8 & 0x7F;
+        let variant = self.shift >> 6 & 0x3;
+        self.shift = 0;
+        self.bits = 0;
+        self.state = EepromState::Idle;
+
+        match (opcode, variant) {
+            // READ, a dummy 0 is output before the word.
+            (0b10, _) => {
+                self.addr = addr;
+                self.state = EepromState::Read;
+                self.dout = false;
+            },
+            // WRITE.
+            (0b01, _) => self.state = EepromState::Write(Some(addr)),
+            // ERASE.
+            (0b11, _) => if self.write_enable { self.set_word(addr, 0xFFFF) },
+            // EWDS.
+            (0b00, 0b00) => self.write_enable = false,
+            // WRAL.
+            (0b00, 0b01) => self.state = EepromState::Write(None),
+            // ERAL.
+            (0b00, 0b10) => if self.write_enable { self.data.fill(0xFF) },
+            // EWEN.
+            _ => self.write_enable = true,
+        }
+    }
+
+    fn word(&self, addr: u8) -> u16 {
+        let idx = addr as usize * 2;
+        u16::from_le_bytes([self.data[idx], self.data[idx + 1]])
+    }
+
+    fn set_word(&mut self, addr: u8, word: u16) {
+        let idx = addr as usize * 2;
+        self.data[idx..idx + 2].copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use crate::cartridge::Cartridge;
+    use super::MBC7;
+
+    fn mbc7() -> MBC7 {
+        // Each bank is filled with its own number.
+        let rom = (0..8).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        let mut mbc = MBC7::new(rom, None);
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x4000, 0x40);
+        mbc
+    }
+
+    // Clocks bits into the EEPROM with chip select held high, returning data out after each.
+    fn send(mbc: &mut MBC7, bits: &[u8]) -> Vec<u8> {
+        bits.iter().map(|&bit| {
+            mbc.write_byte(0xA080, 0x80 | bit << 1);
+            mbc.write_byte(0xA080, 0xC0 | bit << 1);
+            mbc.read_byte(0xA080) & 1
+        }).collect()
+    }
+
+    fn bits(value: u16, len: usize) -> Vec<u8> {
+        (0..len).rev().map(|i| (value >> i & 1) as u8).collect()
+    }
+
+    fn command(mbc: &mut MBC7, opcode: u16, addr: u16, data: Option<u16>) -> Vec<u8> {
+        mbc.write_byte(0xA080, 0x00);
+        let mut cmd = bits(0b100 | opcode, 3);
+        cmd.extend(bits(addr, 8));
+        if let Some(data) = data {
+            cmd.extend(bits(data, 16));
+        }
+        send(mbc, &cmd)
+    }
+
+    #[test]
+    fn rom_bank_select() {
+        let mut mbc = mbc7();
+        assert_eq!(mbc.read_byte(0x4000), 1);
+        mbc.write_byte(0x2000, 0x05);
+        assert_eq!(mbc.read_byte(0x4000), 5);
+        assert_eq!(mbc.read_byte(0x0000), 0);
+    }
+
+    #[test]
+    fn eeprom_write_read() {
+        let mut mbc = mbc7();
+        // Writes are ignored until enabled with EWEN.
+        command(&mut mbc, 0b01, 0x12, Some(0xBEEF));
+        command(&mut mbc, 0b10, 0x12, None);
+        assert_eq!(send(&mut mbc, &[0; 16]), bits(0xFFFF, 16));
+
+        command(&mut mbc, 0b00, 0xC0, None);
+        command(&mut mbc, 0b01, 0x12, Some(0xBEEF));
+        // Dummy 0 after the address, then the word.
+        let out = command(&mut mbc, 0b10, 0x12, None);
+        assert_eq!(out.last(), Some(&0));
+        assert_eq!(send(&mut mbc, &[0; 16]), bits(0xBEEF, 16));
+
+        // ERASE.
+        command(&mut mbc, 0b11, 0x12, None);
+        command(&mut mbc, 0b10, 0x12, None);
+        assert_eq!(send(&mut mbc, &[0; 16]), bits(0xFFFF, 16));
+    }
+
+    #[test]
+    fn tilt_latch() {
+        let mut mbc = mbc7();
+        mbc.tilt_sensor().unwrap().set_tilt(512, -256);
+        mbc.write_byte(0xA000, 0x55);
+        assert_eq!(mbc.read_byte(0xA020), 0x00);
+        assert_eq!(mbc.read_byte(0xA030), 0x80);
+
+        mbc.write_byte(0xA010, 0xAA);
+        let x = u16::from_le_bytes([mbc.read_byte(0xA020), mbc.read_byte(0xA030)]);
+        let y = u16::from_le_bytes([mbc.read_byte(0xA040), mbc.read_byte(0xA050)]);
+        assert_eq!(x, 0x81D0 - 0x70);
+        assert_eq!(y, 0x81D0 - 0x38);
+
+        // Held until erased again.
+        mbc.tilt_sensor().unwrap().set_tilt(0, 0);
+        mbc.write_byte(0xA010, 0xAA);
+        assert_eq!(mbc.read_byte(0xA020), (0x81D0_u16 - 0x70) as u8);
+        mbc.write_byte(0xA000, 0x55);
+        mbc.write_byte(0xA010, 0xAA);
+        assert_eq!(mbc.read_byte(0xA020), 0xD0);
+
+        // Registers are hidden unless both enables are set.
+        mbc.write_byte(0x4000, 0x00);
+        assert_eq!(mbc.read_byte(0xA020), 0xFF);
+    }
+}
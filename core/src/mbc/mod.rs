@@ -1,17 +1,58 @@
 use std::{path::PathBuf, io::{ErrorKind, Read}, fs::File};
 
+use crate::cartridge::CartError;
+
 pub mod mbc1;
 pub mod mbc2;
 pub mod mbc3;
 pub mod mbc5;
+pub mod mbc6;
+pub mod mbc7;
+pub mod huc1;
+pub mod rom_ram;
+
+// Battery saves are the cartridge RAM followed by a 4 byte little endian CRC32 of the RAM.
+pub fn append_crc(ram: &[u8]) -> Vec<u8> {
+    let mut data = ram.to_vec();
+    data.extend_from_slice(&crc32fast::hash(ram).to_le_bytes());
+    data
+}
+
+// Checks the CRC32 footer of a save and returns the RAM without it. Saves written before the
+// footer was added are the RAM alone, a save of exactly ram_size bytes is loaded as it is.
+pub fn strip_crc(data: &[u8], ram_size: usize) -> Result<Vec<u8>, CartError> {
+    if data.len() == ram_size {
+        return Ok(data.to_vec());
+    }
+    if data.len() < 4 {
+        return Err(CartError::CorruptSave);
+    }
+    let (ram, footer) = data.split_at(data.len() - 4);
+    if crc32fast::hash(ram).to_le_bytes() != footer {
+        return Err(CartError::CorruptSave);
+    }
+    Ok(ram.to_vec())
+}
+
+// Reads the save file, if it exists, and checks its integrity.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn verify_save(save_path: &PathBuf, ram_size: usize) -> Result<(), CartError> {
+    match std::fs::read(save_path) {
+        Ok(data) => strip_crc(&data, ram_size).map(|_| ()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
 
+// Corrupt saves fall back to zeroed RAM, open_cartridge rejects them beforehand
+// unless told to recover.
 #[cfg(not(target_arch = "wasm32"))]
 fn load_save(save_path: &PathBuf, ram_size: usize) -> Vec<u8> {
     match File::open(save_path) {
         Ok(mut file) => {
-            let mut ram = vec![];
-            file.read_to_end(&mut ram).unwrap();
-            ram
+            let mut data = vec![];
+            file.read_to_end(&mut data).unwrap();
+            strip_crc(&data, ram_size).unwrap_or_else(|_| vec![0; ram_size])
         },
         Err(ref e) if e.kind() == ErrorKind::NotFound => {
             vec![0; ram_size]
@@ -20,3 +61,57 @@ fn load_save(save_path: &PathBuf, ram_size: usize) -> Vec<u8> {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use crate::cartridge::CartError;
+    use super::{append_crc, strip_crc};
+
+    #[test]
+    fn crc_round_trip() {
+        let ram: Vec<u8> = (0..=255).collect();
+        let data = append_crc(&ram);
+        assert_eq!(data.len(), ram.len() + 4);
+        assert_eq!(strip_crc(&data, ram.len()).unwrap(), ram);
+    }
+
+    #[test]
+    fn legacy_save() {
+        // Saves without a footer are accepted if they are the size of the RAM.
+        let ram = vec![0x56; 0x2000];
+        assert_eq!(strip_crc(&ram, 0x2000).unwrap(), ram);
+        assert!(matches!(strip_crc(&ram[..0x1000], 0x2000), Err(CartError::CorruptSave)));
+        // Even if the last 4 bytes happen to look like a footer.
+        let mut ram = append_crc(&[0x56; 0x1FFC]);
+        *ram.last_mut().unwrap() ^= 0xFF;
+        assert_eq!(strip_crc(&ram, 0x2000).unwrap(), ram);
+    }
+
+    #[test]
+    fn corrupt_save() {
+        let mut data = append_crc(&[0x12; 512]);
+        *data.last_mut().unwrap() ^= 0xFF;
+        assert!(matches!(strip_crc(&data, 512), Err(CartError::CorruptSave)));
+        assert!(matches!(strip_crc(&[0x00; 3], 512), Err(CartError::CorruptSave)));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn corrupt_save_file() {
+        let path = std::env::temp_dir().join("gameboy_corrupt_save_test.sav");
+        let mut data = append_crc(&[0x34; 2048]);
+        std::fs::write(&path, &data).unwrap();
+        assert!(super::verify_save(&path, 2048).is_ok());
+        assert_eq!(super::load_save(&path, 2048), vec![0x34; 2048]);
+
+        *data.last_mut().unwrap() ^= 0x01;
+        std::fs::write(&path, &data).unwrap();
+        assert!(matches!(super::verify_save(&path, 2048), Err(CartError::CorruptSave)));
+        assert_eq!(super::load_save(&path, 2048), vec![0; 2048]);
+
+        // A save from before the footer was added.
+        std::fs::write(&path, [0x78; 2048]).unwrap();
+        assert!(super::verify_save(&path, 2048).is_ok());
+        assert_eq!(super::load_save(&path, 2048), vec![0x78; 2048]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
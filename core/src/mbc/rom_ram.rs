@@ -0,0 +1,118 @@
+use std::{path::PathBuf, fs::File, io::Write};
+
+use crate::{bus::MemoryBus, cartridge::Cartridge, state::{check_len, CartState, SaveStateError}};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{load_save, append_crc};
+
+// No MBC, 32 KiB of ROM and 8 KiB of RAM that is always enabled.
+pub(crate) const RAM_SIZE: usize = 0x2000;
+
+pub struct RomRam {
+    rom:        Vec<u8>,
+    ram:        Vec<u8>,
+
+    save_path:  Option<PathBuf>
+}
+
+impl RomRam {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(rom: Vec<u8>, save_path: Option<PathBuf>) -> Self {
+
+        let ram = match save_path {
+            Some(ref path) => load_save(path, RAM_SIZE),
+            None => vec![0; RAM_SIZE],
+        };
+
+        Self { rom, ram, save_path }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn new(rom: Vec<u8>, save_data: Option<Vec<u8>>) -> Self {
+
+        let ram = match save_data {
+            Some(data) => data,
+            None => vec![0; RAM_SIZE],
+        };
+
+        Self { rom, ram, save_path: None }
+    }
+}
+
+impl Cartridge for RomRam {
+
+    fn len(&self) -> usize { self.rom.len() }
+
+    fn rom(&self) -> &[u8] { &self.rom }
+
+    fn save_state(&self) -> CartState {
+        CartState {
+            ram: self.ram.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn load_state(&mut self, state: &CartState) -> Result<(), SaveStateError> {
+        check_len("cartridge ram", &state.ram, self.ram.len())?;
+        self.ram.clone_from(&state.ram);
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save(&self) {
+        if let Some(path) = &self.save_path {
+            File::create(path).and_then(
+                |mut f| f.write_all(&append_crc(&self.ram))
+            ).unwrap()
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn save(&self) -> *const u8 {
+        self.ram.as_ptr()
+    }
+}
+
+impl MemoryBus for RomRam {
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0x0000 ..= 0x7FFF => self.rom.get(address as usize).copied().unwrap_or(0xFF),
+            0xA000 ..= 0xBFFF => (address as usize - 0xA000).checked_rem(self.ram.len())
+                .map_or(0xFF, |idx| self.ram[idx]),
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, b: u8) {
+        if let 0xA000 ..= 0xBFFF = address {
+            if let Some(idx) = (address as usize - 0xA000).checked_rem(self.ram.len()) {
+                self.ram[idx] = b;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use crate::cartridge::Cartridge;
+    use super::RomRam;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn ram_persists() {
+        let path = std::env::temp_dir().join("gameboy_rom_ram_test.sav");
+        let _ = std::fs::remove_file(&path);
+
+        let mut cart = RomRam::new(vec![0; 0x8000], Some(path.clone()));
+        cart.write_byte(0xA100, 0x42);
+        assert_eq!(cart.read_byte(0xA100), 0x42);
+        // ROM is read only.
+        cart.write_byte(0x0100, 0x42);
+        assert_eq!(cart.read_byte(0x0100), 0x00);
+        cart.save();
+
+        let cart = RomRam::new(vec![0; 0x8000], Some(path.clone()));
+        assert_eq!(cart.read_byte(0xA100), 0x42);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,7 +3,7 @@ use std::rc::Rc;
 
 use crate::SCREEN_WIDTH;
 use super::cartridge::Cartridge;
-use super::serial::SerialCallback;
+use super::serial::{SerialCallback, LinkCable};
 use super::cartridge;
 use super::bus::MemoryBus;
 use super::timer::Timer;
@@ -11,16 +11,32 @@ use super::gpu::GPU;
 use super::keypad::KeyPad;
 use super::intf::Intf;
 use super::serial::Serial;
+use super::hdma::HDMA;
+use super::ir::IrPort;
+use super::sgb::SgbController;
+use super::cheat::{CheatEngine, CheatError};
+use super::state::{check_len, SaveState, SaveStateError, MemoryState, SAVE_STATE_VERSION};
+use super::mbc::append_crc;
 #[cfg(feature = "audio")]
 use super::apu::APU;
 
 const HRAM_SIZE: usize = 127;        // High RAM.
-const WRAM_SIZE:  usize = 8_192;    // 8KB Work RAM.
+const WRAM_SIZE:  usize = 32_768;   // 8 banks of 4KB Work RAM, 2 used on DMG.
+const WRAM_BANK_SIZE: usize = 4_096;
+// OAM DMA takes 160 M-cycles.
+const DMA_CYCLES: u32 = 640;
 
 pub struct Memory {
     
     cartridge:      Box<dyn cartridge::Cartridge>,    
     wram:           [u8; WRAM_SIZE],
+    // 0xFF70 - SVBK | Bank mapped to D000-DFFF, 1-7 on CGB and always 1 on DMG.
+    wram_bank:      usize,
+    cgb:            bool,
+    // 0xFF4D - KEY1 | CGB only, the CPU and timer run at twice the speed of everything else
+    // after STOP is executed with the switch armed.
+    double_speed:   bool,
+    speed_switch_armed: bool,
     hram:           [u8; HRAM_SIZE],
     timer:          Timer,
     
@@ -30,6 +46,13 @@ pub struct Memory {
     #[cfg(feature = "audio")]
     pub apu:        Option<APU>,
     serial:         Serial,
+    hdma:           HDMA,
+    ir:             IrPort,
+    // Cycles left of an OAM DMA transfer, during which only IO and HRAM are accessible.
+    dma_cycles:     u32,
+    cheats:         CheatEngine,
+    // Receives Super Game Boy packets sent through FF00, when running SGB roms as an SGB.
+    sgb:            Option<SgbController>,
     
     // inte is written to buy game.
     inte:           u8,
@@ -47,10 +70,19 @@ impl Memory {
             apu:        None,
             gpu:        GPU::new(intf.clone()),
             wram:       [0; WRAM_SIZE],
+            wram_bank:  1,
+            cgb:        false,
+            double_speed:       false,
+            speed_switch_armed: false,
             hram:       [0; HRAM_SIZE],
             timer:      Timer::new(intf.clone()),
             keypad:     KeyPad::new(intf.clone()),
             serial:     Serial::new(intf.clone(), callback),
+            hdma:       HDMA::new(),
+            ir:         IrPort::new(),
+            dma_cycles: 0,
+            cheats:     CheatEngine::new(),
+            sgb:        None,
             inte:       0,
             intf,
         };
@@ -59,13 +91,14 @@ impl Memory {
     }
 }
 
-impl MemoryBus for Memory {
+impl Memory {
 
-    fn read_byte(&self, address: u16) -> u8 {
+    // Read ignoring the OAM DMA bus lock, for the DMA transfers themselves.
+    fn read_unlocked(&self, address: u16) -> u8 {
         match address {
             // 0000-3FFF   16KB ROM Bank 00     (in cartridge, fixed at bank 00)
             // 4000-7FFF   16KB ROM Bank 01..NN (in cartridge, switchable bank number)
-            0x0000 ..= 0x7FFF => self.cartridge.read_byte(address),
+            0x0000 ..= 0x7FFF => self.cheats.patch_read(address, self.cartridge.read_byte(address)),
             
             // 8000-9FFF   8KB Video RAM (VRAM) (switchable bank 0-1 in CGB Mode)
             0x8000 ..= 0x9FFF => self.gpu.read_byte(address),
@@ -75,12 +108,14 @@ impl MemoryBus for Memory {
 
             // C000-CFFF   4KB Work RAM Bank 0 (WRAM)
             // D000-DFFF   4KB Work RAM Bank 1 (WRAM)  (switchable bank 1-7 in CGB Mode)
-            0xC000 ..= 0xDFFF => self.wram[address as usize - 0xC000],
             // E000-FDFF   Same as C000-DDFF (ECHO)    (typically not used)
-            0xE000 ..= 0xEFFF => self.wram[address as usize - 0xE000],
+            0xC000 ..= 0xFDFF => self.wram[self.wram_index(address)],
 
             // FE00-FE9F   Sprite Attribute Table (OAM)
             0xFE00 ..= 0xFE9F => self.gpu.read_byte(address),
+            // FEA0-FEFF   Not Usable, reads 0xFF while the PPU is drawing and 0x00 otherwise.
+            0xFEA0 ..= 0xFEFF if self.gpu.is_drawing() => 0xFF,
+            0xFEA0 ..= 0xFEFF => 0x00,
 
             // I/O Ports 
             0xFF00 => self.keypad.read_byte(address),                     // Joypad input
@@ -93,6 +128,12 @@ impl MemoryBus for Memory {
                 None => 0,
             },
             0xFF40 ..= 0xFF4B => self.gpu.read_byte(address),
+            0xFF4D if self.cgb => 0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8,
+            0xFF4F => self.gpu.read_byte(address),                        // CGB VRAM bank
+            0xFF51 ..= 0xFF55 => self.hdma.read_byte(address),
+            0xFF56 => self.ir.read_byte(address),                         // Infrared port
+            0xFF68 ..= 0xFF6B => self.gpu.read_byte(address),             // CGB palettes
+            0xFF70 if self.cgb => 0xF8 | self.wram_bank as u8,            // CGB WRAM bank
 
             // FF80-FFFE   High RAM (HRAM)
             0xFF80 ..= 0xFFFE => self.hram[address as usize - 0xFF80],
@@ -102,16 +143,33 @@ impl MemoryBus for Memory {
             _ => 0,
         }
     }
+}
+
+impl MemoryBus for Memory {
+
+    fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            // The bus is in use by OAM DMA, reads see open bus.
+            0x0000 ..= 0xFE9F if self.dma_cycles > 0 => 0xFF,
+            _ => self.read_unlocked(address),
+        }
+    }
 
     fn write_byte(&mut self, address: u16, b: u8) {
         match address {
+            0x0000 ..= 0xFE9F if self.dma_cycles > 0 => {},
             0x0000 ..= 0x7FFF => self.cartridge.write_byte(address, b),
             0x8000 ..= 0x9FFF => self.gpu.write_byte(address, b),
             0xA000 ..= 0xBFFF => self.cartridge.write_byte(address, b),
-            0xC000 ..= 0xDFFF => self.wram[address as usize - 0xC000] = b,
-            0xE000 ..= 0xEFFF => self.wram[address as usize - 0xE000] = b,
+            0xC000 ..= 0xFDFF => self.wram[self.wram_index(address)] = b,
             0xFE00 ..= 0xFE9F => self.gpu.write_byte(address, b),
-            0xFF00 => self.keypad.write_byte(address, b),
+            0xFEA0 ..= 0xFEFF => {},
+            0xFF00 => {
+                self.keypad.write_byte(address, b);
+                if let Some(colours) = self.sgb.as_mut().and_then(|sgb| sgb.write(b)) {
+                    self.gpu.set_colours(colours);
+                }
+            },
             0xFF01 ..= 0xFF02 => self.serial.write_byte(address, b),
             0xFF04 ..= 0xFF07 => self.timer.write_byte(address, b),
             0xFF0F => self.intf.borrow_mut().write_byte(address, b),
@@ -123,6 +181,20 @@ impl MemoryBus for Memory {
             0xFF40 ..= 0xFF45 => self.gpu.write_byte(address, b),
             0xFF46 => self.dma_transfer(b),
             0xFF47 ..= 0xFF4B => self.gpu.write_byte(address, b),
+            0xFF4D if self.cgb => self.speed_switch_armed = b & 0x01 != 0,
+            0xFF4F => self.gpu.write_byte(address, b),
+            0xFF51 ..= 0xFF54 => self.hdma.write_byte(address, b),
+            0xFF55 => {
+                self.hdma.write_byte(address, b);
+                // General purpose DMA copies everything at once, H-Blank DMA a block each H-Blank.
+                while self.hdma.active && !self.hdma.hblank {
+                    self.hdma_block();
+                }
+            },
+            0xFF56 => self.ir.write_byte(address, b),
+            0xFF68 ..= 0xFF6B => self.gpu.write_byte(address, b),
+            // Bank 0 selects bank 1.
+            0xFF70 if self.cgb => self.wram_bank = ((b & 0x07) as usize).max(1),
             0xFF80 ..= 0xFFFE => self.hram[address as usize - 0xFF80] = b,
             0xFFFF => self.inte = b,
             _ => {},
@@ -132,13 +204,221 @@ impl MemoryBus for Memory {
 
 impl Memory {
 
+    pub(crate) fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.gpu.set_cgb(cgb);
+        #[cfg(feature = "audio")]
+        if let Some(apu) = self.apu.as_mut() {
+            apu.set_dmg_mode(!cgb);
+        }
+    }
+
+    pub(crate) fn set_sgb(&mut self, sgb: bool) {
+        self.sgb = sgb.then(|| SgbController::new(self.gpu.colours()));
+    }
+
+    // Present when running an SGB rom as an SGB.
+    pub fn sgb(&self) -> Option<&SgbController> { self.sgb.as_ref() }
+
+    // Index into wram of an address in C000-FDFF, echo RAM from E000 mirrors C000.
+    fn wram_index(&self, address: u16) -> usize {
+        let offset = (address as usize - 0xC000) % 0x2000;
+        match offset {
+            0x0000 ..= 0x0FFF => offset,
+            _ => self.wram_bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE,
+        }
+    }
+
     pub fn update(&mut self, cycles: u32) {
+        self.dma_cycles = self.dma_cycles.saturating_sub(cycles);
         self.timer.update(cycles);
-        self.gpu.update(cycles);
+        self.serial.update();
+        self.ir.update(cycles);
+        // cycles are of the CPU clock, the GPU and APU don't speed up in double speed mode.
+        let dots = if self.double_speed { cycles / 2 } else { cycles };
+        let frame = self.gpu.frame_count();
+        self.gpu.update(dots);
+        if self.gpu.frame_count() != frame {
+            self.apply_cheats();
+        }
+        if self.hdma.active && self.hdma.hblank && self.gpu.hblank_started() {
+            self.hdma_block();
+        }
         #[cfg(feature = "audio")]
-        let _ = self.apu.as_mut().map_or((), |apu| apu.next(cycles));
+        if let Some(apu) = self.apu.as_mut() {
+            // The frame sequencer follows DIV bit 5 instead of 4, so it keeps to 512Hz.
+            let div = self.timer.read_byte(0xFF04);
+            apu.next(dots, if self.double_speed { div >> 1 } else { div });
+        }
     } 
 
+    pub fn is_double_speed(&self) -> bool { self.double_speed }
+
+    // Called by STOP, switches speed if KEY1 was armed and returns whether it did.
+    pub(crate) fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), CheatError> {
+        self.cheats.add(code)
+    }
+
+    // Writes the GameShark codes, once per frame as the real device does at V-Blank.
+    fn apply_cheats(&mut self) {
+        let writes: Vec<(u8, u16, u8)> = self.cheats.writes().collect();
+        for (bank, address, b) in writes {
+            match (bank, address) {
+                (0x90 ..= 0x97, 0xD000 ..= 0xDFFF) if self.cgb => {
+                    let bank = ((bank & 0x07) as usize).max(1);
+                    self.wram[bank * WRAM_BANK_SIZE + address as usize - 0xD000] = b;
+                },
+                _ => self.write_byte(address, b),
+            }
+        }
+    }
+
+    // Adds the APU for sound at sample_rate, unless there already is one. Sound registers
+    // written before this were ignored, so it should be called before running the rom.
+    #[cfg(feature = "audio")]
+    pub fn enable_audio(&mut self, sample_rate: u32) {
+        if self.apu.is_none() {
+            let mut apu = APU::power_up(sample_rate);
+            apu.set_dmg_mode(!self.cgb);
+            self.apu = Some(apu);
+        }
+    }
+
+    // Number of audio samples dropped since last call, always 0 without audio.
+    pub fn dropped_audio_samples(&mut self) -> u32 {
+        #[cfg(feature = "audio")]
+        if let Some(apu) = self.apu.as_mut() {
+            return apu.take_dropped_samples();
+        }
+        0
+    }
+
+    // Leaves channel 0-3 out of the audio mix, does nothing without audio.
+    pub fn mute_channel(&mut self, ch: u8, muted: bool) {
+        #[cfg(feature = "audio")]
+        if let Some(apu) = self.apu.as_mut() {
+            apu.mute_channel(ch, muted);
+        }
+        #[cfg(not(feature = "audio"))]
+        let _ = (ch, muted);
+    }
+
+    // Signal received by the infrared port, read back through bit 1 of FF56,
+    // and by the cartridge if it has its own port.
+    pub fn set_ir_input(&mut self, signal: bool) {
+        self.ir.set_input(signal);
+        self.cartridge.receive_ir(signal);
+    }
+
+    // Has the infrared port receive its own LED, for testing IR games without a second device.
+    pub fn set_ir_loopback(&mut self, enabled: bool) {
+        self.ir.set_loopback(enabled);
+    }
+
+    pub fn set_link(&mut self, link: Box<dyn LinkCable>) {
+        self.serial.set_link(link);
+    }
+
+    // Tilt for cartridges with an accelerometer, ignored by others.
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        if let Some(sensor) = self.cartridge.tilt_sensor() {
+            sensor.set_tilt(x, y);
+        }
+    }
+
+    // Whether the game has the infrared LED, of the CGB or the cartridge, switched on.
+    pub fn ir_led(&self) -> bool {
+        self.ir.led_on() || self.cartridge.send_ir()
+    }
+
+    pub fn is_rumbling(&self) -> bool {
+        self.cartridge.is_rumbling()
+    }
+
+    // Everything but the cpu registers, which are filled in by the cpu.
+    pub fn to_save_state(&self) -> SaveState {
+        SaveState {
+            version:    SAVE_STATE_VERSION,
+            memory:     MemoryState {
+                wram:           self.wram.to_vec(),
+                wram_bank:      self.wram_bank as u8,
+                double_speed:   self.double_speed,
+                speed_switch_armed: self.speed_switch_armed,
+                hram:           self.hram.to_vec(),
+                inte:           self.inte,
+                intf:           self.intf.borrow().read_byte(0xFF0F),
+                keypad_select:  self.keypad.select(),
+                serial_data:    self.serial.read_byte(0xFF01),
+                serial_control: self.serial.read_byte(0xFF02),
+                hdma_src:       self.hdma.src,
+                hdma_dst:       self.hdma.dst,
+                hdma_len:       self.hdma.len,
+                hdma_hblank:    self.hdma.hblank,
+                hdma_active:    self.hdma.active,
+                ir:             self.ir.read_byte(0xFF56),
+                cart:           self.cartridge.save_state(),
+            },
+            gpu:        self.gpu.save_state(),
+            #[cfg(feature = "audio")]
+            apu:        self.apu.as_ref().map(|apu| apu.save_state()),
+            timer:      self.timer.save_state(),
+            cart_type:  self.cart_type(),
+            cart_title: self.cart_title(),
+            ..Default::default()
+        }
+    }
+
+    pub fn cart_type(&self) -> String { self.cartridge.cartridge_type() }
+
+    pub fn cart_title(&self) -> String { self.cartridge.title().trim_end_matches('\0').to_string() }
+
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), SaveStateError> {
+        // Lengths are checked as each part is loaded, put back the parts already loaded.
+        let backup = self.to_save_state();
+        self.restore_state(state).inspect_err(|_| {
+            self.restore_state(&backup).expect("state taken from this emulator");
+        })
+    }
+
+    fn restore_state(&mut self, state: &SaveState) -> Result<(), SaveStateError> {
+        let mem = &state.memory;
+        check_len("wram", &mem.wram, WRAM_SIZE)?;
+        check_len("hram", &mem.hram, HRAM_SIZE)?;
+        self.wram.copy_from_slice(&mem.wram);
+        self.wram_bank = (mem.wram_bank & 0x07).max(1) as usize;
+        self.double_speed = mem.double_speed;
+        self.speed_switch_armed = mem.speed_switch_armed;
+        self.hram.copy_from_slice(&mem.hram);
+        self.inte = mem.inte;
+        self.intf.borrow_mut().write_byte(0xFF0F, mem.intf);
+        self.keypad.write_byte(0xFF00, mem.keypad_select);
+        // Written directly as a write to FF02 would start a transfer.
+        self.serial.load_state(mem.serial_data, mem.serial_control);
+        self.hdma.src    = mem.hdma_src;
+        self.hdma.dst    = mem.hdma_dst;
+        self.hdma.len    = mem.hdma_len;
+        self.hdma.hblank = mem.hdma_hblank;
+        self.hdma.active = mem.hdma_active;
+        self.ir.write_byte(0xFF56, mem.ir);
+        self.cartridge.load_state(&mem.cart)?;
+        self.gpu.load_state(&state.gpu)?;
+        self.timer.load_state(&state.timer);
+        #[cfg(feature = "audio")]
+        if let (Some(apu), Some(apu_state)) = (self.apu.as_mut(), state.apu.as_ref()) {
+            apu.load_state(apu_state)?;
+        }
+        Ok(())
+    }
+
     // Set inital values, rest should be randomised but we can also set to 0.
     fn initialise(&mut self) {
         // http://www.codeslinger.co.uk/pages/projects/gameboy/hardware.html
@@ -164,6 +444,7 @@ impl Memory {
         self.write_byte(0xFF25, 0xF3);
         self.write_byte(0xFF26, 0xF1);
         self.write_byte(0xFF40, 0x91);
+        self.gpu.skip_warm_up();
         self.write_byte(0xFF42, 0x00);
         self.write_byte(0xFF43, 0x00);
         self.write_byte(0xFF45, 0x00);
@@ -176,10 +457,30 @@ impl Memory {
     }
 
     // Direct memory transfer (DMA) from ROM/RAm to OAM.
+    // Sources E000-FFFF mirror work RAM at C000-DFFF, VRAM reads 0xFF while the PPU is drawing.
     fn dma_transfer(&mut self, src_address: u8) {
+        let src_address = if src_address >= 0xE0 { src_address & 0xDF } else { src_address };
         let base_address: u16 = (src_address as u16) << 8;
+        let vram_locked = self.gpu.is_drawing();
         for x in 0..SCREEN_WIDTH as u16 {
-            self.gpu.write_byte(0xFE00 + x, self.read_byte(base_address + x));
+            let address = base_address + x;
+            let b = match address {
+                0x8000 ..= 0x9FFF if vram_locked => 0xFF,
+                _ => self.read_unlocked(address),
+            };
+            self.gpu.write_byte(0xFE00 + x, b);
+        }
+        // The copy is done at once, the bus is then locked for as long as it would take.
+        self.dma_cycles = DMA_CYCLES;
+    }
+
+    // Copies the next 16 bytes of a HDMA transfer into VRAM.
+    fn hdma_block(&mut self) {
+        let (src, dst) = self.hdma.next_block();
+        for x in 0..16 {
+            let address = src.wrapping_add(x);
+            let b = if HDMA::valid_source(address) { self.read_unlocked(address) } else { 0xFF };
+            self.gpu.write_byte(dst + x, b);
         }
     }
 
@@ -189,4 +490,310 @@ impl Memory {
     
     #[cfg(target_arch = "wasm32")]
     pub fn save(&self) -> *const u8 { self.cartridge.save() }
+
+    // Cartridge RAM with the CRC32 footer, in the form open_cartridge takes it back.
+    pub fn save_data(&self) -> Vec<u8> {
+        append_crc(&self.cartridge.save_state().ram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::MemoryBus;
+    use crate::cartridge::ROM;
+    use super::Memory;
+
+    fn memory() -> Memory {
+        Memory::new(Box::new(ROM::new(vec![0; 0x8000])), None)
+    }
+
+    #[test]
+    fn echo_ram() {
+        let mut mem = memory();
+        mem.write_byte(0xE100, 0x12);
+        assert_eq!(mem.read_byte(0xC100), 0x12);
+        mem.write_byte(0xC200, 0x34);
+        assert_eq!(mem.read_byte(0xE200), 0x34);
+        mem.write_byte(0xE1FF, 0x56);
+        assert_eq!(mem.wram[0x01FF], 0x56);
+        assert_eq!(mem.read_byte(0xC1FF), 0x56);
+        // The upper part mirrors D000-DDFF.
+        mem.write_byte(0xFDFF, 0x78);
+        assert_eq!(mem.read_byte(0xDDFF), 0x78);
+    }
+
+    #[test]
+    fn cheats() {
+        let mut mem = memory();
+        mem.add_cheat("3E1-0BF").unwrap();
+        mem.add_cheat("010238CD").unwrap();
+        assert!(mem.add_cheat("XYZ").is_err());
+        assert_eq!(mem.read_byte(0x010B), 0x3E);
+        assert_eq!(mem.read_byte(0x010C), 0x00);
+
+        // GameShark codes are written each frame.
+        assert_eq!(mem.read_byte(0xCD38), 0x00);
+        while mem.gpu.frame_count() == 0 {
+            mem.update(4);
+        }
+        assert_eq!(mem.read_byte(0xCD38), 0x02);
+    }
+
+    #[test]
+    fn double_speed() {
+        let mut mem = memory();
+        assert_eq!(mem.read_byte(0xFF4D), 0x00);
+        mem.write_byte(0xFF4D, 0x01);
+        assert!(!mem.switch_speed());
+
+        mem.set_cgb(true);
+        assert_eq!(mem.read_byte(0xFF4D), 0x7E);
+        mem.write_byte(0xFF4D, 0x01);
+        assert_eq!(mem.read_byte(0xFF4D), 0x7F);
+        assert!(mem.switch_speed());
+        assert_eq!(mem.read_byte(0xFF4D), 0xFE);
+        assert!(mem.is_double_speed());
+
+        // A line takes twice as many CPU cycles.
+        let ly = mem.read_byte(0xFF44);
+        for _ in 0..456 * 2 / 4 {
+            mem.update(4);
+        }
+        assert_eq!(mem.read_byte(0xFF44), ly + 1);
+    }
+
+    #[test]
+    fn wram_banks() {
+        let mut mem = memory();
+        mem.set_cgb(true);
+        mem.write_byte(0xC000, 0xC0);
+        for bank in 1..8 {
+            mem.write_byte(0xFF70, bank);
+            assert_eq!(mem.read_byte(0xFF70), 0xF8 | bank);
+            mem.write_byte(0xD000, 0x10 + bank);
+        }
+        for bank in 1..8 {
+            mem.write_byte(0xFF70, bank);
+            assert_eq!(mem.read_byte(0xD000), 0x10 + bank);
+            // Echo RAM follows the selected bank, bank 0 is always at C000.
+            assert_eq!(mem.read_byte(0xF000), 0x10 + bank);
+            assert_eq!(mem.read_byte(0xC000), 0xC0);
+            assert_eq!(mem.read_byte(0xE000), 0xC0);
+        }
+
+        // Bank 0 selects bank 1.
+        mem.write_byte(0xFF70, 0x00);
+        assert_eq!(mem.read_byte(0xFF70), 0xF9);
+        assert_eq!(mem.read_byte(0xD000), 0x11);
+    }
+
+    #[test]
+    fn wram_bank_dmg() {
+        let mut mem = memory();
+        mem.write_byte(0xD000, 0x11);
+        mem.write_byte(0xFF70, 0x02);
+        assert_eq!(mem.read_byte(0xFF70), 0x00);
+        assert_eq!(mem.read_byte(0xD000), 0x11);
+    }
+
+    #[test]
+    fn unusable_region() {
+        let mut mem = memory();
+        mem.write_byte(0xFEA0, 0x12);
+        // Step through a line and into VBlank, checking the value seen in each mode.
+        let mut seen = [None; 4];
+        for _ in 0..(145 * 456 / 4) {
+            mem.update(4);
+            let mode = (mem.read_byte(0xFF41) & 0x03) as usize;
+            for address in [0xFEA0, 0xFEFF] {
+                let b = mem.read_byte(address);
+                assert_eq!(*seen[mode].get_or_insert(b), b);
+            }
+        }
+        assert_eq!(seen, [Some(0x00), Some(0x00), Some(0x00), Some(0xFF)]);
+    }
+
+    #[test]
+    fn general_purpose_dma() {
+        let mut mem = memory();
+        for x in 0..0x20 {
+            mem.write_byte(0xC000 + x, x as u8 + 1);
+        }
+        mem.write_byte(0xFF51, 0xC0);
+        mem.write_byte(0xFF52, 0x00);
+        mem.write_byte(0xFF53, 0x00);
+        mem.write_byte(0xFF54, 0x10);
+        mem.write_byte(0xFF55, 0x00);
+
+        for x in 0..0x10 {
+            assert_eq!(mem.read_byte(0x8010 + x), x as u8 + 1);
+        }
+        // Only one block was copied.
+        assert_eq!(mem.read_byte(0x8020), 0x00);
+        assert_eq!(mem.read_byte(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn dma_invalid_source() {
+        let mut mem = memory();
+        for x in 0..0x10 {
+            mem.write_byte(0x8000 + x, 0x12);
+            mem.write_byte(0xC000 + x, 0x34);
+        }
+        // VRAM and echo RAM sources copy 0xFF.
+        for (src, expected) in [(0x80, 0xFF), (0xE0, 0xFF), (0xC0, 0x34)] {
+            mem.write_byte(0xFF51, src);
+            mem.write_byte(0xFF52, 0x00);
+            mem.write_byte(0xFF53, 0x01);
+            mem.write_byte(0xFF54, 0x00);
+            mem.write_byte(0xFF55, 0x00);
+            for x in 0..0x10 {
+                assert_eq!(mem.read_byte(0x8100 + x), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn hblank_dma() {
+        let mut mem = memory();
+        for x in 0..0x30 {
+            mem.write_byte(0xC000 + x, x as u8 + 1);
+        }
+        mem.write_byte(0xFF51, 0xC0);
+        mem.write_byte(0xFF52, 0x00);
+        mem.write_byte(0xFF53, 0x00);
+        mem.write_byte(0xFF54, 0x00);
+        mem.write_byte(0xFF55, 0x82);
+        assert_eq!(mem.read_byte(0xFF55), 0x02);
+        assert_eq!(mem.read_byte(0x8000), 0x00);
+
+        // Lines on which each block was copied.
+        let mut lines = Vec::new();
+        let mut copied = 0;
+        for _ in 0..456 {
+            mem.update(4);
+            let now = (0..0x30).filter(|x| mem.read_byte(0x8000 + x) != 0).count();
+            if now != copied {
+                lines.push(mem.read_byte(0xFF44));
+                copied = now;
+            }
+        }
+        assert_eq!(lines, vec![0, 1, 2]);
+        assert_eq!(copied, 0x30);
+        assert_eq!(mem.read_byte(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn dma_echo_source() {
+        let mut mem = memory();
+        for x in 0..0xA0 {
+            mem.write_byte(0xC000 + x, x as u8);
+        }
+        mem.write_byte(0xFF46, 0xE0);
+        mem.update(640);
+        for x in 0..0xA0 {
+            assert_eq!(mem.read_byte(0xFE00 + x), x as u8);
+        }
+    }
+
+    #[test]
+    fn dma_vram_during_mode_3() {
+        let mut mem = memory();
+        for x in 0..0xA0 {
+            mem.write_byte(0x8000 + x, 0x12);
+        }
+
+        // Mode 2 (OAM scan), VRAM is readable.
+        mem.write_byte(0xFF40, 0x91);
+        assert_eq!(mem.read_byte(0xFF41) & 0x03, 2);
+        mem.write_byte(0xFF46, 0x80);
+        mem.update(640);
+        assert_eq!(mem.read_byte(0xFE00), 0x12);
+
+        // Mode 3 (drawing).
+        while mem.read_byte(0xFF41) & 0x03 != 3 {
+            mem.update(4);
+        }
+        assert_eq!(mem.read_byte(0xFF41) & 0x03, 3);
+        mem.write_byte(0xFF46, 0x80);
+        mem.update(640);
+        for x in 0..0xA0 {
+            assert_eq!(mem.read_byte(0xFE00 + x), 0xFF);
+        }
+    }
+
+    #[test]
+    fn dma_bus_lock() {
+        let mut mem = memory();
+        mem.write_byte(0xC000, 0x42);
+        mem.write_byte(0xFF46, 0xC0);
+
+        assert_eq!(mem.read_byte(0xC000), 0xFF);
+        assert_eq!(mem.read_byte(0xFE00), 0xFF);
+        mem.write_byte(0xC001, 0x99);
+        // HRAM and IO are still accessible.
+        mem.write_byte(0xFF80, 0x12);
+        assert_eq!(mem.read_byte(0xFF80), 0x12);
+        mem.write_byte(0xFF42, 0x34);
+        assert_eq!(mem.read_byte(0xFF42), 0x34);
+
+        mem.update(636);
+        assert_eq!(mem.read_byte(0xC000), 0xFF);
+        mem.update(4);
+        assert_eq!(mem.read_byte(0xC000), 0x42);
+        assert_eq!(mem.read_byte(0xC001), 0x00);
+        assert_eq!(mem.read_byte(0xFE00), 0x42);
+    }
+
+    #[test]
+    fn dma_restart() {
+        let mut mem = memory();
+        for x in 0..0xA0 {
+            mem.write_byte(0xC000 + x, x as u8 + 1);
+        }
+        mem.write_byte(0xFF46, 0xC0);
+        mem.update(100);
+        // Restarted while running, the copy isn't locked out by the first transfer.
+        mem.write_byte(0xFF46, 0xC0);
+        // A general purpose DMA during OAM DMA copies the source too.
+        mem.write_byte(0xFF51, 0xC0);
+        mem.write_byte(0xFF52, 0x00);
+        mem.write_byte(0xFF53, 0x00);
+        mem.write_byte(0xFF54, 0x00);
+        mem.write_byte(0xFF55, 0x00);
+        mem.update(640);
+        for x in 0..0xA0 {
+            assert_eq!(mem.read_byte(0xFE00 + x), x as u8 + 1);
+        }
+        for x in 0..0x10 {
+            assert_eq!(mem.read_byte(0x8000 + x), x as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn save_data() {
+        use crate::mbc::{mbc1::MBC1, strip_crc};
+
+        let mut mem = Memory::new(Box::new(MBC1::new(vec![0; 0x8000], 0x2000, None)), None);
+        mem.write_byte(0x0000, 0x0A);
+        mem.write_byte(0xA001, 0x12);
+        let ram = strip_crc(&mem.save_data(), 0x2000).unwrap();
+        assert_eq!(ram.len(), 0x2000);
+        assert_eq!(ram[1], 0x12);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn enable_audio() {
+        let mut mem = memory();
+        mem.enable_audio(48_000);
+        mem.write_byte(0xFF26, 0x80);
+        mem.write_byte(0xFF24, 0x77);
+        // Already enabled, the APU is kept.
+        mem.enable_audio(44_100);
+        assert_eq!(mem.read_byte(0xFF24), 0x77);
+
+        mem.update(70_224);
+        assert!(!mem.apu.as_ref().unwrap().buffer.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,287 @@
+use crate::serial::LinkCable;
+
+// Game Boy Printer, connected in place of a link cable.
+// The Game Boy sends packets of 0x88 0x33, command, compression, length (LE u16), data,
+// checksum (LE u16) and two 0x00 bytes, to which the printer answers 0x81 then its status.
+// https://gbdev.io/pandocs/Gameboy_Printer.html
+
+pub const PRINTER_WIDTH: usize = 160;
+
+const INITIALIZE: u8 = 0x01;
+const PRINT: u8      = 0x02;
+const DATA: u8       = 0x04;
+const INQUIRY: u8    = 0x0F;
+
+// Status bits.
+const CHECKSUM_ERROR: u8   = 0x01;
+const IMAGE_DATA_FULL: u8  = 0x04;
+const UNPROCESSED_DATA: u8 = 0x08;
+
+// Tile data of 9 data packets, 160x144.
+const BUFFER_SIZE: usize = 0x2000 + 0x0680;
+
+// Called with a printed image, one shade from 0 (white) to 3 (black) per pixel, and its height.
+pub type PrintCallback = Box<dyn FnMut(&[u8], usize)>;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    state:       State,
+    command:     u8,
+    compressed:  bool,
+    length:      usize,
+    data:        Vec<u8>,
+    checksum:    u16,
+    sum:         u16,
+
+    // Decompressed tile data, 20 tiles per row.
+    buffer:      Vec<u8>,
+    status:      u8,
+    on_print:    PrintCallback,
+}
+
+impl Printer {
+    pub fn new(on_print: PrintCallback) -> Self {
+        Self {
+            state:      State::Magic1,
+            command:    0,
+            compressed: false,
+            length:     0,
+            data:       Vec::new(),
+            checksum:   0,
+            sum:        0,
+            buffer:     Vec::new(),
+            status:     0,
+            on_print,
+        }
+    }
+
+    // Handles a byte of a packet and returns the byte sent back.
+    fn receive_byte(&mut self, b: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            State::Magic1 if b == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if b == 0x33 => {
+                self.sum = 0;
+                State::Command
+            },
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = b;
+                self.sum = b as u16;
+                State::Compression
+            },
+            State::Compression => {
+                self.compressed = b & 0x01 != 0;
+                self.sum = self.sum.wrapping_add(b as u16);
+                State::LengthLow
+            },
+            State::LengthLow => {
+                self.length = b as usize;
+                self.sum = self.sum.wrapping_add(b as u16);
+                State::LengthHigh
+            },
+            State::LengthHigh => {
+                self.length |= (b as usize) << 8;
+                self.sum = self.sum.wrapping_add(b as u16);
+                self.data.clear();
+                if self.length == 0 { State::ChecksumLow } else { State::Data }
+            },
+            State::Data => {
+                self.data.push(b);
+                self.sum = self.sum.wrapping_add(b as u16);
+                if self.data.len() == self.length { State::ChecksumLow } else { State::Data }
+            },
+            State::ChecksumLow => {
+                self.checksum = b as u16;
+                State::ChecksumHigh
+            },
+            State::ChecksumHigh => {
+                self.checksum |= (b as u16) << 8;
+                State::Alive
+            },
+            State::Alive => {
+                response = 0x81;
+                State::Status
+            },
+            State::Status => {
+                self.run_command();
+                response = self.status;
+                State::Magic1
+            },
+        };
+        response
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.sum {
+            self.status |= CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !CHECKSUM_ERROR;
+
+        match self.command {
+            INITIALIZE => {
+                self.buffer.clear();
+                self.status = 0;
+            },
+            DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(space)]);
+                if !self.buffer.is_empty() {
+                    self.status |= UNPROCESSED_DATA;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= IMAGE_DATA_FULL;
+                }
+            },
+            PRINT => {
+                // Sheets, margins, palette and exposure.
+                let palette = self.data.get(2).copied().unwrap_or(0);
+                let (image, height) = self.image(palette);
+                if height > 0 {
+                    (self.on_print)(&image, height);
+                }
+                self.buffer.clear();
+                self.status &= !(UNPROCESSED_DATA | IMAGE_DATA_FULL);
+            },
+            INQUIRY => {},
+            _ => {},
+        }
+    }
+
+    // Converts the tile data to shades, in complete rows of tiles.
+    fn image(&self, palette: u8) -> (Vec<u8>, usize) {
+        // Some games send 0, meaning the usual palette.
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        let rows = self.buffer.len() / (20 * 16);
+        let height = rows * 8;
+        let mut image = vec![0; PRINTER_WIDTH * height];
+
+        for (i, tile) in self.buffer.chunks_exact(16).take(rows * 20).enumerate() {
+            let (tile_x, tile_y) = (i % 20, i / 20);
+            for y in 0..8 {
+                let (lo, hi) = (tile[y * 2], tile[y * 2 + 1]);
+                for x in 0..8 {
+                    let bit = 7 - x;
+                    let colour = ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1);
+                    let shade = (palette >> (colour * 2)) & 0x03;
+                    image[(tile_y * 8 + y) * PRINTER_WIDTH + tile_x * 8 + x] = shade;
+                }
+            }
+        }
+        (image, height)
+    }
+}
+
+// Run length encoding, a byte with bit 7 set repeats the next byte (n & 0x7F) + 2 times,
+// otherwise the next n + 1 bytes are copied.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let n = data[i];
+        i += 1;
+        if n & 0x80 != 0 {
+            if let Some(&b) = data.get(i) {
+                out.resize(out.len() + (n & 0x7F) as usize + 2, b);
+            }
+            i += 1;
+        } else {
+            let end = (i + n as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+impl LinkCable for Printer {
+
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.receive_byte(out)
+    }
+
+    // The printer never provides the clock.
+    fn receive(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use crate::serial::LinkCable;
+    use super::{Printer, PRINTER_WIDTH, decompress};
+
+    // Sends a packet, returning the last two responses.
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let len = data.len() as u16;
+        let mut packet = vec![0x88, 0x33, command, compressed as u8, len as u8, (len >> 8) as u8];
+        packet.extend_from_slice(data);
+        let sum = packet[2..].iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+        packet.extend_from_slice(&[sum as u8, (sum >> 8) as u8, 0x00, 0x00]);
+
+        let responses: Vec<u8> = packet.iter().map(|b| printer.transfer(*b)).collect();
+        assert!(responses[..responses.len() - 2].iter().all(|b| *b == 0));
+        (responses[responses.len() - 2], responses[responses.len() - 1])
+    }
+
+    #[test]
+    fn print_image() {
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let p = printed.clone();
+        let mut printer = Printer::new(Box::new(move |image: &[u8], height| {
+            p.borrow_mut().push((image.to_vec(), height));
+        }));
+
+        assert_eq!(send(&mut printer, 0x01, false, &[]), (0x81, 0x00));
+        // Two rows of tiles, each pixel colour 3.
+        assert_eq!(send(&mut printer, 0x04, false, &[0xFF; 0x280]), (0x81, 0x08));
+        // Same again compressed, four runs of 129 and one of 124.
+        let mut rle = vec![0xFF; 8];
+        rle.extend_from_slice(&[0xFA, 0xFF]);
+        assert_eq!(send(&mut printer, 0x04, true, &rle), (0x81, 0x08));
+        assert_eq!(send(&mut printer, 0x0F, false, &[]), (0x81, 0x08));
+        // Palette maps colour 3 to shade 2.
+        assert_eq!(send(&mut printer, 0x02, false, &[0x01, 0x13, 0xA4, 0x40]), (0x81, 0x00));
+
+        let printed = printed.borrow();
+        assert_eq!(printed.len(), 1);
+        let (image, height) = &printed[0];
+        assert_eq!(*height, 32);
+        assert_eq!(image.len(), PRINTER_WIDTH * 32);
+        assert!(image.iter().all(|shade| *shade == 2));
+
+        // Bad checksum.
+        let mut packet = vec![0x88, 0x33, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let last = packet.split_off(8);
+        for b in packet {
+            printer.transfer(b);
+        }
+        assert_eq!(printer.transfer(last[0]), 0x81);
+        assert_eq!(printer.transfer(last[1]) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn rle() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34]), vec![0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+    }
+}
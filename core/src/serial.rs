@@ -9,6 +9,17 @@ use super::intf::{Intf, InterruptSource};
 // This is because test roms often send results to the serial memory address.
 pub type SerialCallback = Option<Box<dyn Fn(u8)>>;
 
+// Link cable to another Game Boy, provided by the frontend.
+pub trait LinkCable {
+    // Transfer started with the internal clock, sends out and returns the byte received,
+    // 0xFF if the other side does not answer.
+    fn transfer(&mut self, out: u8) -> u8;
+
+    // While waiting on the external clock, takes a byte sent by the other side, if one
+    // has arrived, and answers it with out.
+    fn receive(&mut self, out: u8) -> Option<u8>;
+}
+
 pub struct Serial {
     // Before a transfer, it holds the next byte that will go out.
     data: u8,
@@ -20,13 +31,39 @@ pub struct Serial {
     control: u8,
 
     callback: SerialCallback, 
+    link: Option<Box<dyn LinkCable>>,
     
     intf: Rc<RefCell<Intf>>
 }
 
 impl Serial {
     pub fn new(intf: Rc<RefCell<Intf>>, callback: SerialCallback) -> Self { 
-        Self { intf, data: 0, control: 0, callback } 
+        Self { intf, data: 0, control: 0, callback, link: None } 
+    }
+
+    pub fn set_link(&mut self, link: Box<dyn LinkCable>) {
+        self.link = Some(link);
+    }
+
+    // Completes a transfer waiting on the clock of the other Game Boy.
+    pub fn update(&mut self) {
+        if self.control & 0x81 != 0x80 {
+            return;
+        }
+        if let Some(b) = self.link.as_mut().and_then(|link| link.receive(self.data)) {
+            self.complete(b);
+        }
+    }
+
+    fn complete(&mut self, received: u8) {
+        self.data = received;
+        self.control &= 0x7F;
+        self.intf.borrow_mut().set_interrupt(InterruptSource::Serial);
+    }
+
+    pub fn load_state(&mut self, data: u8, control: u8) {
+        self.data    = data;
+        self.control = control;
     }
 }
 
@@ -45,6 +82,13 @@ impl MemoryBus for Serial {
             0xFF01 => self.data = b,
             0xFF02 => {
                 self.control = b;
+                if b & 0x81 == 0x81 {
+                    if let Some(link) = self.link.as_mut() {
+                        let received = link.transfer(self.data);
+                        self.complete(received);
+                        return;
+                    }
+                }
                 if b == 0x81 {
                     match &self.callback {
                         Some(callback) => {
@@ -61,3 +105,53 @@ impl MemoryBus for Serial {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use crate::bus::MemoryBus;
+    use crate::intf::Intf;
+    use super::{LinkCable, Serial};
+
+    // Other side always sends 0x42, and records what it was sent.
+    struct Loopback(Rc<RefCell<Vec<u8>>>);
+
+    impl LinkCable for Loopback {
+        fn transfer(&mut self, out: u8) -> u8 {
+            self.0.borrow_mut().push(out);
+            0x42
+        }
+
+        fn receive(&mut self, out: u8) -> Option<u8> {
+            self.0.borrow_mut().push(out);
+            Some(0x42)
+        }
+    }
+
+    #[test]
+    fn link_transfer() {
+        let intf = Rc::new(RefCell::new(Intf::new()));
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let mut serial = Serial::new(intf.clone(), None);
+        serial.set_link(Box::new(Loopback(sent.clone())));
+
+        // Internal clock.
+        serial.write_byte(0xFF01, 0x12);
+        serial.write_byte(0xFF02, 0x81);
+        assert_eq!(serial.read_byte(0xFF01), 0x42);
+        assert_eq!(serial.read_byte(0xFF02), 0x01);
+        assert_eq!(intf.borrow().read_byte(0xFF0F) & 0x08, 0x08);
+
+        // External clock only completes when the other side sends.
+        serial.write_byte(0xFF01, 0x34);
+        serial.update();
+        assert_eq!(serial.read_byte(0xFF01), 0x34);
+        serial.write_byte(0xFF02, 0x80);
+        serial.update();
+        assert_eq!(serial.read_byte(0xFF01), 0x42);
+        assert_eq!(serial.read_byte(0xFF02), 0x00);
+        assert_eq!(*sent.borrow(), vec![0x12, 0x34]);
+    }
+}
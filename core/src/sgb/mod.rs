@@ -0,0 +1,234 @@
+use crate::gpu::rgb888;
+
+// Super Game Boy command packets, sent by the game over P14 and P15 of the joypad register.
+/*
+A transfer starts with a reset pulse, P14 and P15 both low. Each of the 128 bits of a packet is
+then sent LSB first by pulling P14 low for a 0 or P15 low for a 1, with both high in between,
+and a final 0 bit ends the packet. The first byte is the command code * 8 plus the number of
+packets the command takes, 1-7.
+
+    Code  Name     Parameters
+    00    PAL01    Colour 0 of every palette, colours 1-3 of palette 0 then of palette 1.
+    01    PAL23    The same for palettes 2 and 3.
+    0C    ATRC_EN  Bit 0 of byte 1, 0=attraction mode (the demo screen) enabled, 1=disabled.
+
+Colours are RGB555, little endian. Without attribute commands the whole screen uses palette 0.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SgbState {
+    // Waiting for a reset pulse.
+    Idle,
+    // Waiting for the next bit, both lines high.
+    Ready,
+    // A bit was sent, waiting for both lines to go high again.
+    Bit,
+    // All 128 bits sent, waiting for the 0 stop bit.
+    Stop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SgbCommand {
+    // PAL01 and PAL23, the first of the two palettes and their colours.
+    Palettes { first: usize, colours: [[u32; 4]; 2] },
+    AttractionEnable(bool),
+}
+
+impl SgbCommand {
+    // Commands that aren't supported are ignored.
+    fn parse(packet: &[u8; 16]) -> Option<Self> {
+        let colour = |i: usize| {
+            let rgb555 = u16::from_le_bytes([packet[i], packet[i + 1]]) as u32;
+            rgb888(rgb555 & 0x1F, (rgb555 >> 5) & 0x1F, (rgb555 >> 10) & 0x1F)
+        };
+        match packet[0] >> 3 {
+            code @ (0x00 | 0x01) => Some(SgbCommand::Palettes {
+                first: code as usize * 2,
+                colours: [
+                    [colour(1), colour(3), colour(5), colour(7)],
+                    [colour(1), colour(9), colour(11), colour(13)],
+                ],
+            }),
+            0x0C => Some(SgbCommand::AttractionEnable(packet[1] & 0x01 == 0)),
+            _ => None,
+        }
+    }
+}
+
+pub struct SgbController {
+    packet_buf:     [u8; 16],
+    bit_pos:        u8,
+    state:          SgbState,
+    // Packets still to come of a multi packet command, these aren't parsed.
+    packets_left:   u8,
+    palettes:       [[u32; 4]; 4],
+    attraction:     bool,
+}
+
+impl SgbController {
+
+    pub fn new(colours: [u32; 4]) -> Self {
+        Self {
+            packet_buf:     [0; 16],
+            bit_pos:        0,
+            state:          SgbState::Idle,
+            packets_left:   0,
+            palettes:       [colours; 4],
+            attraction:     true,
+        }
+    }
+
+    pub fn attraction_enabled(&self) -> bool { self.attraction }
+
+    // Called with every write to FF00, returns the new screen colours when a packet changes them.
+    pub fn write(&mut self, b: u8) -> Option<[u32; 4]> {
+        let packet = self.receive(b & 0x30)?;
+        if self.packets_left > 0 {
+            self.packets_left -= 1;
+            return None;
+        }
+        self.packets_left = (packet[0] & 0x07).saturating_sub(1);
+
+        match SgbCommand::parse(&packet)? {
+            SgbCommand::Palettes { first, colours } => {
+                self.palettes[first] = colours[0];
+                self.palettes[first + 1] = colours[1];
+                // Colour 0 is shared.
+                for palette in self.palettes.iter_mut() {
+                    palette[0] = colours[0][0];
+                }
+                Some(self.palettes[0])
+            },
+            SgbCommand::AttractionEnable(enabled) => {
+                self.attraction = enabled;
+                None
+            },
+        }
+    }
+
+    // P14 and P15 as written, returns the packet once the stop bit is sent.
+    fn receive(&mut self, lines: u8) -> Option<[u8; 16]> {
+        // A reset pulse restarts the transfer at any point.
+        if lines == 0x00 {
+            self.state = SgbState::Ready;
+            self.packet_buf = [0; 16];
+            self.bit_pos = 0;
+            return None;
+        }
+        match (self.state, lines) {
+            (SgbState::Ready, 0x10 | 0x20) => {
+                if lines == 0x10 {
+                    self.packet_buf[self.bit_pos as usize / 8] |= 1 << (self.bit_pos % 8);
+                }
+                self.bit_pos += 1;
+                self.state = SgbState::Bit;
+            },
+            (SgbState::Bit, 0x30) => {
+                self.state = if self.bit_pos == 128 { SgbState::Stop } else { SgbState::Ready };
+            },
+            (SgbState::Stop, 0x20) => {
+                self.state = SgbState::Idle;
+                return Some(self.packet_buf);
+            },
+            // A 1 stop bit, the packet is dropped.
+            (SgbState::Stop, 0x10) => self.state = SgbState::Idle,
+            _ => {},
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SgbCommand, SgbController};
+
+    const CLASSIC: [u32; 4] = [0xe0f8d0, 0x88c070, 0x346856, 0x081820];
+
+    // FF00 writes to send a packet, including the reset pulse and stop bit.
+    fn packet_writes(packet: &[u8; 16]) -> Vec<u8> {
+        let mut writes = vec![0x00, 0x30];
+        for i in 0..128 {
+            let bit = packet[i / 8] >> (i % 8) & 0x01;
+            writes.push(if bit == 1 { 0x10 } else { 0x20 });
+            writes.push(0x30);
+        }
+        writes.extend([0x20, 0x30]);
+        writes
+    }
+
+    fn send(sgb: &mut SgbController, packet: &[u8; 16]) -> Option<[u32; 4]> {
+        packet_writes(packet).into_iter().fold(None, |colours, b| sgb.write(b).or(colours))
+    }
+
+    // Red, green, blue and white, RGB555.
+    const PAL01: [u8; 16] = [0x01, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0xFF, 0x7F, 0, 0, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn parse() {
+        assert_eq!(SgbCommand::parse(&PAL01), Some(SgbCommand::Palettes {
+            first: 0,
+            colours: [[0xFF0000, 0x00FF00, 0x0000FF, 0xFFFFFF], [0xFF0000, 0x000000, 0x000000, 0x000000]],
+        }));
+        let mut pal23 = PAL01;
+        pal23[0] = 0x01 << 3 | 1;
+        assert!(matches!(SgbCommand::parse(&pal23), Some(SgbCommand::Palettes { first: 2, .. })));
+
+        let mut atrc_en = [0; 16];
+        atrc_en[0] = 0x0C << 3 | 1;
+        assert_eq!(SgbCommand::parse(&atrc_en), Some(SgbCommand::AttractionEnable(true)));
+        atrc_en[1] = 0x01;
+        assert_eq!(SgbCommand::parse(&atrc_en), Some(SgbCommand::AttractionEnable(false)));
+
+        // MLT_REQ isn't supported.
+        let mut mlt_req = [0; 16];
+        mlt_req[0] = 0x11 << 3 | 1;
+        assert_eq!(SgbCommand::parse(&mlt_req), None);
+    }
+
+    #[test]
+    fn packets() {
+        let mut sgb = SgbController::new(CLASSIC);
+        assert_eq!(send(&mut sgb, &PAL01), Some([0xFF0000, 0x00FF00, 0x0000FF, 0xFFFFFF]));
+
+        // PAL23 only changes colour 0 of palette 0.
+        let mut pal23 = [0; 16];
+        pal23[0] = 0x01 << 3 | 1;
+        assert_eq!(send(&mut sgb, &pal23), Some([0x000000, 0x00FF00, 0x0000FF, 0xFFFFFF]));
+        assert_eq!(sgb.palettes[3], [0; 4]);
+
+        let mut atrc_en = [0; 16];
+        atrc_en[0] = 0x0C << 3 | 1;
+        atrc_en[1] = 0x01;
+        assert_eq!(send(&mut sgb, &atrc_en), None);
+        assert!(!sgb.attraction_enabled());
+    }
+
+    #[test]
+    fn interrupted_packets() {
+        let mut sgb = SgbController::new(CLASSIC);
+
+        // Reset half way through, then the whole packet.
+        let writes = packet_writes(&PAL01);
+        for b in &writes[..100] {
+            assert_eq!(sgb.write(*b), None);
+        }
+        assert!(send(&mut sgb, &PAL01).is_some());
+
+        // Wrong stop bit.
+        let mut writes = packet_writes(&PAL01);
+        let len = writes.len();
+        writes[len - 2] = 0x10;
+        assert!(writes.into_iter().all(|b| sgb.write(b).is_none()));
+
+        // Joypad reads in between, which select one line at a time without a transfer, are ignored.
+        for b in [0x10, 0x30, 0x20, 0x30] {
+            assert_eq!(sgb.write(b), None);
+        }
+
+        // The second packet of a two packet command isn't a command.
+        let mut attr_blk = [0; 16];
+        attr_blk[0] = 0x04 << 3 | 2;
+        assert_eq!(send(&mut sgb, &attr_blk), None);
+        assert_eq!(send(&mut sgb, &PAL01), None);
+        assert!(send(&mut sgb, &PAL01).is_some());
+    }
+}
@@ -0,0 +1,495 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+// Save states are the magic bytes, the format version (u32 little endian)
+// and then the bincode encoded SaveState.
+pub const SAVE_STATE_VERSION: u32 = 10;
+const MAGIC: &[u8; 4] = b"GBRS";
+
+#[derive(Error, Debug)]
+pub enum SaveStateError {
+    #[error("data is not a save state")]
+    InvalidMagic,
+    #[error("save state version {found} is not supported, expected version {expected}")]
+    VersionMismatch { found: u32, expected: u32 },
+    #[error("save state is for a different cartridge: {0}")]
+    WrongCartridge(String),
+    #[error("save state {field} is {found} bytes, expected {expected}")]
+    InvalidLength { field: &'static str, found: usize, expected: usize },
+    #[error(transparent)]
+    Encoding(#[from] bincode::Error),
+}
+
+type Result<T> = std::result::Result<T, SaveStateError>;
+
+// Buffers are copied into fixed size arrays, an edited or truncated state could have any length.
+pub(crate) fn check_len(field: &'static str, found: &[u8], expected: usize) -> Result<()> {
+    if found.len() != expected {
+        return Err(SaveStateError::InvalidLength { field, found: found.len(), expected });
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SaveState {
+    pub version:    u32,
+    pub cpu:        CpuState,
+    pub memory:     MemoryState,
+    pub gpu:        GpuState,
+    // None if audio was disabled.
+    pub apu:        Option<ApuState>,
+    pub timer:      TimerState,
+    pub cart_type:  String,
+    pub cart_title: String,
+    // Room for future additions without bumping the version.
+    pub extra:      HashMap<String, Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CpuState {
+    pub af:                 u16,
+    pub bc:                 u16,
+    pub de:                 u16,
+    pub hl:                 u16,
+    pub sp:                 u16,
+    pub pc:                 u16,
+    pub halted:             bool,
+    pub ime:                bool,
+    pub disable_interrupt:  u8,
+    pub enable_interrupt:   u8,
+    // The byte after HALT is read twice.
+    pub halt_bug:           bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MemoryState {
+    pub wram:           Vec<u8>,
+    pub wram_bank:      u8,
+    // KEY1, CGB double speed mode.
+    pub double_speed:   bool,
+    pub speed_switch_armed: bool,
+    pub hram:           Vec<u8>,
+    pub inte:           u8,
+    pub intf:           u8,
+    // FF00 button group select.
+    pub keypad_select:  u8,
+    pub serial_data:    u8,
+    pub serial_control: u8,
+    pub hdma_src:       u16,
+    pub hdma_dst:       u16,
+    pub hdma_len:       u8,
+    pub hdma_hblank:    bool,
+    pub hdma_active:    bool,
+    pub ir:             u8,
+    pub cart:           CartState,
+}
+
+// Banking registers and RAM of the cartridge, unused fields are left default.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CartState {
+    pub rom_bank:   usize,
+    // Bank of the second ROM window, MBC6 only.
+    pub rom_bank_b: usize,
+    pub ram_bank:   usize,
+    pub ram_enable: bool,
+    pub mode:       bool,
+    pub ram:        Vec<u8>,
+    // MBC3 clock, and the last value written to its latch register.
+    pub rtc:        Option<RtcState>,
+    pub latch:      u8,
+    // MBC5 rumble motor.
+    pub rumble:     bool,
+    pub mbc7:       Option<Mbc7State>,
+    // HuC1 infrared signal received and LED.
+    pub ir_rx:      bool,
+    pub ir_tx:      bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RtcState {
+    pub seconds:    u8,
+    pub minutes:    u8,
+    pub hours:      u8,
+    pub dl:         u8,
+    pub dh:         u8,
+    pub halted:     bool,
+    // Unix time the clock counts from.
+    pub zero:       u64,
+}
+
+// EEPROM interface and accelerometer of MBC7, the EEPROM itself is CartState::ram.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Mbc7State {
+    // 0 idle, 1 command, 2 read, 3 write to write_addr, 4 write to every word.
+    pub eeprom_mode:    u8,
+    pub write_addr:     u8,
+    pub shift:          u16,
+    pub bits:           u8,
+    pub addr:           u8,
+    pub write_enable:   bool,
+    pub cs:             bool,
+    pub clk:            bool,
+    pub di:             bool,
+    pub dout:           bool,
+    pub tilt_x:         i16,
+    pub tilt_y:         i16,
+    pub latched_x:      u16,
+    pub latched_y:      u16,
+    pub latched:        bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct GpuState {
+    pub vram:       Vec<u8>,
+    pub oam:        Vec<u8>,
+    pub lcdc:       u8,
+    pub stat:       u8,
+    pub mode:       u8,
+    pub h_blank:    bool,
+    pub scroll_y:   u8,
+    pub scroll_x:   u8,
+    pub ly:         u8,
+    pub ly_compare: u8,
+    pub window_y:   u8,
+    pub window_x:   u8,
+    pub window_line:    u8,
+    pub bg_palette: u8,
+    pub obp0:       u8,
+    pub obp1:       u8,
+    pub dots:       u32,
+    pub warm_up_cycles: u32,
+    pub vram_bank:  u8,
+    // CGB palette indexes and RAM.
+    pub bg_palette_idx:     u8,
+    pub bg_palette_ram:     Vec<u8>,
+    pub obj_palette_idx:    u8,
+    pub obj_palette_ram:    Vec<u8>,
+}
+
+// Register file of the APU, the position within each waveform is not kept.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ApuState {
+    // NRx0-NRx4 of square 1, square 2, wave, noise and the mixer (NR50-NR52).
+    pub regs:       Vec<[u8; 5]>,
+    pub wave_ram:   Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TimerState {
+    pub divider:    u8,
+    pub counter:    u8,
+    pub modulo:     u8,
+    pub overflow_pending:   bool,
+    pub enable:     bool,
+    pub div_clock:  (u32, u32),
+    pub mod_clock:  (u32, u32),
+}
+
+// Encoded save states of the most recent frames, oldest first, for rewinding.
+pub struct RewindBuffer {
+    snapshots:  VecDeque<Vec<u8>>,
+    max_frames: usize,
+}
+
+// Ten seconds of frames.
+pub const DEFAULT_REWIND_FRAMES: usize = 600;
+
+impl Default for RewindBuffer {
+    fn default() -> Self { Self::new(DEFAULT_REWIND_FRAMES) }
+}
+
+impl RewindBuffer {
+
+    // 0 frames disables rewinding.
+    pub fn new(max_frames: usize) -> Self {
+        Self { snapshots: VecDeque::new(), max_frames }
+    }
+
+    pub fn is_enabled(&self) -> bool { self.max_frames > 0 }
+
+    pub fn len(&self) -> usize { self.snapshots.len() }
+
+    pub fn is_empty(&self) -> bool { self.snapshots.is_empty() }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > self.max_frames {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // Drops the latest n snapshots, returning the one before them which is now the latest.
+    pub fn rewind(&mut self, n: usize) -> Option<Vec<u8>> {
+        if n >= self.snapshots.len() {
+            return None;
+        }
+        self.snapshots.truncate(self.snapshots.len() - n);
+        self.snapshots.back().cloned()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+}
+
+impl SaveState {
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend(bincode::serialize(self)?);
+        Ok(buf)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 8 || &buf[..4] != MAGIC {
+            return Err(SaveStateError::InvalidMagic);
+        }
+        let version = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::VersionMismatch { found: version, expected: SAVE_STATE_VERSION });
+        }
+        Ok(bincode::deserialize(&buf[8..])?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::bus::MemoryBus;
+    use crate::cartridge::{Cartridge, ROM};
+    use crate::cpu::CPU;
+    use crate::gpu::GPU;
+    use crate::intf::Intf;
+    use crate::timer::Timer;
+    use super::{CartState, RewindBuffer, SaveState, SaveStateError, SAVE_STATE_VERSION};
+
+    fn rom() -> Box<ROM> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x139].copy_from_slice(b"STATE");
+        Box::new(ROM::new(rom))
+    }
+
+    #[test]
+    fn timer_round_trip() {
+        let intf = Rc::new(RefCell::new(Intf::new()));
+        let mut timer = Timer::new(intf.clone());
+        timer.write_byte(0xFF06, 0x42);
+        timer.write_byte(0xFF07, 0x05);
+        timer.update(1000);
+        let state = timer.save_state();
+
+        let mut restored = Timer::new(intf);
+        restored.load_state(&state);
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.read_byte(0xFF06), 0x42);
+        assert_eq!(restored.read_byte(0xFF07), timer.read_byte(0xFF07));
+    }
+
+    #[test]
+    fn gpu_round_trip() {
+        let intf = Rc::new(RefCell::new(Intf::new()));
+        let mut gpu = GPU::new(intf.clone());
+        gpu.write_byte(0x8010, 0xAB);
+        gpu.write_byte(0xFE00, 0x10);
+        gpu.write_byte(0xFF40, 0x91);
+        gpu.write_byte(0xFF47, 0xE4);
+        gpu.update(500);
+        let state = gpu.save_state();
+
+        let mut restored = GPU::new(intf);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.read_byte(0x8010), 0xAB);
+        assert_eq!(restored.read_byte(0xFF44), gpu.read_byte(0xFF44));
+    }
+
+    #[test]
+    fn cpu_round_trip() {
+        let mut cpu = CPU::new(rom(), None);
+        cpu.mem.write_byte(0xC123, 0x77);
+        cpu.mem.write_byte(0xFF80, 0x55);
+        for _ in 0..100 {
+            let cycles = cpu.tick();
+            cpu.mem.update(cycles);
+        }
+        let mut state = cpu.to_save_state();
+        assert_eq!(state.cart_title, "STATE");
+        state.cpu.halt_bug = true;
+
+        let restored = CPU::from_save_state(rom(), None, &state).unwrap();
+        assert_eq!(restored.to_save_state(), state);
+        assert_eq!(restored.mem.read_byte(0xC123), 0x77);
+        assert_eq!(restored.mem.read_byte(0xFF80), 0x55);
+    }
+
+    // Loads the state of cart into restored, which has to give the same state back.
+    fn cart_round_trip(cart: &dyn Cartridge, restored: &mut dyn Cartridge) -> CartState {
+        let state = cart.save_state();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        state
+    }
+
+    #[test]
+    fn mapper_round_trip() {
+        use crate::mbc::{mbc3::MBC3, mbc5::MBC5, mbc7::MBC7, huc1::HuC1};
+
+        let rtc_path = std::env::temp_dir().join("gameboy_state_missing_test.rtc");
+        let mut mbc3 = MBC3::new(vec![0; 0x8000], 0x2000, None, Some(rtc_path.clone()));
+        mbc3.write_byte(0x0000, 0x0A);
+        mbc3.write_byte(0x4000, 0x0C);
+        mbc3.write_byte(0xA000, 0x40);
+        mbc3.write_byte(0x6000, 0x00);
+        let mut restored = MBC3::new(vec![0; 0x8000], 0x2000, None, Some(rtc_path));
+        let state = cart_round_trip(&mbc3, &mut restored);
+        assert!(state.rtc.unwrap().halted);
+
+        let mut mbc5 = MBC5::new(vec![0; 0x8000], 0x2000, None, true);
+        mbc5.write_byte(0x4000, 0x08);
+        let mut restored = MBC5::new(vec![0; 0x8000], 0x2000, None, true);
+        assert!(cart_round_trip(&mbc5, &mut restored).rumble);
+        assert!(restored.is_rumbling());
+
+        let mut mbc7 = MBC7::new(vec![0; 0x8000], None);
+        mbc7.tilt_sensor().unwrap().set_tilt(100, -50);
+        mbc7.write_byte(0x0000, 0x0A);
+        mbc7.write_byte(0x4000, 0x40);
+        mbc7.write_byte(0xA000, 0x55);
+        mbc7.write_byte(0xA010, 0xAA);
+        // Chip select and a start bit, the EEPROM waits for a command.
+        mbc7.write_byte(0xA080, 0x82);
+        mbc7.write_byte(0xA080, 0xC2);
+        let mut restored = MBC7::new(vec![0; 0x8000], None);
+        let state = cart_round_trip(&mbc7, &mut restored).mbc7.unwrap();
+        assert_eq!((state.eeprom_mode, state.tilt_x, state.latched), (1, 100, true));
+
+        let mut huc1 = HuC1::new(vec![0; 0x8000], 0x2000, None);
+        huc1.receive_ir(true);
+        huc1.write_byte(0x0000, 0x0E);
+        huc1.write_byte(0xA000, 0x01);
+        let mut restored = HuC1::new(vec![0; 0x8000], 0x2000, None);
+        let state = cart_round_trip(&huc1, &mut restored);
+        assert!(state.ir_rx && state.ir_tx);
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let mut cpu = CPU::new(rom(), None);
+        cpu.mem.write_byte(0xC000, 0x11);
+        let before = cpu.to_save_state();
+        let mut state = before.clone();
+        state.memory.wram[0] = 0x22;
+
+        let mut truncated = state.clone();
+        truncated.memory.hram.pop();
+        assert!(matches!(
+            cpu.load_save_state(&truncated),
+            Err(SaveStateError::InvalidLength { field: "hram", found: 126, expected: 127 })
+        ));
+        // WRAM is loaded before the GPU finds its VRAM too long, and put back.
+        let mut truncated = state.clone();
+        truncated.gpu.vram.push(0);
+        assert!(matches!(cpu.load_save_state(&truncated), Err(SaveStateError::InvalidLength { field: "vram", .. })));
+        assert_eq!(cpu.to_save_state(), before);
+
+        cpu.load_save_state(&state).unwrap();
+        assert_eq!(cpu.mem.read_byte(0xC000), 0x22);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn apu_round_trip() {
+        use crate::apu::APU;
+
+        let mut apu = APU::power_up(44_100);
+        apu.write_byte(0xFF26, 0x80);
+        apu.write_byte(0xFF12, 0xF3);
+        apu.write_byte(0xFF30, 0x12);
+        let state = apu.save_state();
+
+        let mut restored = APU::power_up(44_100);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.read_byte(0xFF12), 0xF3);
+
+        let mut truncated = state.clone();
+        truncated.wave_ram.truncate(4);
+        assert!(matches!(restored.load_state(&truncated), Err(SaveStateError::InvalidLength { field: "wave ram", .. })));
+    }
+
+    #[test]
+    fn rewind_buffer() {
+        let mut rewind = RewindBuffer::new(3);
+        for frame in 0..5 {
+            rewind.push(vec![frame]);
+        }
+        // Only the last 3 frames are kept.
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.rewind(3), None);
+        assert_eq!(rewind.rewind(1), Some(vec![3]));
+        assert_eq!(rewind.len(), 2);
+        assert_eq!(rewind.rewind(0), Some(vec![3]));
+        assert_eq!(rewind.rewind(1), Some(vec![2]));
+        rewind.clear();
+        assert!(rewind.is_empty());
+
+        let mut disabled = RewindBuffer::new(0);
+        disabled.push(vec![0]);
+        assert!(!disabled.is_enabled());
+        assert_eq!(disabled.rewind(0), None);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut state = CPU::new(rom(), None).to_save_state();
+        state.extra.insert("note".to_string(), vec![1, 2, 3]);
+        let bytes = state.to_bytes().unwrap();
+        assert_eq!(&bytes[..4], b"GBRS");
+        assert_eq!(SaveState::from_bytes(&bytes).unwrap(), state);
+    }
+
+    #[test]
+    fn cpu_bytes_round_trip() {
+        let mut cpu = CPU::new(rom(), None);
+        for _ in 0..50 {
+            let cycles = cpu.tick();
+            cpu.mem.update(cycles);
+        }
+        cpu.mem.write_byte(0xC000, 0x99);
+        let bytes = cpu.save_state().unwrap();
+
+        let mut restored = CPU::new(rom(), None);
+        restored.load_state(&bytes).unwrap();
+        assert_eq!(restored.to_save_state(), cpu.to_save_state());
+        assert_eq!(restored.mem.read_byte(0xC000), 0x99);
+        assert!(restored.load_state(&bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut bytes = CPU::new(rom(), None).to_save_state().to_bytes().unwrap();
+        bytes[4..8].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            SaveState::from_bytes(&bytes),
+            Err(SaveStateError::VersionMismatch { found, .. }) if found == SAVE_STATE_VERSION + 1
+        ));
+
+        bytes[0] = b'X';
+        assert!(matches!(SaveState::from_bytes(&bytes), Err(SaveStateError::InvalidMagic)));
+    }
+
+    #[test]
+    fn wrong_cartridge() {
+        let mut state = CPU::new(rom(), None).to_save_state();
+        state.cart_title = "OTHER".to_string();
+        assert!(matches!(
+            CPU::from_save_state(rom(), None, &state),
+            Err(SaveStateError::WrongCartridge(_))
+        ));
+    }
+}
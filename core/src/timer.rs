@@ -5,6 +5,7 @@ use super::bit::Bit;
 use super::bus::MemoryBus;
 use super::clock::Clock;
 use super::intf::{Intf, InterruptSource};
+use super::state::TimerState;
 
 #[derive(Default)]
 pub struct Timer {
@@ -22,6 +23,10 @@ pub struct Timer {
     // Holds value to set TIMA when reset.
     modulo: u8,
 
+    // TIMA overflowed and reads 0 until the next update, which reloads it from TMA
+    // and requests the interrupt.
+    overflow_pending: bool,
+
     // FF07 - Timer control (R/W).
     /*  Bit  2   - Timer Enable
     Bits 1-0 - Input Clock Select
@@ -64,7 +69,11 @@ impl MemoryBus for Timer {
     fn write_byte(&mut self, address: u16, b: u8) {
         match address {
             0xFF04 => { self.divider = b },
-            0xFF05 => { self.counter = b },
+            // Writing TIMA while the reload is pending cancels it.
+            0xFF05 => {
+                self.counter = b;
+                self.overflow_pending = false;
+            },
             0xFF06 => { self.modulo = b },
             0xFF07 => {
                 self.enable = b.bit(2);
@@ -94,17 +103,86 @@ impl Timer {
 
     pub fn update(&mut self, cycles: u32) {
         self.divider = self.divider.wrapping_add(self.div_clock.tick(cycles) as u8);
+        self.reload();
         
         if self.enable {
             for _ in 0..self.mod_clock.tick(cycles) {
+                self.reload();
                 self.counter = self.counter.wrapping_add(1);
+                self.overflow_pending = self.counter == 0;
+            }
+        }
+    }
 
-                if self.counter == 0 {
-                    self.counter = self.modulo;
-                    self.intf.borrow_mut().set_interrupt(InterruptSource::Timer);
-                }
+    fn reload(&mut self) {
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.counter = self.modulo;
+            self.intf.borrow_mut().set_interrupt(InterruptSource::Timer);
+        }
+    }
 
-            }
+    pub fn save_state(&self) -> TimerState {
+        TimerState {
+            divider:    self.divider,
+            counter:    self.counter,
+            modulo:     self.modulo,
+            overflow_pending:   self.overflow_pending,
+            enable:     self.enable,
+            div_clock:  (self.div_clock.period, self.div_clock.n),
+            mod_clock:  (self.mod_clock.period, self.mod_clock.n),
         }
     }
+
+    pub fn load_state(&mut self, state: &TimerState) {
+        self.divider    = state.divider;
+        self.counter    = state.counter;
+        self.modulo     = state.modulo;
+        self.overflow_pending = state.overflow_pending;
+        self.enable     = state.enable;
+        self.div_clock  = Clock { period: state.div_clock.0, n: state.div_clock.1 };
+        self.mod_clock  = Clock { period: state.mod_clock.0, n: state.mod_clock.1 };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::bus::MemoryBus;
+    use crate::intf::Intf;
+    use super::Timer;
+
+    fn timer() -> (Timer, Rc<RefCell<Intf>>) {
+        let intf = Rc::new(RefCell::new(Intf::new()));
+        let mut timer = Timer::new(intf.clone());
+        // Enabled, incrementing every 16 cycles.
+        timer.write_byte(0xFF07, 0x05);
+        timer.write_byte(0xFF06, 0x42);
+        timer.write_byte(0xFF05, 0xFF);
+        (timer, intf)
+    }
+
+    #[test]
+    fn tima_reads_zero_on_overflow() {
+        let (mut timer, intf) = timer();
+        timer.update(16);
+        assert_eq!(timer.read_byte(0xFF05), 0x00);
+        assert_eq!(intf.borrow().read_byte(0xFF0F) & 0x04, 0);
+
+        timer.update(4);
+        assert_eq!(timer.read_byte(0xFF05), 0x42);
+        assert_eq!(intf.borrow().read_byte(0xFF0F) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let (mut timer, intf) = timer();
+        timer.update(16);
+        timer.write_byte(0xFF05, 0x10);
+        timer.update(4);
+        assert_eq!(timer.read_byte(0xFF05), 0x10);
+        assert_eq!(intf.borrow().read_byte(0xFF0F) & 0x04, 0);
+    }
 }
@@ -0,0 +1,43 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use core::cartridge::ROM;
+use core::cpu::CPU;
+
+// Opcodes that do not exist on the Sharp LR35902, these lock up real hardware and run as NOP.
+const ILLEGAL: [u8; 11] = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+
+// Executes opcode on a fresh CPU, the rom after PC (0x100) holds operand.
+fn execute(opcode: u8, operand: u8) -> std::thread::Result<u32> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100] = operand;
+    let mut cpu = CPU::new(Box::new(ROM::new(rom)), None);
+    catch_unwind(AssertUnwindSafe(|| cpu.execute(opcode)))
+}
+
+fn check(results: impl Iterator<Item = (u8, std::thread::Result<u32>)>, expected_panics: &[u8], name: &str) {
+    let mut panics = Vec::new();
+    let mut zero_cycles = Vec::new();
+    for (opcode, result) in results {
+        match result {
+            Err(_) => panics.push(opcode),
+            Ok(0) => zero_cycles.push(opcode),
+            Ok(_) => {},
+        }
+    }
+    let format = |ops: &[u8]| ops.iter().map(|op| format!("{:#04X}", op)).collect::<Vec<_>>().join(", ");
+    assert_eq!(panics, expected_panics, "unimplemented {} opcodes: [{}]", name, format(&panics));
+    assert!(zero_cycles.is_empty(), "{} opcodes taking 0 cycles: [{}]", name, format(&zero_cycles));
+}
+
+#[test]
+fn base_opcodes() {
+    check((0..=0xFF).map(|op| (op, execute(op, 0x00))), &[], "base");
+    for op in ILLEGAL {
+        assert_eq!(execute(op, 0x00).unwrap(), 4, "{:#04X}", op);
+    }
+}
+
+#[test]
+fn cb_opcodes() {
+    check((0..=0xFF).map(|op| (op, execute(0xCB, op))), &[], "CB");
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap, io::ErrorKind, path::{Path, PathBuf}};
+use anyhow::{anyhow, Context, Result};
+use minifb::Key;
+use serde::{Deserialize, Serialize};
+
+use core::keypad::GbKey;
+
+// Copy of minifb::Scale such that it implements clap::ValueEnum.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayScale {
+    X1,
+    X2,
+    #[default]
+    X4,
+    X8,
+    X16,
+    X32,
+}
+
+// Settings from the config file, command line arguments take precedence.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub scale:      DisplayScale,
+    pub audio:      bool,
+    pub serial:     bool,
+    // minifb key names to Game Boy button names, { "Z" = "A" }.
+    // Bound buttons no longer use their default keys.
+    pub bindings:   HashMap<String, String>,
+}
+
+const DEFAULT_BINDINGS: [(Key, GbKey); 8] = [
+    (Key::Right,  GbKey::Right),
+    (Key::Up,     GbKey::Up),
+    (Key::Left,   GbKey::Left),
+    (Key::Down,   GbKey::Down),
+    (Key::Z,      GbKey::A),
+    (Key::X,      GbKey::B),
+    (Key::Space,  GbKey::Select),
+    (Key::Enter,  GbKey::Start),
+];
+
+// Keys that can be bound, named as in minifb::Key.
+const KEYS: [Key; 80] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal,
+    Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket, Key::Semicolon,
+    Key::Slash, Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape,
+    Key::Home, Key::Insert, Key::PageDown, Key::PageUp, Key::Space, Key::Tab,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl, Key::LeftAlt, Key::RightAlt,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4,
+    Key::NumPad5, Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9,
+    Key::NumPadEnter, Key::NumPadPlus,
+];
+
+const BUTTONS: [(&str, GbKey); 8] = [
+    ("Right",  GbKey::Right),
+    ("Left",   GbKey::Left),
+    ("Up",     GbKey::Up),
+    ("Down",   GbKey::Down),
+    ("A",      GbKey::A),
+    ("B",      GbKey::B),
+    ("Select", GbKey::Select),
+    ("Start",  GbKey::Start),
+];
+
+// ~/.config/gameboy/config.toml
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/gameboy/config.toml"))
+}
+
+impl Config {
+
+    // The default file is optional, one given with --config is not.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match (path, default_path()) {
+            (Some(path), _) => (path.to_path_buf(), true),
+            (None, Some(path)) => (path, false),
+            (None, None) => return Ok(Self::default()),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("failed to parse config {}", path.display())),
+            Err(e) if !required && e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("failed to read config {}", path.display())),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    // Default keys of buttons that are not bound, followed by the configured bindings.
+    pub fn effective_bindings(&self) -> HashMap<String, String> {
+        let bound: Vec<&String> = self.bindings.values().collect();
+        let mut bindings: HashMap<String, String> = DEFAULT_BINDINGS.iter()
+            .map(|(key, button)| (format!("{:?}", key), button_name(button).to_string()))
+            .filter(|(_, button)| !bound.contains(&button))
+            .collect();
+        bindings.extend(self.bindings.clone());
+        bindings
+    }
+
+    pub fn keys(&self) -> Result<Vec<(Key, GbKey)>> {
+        self.effective_bindings().iter().map(|(key, button)| {
+            let key = KEYS.iter()
+                .find(|k| format!("{:?}", k) == *key)
+                .ok_or_else(|| anyhow!("unknown key '{}' in bindings", key))?;
+            let button = BUTTONS.iter()
+                .find(|(name, _)| name == button)
+                .map(|(_, b)| b.clone())
+                .ok_or_else(|| anyhow!("unknown button '{}' in bindings", button))?;
+            Ok((*key, button))
+        }).collect()
+    }
+}
+
+fn button_name(button: &GbKey) -> &'static str {
+    BUTTONS.iter()
+        .find(|(_, b)| std::mem::discriminant(b) == std::mem::discriminant(button))
+        .map(|(name, _)| *name)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use minifb::Key;
+    use super::{Config, DisplayScale};
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(r#"
+            scale = "x2"
+            audio = true
+
+            [bindings]
+            J = "A"
+            K = "B"
+        "#).unwrap();
+        assert_eq!(config.scale, DisplayScale::X2);
+        assert!(config.audio);
+        assert!(!config.serial);
+
+        // Z and X are replaced, the rest keep their defaults.
+        let keys = config.keys().unwrap();
+        assert_eq!(keys.len(), 8);
+        assert!(keys.iter().any(|(key, _)| *key == Key::J));
+        assert!(keys.iter().any(|(key, _)| *key == Key::Enter));
+        assert!(!keys.iter().any(|(key, _)| *key == Key::Z));
+
+        // Round trips through --dump-config.
+        assert_eq!(Config::parse(&config.to_toml().unwrap()).unwrap(), config);
+
+        let config = Config::parse("[bindings]\nNotAKey = \"A\"").unwrap();
+        assert!(config.keys().is_err());
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use gilrs::{Axis, Button, EventType, Gilrs};
+
+use core::keypad::GbKey;
+
+// How far the left stick has to be pushed to count as a direction.
+const DEADZONE: f32 = 0.5;
+
+const BUTTONS: [(Button, GbKey); 8] = [
+    (Button::South,     GbKey::A),
+    (Button::East,      GbKey::B),
+    (Button::Start,     GbKey::Start),
+    (Button::Select,    GbKey::Select),
+    (Button::DPadUp,    GbKey::Up),
+    (Button::DPadDown,  GbKey::Down),
+    (Button::DPadLeft,  GbKey::Left),
+    (Button::DPadRight, GbKey::Right),
+];
+
+// Every connected controller, which can be plugged in or out while running.
+pub struct Gamepads {
+    gilrs: Gilrs,
+}
+
+impl Gamepads {
+
+    pub fn new() -> Result<Self> {
+        let gilrs = Gilrs::new().map_err(|e| anyhow!("failed to initialise gamepads: {}", e))?;
+        for (_, gamepad) in gilrs.gamepads() {
+            println!("Gamepad connected: {}", gamepad.name());
+        }
+        Ok(Self { gilrs })
+    }
+
+    // Handles pending events, which also updates the state of each controller.
+    pub fn poll(&mut self) {
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::Connected => println!("Gamepad connected: {}", self.gilrs.gamepad(event.id).name()),
+                EventType::Disconnected => println!("Gamepad disconnected: {}", self.gilrs.gamepad(event.id).name()),
+                _ => {},
+            }
+        }
+    }
+
+    // Buttons held on any controller.
+    pub fn pressed(&self) -> Vec<GbKey> {
+        let mut pressed = Vec::new();
+        for (_, gamepad) in self.gilrs.gamepads() {
+            let stick = (gamepad.value(Axis::LeftStickX), gamepad.value(Axis::LeftStickY));
+            for (button, key) in BUTTONS.iter() {
+                if gamepad.is_pressed(*button) || stick_pressed(key, stick) {
+                    pressed.push(key.clone());
+                }
+            }
+        }
+        pressed
+    }
+}
+
+// Direction of the left stick, up is positive y.
+fn stick_pressed(key: &GbKey, (x, y): (f32, f32)) -> bool {
+    match key {
+        GbKey::Right => x > DEADZONE,
+        GbKey::Left  => x < -DEADZONE,
+        GbKey::Up    => y > DEADZONE,
+        GbKey::Down  => y < -DEADZONE,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::keypad::GbKey;
+    use super::stick_pressed;
+
+    #[test]
+    fn stick_deadzone() {
+        assert!(stick_pressed(&GbKey::Right, (0.8, 0.0)));
+        assert!(!stick_pressed(&GbKey::Right, (0.4, 0.0)));
+        assert!(stick_pressed(&GbKey::Down, (0.0, -0.6)));
+        assert!(!stick_pressed(&GbKey::Up, (0.0, -0.6)));
+        assert!(!stick_pressed(&GbKey::A, (1.0, 1.0)));
+    }
+}
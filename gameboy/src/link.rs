@@ -0,0 +1,145 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+
+use core::builder::LinkCable;
+
+// How long the side providing the clock waits for the other to answer.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bit 7 of the tag marks an answer, the rest is the sequence number of the transfer answered.
+const REPLY: u8 = 0x80;
+
+// Link cable over TCP, each serial byte is sent as a tag byte then the data.
+// Messages from the other side are read by a background thread.
+pub struct TcpLink {
+    stream:     TcpStream,
+    incoming:   Receiver<(u8, u8)>,
+    // Sequence number of the last transfer started, so answers arriving after the timeout
+    // aren't taken as the answer to the next one.
+    seq:        u8,
+}
+
+impl TcpLink {
+
+    // Waits for the other emulator to connect.
+    pub fn listen(port: u16) -> Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).context("failed to open link port")?;
+        println!("waiting for link cable on port {}", port);
+        let (stream, _) = listener.accept().context("failed to accept link cable")?;
+        Self::new(stream)
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).context("failed to connect link cable")?;
+        Self::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> Result<Self> {
+        stream.set_nodelay(true)?;
+        let mut reader = stream.try_clone()?;
+        let (tx, incoming) = mpsc::channel();
+        // Ends when the connection closes or the link is dropped.
+        thread::spawn(move || {
+            let mut msg = [0; 2];
+            while reader.read_exact(&mut msg).is_ok() && tx.send((msg[0], msg[1])).is_ok() {}
+        });
+        Ok(Self { stream, incoming, seq: 0 })
+    }
+}
+
+impl LinkCable for TcpLink {
+
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.seq = (self.seq + 1) & !REPLY;
+        if self.stream.write_all(&[self.seq, out]).is_err() {
+            return 0xFF;
+        }
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match self.incoming.recv_timeout(wait) {
+                Ok((tag, b)) if tag == self.seq | REPLY => return b,
+                // Late answers to earlier transfers, or the other side starting one too.
+                Ok(_) => {},
+                Err(_) => return 0xFF,
+            }
+        }
+    }
+
+    fn receive(&mut self, out: u8) -> Option<u8> {
+        loop {
+            let (tag, b) = self.incoming.try_recv().ok()?;
+            // Late answers to transfers this side started are dropped.
+            if tag & REPLY == 0 {
+                let _ = self.stream.write_all(&[tag | REPLY, out]);
+                return Some(b);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use core::builder::LinkCable;
+    use super::TcpLink;
+
+    #[test]
+    fn exchange_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut link = TcpLink::new(stream).unwrap();
+            // Waits on the external clock until the client sends.
+            loop {
+                if let Some(b) = link.receive(0x34) {
+                    return b;
+                }
+                thread::yield_now();
+            }
+        });
+
+        let mut client = TcpLink::connect(addr).unwrap();
+        assert_eq!(client.transfer(0x12), 0x34);
+        assert_eq!(server.join().unwrap(), 0x12);
+
+        // No one is answering any more.
+        assert_eq!(client.transfer(0x12), 0xFF);
+    }
+
+    #[test]
+    fn late_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut link = TcpLink::new(stream).unwrap();
+            // Answers the first transfer after the client gave up on it.
+            thread::sleep(super::TIMEOUT + Duration::from_millis(500));
+            let mut received = Vec::new();
+            for out in [0x34, 0x78] {
+                loop {
+                    if let Some(b) = link.receive(out) {
+                        received.push(b);
+                        break;
+                    }
+                    thread::yield_now();
+                }
+            }
+            received
+        });
+
+        let mut client = TcpLink::connect(addr).unwrap();
+        assert_eq!(client.transfer(0x12), 0xFF);
+        // The answer of 0x34 to the first transfer is dropped when it arrives.
+        assert_eq!(client.transfer(0x56), 0x78);
+        assert_eq!(server.join().unwrap(), vec![0x12, 0x56]);
+    }
+}
@@ -1,30 +1,67 @@
 use cpal::OutputCallbackInfo;
 use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
-use minifb::{Window, WindowOptions, Scale, Key};
+use minifb::{Window, WindowOptions, Scale, Key, KeyRepeat};
 use clap::Parser;
 use anyhow::{Result, ensure, Context, Ok};
-use std::{path::Path, ffi::OsStr};
+use std::{path::{Path, PathBuf}, ffi::OsStr, rc::Rc, cell::Cell};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs::File, io::{BufWriter, Write}};
 
 use core::{
     {SCREEN_HEIGHT, SCREEN_WIDTH},
-    cpu::CPU,
-    keypad::GbKey,
+    cpu::{CPU, BREAKPOINT_CYCLES},
     cartridge,
-    apu::APU,
+    apu::ApuEvent,
+    builder::{GameboyBuilder, CompatMode},
 };
 
+use wav::WavWriter;
+use config::{Config, DisplayScale};
+use link::TcpLink;
+
 #[cfg(test)]
 mod test;
+mod wav;
+mod config;
+mod link;
+mod printout;
+#[cfg(feature = "gamepad")]
+mod gamepad;
+
+type Recorder = Arc<Mutex<WavWriter<BufWriter<File>>>>;
+
+// Copy of core::builder::CompatMode such that it implements clap::ValueEnum.
+#[derive(clap::ValueEnum, Clone, Copy, Default)]
+enum Model {
+    #[default]
+    Auto,
+    Dmg,
+    Cgb,
+    Sgb,
+}
+
+impl From<Model> for CompatMode {
+    fn from(model: Model) -> Self {
+        match model {
+            Model::Auto => CompatMode::Auto,
+            Model::Dmg  => CompatMode::Dmg,
+            Model::Cgb  => CompatMode::Cgb,
+            Model::Sgb  => CompatMode::Sgb,
+        }
+    }
+}
 
 #[derive(Parser)]
 #[command(author = "Nathanw", about  = "A Rust powered Gameboy emulator.")]
 struct Args {
-    #[arg(short, long, help = "Path to rom")]
-    path:   String,
+    #[arg(short, long, help = "Path to rom (.gb or .zip)")]
+    #[arg(required_unless_present = "dump_config")]
+    path:   Option<String>,
 
-    #[arg(short = 'x', long, help = "Display scale factor")]
-    #[arg(value_enum, default_value_t)]
-    scale:  DisplayScale,
+    #[arg(short = 'x', long, help = "Display scale factor [default: x4]")]
+    #[arg(value_enum)]
+    scale:  Option<DisplayScale>,
 
     #[arg(short, long, help = "Enable audio")]
     #[arg(default_value = "false")]
@@ -33,33 +70,158 @@ struct Args {
     #[arg(short, long, help = "Print serial write to stdout")]
     #[arg(default_value = "false")]
     serial: bool,
-}
 
-// Copy of minifb::Scale such that it implements clap::ValueEnum.
-#[derive(clap::ValueEnum, Clone, Default, Debug)]
-enum DisplayScale {
-    X1,
-    X2,
-    #[default]
-    X4,
-    X8,
-    X16,
-    X32,
+    #[arg(long, help = "Start with empty RAM if the save file is corrupt")]
+    #[arg(default_value = "false")]
+    reset_corrupt_save: bool,
+
+    #[arg(long, help = "Write a trace of every instruction to a file")]
+    trace:  Option<String>,
+
+    #[arg(short, long = "break", help = "Stop at address (hex) and open the debugger, can be repeated")]
+    #[arg(value_parser = parse_hex)]
+    breakpoints: Vec<u16>,
+
+    #[arg(long = "cheat", help = "Game Genie (ABC-DEF-GHI) or GameShark (01VVAAAA) code, can be repeated")]
+    cheats: Vec<String>,
+
+    #[arg(long, help = "Print count instructions from a hex address and exit")]
+    #[arg(num_args = 2, value_names = ["START", "COUNT"])]
+    disassemble: Option<Vec<String>>,
+
+    #[arg(long, help = "Emulation speed multiplier up to 1000, 0 runs uncapped")]
+    #[arg(default_value = "1", value_parser = clap::value_parser!(u32).range(0..=1000))]
+    speed:  u32,
+
+    #[arg(long, help = "Seconds of history kept to rewind through by holding R, 0 disables it")]
+    #[arg(default_value = "10")]
+    rewind_buffer: usize,
+
+    #[arg(long, help = "Hardware to emulate, auto follows the cartridge header")]
+    #[arg(value_enum, default_value = "auto")]
+    model:  Model,
+
+    #[arg(long, help = "Mute audio channel 1")]
+    #[arg(default_value = "false")]
+    mute_ch1: bool,
+
+    #[arg(long, help = "Mute audio channel 2")]
+    #[arg(default_value = "false")]
+    mute_ch2: bool,
+
+    #[arg(long, help = "Mute audio channel 3")]
+    #[arg(default_value = "false")]
+    mute_ch3: bool,
+
+    #[arg(long, help = "Mute audio channel 4")]
+    #[arg(default_value = "false")]
+    mute_ch4: bool,
+
+    #[arg(long, help = "Draw every sprite on a line instead of the first 10, reduces flicker")]
+    #[arg(default_value = "false")]
+    no_sprite_limit: bool,
+
+    #[arg(long, help = "Show CGB colours as on the LCD instead of the raw palette values")]
+    #[arg(default_value = "false")]
+    color_correction: bool,
+
+    #[arg(long, help = "Audio buffer in milliseconds, rounded up to whole frames [default: 33]")]
+    audio_latency: Option<u32>,
+
+    #[arg(long, help = "Record audio to a WAV file, enables audio")]
+    record: Option<String>,
+
+    #[arg(long, help = "Refuse roms whose global checksum is incorrect")]
+    #[arg(default_value = "false")]
+    strict: bool,
+
+    #[arg(long, help = "Wait for another emulator to connect a link cable on this port")]
+    #[arg(conflicts_with = "link_client")]
+    link_server: Option<u16>,
+
+    #[arg(long, help = "Connect a link cable to another emulator (addr:port)")]
+    link_client: Option<String>,
+
+    #[arg(long, help = "Connect a Game Boy Printer, prints are saved as PNG files in this directory")]
+    #[arg(conflicts_with_all = ["link_server", "link_client"])]
+    printer: Option<PathBuf>,
+
+    #[arg(long, help = "Config file to use instead of ~/.config/gameboy/config.toml")]
+    config: Option<PathBuf>,
+
+    #[arg(long, help = "Print the effective config as TOML and exit")]
+    #[arg(default_value = "false")]
+    dump_config: bool,
+
+    #[arg(long, help = "Print the cartridge header and exit")]
+    #[arg(default_value = "false")]
+    info: bool,
+
+    #[arg(long, help = "Open roms with an incorrect logo or header checksum")]
+    #[arg(default_value = "false")]
+    #[arg(conflicts_with = "strict")]
+    no_verify: bool,
 }
 
 fn main() -> Result<()> {
 
     let args = Args::parse();
-    let rom_name = args.path;
+
+    // Flags can only switch settings on, so they are combined with the config file.
+    let mut config = Config::load(args.config.as_deref())?;
+    config.scale = args.scale.unwrap_or(config.scale);
+    config.audio |= args.audio;
+    config.serial |= args.serial;
+    if args.dump_config {
+        config.bindings = config.effective_bindings();
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let keys = config.keys()?;
+
+    // Runs without controllers if they are not supported.
+    #[cfg(feature = "gamepad")]
+    let mut gamepads = gamepad::Gamepads::new()
+        .map_err(|e| eprintln!("{:#}", e))
+        .ok();
+
+    let rom_name = args.path.unwrap_or_default();
 
     let rom_path = Path::new(&rom_name);
     ensure!(rom_path.exists(), "file path provided does not exist");
-    ensure!(rom_path.extension() == Some(OsStr::new("gb")), "file provided does not have the extention '.gb'");
+    ensure!(
+        rom_path.extension() == Some(OsStr::new("gb")) || rom_path.extension() == Some(OsStr::new("zip")),
+        "file provided does not have the extention '.gb' or '.zip'"
+    );
 
-    let cartridge = cartridge::open_cartridge(rom_path).context("failed loading cartridge")?;
+    let recovery = if args.reset_corrupt_save {
+        cartridge::SaveRecovery::Reset
+    } else {
+        cartridge::SaveRecovery::Fail
+    };
+    let cartridge = if args.no_verify {
+        cartridge::open_cartridge_unverified(rom_path, recovery)
+    } else {
+        cartridge::open_cartridge_with(rom_path, recovery)
+    }.context("failed loading cartridge")?;
+    if args.strict {
+        cartridge.verify_global_checksum().context("failed loading cartridge")?;
+    }
+    let info = cartridge.info();
+    if args.info {
+        println!("{}", info);
+        return Ok(());
+    }
+    if let Some(range) = &args.disassemble {
+        let start = parse_hex(&range[0]).context("invalid start address")?;
+        let count = range[1].parse().context("invalid instruction count")?;
+        let cpu = GameboyBuilder::new().cartridge(cartridge).build().context("failed loading cartridge")?;
+        print_listing(&cpu, start, count);
+        return Ok(());
+    }
 
     let opts = WindowOptions {
-        scale: match args.scale {
+        scale: match config.scale {
             DisplayScale::X1  => Scale::X1,
             DisplayScale::X2  => Scale::X2,
             DisplayScale::X4  => Scale::X4,
@@ -70,50 +232,121 @@ fn main() -> Result<()> {
         ..Default::default()
     };
 
+    let title = info.title.to_lowercase();
     let mut display = Window::new(
-        &cartridge.title().to_lowercase(),
+        &title,
         SCREEN_WIDTH,
         SCREEN_HEIGHT,
         opts,
     ).context("failed to create window")?;
     
-    let callback: Option<Box<dyn Fn(u8)>> = if args.serial {
-        Some(Box::new(|b: u8| { print!("{}", b as char); }))
+    let mut builder = GameboyBuilder::new().cartridge(cartridge).cgb_mode(args.model.into());
+    if config.serial {
+        builder = builder.serial_callback(Box::new(|b: u8| { print!("{}", b as char); }));
+    }
+    if let Some(port) = args.link_server {
+        builder = builder.link_cable(Box::new(TcpLink::listen(port)?));
+    }
+    if let Some(addr) = &args.link_client {
+        builder = builder.link_cable(Box::new(TcpLink::connect(addr.as_str())?));
+    }
+    if let Some(dir) = args.printer {
+        builder = builder.link_cable(Box::new(printout::printer(dir)?));
+    }
+
+    let audio_device = if config.audio || args.record.is_some() {
+        Some(audio_device().context("failed to initialise audio")?)
     } else {
         None
     };
+    if let Some((_, config)) = &audio_device {
+        builder = builder.audio(config.sample_rate().0);
+        if let Some(ms) = args.audio_latency {
+            builder = builder.audio_buffer_frames(latency_frames(ms));
+        }
+    }
 
-    let mut cpu = CPU::new(cartridge, callback);
+    let mut cpu = builder.build().context("failed loading cartridge")?;
 
-    let audio_stream = if args.audio {
-        initialise_audio(&mut cpu).context("failed to initialise audio")?
-    } else { 
-        None
+    let muted = [args.mute_ch1, args.mute_ch2, args.mute_ch3, args.mute_ch4];
+    for (ch, muted) in muted.into_iter().enumerate() {
+        cpu.mem.mute_channel(ch as u8, muted);
+    }
+    cpu.mem.gpu.set_sprite_limit(!args.no_sprite_limit);
+    cpu.mem.gpu.set_color_correction(args.color_correction);
+    cpu.set_rewind_frames(args.rewind_buffer * 60);
+
+    for address in &args.breakpoints {
+        cpu.add_breakpoint(*address);
+    }
+
+    for code in &args.cheats {
+        cpu.add_cheat(code)?;
+    }
+
+    if let Some(path) = &args.trace {
+        let file = File::create(path).context("failed to create trace file")?;
+        cpu.enable_trace(Box::new(BufWriter::new(file)));
+    }
+
+    // Enabled audio channels, shown as labels in the window title.
+    let channels = Rc::new(Cell::new([false; 4]));
+    let mut shown_channels = channels.get();
+
+    let recorder = match (&args.record, &audio_device) {
+        (Some(path), Some((_, config))) => {
+            let file = File::create(path).context("failed to create audio recording")?;
+            let wav = WavWriter::new(BufWriter::new(file), config.sample_rate().0)?;
+            Some(Arc::new(Mutex::new(wav)))
+        },
+        _ => None,
+    };
+
+    let audio_stream = match audio_device {
+        Some((device, config)) => Some(
+            initialise_audio(&mut cpu, device, config, channels.clone(), recorder.clone())
+                .context("failed to initialise audio")?
+        ),
+        None => None,
     };
 
-    let keys = [
-        (Key::Right,  GbKey::Right),
-        (Key::Up,     GbKey::Up),
-        (Key::Left,   GbKey::Left),
-        (Key::Down,   GbKey::Down),
-        (Key::Z,      GbKey::A),
-        (Key::X,      GbKey::B),
-        (Key::Space,  GbKey::Select),
-        (Key::Enter,  GbKey::Start),
-    ];
+    // Save state slots 1-4, F6-F9 save and F1-F4 load. F5 loads the last slot saved to.
+    let state_keys = [(Key::F6, Key::F1), (Key::F7, Key::F2), (Key::F8, Key::F3), (Key::F9, Key::F4)];
+    let mut last_slot = None;
+
+    // The gameboy runs at ~59.7 frames per second, times the speed multiplier.
+    cpu.set_speed(args.speed);
+    let frame_time = Duration::from_secs_f64(70_224.0 / 4_194_304.0 / args.speed.max(1) as f64);
+    let mut next_frame = Instant::now();
+    let mut rumbling = false;
 
     while display.is_open() {
 
-        let cycles = cpu.step();
-        cpu.mem.update(cycles);
+        // Holding R steps back a frame at a time, until the history runs out.
+        if display.is_key_down(Key::R) {
+            cpu.rewind(1);
+        } else {
+            let frame = cpu.run_until_vblank();
+            if frame.breakpoint_hit.is_some() && !debugger(&mut cpu)? {
+                break;
+            }
+        }
 
-        if cpu.mem.gpu.check_updated() {
-            display.update_with_buffer(
-                cpu.mem.gpu.pixels.as_ref(), 
-                SCREEN_WIDTH, 
-                SCREEN_HEIGHT,
-            ).context("failed to update display")?;
+        if channels.get() != shown_channels {
+            shown_channels = channels.get();
+            display.set_title(&channel_title(&title, shown_channels));
         }
+
+        if cpu.mem.is_rumbling() != rumbling {
+            rumbling = !rumbling;
+            println!("rumble {}", if rumbling { "on" } else { "off" });
+        }
+
+        display.update_with_buffer(
+            cpu.mem.gpu.pixels.as_ref(), 
+            SCREEN_WIDTH, 
+            SCREEN_HEIGHT,
+        ).context("failed to update display")?;
         
         for (input, key) in keys.iter() {
             if display.is_key_down(*input) {
@@ -123,7 +356,43 @@ fn main() -> Result<()> {
             }
         }
 
-        if !cpu.flip() { continue; }
+        // Only presses buttons, so keys held on the keyboard are never released.
+        #[cfg(feature = "gamepad")]
+        if let Some(gamepads) = gamepads.as_mut() {
+            gamepads.poll();
+            for key in gamepads.pressed() {
+                cpu.mem.keypad.key_press(key);
+            }
+        }
+
+        for (idx, (save_key, load_key)) in state_keys.iter().enumerate() {
+            let slot = idx + 1;
+            if display.is_key_pressed(*save_key, KeyRepeat::No) {
+                match save_state(&cpu, &state_path(rom_path, slot)) {
+                    std::result::Result::Ok(()) => {
+                        println!("saved state {}", slot);
+                        last_slot = Some(slot);
+                    },
+                    Err(e) => eprintln!("failed to save state {}: {:#}", slot, e),
+                }
+            }
+            if display.is_key_pressed(*load_key, KeyRepeat::No) {
+                load_slot(&mut cpu, rom_path, slot);
+            }
+        }
+        if display.is_key_pressed(Key::F5, KeyRepeat::No) {
+            if let Some(slot) = last_slot {
+                load_slot(&mut cpu, rom_path, slot);
+            }
+        }
+
+        next_frame += frame_time;
+        let now = Instant::now();
+        if cpu.speed() != 0 && next_frame > now {
+            std::thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
     }
 
     // Drop the audio stream if it exists.
@@ -131,20 +400,133 @@ fn main() -> Result<()> {
         drop(stream)
     }
 
+    // Fill in the sizes of the recording.
+    if let Some(recorder) = recorder {
+        recorder.lock().expect("failed to lock audio recording").finish()
+            .context("failed to finish audio recording")?;
+    }
+
+    // Flushes the trace file.
+    cpu.disable_trace();
+
     // Save.
     cpu.mem.save();
     Ok(())
 }
 
-fn initialise_audio(cpu: &mut CPU) -> Result<Option<cpal::Stream>> {
+// Save states are kept next to the rom as <rom>_state<N>.bin.
+fn state_path(rom_path: &Path, slot: usize) -> PathBuf {
+    let stem = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+    rom_path.with_file_name(format!("{}_state{}.bin", stem, slot))
+}
+
+fn save_state(cpu: &CPU, path: &Path) -> Result<()> {
+    std::fs::write(path, cpu.save_state()?)?;
+    Ok(())
+}
+
+fn load_slot(cpu: &mut CPU, rom_path: &Path, slot: usize) {
+    let result = std::fs::read(state_path(rom_path, slot))
+        .map_err(anyhow::Error::from)
+        .and_then(|data| cpu.load_state(&data).map_err(anyhow::Error::from));
+    match result {
+        std::result::Result::Ok(()) => println!("loaded state {}", slot),
+        Err(e) => eprintln!("failed to load state {}: {:#}", slot, e),
+    }
+}
+
+// Whole frames of audio covering ms milliseconds, at least one.
+fn latency_frames(ms: u32) -> u32 {
+    let frame_ms = 70_224.0 / 4_194_304.0 * 1000.0;
+    (f64::from(ms) / frame_ms).ceil().max(1.0) as u32
+}
+
+fn parse_hex(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s.trim_start_matches("0x").trim_start_matches('$'), 16)
+}
+
+fn print_listing(cpu: &CPU, start: u16, count: u16) {
+    for (address, asm) in cpu.disassemble_range(start, count) {
+        println!("{:04X}: {}", address, asm);
+    }
+}
+
+// Prompt on stdin while stopped at a breakpoint, returns false to quit.
+fn debugger(cpu: &mut CPU) -> Result<bool> {
+    let stdin = std::io::stdin();
+    // What follows the breakpoint, the prompt then shows one instruction at a time.
+    print_listing(cpu, cpu.pc(), 8);
+    loop {
+        let (asm, _) = cpu.disassemble(cpu.pc());
+        print!("{:04X}: {}\n> ", cpu.pc(), asm);
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(false);
+        }
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("s"), _) => {
+                // A breakpoint at the next instruction stops the first tick.
+                let mut cycles = cpu.tick();
+                if cycles == BREAKPOINT_CYCLES { cycles = cpu.tick() }
+                cpu.mem.update(cycles);
+            },
+            (Some("c"), _) => return Ok(true),
+            (Some("r"), _) => println!("{:04X?}", cpu.cpu_state()),
+            (Some("l"), address) => match address.map(parse_hex).unwrap_or(std::result::Result::Ok(cpu.pc())) {
+                std::result::Result::Ok(address) => print_listing(cpu, address, 8),
+                Err(e) => println!("invalid address: {}", e),
+            },
+            (Some("b"), Some(address)) => match parse_hex(address) {
+                std::result::Result::Ok(address) => cpu.add_breakpoint(address),
+                Err(e) => println!("invalid address: {}", e),
+            },
+            (Some("q"), _) => return Ok(false),
+            _ => println!("commands: s (step), c (continue), r (registers), l [hex] (list), b <hex> (add breakpoint), q (quit)"),
+        }
+    }
+}
+
+fn channel_title(title: &str, channels: [bool; 4]) -> String {
+    let labels: Vec<String> = channels.iter().enumerate()
+        .map(|(i, on)| if *on { format!("ch{}", i + 1) } else { "---".to_string() })
+        .collect();
+    format!("{} [{}]", title, labels.join(" "))
+}
 
+fn audio_device() -> Result<(cpal::Device, cpal::SupportedStreamConfig)> {
     let device = cpal::default_host().default_output_device().context("failed to find audio output device.")?;
     let config = device.default_output_config()?;
+    Ok((device, config))
+}
+
+// The cpu must have been built with audio at the config's sample rate.
+fn initialise_audio(
+    cpu: &mut CPU,
+    device: cpal::Device,
+    config: cpal::SupportedStreamConfig,
+    channels: Rc<Cell<[bool; 4]>>,
+    recorder: Option<Recorder>,
+) -> Result<cpal::Stream> {
+
     let err_fn = |err| eprintln!("an error occurred on audio stream: {}", err);
     
-    let apu = APU::power_up(config.sample_rate().0);
+    let apu = cpu.mem.apu.as_mut().context("audio was not enabled")?;
+    apu.set_event_callback(Box::new(move |event| {
+        let mut state = channels.get();
+        match event {
+            ApuEvent::ChannelEnabled { ch }  => state[ch - 1] = true,
+            ApuEvent::ChannelDisabled { ch } => state[ch - 1] = false,
+            _ => return,
+        }
+        channels.set(state);
+    }));
     let stream_buffer = apu.buffer.clone();
-    cpu.mem.apu = Some(apu);
+    // The buffer is empty until the first frame, so only warn once samples have arrived, and only once.
+    let mut playing = false;
+    let mut warned = false;
 
     let stream = device.build_output_stream(
         &config.config(), 
@@ -152,13 +534,29 @@ fn initialise_audio(cpu: &mut CPU) -> Result<Option<cpal::Stream>> {
             let mut in_buf = stream_buffer.lock().expect("failed to lock audio buffer");
             let length = std::cmp::min(out_buf.len() / 2, in_buf.len());
             
-            for (idx, (data_l, data_r)) in in_buf.drain(..length).enumerate() {
-                out_buf[idx * 2] = data_l;
-                out_buf[idx * 2 + 1] = data_r;
+            let samples: Vec<(f32, f32)> = in_buf.drain(..length).collect();
+            for (idx, (data_l, data_r)) in samples.iter().enumerate() {
+                out_buf[idx * 2] = *data_l;
+                out_buf[idx * 2 + 1] = *data_r;
+            }
+            if length < out_buf.len() / 2 {
+                out_buf[length * 2..].fill(0.0);
+                if playing && !warned {
+                    warned = true;
+                    eprintln!("audio buffer underrun, a higher --audio-latency may help");
+                }
+            }
+            playing |= length > 0;
+
+            if let Some(recorder) = &recorder {
+                let mut wav = recorder.lock().expect("failed to lock audio recording");
+                if let Err(e) = wav.write_samples(&samples) {
+                    eprintln!("failed to write audio recording: {}", e);
+                }
             }
         },
         err_fn,
     ).context("failed to build audio stream")?;
     stream.play().context("failed to play audio stream")?;
-    Ok(Some(stream))
+    Ok(stream)
 }
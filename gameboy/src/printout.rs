@@ -0,0 +1,52 @@
+use std::{fs::File, io::BufWriter, path::{Path, PathBuf}};
+use anyhow::{Context, Result};
+
+use core::printer::{Printer, PRINTER_WIDTH};
+
+// Grey levels of the printer shades, white to black.
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+// Printer that saves each print to dir as print_<n>.png.
+pub fn printer(dir: PathBuf) -> Result<Printer> {
+    std::fs::create_dir_all(&dir).context("failed to create printer output directory")?;
+    let mut count = 0;
+    Ok(Printer::new(Box::new(move |image: &[u8], height| {
+        count += 1;
+        let path = dir.join(format!("print_{}.png", count));
+        match write_png(&path, image, height) {
+            Ok(()) => println!("printed {}", path.display()),
+            Err(e) => eprintln!("failed to save print {}: {:#}", path.display(), e),
+        }
+    })))
+}
+
+pub fn write_png(path: &Path, image: &[u8], height: usize) -> Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), PRINTER_WIDTH as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let pixels: Vec<u8> = image.iter().map(|shade| SHADES[*shade as usize & 0x03]).collect();
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use core::printer::PRINTER_WIDTH;
+    use super::write_png;
+
+    #[test]
+    fn png_output() {
+        let path = std::env::temp_dir().join("gameboy_printout_test.png");
+        let image: Vec<u8> = (0..PRINTER_WIDTH * 16).map(|i| (i % 4) as u8).collect();
+        write_png(&path, &image, 16).unwrap();
+
+        let decoder = png::Decoder::new(std::fs::File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (PRINTER_WIDTH as u32, 16));
+        assert_eq!(&pixels[..4], &[0xFF, 0xAA, 0x55, 0x00]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
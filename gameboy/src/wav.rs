@@ -0,0 +1,111 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: usize = 44;
+// Offsets of the RIFF and data chunk sizes, filled in by finish.
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 40;
+
+// Writes stereo 16-bit PCM samples to a WAV file.
+pub struct WavWriter<W: Write + Seek> {
+    writer:     W,
+    data_size:  u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+
+    // Writes the header with empty chunk sizes.
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<Self> {
+        writer.write_all(&header(sample_rate, 0))?;
+        Ok(Self { writer, data_size: 0 })
+    }
+
+    // Samples as output by the APU, clamped to -1.0..=1.0.
+    pub fn write_samples(&mut self, samples: &[(f32, f32)]) -> io::Result<()> {
+        for (l, r) in samples {
+            self.writer.write_all(&to_i16(*l).to_le_bytes())?;
+            self.writer.write_all(&to_i16(*r).to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * u32::from(CHANNELS * BITS_PER_SAMPLE / 8);
+        Ok(())
+    }
+
+    // Fills in the chunk sizes, samples can still be added after which it should be called again.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.writer.write_all(&(HEADER_SIZE as u32 - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+// RIFF header, fmt chunk and the start of the data chunk.
+fn header(sample_rate: u32, data_size: u32) -> [u8; HEADER_SIZE] {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let byte_rate = sample_rate * u32::from(block_align);
+
+    let mut header = [0; HEADER_SIZE];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&(HEADER_SIZE as u32 - 8 + data_size).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16_u32.to_le_bytes());          // fmt chunk size
+    header[20..22].copy_from_slice(&1_u16.to_le_bytes());           // PCM
+    header[22..24].copy_from_slice(&CHANNELS.to_le_bytes());
+    header[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+    header[32..34].copy_from_slice(&block_align.to_le_bytes());
+    header[34..36].copy_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use core::apu::APU;
+    use core::bus::MemoryBus;
+    use super::WavWriter;
+
+    #[test]
+    fn record_frame() {
+        // Square channel 1 at full volume on both sides.
+        let mut apu = APU::power_up(44_100);
+        for (address, b) in [(0xff26, 0x80), (0xff24, 0x77), (0xff25, 0x11), (0xff11, 0x80), (0xff12, 0xf0), (0xff14, 0x87)] {
+            apu.write_byte(address, b);
+        }
+        apu.next(70_224, 0);
+        let samples = std::mem::take(&mut *apu.buffer.lock().unwrap());
+        assert!(!samples.is_empty());
+
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44_100).unwrap();
+        wav.write_samples(&samples).unwrap();
+        wav.finish().unwrap();
+        let bytes = wav.writer.into_inner();
+
+        let data_size = samples.len() as u32 * 4;
+        assert_eq!(bytes.len(), 44 + data_size as usize);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(bytes[4..8], (36 + data_size).to_le_bytes());
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(bytes[16..24], [16, 0, 0, 0, 1, 0, 2, 0]);
+        assert_eq!(bytes[24..28], 44_100_u32.to_le_bytes());
+        assert_eq!(bytes[28..32], (44_100_u32 * 4).to_le_bytes());
+        assert_eq!(bytes[32..36], [4, 0, 16, 0]);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(bytes[40..44], data_size.to_le_bytes());
+
+        let (l, r) = samples[samples.len() / 2];
+        let expected = [super::to_i16(l).to_le_bytes(), super::to_i16(r).to_le_bytes()].concat();
+        let idx = 44 + samples.len() / 2 * 4;
+        assert_eq!(bytes[idx..idx + 4], expected);
+    }
+}
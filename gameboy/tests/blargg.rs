@@ -0,0 +1,47 @@
+// Blargg's cpu_instrs test roms, which report their results over the serial port.
+// These are slow, run them with "cargo test --release --test blargg -- --ignored".
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use core::cartridge::open_cartridge;
+use core::cpu::CPU;
+
+// About 4 minutes of emulated time.
+const MAX_CYCLES: u64 = 1_000_000_000;
+
+fn run_rom(name: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test_roms").join(name);
+    let cartridge = open_cartridge(&path)
+        .unwrap_or_else(|e| panic!("failed to open {}: {}", path.display(), e));
+
+    let output = Rc::new(RefCell::new(String::new()));
+    let sink = output.clone();
+    let mut cpu = CPU::new(
+        cartridge,
+        Some(Box::new(move |b: u8| sink.borrow_mut().push(b as char))),
+    );
+
+    let mut total_cycles = 0_u64;
+    let mut checked_len = 0;
+    while total_cycles < MAX_CYCLES {
+        let cycles = cpu.tick();
+        cpu.mem.update(cycles);
+        total_cycles += cycles as u64;
+
+        // Only search the output when something new has been written.
+        let output = output.borrow();
+        if output.len() != checked_len {
+            checked_len = output.len();
+            if output.contains("Passed") {
+                return;
+            }
+            assert!(!output.contains("Failed"), "{} failed:\n{}", name, output);
+        }
+    }
+    panic!("{} timed out:\n{}", name, output.borrow());
+}
+
+#[test]
+#[ignore = "slow, run with --release"]
+fn cpu_instrs() { run_rom("cpu_instrs.gb") }
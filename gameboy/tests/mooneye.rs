@@ -0,0 +1,75 @@
+// Mooneye-gb acceptance test roms, which report their results in the registers.
+// The roms aren't in the tree, they go in test_roms/mooneye laid out as in the
+// mooneye-test-suite release.
+// Run them with "cargo test --release --features integration_tests --test mooneye -- --ignored".
+#![cfg(feature = "integration_tests")]
+use std::path::Path;
+
+use core::cartridge::open_cartridge;
+use core::cpu::CPU;
+
+// About 24 seconds of emulated time.
+const MAX_CYCLES: u64 = 100_000_000;
+
+// Registers (B, C, D, E, H, L) once a test has finished.
+const PASSED: (u8, u8, u8, u8, u8, u8) = (3, 5, 8, 13, 21, 34);
+const FAILED: (u8, u8, u8, u8, u8, u8) = (0x42, 0x42, 0x42, 0x42, 0x42, 0x42);
+
+fn run_rom(name: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test_roms/mooneye").join(name);
+    let cartridge = open_cartridge(&path)
+        .unwrap_or_else(|e| panic!("failed to open {}: {}", path.display(), e));
+    let mut cpu = CPU::new(cartridge, None);
+
+    let mut total_cycles = 0_u64;
+    while total_cycles < MAX_CYCLES {
+        let cycles = cpu.tick();
+        cpu.mem.update(cycles);
+        total_cycles += cycles as u64;
+
+        match cpu.register_snapshot() {
+            PASSED => return,
+            FAILED => panic!("{} failed", name),
+            _ => {},
+        }
+    }
+    panic!("{} timed out, registers {:?}", name, cpu.register_snapshot());
+}
+
+macro_rules! mooneye_tests {
+    ($($test:ident => $rom:literal,)*) => {
+        $(
+            #[test]
+            #[ignore = concat!("needs test_roms/mooneye/", $rom)]
+            fn $test() { run_rom($rom) }
+        )*
+    };
+}
+
+mooneye_tests! {
+    halt_ime0_ei                => "acceptance/halt_ime0_ei.gb",
+    halt_ime0_nointr_timing     => "acceptance/halt_ime0_nointr_timing.gb",
+    halt_ime1_timing            => "acceptance/halt_ime1_timing.gb",
+    halt_ime1_timing2           => "acceptance/halt_ime1_timing2-GS.gb",
+    intr_timing                 => "acceptance/intr_timing.gb",
+    ei_sequence                 => "acceptance/ei_sequence.gb",
+    ei_timing                   => "acceptance/ei_timing.gb",
+    di_timing                   => "acceptance/di_timing-GS.gb",
+    rapid_di_ei                 => "acceptance/rapid_di_ei.gb",
+    oam_dma_restart             => "acceptance/oam_dma_restart.gb",
+    oam_dma_start               => "acceptance/oam_dma_start.gb",
+    oam_dma_timing              => "acceptance/oam_dma_timing.gb",
+    timer_div_write             => "acceptance/timer/div_write.gb",
+    timer_rapid_toggle          => "acceptance/timer/rapid_toggle.gb",
+    timer_tim00                 => "acceptance/timer/tim00.gb",
+    timer_tim00_div_trigger     => "acceptance/timer/tim00_div_trigger.gb",
+    timer_tim01                 => "acceptance/timer/tim01.gb",
+    timer_tim01_div_trigger     => "acceptance/timer/tim01_div_trigger.gb",
+    timer_tim10                 => "acceptance/timer/tim10.gb",
+    timer_tim10_div_trigger     => "acceptance/timer/tim10_div_trigger.gb",
+    timer_tim11                 => "acceptance/timer/tim11.gb",
+    timer_tim11_div_trigger     => "acceptance/timer/tim11_div_trigger.gb",
+    timer_tima_reload           => "acceptance/timer/tima_reload.gb",
+    timer_tima_write_reloading  => "acceptance/timer/tima_write_reloading.gb",
+    timer_tma_write_reloading   => "acceptance/timer/tma_write_reloading.gb",
+}
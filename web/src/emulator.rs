@@ -1,47 +1,396 @@
-use core::cpu::CPU;
-use core::cartridge::{open_cartridge, Cartridge};
-use core::keypad::GbKey;
+use gb::cpu::CPU;
+use gb::builder::{self, GameboyBuilder};
+use gb::cartridge::{open_cartridge, Cartridge, CartError};
+use gb::keypad::GbKey;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+use web_sys::ImageData;
+#[cfg(feature = "video-record")]
+use web_sys::HtmlCanvasElement;
+#[cfg(feature = "video-record")]
+use crate::recorder::VideoRecorder;
 
 pub const DEMO_DATA: &'static [u8] = include_bytes!("../pocket.gb");
+// Time a tick may spend running frames, leaving the rest of the
+// 16ms tick to the browser.
+const UNCAPPED_BUDGET_MS: f64 = 12.0;
 
-pub struct Emulator(pub CPU);
+// Stats collected over the last emulated frame.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct FrameStats {
+    pub frame_cycles:           u32,
+    pub dropped_audio_samples:  u32,
+    pub render_time_ms:         f64,
+}
+
+#[wasm_bindgen]
+pub struct Emulator {
+    cpu:            CPU,
+    stats:          FrameStats,
+    frame_ready:    bool,
+    #[cfg(feature = "video-record")]
+    recorder:       Option<VideoRecorder>,
+}
 
 impl Default for Emulator {
     fn default() -> Self {
         let demo = open_cartridge(DEMO_DATA.to_vec(), None).unwrap();
-        Self(CPU::new(demo, None))
+        Self::new(demo)
     }
 }
 
 impl Emulator {
 
     pub fn new(rom_data: Box<dyn Cartridge>) -> Self {
-        Self(CPU::new(rom_data, None))
+        // Building only fails when opening the cartridge, which has already been done.
+        Self::from_cpu(GameboyBuilder::new().cartridge(rom_data).build().unwrap())
     }
 
+    fn from_cpu(cpu: CPU) -> Self {
+        Self {
+            cpu,
+            stats:          FrameStats::default(),
+            frame_ready:    false,
+            #[cfg(feature = "video-record")]
+            recorder:       None,
+        }
+    }
+
+    // Runs one frame per unit of speed, stopping early once the time budget is spent.
+    // Uncapped runs frames until the budget is spent.
     pub fn tick(&mut self) {
-        let mut frame_cycles = 0;
-        while frame_cycles < 69_905 {
-            let cycles = self.0.tick();
-            self.0.mem.update(cycles);
-            frame_cycles += cycles;
+        let performance = gloo::utils::window().performance();
+        let start = performance.as_ref().map_or(0.0, |p| p.now());
+        let frames = match self.cpu.speed() {
+            0 => u32::MAX,
+            n => n,
+        };
+
+        for _ in 0..frames {
+            let frame = self.cpu.run_until_vblank();
+            self.frame_ready = frame.breakpoint_hit.is_none();
+            self.stats.frame_cycles = frame.cycles_elapsed;
+            if !self.frame_ready {
+                break;
+            }
+            if !performance.as_ref().is_some_and(|p| p.now() - start <= UNCAPPED_BUDGET_MS) {
+                break;
+            }
         }
+        self.stats.dropped_audio_samples = self.cpu.mem.dropped_audio_samples();
     }
 
     pub fn is_display_updated(&mut self) -> bool {
-        self.0.mem.gpu.check_updated()
+        std::mem::take(&mut self.frame_ready)
     }
 
+    // Converts the gpu pixel buffer into canvas image data, timing the conversion.
+    pub fn image_data(&mut self) -> ImageData {
+        let performance = gloo::utils::window().performance();
+        let start = performance.as_ref().map_or(0.0, |p| p.now());
+
+        let img_data = ImageData::new_with_u8_clamped_array(
+            Clamped(self.cpu.mem.gpu.pixels.as_slice()),
+            160,
+        ).unwrap();
+
+        self.stats.render_time_ms = performance.map_or(0.0, |p| p.now() - start);
+        img_data
+    }
+
+    pub fn stats(&self) -> FrameStats { self.stats }
+
     pub fn key_down(&mut self, key: GbKey) {
-        self.0.mem.keypad.key_press(key);
+        self.cpu.mem.keypad.key_press(key);
     }
 
     pub fn key_up(&mut self, key: GbKey) {
-        self.0.mem.keypad.key_release(key);
+        self.cpu.mem.keypad.key_release(key);
     }
 
     pub fn change_palette(&mut self, palette: [u32; 4]) {
-        self.0.mem.gpu.set_colours(palette);
+        self.cpu.mem.gpu.set_colours(palette);
     }
 }
 
+#[wasm_bindgen]
+impl Emulator {
+
+    // Loads a rom for use from JavaScript, errors are thrown as JS Error objects.
+    pub fn from_rom(bytes: Vec<u8>) -> Result<Emulator, JsError> {
+        EmulatorBuilder::new().rom(bytes).build()
+    }
+
+    pub fn is_cgb(&self) -> bool { self.cpu.is_cgb() }
+
+    // Frames run per tick, 0 runs as many as time allows.
+    pub fn set_speed(&mut self, speed: u32) {
+        self.cpu.set_speed(speed);
+    }
+
+    pub fn speed(&self) -> u32 { self.cpu.speed() }
+
+    // Changes the colours once the given frame has been drawn, for effects timed to the game.
+    // The palette is 4 colours as 0xRRGGBB, lightest first.
+    pub fn schedule_palette(&mut self, frame: u64, palette: Vec<u32>) -> Result<(), JsError> {
+        let palette = <[u32; 4]>::try_from(palette)
+            .map_err(|p| JsError::new(&format!("a palette is 4 colours, got {}", p.len())))?;
+        self.cpu.mem.gpu.palette_scheduler.schedule_at(frame, palette);
+        Ok(())
+    }
+
+    // Channel 0-3 for audio channels 1-4.
+    pub fn mute_channel(&mut self, ch: u8, muted: bool) {
+        self.cpu.mem.mute_channel(ch, muted);
+    }
+
+    // Accelerometer of MBC7 cartridges, -512 to 512 for -1g to 1g on each axis.
+    // Intended to be fed from DeviceMotionEvent.accelerationIncludingGravity.
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        self.cpu.mem.set_tilt(x, y);
+    }
+
+    // Starts collecting sound at sample_rate, before the rom is run.
+    #[cfg(feature = "audio")]
+    pub fn enable_audio(&mut self, sample_rate: u32) {
+        self.cpu.mem.enable_audio(sample_rate);
+    }
+
+    // Calls callback with each channel change as {type, ch, hz | vol}, e.g. to draw a
+    // visualiser. Sound has to be enabled first.
+    #[cfg(feature = "audio")]
+    pub fn set_apu_event_callback(&mut self, callback: js_sys::Function) -> Result<(), JsError> {
+        let apu = self.cpu.mem.apu.as_mut()
+            .ok_or_else(|| JsError::new("enable_audio has to be called first"))?;
+        apu.set_event_callback(Box::new(move |event| {
+            if let Ok(event) = serde_wasm_bindgen::to_value(&event) {
+                let _ = callback.call1(&JsValue::NULL, &event);
+            }
+        }));
+        Ok(())
+    }
+
+    // Sound since the last call, interleaved left and right, for an AudioBuffer or AudioWorklet.
+    #[cfg(feature = "audio")]
+    pub fn drain_audio_samples(&mut self) -> Vec<f32> {
+        match &self.cpu.mem.apu {
+            Some(apu) => apu.buffer.lock().unwrap().drain(..).flat_map(|(l, r)| [l, r]).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Infrared signal from another emulator, e.g. relayed over a WebRTC data channel
+    // with the other side's ir_led.
+    pub fn set_ir_signal(&mut self, active: bool) {
+        self.cpu.mem.set_ir_input(active);
+    }
+
+    // Whether the game has its infrared LED on.
+    pub fn ir_led(&self) -> bool {
+        self.cpu.mem.ir_led()
+    }
+
+    // Rumble motor of MBC5 rumble cartridges.
+    pub fn is_rumbling(&self) -> bool {
+        self.cpu.mem.is_rumbling()
+    }
+
+    // Cartridge RAM, as taken back by EmulatorBuilder::save_data.
+    pub fn save_data(&self) -> Vec<u8> {
+        self.cpu.mem.save_data()
+    }
+
+    // Save state of the whole system, empty if encoding fails.
+    pub fn get_state(&self) -> Vec<u8> {
+        self.cpu.save_state().unwrap_or_default()
+    }
+
+    // False if the state is invalid or was saved with a different rom.
+    pub fn load_state(&mut self, data: Vec<u8>) -> bool {
+        self.cpu.load_state(&data).is_ok()
+    }
+
+    // Seconds of history kept to rewind through, 10 by default, 0 disables it.
+    pub fn set_rewind_seconds(&mut self, seconds: u32) {
+        self.cpu.set_rewind_frames(seconds as usize * 60);
+    }
+
+    // Steps back a frame, for a rewind button held down. False once the history runs out.
+    pub fn rewind(&mut self) -> bool {
+        self.frame_ready = self.cpu.rewind(1);
+        self.frame_ready
+    }
+
+    // Stats of the last frame as a plain JS object.
+    pub fn frame_stats(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.stats).unwrap_or(JsValue::NULL)
+    }
+}
+
+#[cfg(feature = "video-record")]
+impl Emulator {
+
+    pub fn is_recording(&self) -> bool { self.recorder.is_some() }
+
+    // Stops any recording, the future resolves to the WebM file.
+    pub fn finish_video_recording(&mut self) -> Option<impl std::future::Future<Output = Result<Vec<u8>, JsValue>>> {
+        self.recorder.take().map(VideoRecorder::stop)
+    }
+}
+
+#[cfg(feature = "video-record")]
+#[wasm_bindgen]
+impl Emulator {
+
+    // Starts recording the canvas the screen is drawn to.
+    pub fn start_video_recording(&mut self, canvas: &HtmlCanvasElement) -> Result<(), JsValue> {
+        if self.recorder.is_none() {
+            self.recorder = Some(VideoRecorder::from_canvas(canvas)?);
+        }
+        Ok(())
+    }
+
+    // Returns a promise resolving to the WebM file as a Uint8Array, or null if not recording.
+    pub fn stop_video_recording(&mut self) -> js_sys::Promise {
+        match self.finish_video_recording() {
+            Some(video) => wasm_bindgen_futures::future_to_promise(async move {
+                video.await.map(|bytes| js_sys::Uint8Array::from(bytes.as_slice()).into())
+            }),
+            None => js_sys::Promise::resolve(&JsValue::NULL),
+        }
+    }
+}
+
+// Hardware to emulate, see gb::builder::CompatMode.
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub enum CompatMode {
+    Auto,
+    Dmg,
+    Cgb,
+    Sgb,
+}
+
+// GameboyBuilder for JavaScript, e.g.
+//   new EmulatorBuilder().rom(bytes).save_data(save).cgb_mode(CompatMode.Dmg).build()
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct EmulatorBuilder(GameboyBuilder);
+
+#[wasm_bindgen]
+impl EmulatorBuilder {
+
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self { Self::default() }
+
+    pub fn rom(self, data: Vec<u8>) -> Self { Self(self.0.rom(data)) }
+
+    pub fn save_data(self, data: Vec<u8>) -> Self { Self(self.0.save_data(data)) }
+
+    pub fn cgb_mode(self, mode: CompatMode) -> Self {
+        Self(self.0.cgb_mode(match mode {
+            CompatMode::Auto => builder::CompatMode::Auto,
+            CompatMode::Dmg  => builder::CompatMode::Dmg,
+            CompatMode::Cgb  => builder::CompatMode::Cgb,
+            CompatMode::Sgb  => builder::CompatMode::Sgb,
+        }))
+    }
+
+    // Errors are thrown as JS Error objects.
+    pub fn build(self) -> Result<Emulator, JsError> {
+        self.0.build()
+            .map(Emulator::from_cpu)
+            .map_err(|e| JsError::new(&cart_error_message(&e)))
+    }
+}
+
+// Explanation of why a rom could not be loaded, for showing to the user.
+pub fn cart_error_message(err: &CartError) -> String {
+    match err {
+        CartError::IncorrectLogo =>
+            "This file is not a valid Game Boy ROM.".to_string(),
+        CartError::IncorrectChecksum =>
+            "This ROM's header is corrupted, its checksum does not match.".to_string(),
+        CartError::UnsupportedCartType(t) =>
+            format!("This ROM uses a cartridge type that is not supported yet ({:#04X}).", t),
+        CartError::MissingInfo =>
+            "This file is too small to be a Game Boy ROM.".to_string(),
+        CartError::CorruptSave =>
+            "The save file is corrupted and could not be loaded.".to_string(),
+        e => format!("Error loading ROM: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wasm_bindgen_test::wasm_bindgen_test;
+    use gb::cartridge::{open_cartridge, CartError};
+    use super::{Emulator, EmulatorBuilder, CompatMode, DEMO_DATA, cart_error_message};
+
+    #[wasm_bindgen_test]
+    fn frame_stats() {
+        let mut emulator = Emulator::default();
+        // The first frame starts part way through, from power on.
+        emulator.tick();
+        emulator.tick();
+        assert!(emulator.stats().frame_cycles.abs_diff(70_224) <= 16);
+        assert!(emulator.is_display_updated());
+        assert!(!emulator.frame_stats().is_null());
+    }
+
+    #[wasm_bindgen_test]
+    fn cart_errors() {
+        let err = open_cartridge(vec![0; 0x100], None).err().unwrap();
+        assert!(matches!(err, CartError::MissingInfo));
+        assert_eq!(cart_error_message(&err), "This file is too small to be a Game Boy ROM.");
+
+        let mut rom = DEMO_DATA.to_vec();
+        rom[0x104] ^= 0xFF;
+        let err = open_cartridge(rom, None).err().unwrap();
+        assert_eq!(cart_error_message(&err), "This file is not a valid Game Boy ROM.");
+
+        let mut rom = DEMO_DATA.to_vec();
+        rom[0x147] = 0xFC;
+        let err = open_cartridge(rom, None).err().unwrap();
+        assert!(cart_error_message(&err).ends_with("(0xFC)."));
+
+        assert!(Emulator::from_rom(vec![0; 0x100]).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn builder() {
+        let emulator = EmulatorBuilder::new().rom(DEMO_DATA.to_vec()).build().unwrap();
+        assert!(!emulator.is_cgb());
+
+        let emulator = EmulatorBuilder::new()
+            .rom(DEMO_DATA.to_vec())
+            .cgb_mode(CompatMode::Cgb)
+            .build()
+            .unwrap();
+        assert!(emulator.is_cgb());
+
+        assert!(EmulatorBuilder::new().build().is_err());
+        assert!(EmulatorBuilder::new().rom(DEMO_DATA.to_vec()).save_data(vec![0; 3]).build().is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn save_state() {
+        let mut emulator = Emulator::default();
+        emulator.tick();
+        let state = emulator.get_state();
+        assert!(!state.is_empty());
+
+        let mut restored = Emulator::default();
+        assert!(restored.load_state(state.clone()));
+        assert_eq!(restored.get_state(), state);
+        assert!(!restored.load_state(vec![0; 16]));
+    }
+
+    #[wasm_bindgen_test]
+    fn schedule_palette() {
+        let mut emulator = Emulator::default();
+        assert!(emulator.schedule_palette(1, vec![0xFFFFFF, 0xAAAAAA, 0x555555]).is_err());
+        assert!(emulator.schedule_palette(1, vec![0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]).is_ok());
+    }
+}
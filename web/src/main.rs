@@ -4,22 +4,26 @@ use gloo::{
     timers::callback::Interval, 
     utils::document, 
     events::EventListener,
-    dialogs::alert,
     file::File,
+    net::http::Request,
 };
 use web_sys::{
     HtmlCanvasElement,
     HtmlInputElement,
-    ImageData,
     CanvasRenderingContext2d,
+    Gamepad,
+    GamepadButton,
 };
 use wasm_bindgen::JsCast;
-use core::{keypad::GbKey, cartridge::{open_cartridge, Cartridge}};
-use emulator::Emulator;
+use gb::{keypad::GbKey, cartridge::{open_cartridge, Cartridge}};
+use emulator::{Emulator, cart_error_message};
 use panel::{Panel, InfoProps};
 
 const FRAME_TIME: u32 = 16; // Approx 60 FPS.
 const SCALE: f64 = 4.0;
+const FAST_FORWARD: u32 = 4;
+// Ticks between writes of the cartridge RAM to browser storage, about a second.
+const SAVE_INTERVAL: u32 = 60;
 const PALETTES: [(&str, [u32; 4]); 10] = [
     ("Classic",         [0xe0f8d0, 0x88c070, 0x346856, 0x081820]),
     ("2Bit Demichrome", [0xe9efec, 0xa0a08b, 0x555568, 0x211e20]),
@@ -32,9 +36,23 @@ const PALETTES: [(&str, [u32; 4]); 10] = [
     ("Lollipop",        [0xe6f2ef, 0xf783b0, 0x3f6d9e, 0x151640]),
     ("Soviet",          [0xe8d6c0, 0x92938d, 0xa1281c, 0x000000]),
 ];
+// Button indices of the standard gamepad mapping.
+const GAMEPAD_BUTTONS: [(usize, GbKey); 8] = [
+    (0,  GbKey::A),
+    (1,  GbKey::B),
+    (8,  GbKey::Select),
+    (9,  GbKey::Start),
+    (12, GbKey::Up),
+    (13, GbKey::Down),
+    (14, GbKey::Left),
+    (15, GbKey::Right),
+];
 
 mod emulator;
 mod panel;
+mod storage;
+#[cfg(feature = "video-record")]
+mod recorder;
 
 fn main() {
     yew::Renderer::<App>::new().render();
@@ -50,12 +68,29 @@ pub struct App {
     saveable:           bool,
 
     pallette_idx:       usize,
+    muted:              [bool; 4],
+    // Shown under the controls when a rom fails to load.
+    error:              Option<AttrValue>,
     
     canvas:             NodeRef,
     ctx:                Option<CanvasRenderingContext2d>,
     // Dropping interval will stop it from ticking.
     interval:           Interval,
     paused:             bool,
+    // Rumble state after the last tick, the device vibrates when it starts.
+    rumbling:           bool,
+    // Touches held on the on-screen buttons, by identifier.
+    touches:            Vec<(i32, GbKey)>,
+    // Buttons held on any gamepad at the last poll, keys are only sent on changes.
+    prev_gamepad_state: [bool; 16],
+    gamepad_connected:  bool,
+
+    // Ticks since the cartridge RAM was last checked for changes.
+    save_ticks:         u32,
+    // Cartridge RAM as last written to storage.
+    last_save:          Vec<u8>,
+    // Stops saving after a failed write, which is reported once.
+    save_failed:        bool,
     // Dropping these listeners will remove them from the document.
     _key_up_listen:      EventListener,
     _key_down_listen:    EventListener,
@@ -65,12 +100,24 @@ pub struct App {
 
 pub enum Msg {
     Tick,
+    GamepadPoll,
     Pause,
     KeyDown(GbKey),
     KeyUp(GbKey),
+    TouchStart(GbKey, Vec<i32>),
+    TouchEnd(Vec<i32>),
+    ClearSave,
+    SaveFailed(String),
     FileUpload(File),
     NewROM(Box<dyn Cartridge>),
+    LoadError(String),
     CyclePalette,
+    ToggleFastForward,
+    MuteChannel(u8, bool),
+    #[cfg(feature = "video-record")]
+    ToggleRecording,
+    #[cfg(feature = "video-record")]
+    RecordingFinished(Result<Vec<u8>, String>),
 }
 
 impl Component for App {
@@ -83,6 +130,7 @@ impl Component for App {
         let interval = {
             let link = ctx.link().clone();
             Interval::new(FRAME_TIME, move || {
+                link.send_message(Msg::GamepadPoll);
                 link.send_message(Msg::Tick);
             })
         };
@@ -137,6 +185,11 @@ impl Component for App {
             }    
         });
 
+        // Roms can be linked to with ?rom=<url>.
+        if let Some(url) = rom_url() {
+            ctx.link().send_future_batch(fetch_rom(url));
+        }
+
         Self {
             emulator: Emulator::default(),
             is_cgb: false,
@@ -146,9 +199,18 @@ impl Component for App {
             cart_type: "ROM only".into(),
             canvas: NodeRef::default(),
             pallette_idx: 1,
+            muted: [false; 4],
+            error: None,
             ctx: None,
             interval,
             paused: false,
+            rumbling: false,
+            touches: Vec::new(),
+            prev_gamepad_state: [false; 16],
+            gamepad_connected: false,
+            save_ticks: 0,
+            last_save: Vec::new(),
+            save_failed: false,
             _key_up_listen: key_up,
             _key_down_listen: key_down,
             file_reader: None,
@@ -166,9 +228,38 @@ impl Component for App {
                 if self.emulator.is_display_updated() {
                     self.render_frame();
                 }
+                let rumbling = self.emulator.is_rumbling();
+                if rumbling && !self.rumbling {
+                    gloo::utils::window().navigator().vibrate_with_duration(10);
+                }
+                self.rumbling = rumbling;
+
+                self.save_ticks += 1;
+                if self.save_ticks >= SAVE_INTERVAL {
+                    self.save_ticks = 0;
+                    self.store_save(ctx);
+                }
                 true
             },
 
+            // Only renders when a gamepad is connected or disconnected.
+            Msg::GamepadPoll => {
+                let state = gamepad_state();
+                let connected = state.is_some();
+                let state = state.unwrap_or_default();
+                for (idx, key) in GAMEPAD_BUTTONS {
+                    match (self.prev_gamepad_state[idx], state[idx]) {
+                        (false, true) => self.emulator.key_down(key),
+                        (true, false) => self.emulator.key_up(key),
+                        _ => {},
+                    }
+                }
+                self.prev_gamepad_state = state;
+                let changed = connected != self.gamepad_connected;
+                self.gamepad_connected = connected;
+                changed
+            },
+
             Msg::Pause => {
                 self.paused = !self.paused;
                 true
@@ -184,22 +275,31 @@ impl Component for App {
                 false
             },
 
+            Msg::TouchStart(key, ids) => {
+                self.touches.extend(ids.into_iter().map(|id| (id, key.clone())));
+                self.emulator.key_down(key);
+                true
+            },
+
+            // A button stays down while any touch is still holding it.
+            Msg::TouchEnd(ids) => {
+                let (ended, held): (Vec<_>, Vec<_>) = self.touches.drain(..).partition(|(id, _)| ids.contains(id));
+                self.touches = held;
+                for (_, key) in ended {
+                    if !self.touches.iter().any(|(_, k)| *k == key) {
+                        self.emulator.key_up(key);
+                    }
+                }
+                true
+            },
+
             Msg::FileUpload(file) => {
                 let link = ctx.link().clone();
                 self.file_reader = Some(gloo::file::callbacks::read_as_bytes(&file, move |bytes| {
 
                     match bytes {
-                        Ok(bytes) => {
-                            match open_cartridge(bytes, None) {
-                            Ok(cartridge) => {                
-                                link.send_message(Msg::NewROM(cartridge));
-                            },
-                            
-                            Err(e) => alert(&format!("Error loading ROM: {}", e)),
-                            }
-                        },
-                        
-                        Err(e) => alert(&format!("Failed to read bytes: {}", e)),
+                        Ok(bytes) => link.send_future(load_rom(bytes)),
+                        Err(e) => link.send_message(Msg::LoadError(format!("Failed to read file: {}", e))),
                     }
                 }));
                 self.paused = false;
@@ -207,12 +307,41 @@ impl Component for App {
             },
 
             Msg::NewROM(cartridge) => {
-                self.rom_name = cartridge.title().into();
-                self.rom_size = cartridge.len();
-                self.is_cgb = cartridge.is_cgb();
-                self.cart_type = cartridge.cartridge_type().into();
-                self.saveable = cartridge.is_saveable();
+                #[cfg(feature = "video-record")]
+                self.finish_recording(ctx);
+                let info = cartridge.info();
+                self.rom_name = info.title.into();
+                self.rom_size = info.rom_size_bytes;
+                self.is_cgb = info.is_cgb;
+                self.cart_type = info.cart_type_name.into();
+                self.saveable = info.is_saveable;
                 self.emulator = Emulator::new(cartridge);
+                self.last_save = self.emulator.save_data();
+                self.save_failed = false;
+                for (ch, muted) in self.muted.into_iter().enumerate() {
+                    self.emulator.mute_channel(ch as u8, muted);
+                }
+                self.error = None;
+                true
+            },
+
+            Msg::ClearSave => {
+                let key = storage::save_key(&self.rom_name);
+                ctx.link().send_future_batch(async move {
+                    storage::delete(&key).await;
+                    None
+                });
+                false
+            },
+
+            Msg::SaveFailed(message) => {
+                self.save_failed = true;
+                gloo::dialogs::alert(&format!("Failed to save: {}", message));
+                false
+            },
+
+            Msg::LoadError(message) => {
+                self.error = Some(message.into());
                 true
             },
 
@@ -226,11 +355,50 @@ impl Component for App {
                 
                 true
             },
+
+            Msg::ToggleFastForward => {
+                let speed = if self.emulator.speed() == 1 { FAST_FORWARD } else { 1 };
+                self.emulator.set_speed(speed);
+                true
+            },
+
+            Msg::MuteChannel(ch, muted) => {
+                self.muted[ch as usize] = muted;
+                self.emulator.mute_channel(ch, muted);
+                true
+            },
+
+            #[cfg(feature = "video-record")]
+            Msg::ToggleRecording => {
+                if self.emulator.is_recording() {
+                    self.finish_recording(ctx);
+                } else {
+                    let canvas = self.canvas.cast::<HtmlCanvasElement>().unwrap();
+                    if let Err(e) = self.emulator.start_video_recording(&canvas) {
+                        self.error = Some(format!("Failed to start recording: {:?}", e).into());
+                    }
+                }
+                true
+            },
+
+            #[cfg(feature = "video-record")]
+            Msg::RecordingFinished(video) => {
+                match video {
+                    Ok(bytes) => {
+                        if let Err(e) = download_video(&bytes, &format!("{}.webm", self.rom_name.to_lowercase())) {
+                            self.error = Some(format!("Failed to save recording: {:?}", e).into());
+                        }
+                    },
+                    Err(e) => self.error = Some(e.into()),
+                }
+                true
+            },
         }
     }
 
     fn view(&self, ctx: &Context<Self>) -> yew::Html {
 
+        let stats = self.emulator.stats();
         let info_props = props!(
             InfoProps {
                 is_cgb:     self.is_cgb, 
@@ -239,6 +407,10 @@ impl Component for App {
                 cart_type:  self.cart_type.clone(),
                 saveable:   self.saveable,
                 pallette:   AttrValue::from(PALETTES[self.pallette_idx].0),
+                muted:      self.muted,
+                on_mute:    ctx.link().callback(|(ch, muted)| Msg::MuteChannel(ch, muted)),
+                gamepad:    self.gamepad_connected,
+                on_clear_save: ctx.link().callback(|_| Msg::ClearSave),
             }
         );
 
@@ -254,6 +426,8 @@ impl Component for App {
                         height={(144 * SCALE as usize).to_string()}
                         ref={self.canvas.clone()}>
                     </canvas>
+
+                    { self.touch_controls(ctx) }
                     
                     <div class="button-row">
 
@@ -294,7 +468,34 @@ impl Component for App {
                             {"\u{00a0}Change Palette"}
                         </button>
 
+                        <button onclick={ctx.link().callback(|_| Msg::ToggleFastForward)} class="control-button">
+                            <i class="gg-play-forwards"></i>
+                            { if self.emulator.speed() == 1 { "\u{00a0}Fast Forward" } else { "\u{00a0}Normal Speed" } }
+                        </button>
+
+                        { self.record_button(ctx) }
+
                     </div>
+
+                    if let Some(error) = &self.error {
+                        <p class="error">{error}</p>
+                    }
+
+                    <details class="debug">
+                        <summary>{"Debug"}</summary>
+                        <p>
+                            {"Frame cycles: "}
+                            <span style="float:right;">{stats.frame_cycles}</span>
+                        </p>
+                        <p>
+                            {"Dropped audio samples: "}
+                            <span style="float:right;">{stats.dropped_audio_samples}</span>
+                        </p>
+                        <p>
+                            {"Render time: "}
+                            <span style="float:right;">{format!("{:.3} ms", stats.render_time_ms)}</span>
+                        </p>
+                    </details>
                 </div>
             </div>
             <br/>
@@ -323,13 +524,180 @@ impl App {
             }
         };
         
-        let clamped_arr = wasm_bindgen::Clamped(self.emulator.0.mem.gpu.pixels.as_slice());
-        let img_data = ImageData::new_with_u8_clamped_array(
-            clamped_arr,
-            160,
-        ).unwrap();
-
+        let img_data = self.emulator.image_data();
         ctx.put_image_data(&img_data, 0.0, 0.0).unwrap();
         ctx.draw_image_with_html_canvas_element(&ctx.canvas().unwrap(), 0_f64, 0_f64).unwrap();
     }
+
+    #[cfg(feature = "video-record")]
+    fn finish_recording(&mut self, ctx: &Context<Self>) {
+        if let Some(video) = self.emulator.finish_video_recording() {
+            let link = ctx.link().clone();
+            yew::platform::spawn_local(async move {
+                let video = video.await.map_err(|e| format!("Failed to finish recording: {:?}", e));
+                link.send_message(Msg::RecordingFinished(video));
+            });
+        }
+    }
+
+    #[cfg(feature = "video-record")]
+    fn record_button(&self, ctx: &Context<Self>) -> Html {
+        let label = if self.emulator.is_recording() {
+            "\u{23f9}\u{00a0}Stop Recording"
+        } else {
+            "\u{1f3ac}\u{00a0}Record Video"
+        };
+        html! {
+            <button onclick={ctx.link().callback(|_| Msg::ToggleRecording)} class="control-button">
+                {label}
+            </button>
+        }
+    }
+
+    #[cfg(not(feature = "video-record"))]
+    fn record_button(&self, _: &Context<Self>) -> Html {
+        html! {}
+    }
+
+    // Writes the cartridge RAM to browser storage if it has changed.
+    fn store_save(&mut self, ctx: &Context<Self>) {
+        if !self.saveable || self.save_failed {
+            return;
+        }
+        let data = self.emulator.save_data();
+        if data == self.last_save {
+            return;
+        }
+        self.last_save = data.clone();
+        let key = storage::save_key(&self.rom_name);
+        ctx.link().send_future_batch(async move {
+            storage::store(&key, &data).await.err().map(Msg::SaveFailed)
+        });
+    }
+
+    // On-screen buttons, hidden on devices with a mouse.
+    fn touch_controls(&self, ctx: &Context<Self>) -> Html {
+        let start = |key: GbKey| ctx.link().callback(move |e: TouchEvent| {
+            e.prevent_default();
+            Msg::TouchStart(key.clone(), changed_touches(&e))
+        });
+        let end = ctx.link().callback(|e: TouchEvent| {
+            e.prevent_default();
+            Msg::TouchEnd(changed_touches(&e))
+        });
+        let class = |key: GbKey| classes!(
+            "touch-button",
+            self.touches.iter().any(|(_, k)| *k == key).then_some("pressed"),
+        );
+
+        html! {
+            <div class="touch-controls">
+                <svg class="dpad" viewBox="0 0 120 120">
+                    <polygon points="60,52 32,12 88,12" class={class(GbKey::Up)}
+                        ontouchstart={start(GbKey::Up)} ontouchend={end.clone()} ontouchcancel={end.clone()}/>
+                    <polygon points="60,68 32,108 88,108" class={class(GbKey::Down)}
+                        ontouchstart={start(GbKey::Down)} ontouchend={end.clone()} ontouchcancel={end.clone()}/>
+                    <polygon points="52,60 12,32 12,88" class={class(GbKey::Left)}
+                        ontouchstart={start(GbKey::Left)} ontouchend={end.clone()} ontouchcancel={end.clone()}/>
+                    <polygon points="68,60 108,32 108,88" class={class(GbKey::Right)}
+                        ontouchstart={start(GbKey::Right)} ontouchend={end.clone()} ontouchcancel={end.clone()}/>
+                </svg>
+
+                <svg class="start-select-buttons" viewBox="0 0 120 50">
+                    <circle cx="30" cy="18" r="12" class={class(GbKey::Select)}
+                        ontouchstart={start(GbKey::Select)} ontouchend={end.clone()} ontouchcancel={end.clone()}/>
+                    <text x="30" y="46" class="touch-label">{"SELECT"}</text>
+                    <circle cx="90" cy="18" r="12" class={class(GbKey::Start)}
+                        ontouchstart={start(GbKey::Start)} ontouchend={end.clone()} ontouchcancel={end.clone()}/>
+                    <text x="90" y="46" class="touch-label">{"START"}</text>
+                </svg>
+
+                <svg class="ab-buttons" viewBox="0 0 120 120">
+                    <circle cx="35" cy="75" r="24" class={class(GbKey::B)}
+                        ontouchstart={start(GbKey::B)} ontouchend={end.clone()} ontouchcancel={end.clone()}/>
+                    <text x="35" y="80" class="touch-label">{"B"}</text>
+                    <circle cx="85" cy="45" r="24" class={class(GbKey::A)}
+                        ontouchstart={start(GbKey::A)} ontouchend={end.clone()} ontouchcancel={end}/>
+                    <text x="85" y="50" class="touch-label">{"A"}</text>
+                </svg>
+            </div>
+        }
+    }
+}
+
+fn rom_url() -> Option<String> {
+    let search = gloo::utils::window().location().search().ok()?;
+    web_sys::UrlSearchParams::new_with_str(&search).ok()?.get("rom")
+}
+
+// Servers other than the one hosting the page have to allow cross-origin requests.
+async fn fetch_rom(url: String) -> Option<Msg> {
+    let bytes = async {
+        let response = Request::get(&url).send().await.map_err(|e| e.to_string())?;
+        if !response.ok() {
+            return Err(format!("{} {}", response.status(), response.status_text()));
+        }
+        response.binary().await.map_err(|e| e.to_string())
+    }.await;
+
+    match bytes {
+        Ok(bytes) => Some(load_rom(bytes).await),
+        Err(e) => {
+            gloo::dialogs::alert(&format!("Failed to fetch ROM: {}", e));
+            None
+        },
+    }
+}
+
+// Opens the rom along with its save from browser storage, if there is one.
+async fn load_rom(bytes: Vec<u8>) -> Msg {
+    let title = match open_cartridge(bytes.clone(), None) {
+        Ok(cartridge) => cartridge.info().title,
+        Err(e) => return Msg::LoadError(cart_error_message(&e)),
+    };
+    let save = storage::load(&storage::save_key(&title)).await;
+    match open_cartridge(bytes, save) {
+        Ok(cartridge) => Msg::NewROM(cartridge),
+        Err(e) => Msg::LoadError(cart_error_message(&e)),
+    }
+}
+
+// Buttons held on any connected gamepad, None if there are none.
+fn gamepad_state() -> Option<[bool; 16]> {
+    let gamepads = gloo::utils::window().navigator().get_gamepads().ok()?;
+    let mut state = None;
+    // Empty slots are null.
+    for gamepad in gamepads.iter().filter_map(|g| g.dyn_into::<Gamepad>().ok()) {
+        if !gamepad.connected() {
+            continue;
+        }
+        let held = state.get_or_insert([false; 16]);
+        for (i, button) in gamepad.buttons().iter().take(16).enumerate() {
+            if let Ok(button) = button.dyn_into::<GamepadButton>() {
+                held[i] |= button.pressed();
+            }
+        }
+    }
+    state
+}
+
+fn changed_touches(event: &TouchEvent) -> Vec<i32> {
+    let touches = event.changed_touches();
+    (0..touches.length()).filter_map(|i| touches.get(i)).map(|touch| touch.identifier()).collect()
+}
+
+// Saves the recording through a temporary link to it.
+#[cfg(feature = "video-record")]
+fn download_video(bytes: &[u8], file_name: &str) -> Result<(), wasm_bindgen::JsValue> {
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(bytes));
+    let options = web_sys::BlobPropertyBag::new();
+    options.set_type(recorder::MIME_TYPE);
+    let blob = web_sys::Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob)?;
+
+    let link: web_sys::HtmlAnchorElement = document().create_element("a")?.unchecked_into();
+    link.set_href(&url);
+    link.set_download(file_name);
+    link.click();
+    web_sys::Url::revoke_object_url(&url)
 }
@@ -22,6 +22,20 @@ pub struct InfoProps {
     
     #[prop_or(AttrValue::from("Unknown"))]
     pub pallette: AttrValue,
+
+    // Muted audio channels 1-4, toggled through on_mute with the channel 0-3.
+    #[prop_or_default]
+    pub muted: [bool; 4],
+
+    #[prop_or_default]
+    pub on_mute: Callback<(u8, bool)>,
+
+    #[prop_or_default]
+    pub gamepad: bool,
+
+    // Deletes the save kept in the browser.
+    #[prop_or_default]
+    pub on_clear_save: Callback<()>,
 }
 
 #[function_component]
@@ -121,6 +135,38 @@ pub fn Panel(props: &InfoProps) -> Html {
                             {props.pallette.clone()}
                         </span>
                     </p>
+                    if props.gamepad {
+                        <p>
+                            {"Gamepad: "}
+                            <span style="float:right;">
+                                {"\u{1f3ae} Connected"}
+                            </span>
+                        </p>
+                    }
+                    if props.saveable {
+                        <button class="control-button" onclick={props.on_clear_save.reform(|_| ())}>
+                            {"\u{1f4be}\u{00a0}Clear Save"}
+                        </button>
+                    }
+                    <p>
+                        {"Mute: "}
+                        <span style="float:right;">
+                            { for (0..4_u8).map(|ch| {
+                                let on_mute = props.on_mute.clone();
+                                let muted = props.muted[ch as usize];
+                                html! {
+                                    <label>
+                                        <input
+                                            type="checkbox"
+                                            checked={muted}
+                                            onclick={move |_| on_mute.emit((ch, !muted))}
+                                        />
+                                        {format!("CH{}\u{00a0}", ch + 1)}
+                                    </label>
+                                }
+                            }) }
+                        </span>
+                    </p>
                 </div>
 
                 <div class="panel-content" id="about" style="display:none">
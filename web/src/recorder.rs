@@ -0,0 +1,118 @@
+use std::{cell::RefCell, rc::Rc};
+
+use js_sys::{Array, Promise, Uint8Array};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    Blob,
+    BlobEvent,
+    BlobPropertyBag,
+    HtmlCanvasElement,
+    MediaRecorder,
+    MediaRecorderOptions,
+    MediaStream,
+};
+
+const FRAME_RATE: f64 = 60.0;
+pub const MIME_TYPE: &str = "video/webm";
+
+// Records a media stream, normally the screen canvas, to WebM using MediaRecorder.
+pub struct VideoRecorder {
+    recorder:   MediaRecorder,
+    // Chunks of the file, delivered by the recorder as it encodes.
+    chunks:     Rc<RefCell<Vec<Blob>>>,
+    _on_data:   Closure<dyn FnMut(BlobEvent)>,
+}
+
+impl VideoRecorder {
+
+    pub fn from_canvas(canvas: &HtmlCanvasElement) -> Result<Self, JsValue> {
+        let stream = canvas.capture_stream_with_frame_request_rate(FRAME_RATE)?;
+        Self::start(&stream)
+    }
+
+    pub fn start(stream: &MediaStream) -> Result<Self, JsValue> {
+        let options = MediaRecorderOptions::new();
+        options.set_mime_type(MIME_TYPE);
+        let recorder = MediaRecorder::new_with_media_stream_and_media_recorder_options(stream, &options)?;
+
+        let chunks = Rc::new(RefCell::new(Vec::new()));
+        let on_data = {
+            let chunks = chunks.clone();
+            Closure::<dyn FnMut(BlobEvent)>::new(move |event: BlobEvent| {
+                if let Some(data) = event.data() {
+                    chunks.borrow_mut().push(data);
+                }
+            })
+        };
+        recorder.set_ondataavailable(Some(on_data.as_ref().unchecked_ref()));
+        recorder.start()?;
+
+        Ok(Self { recorder, chunks, _on_data: on_data })
+    }
+
+    // Stops recording, waiting for the recorder to deliver its last chunk before
+    // joining them into the complete file.
+    pub async fn stop(self) -> Result<Vec<u8>, JsValue> {
+        let stopped = Promise::new(&mut |resolve, _| {
+            self.recorder.set_onstop(Some(&resolve));
+        });
+        self.recorder.stop()?;
+        JsFuture::from(stopped).await?;
+
+        let parts: Array = self.chunks.borrow().iter().collect();
+        let options = BlobPropertyBag::new();
+        options.set_type(MIME_TYPE);
+        let blob = Blob::new_with_blob_sequence_and_options(&parts, &options)?;
+        let buffer = JsFuture::from(blob.array_buffer()).await?;
+        Ok(Uint8Array::new(&buffer).to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wasm_bindgen::{prelude::*, JsCast};
+    use wasm_bindgen_test::wasm_bindgen_test;
+    use super::VideoRecorder;
+
+    // Stand in for the browser's MediaRecorder, emitting two chunks when stopped.
+    #[wasm_bindgen(inline_js = "
+        export function install_mock_recorder() {
+            globalThis.MediaRecorder = class {
+                constructor(stream, options) {
+                    this.state = 'inactive';
+                    globalThis.mockRecorder = this;
+                }
+                start() { this.state = 'recording'; }
+                stop() {
+                    this.state = 'inactive';
+                    this.ondataavailable({ data: new Blob([new Uint8Array([0x1A, 0x45])]) });
+                    this.ondataavailable({ data: new Blob([new Uint8Array([0xDF, 0xA3])]) });
+                    this.onstop();
+                }
+            };
+        }
+
+        export function mock_recorder_state() {
+            return globalThis.mockRecorder.state;
+        }
+    ")]
+    extern "C" {
+        fn install_mock_recorder();
+        fn mock_recorder_state() -> String;
+    }
+
+    #[wasm_bindgen_test]
+    async fn start_stop() {
+        install_mock_recorder();
+        let stream = js_sys::Object::new().unchecked_into();
+
+        let recorder = VideoRecorder::start(&stream).unwrap();
+        assert_eq!(mock_recorder_state(), "recording");
+
+        let video = recorder.stop().await.unwrap();
+        assert_eq!(mock_recorder_state(), "inactive");
+        // The start of the WebM (EBML) header.
+        assert_eq!(video, vec![0x1A, 0x45, 0xDF, 0xA3]);
+    }
+}
@@ -0,0 +1,132 @@
+use std::cell::Cell;
+use futures::channel::oneshot;
+use gloo::storage::{LocalStorage, Storage};
+use js_sys::{Function, Uint8Array};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{DomException, IdbDatabase, IdbRequest, IdbTransactionMode};
+
+// Cartridge RAM kept in the browser, in IndexedDB or in local storage where
+// IndexedDB is not available.
+
+const DB_NAME: &str = "gameboy";
+const STORE: &str = "saves";
+
+pub fn save_key(rom_name: &str) -> String {
+    format!("{}_sram", rom_name)
+}
+
+pub async fn load(key: &str) -> Option<Vec<u8>> {
+    match open().await {
+        Ok(db) => {
+            let data = get(&db, key).await.ok().flatten();
+            db.close();
+            data
+        },
+        Err(_) => LocalStorage::get(key).ok(),
+    }
+}
+
+pub async fn store(key: &str, data: &[u8]) -> Result<(), String> {
+    match open().await {
+        Ok(db) => {
+            let result = put(&db, key, data).await.map_err(error_message);
+            db.close();
+            result
+        },
+        Err(_) => LocalStorage::set(key, data).map_err(|e| e.to_string()),
+    }
+}
+
+pub async fn delete(key: &str) {
+    match open().await {
+        Ok(db) => {
+            let _ = remove(&db, key).await;
+            db.close();
+        },
+        Err(_) => LocalStorage::delete(key),
+    }
+}
+
+async fn open() -> Result<IdbDatabase, JsValue> {
+    let factory = gloo::utils::window().indexed_db()?
+        .ok_or_else(|| JsValue::from_str("IndexedDB is not available"))?;
+    let request = factory.open_with_u32(DB_NAME, 1)?;
+
+    // Only called when the database is first created.
+    let upgrade = Closure::<dyn FnMut(JsValue)>::new(|event: JsValue| {
+        let db = event.dyn_into::<web_sys::Event>().ok()
+            .and_then(|e| e.target())
+            .and_then(|target| target.dyn_into::<IdbRequest>().ok())
+            .and_then(|request| request.result().ok())
+            .and_then(|db| db.dyn_into::<IdbDatabase>().ok());
+        if let Some(db) = db {
+            let _ = db.create_object_store(STORE);
+        }
+    });
+    request.set_onupgradeneeded(Some(upgrade.as_ref().unchecked_ref()));
+    let db = wait(&request).await;
+    request.set_onupgradeneeded(None);
+    db?.dyn_into()
+}
+
+async fn get(db: &IdbDatabase, key: &str) -> Result<Option<Vec<u8>>, JsValue> {
+    let store = db.transaction_with_str(STORE)?.object_store(STORE)?;
+    let value = wait(&store.get(&JsValue::from_str(key))?).await?;
+    Ok((!value.is_undefined()).then(|| Uint8Array::new(&value).to_vec()))
+}
+
+// Waits for the transaction rather than the request, as running out of space
+// aborts the transaction after the request itself has succeeded.
+async fn put(db: &IdbDatabase, key: &str, data: &[u8]) -> Result<(), JsValue> {
+    let transaction = db.transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)?;
+    transaction.object_store(STORE)?.put_with_key(&Uint8Array::from(data), &JsValue::from_str(key))?;
+    event(|f| {
+        transaction.set_oncomplete(f);
+        transaction.set_onerror(f);
+        transaction.set_onabort(f);
+    }).await;
+    match transaction.error() {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+async fn remove(db: &IdbDatabase, key: &str) -> Result<(), JsValue> {
+    let transaction = db.transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)?;
+    wait(&transaction.object_store(STORE)?.delete(&JsValue::from_str(key))?).await?;
+    Ok(())
+}
+
+// Result of the request once it has finished.
+async fn wait(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    event(|f| {
+        request.set_onsuccess(f);
+        request.set_onerror(f);
+    }).await;
+    match request.error()? {
+        Some(e) => Err(e.into()),
+        None => request.result(),
+    }
+}
+
+// Resolves once any of the handlers passed to listen is called, which are then removed.
+async fn event(listen: impl Fn(Option<&Function>)) {
+    let (tx, rx) = oneshot::channel();
+    let tx = Cell::new(Some(tx));
+    let callback = Closure::<dyn FnMut()>::new(move || {
+        if let Some(tx) = tx.take() {
+            let _ = tx.send(());
+        }
+    });
+    listen(Some(callback.as_ref().unchecked_ref()));
+    let _ = rx.await;
+    listen(None);
+}
+
+fn error_message(e: JsValue) -> String {
+    match e.dyn_ref::<DomException>() {
+        Some(e) if e.name() == "QuotaExceededError" => "There is not enough storage space to keep the save.".to_string(),
+        Some(e) => e.message(),
+        None => format!("{:?}", e),
+    }
+}